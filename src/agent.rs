@@ -0,0 +1,679 @@
+//! `syncz agent`: one long-running process for the jobs in a job file that
+//! should happen on their own, watching the `watch = true` ones and running
+//! the ones with `every` on schedule. `syncz ctl` talks to it over a unix
+//! socket to show, pause, resume or trigger jobs.
+
+use crate::{
+    data_dir, default_jobs_path, format_size, job_argv, parse_job_file, run_job, AgentArgs,
+    CtlArgs, CtlCommand, Job, JobOutcome, JobResult,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How long a watcher that exited waits before it's started again.
+const WATCH_RESTART_DELAY: Duration = Duration::from_secs(10);
+
+// Log lines kept for `ctl status` and the dashboard.
+const RECENT_LOG_LINES: usize = 100;
+static RECENT_LOG: Mutex<VecDeque<String>> = Mutex::new(VecDeque::new());
+// Set while the dashboard owns the terminal.
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn socket_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("agent.sock"))
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub(crate) enum Request {
+    Status,
+    Pause { job: Option<String> },
+    Resume { job: Option<String> },
+    Trigger { job: String },
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct LastRun {
+    pub(crate) finished: String,
+    pub(crate) ok: bool,
+    pub(crate) bytes: u64,
+    pub(crate) error: Option<String>,
+}
+
+impl LastRun {
+    fn new(bytes: u64, error: Option<String>) -> Self {
+        LastRun {
+            finished: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+            ok: error.is_none(),
+            bytes,
+            error,
+        }
+    }
+}
+
+pub(crate) struct AgentJob {
+    pub(crate) job: Job,
+    // Seconds between scheduled runs.
+    pub(crate) every: Option<u64>,
+    pub(crate) paused: bool,
+    // A one-off run (scheduled or triggered) is in progress.
+    pub(crate) running: bool,
+    pub(crate) due: Option<Instant>,
+    // Set by `ctl trigger`; runs once even while paused.
+    pub(crate) triggered: bool,
+    watcher: Option<Child>,
+    restart_at: Option<Instant>,
+    pub(crate) last: Option<LastRun>,
+    // Files the watcher saw change that haven't been synced yet.
+    pub(crate) queued: usize,
+    // Files changed on both sides, from the last sync that found any.
+    pub(crate) conflicts: Vec<String>,
+    // The transfer in progress: percent done and rsync's current speed.
+    pub(crate) progress: Option<(u64, String)>,
+}
+
+impl AgentJob {
+    pub(crate) fn new(job: Job, now: Instant) -> Result<Self> {
+        let every = job
+            .every
+            .as_deref()
+            .map(crate::schedule::parse_interval)
+            .transpose()?;
+        Ok(AgentJob {
+            // The first scheduled run happens when the agent starts.
+            due: every.map(|_| now),
+            every,
+            job,
+            paused: false,
+            running: false,
+            triggered: false,
+            watcher: None,
+            restart_at: None,
+            last: None,
+            queued: 0,
+            conflicts: Vec::new(),
+            progress: None,
+        })
+    }
+
+    // Follows one JSON event printed by the job's syncz.
+    pub(crate) fn observe(&mut self, line: &str) {
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+            return;
+        };
+        match event["event"].as_str() {
+            Some("watch") => {
+                self.queued += event["changed"].as_array().map_or(0, Vec::len);
+            }
+            Some("progress") => {
+                self.progress = event["percent"]
+                    .as_u64()
+                    .map(|percent| (percent, event["speed"].as_str().unwrap_or("").to_string()));
+            }
+            Some("conflicts") => {
+                self.conflicts = event["paths"]
+                    .as_array()
+                    .map(|paths| {
+                        paths
+                            .iter()
+                            .filter_map(|p| p.as_str().map(str::to_string))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                if !self.conflicts.is_empty() {
+                    log(&format!(
+                        "{}: {} conflict(s): {}",
+                        self.job.name,
+                        self.conflicts.len(),
+                        self.conflicts.join(", ")
+                    ));
+                }
+            }
+            Some("transfer") | Some("error") => {
+                let mut outcome = JobOutcome::default();
+                outcome.observe(line);
+                if self.job.watch {
+                    log(&format!(
+                        "{}: {}",
+                        self.job.name,
+                        match &outcome.error {
+                            None => format!("synced {}", format_size(outcome.bytes)),
+                            Some(e) => format!("failed: {}", e),
+                        }
+                    ));
+                }
+                self.last = Some(LastRun::new(outcome.bytes, outcome.error));
+                self.queued = 0;
+                self.progress = None;
+            }
+            _ => {}
+        }
+    }
+
+    fn mode(&self) -> String {
+        match self.every {
+            _ if self.job.watch => "watch".to_string(),
+            Some(secs) => format!("every {}", crate::schedule::format_interval(secs)),
+            None => "manual".to_string(),
+        }
+    }
+
+    fn state(&self) -> &'static str {
+        if self.running {
+            "running"
+        } else if self.paused {
+            "paused"
+        } else if self.watcher.is_some() {
+            "watching"
+        } else {
+            "idle"
+        }
+    }
+
+    fn status(&self, now: Instant) -> serde_json::Value {
+        let next = match (self.paused, self.due) {
+            (false, Some(due)) => Some(due.saturating_duration_since(now).as_secs()),
+            _ => None,
+        };
+        json!({
+            "job": self.job.name,
+            "mode": self.mode(),
+            "state": self.state(),
+            "next_run_secs": next,
+            "last_run": self.last,
+            "queued": self.queued,
+            "conflicts": self.conflicts,
+            "percent": self.progress.as_ref().map(|(percent, _)| percent),
+            "speed": self.progress.as_ref().map(|(_, speed)| speed),
+        })
+    }
+}
+
+// Applies a control request to the jobs; the reply goes back over the socket.
+pub(crate) fn handle(jobs: &mut [AgentJob], request: &Request, now: Instant) -> serde_json::Value {
+    let select = |jobs: &mut [AgentJob], name: Option<&String>| -> Result<Vec<usize>> {
+        match name {
+            None => Ok((0..jobs.len()).collect()),
+            Some(name) => jobs
+                .iter()
+                .position(|j| &j.job.name == name)
+                .map(|i| vec![i])
+                .ok_or_else(|| anyhow!("no job named '{}'", name)),
+        }
+    };
+    let result = match request {
+        Request::Status | Request::Stop => Ok(()),
+        Request::Pause { job } | Request::Resume { job } => {
+            let pause = matches!(request, Request::Pause { .. });
+            select(jobs, job.as_ref()).map(|indices| {
+                for i in indices {
+                    jobs[i].paused = pause;
+                }
+            })
+        }
+        Request::Trigger { job } => select(jobs, Some(job)).map(|indices| {
+            for i in indices {
+                jobs[i].triggered = true;
+            }
+        }),
+    };
+    match result {
+        Ok(()) => json!({
+            "ok": true,
+            "jobs": jobs.iter().map(|j| j.status(now)).collect::<Vec<_>>(),
+            "log": RECENT_LOG.lock().map(|log| log.iter().cloned().collect::<Vec<_>>()).unwrap_or_default(),
+        }),
+        Err(e) => json!({ "ok": false, "error": format!("{:#}", e) }),
+    }
+}
+
+enum Update {
+    Finished(usize, JobResult),
+    Event(usize, String),
+}
+
+pub(crate) fn run(opts: &AgentArgs) -> Result<()> {
+    let socket = socket_path()?;
+    let running = UnixStream::connect(&socket).is_ok();
+    if running && opts.tui {
+        return crate::dashboard::show(&socket, false);
+    }
+    if running {
+        bail!("an agent is already running ({})", socket.display());
+    }
+    let file = match &opts.file {
+        Some(file) => file.clone(),
+        None => default_jobs_path()?,
+    };
+    let file = std::path::absolute(&file)?;
+    let config = opts
+        .config
+        .as_deref()
+        .map(std::path::absolute)
+        .transpose()?;
+    let contents =
+        fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+    let jobs = parse_job_file(&contents)
+        .with_context(|| format!("invalid job file {}", file.display()))?;
+    let now = Instant::now();
+    let mut jobs = jobs
+        .jobs
+        .into_iter()
+        .map(|job| AgentJob::new(job, now))
+        .collect::<Result<Vec<_>>>()?;
+
+    if let Some(parent) = socket.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let _ = fs::remove_file(&socket);
+
+    if opts.detach {
+        return detach(&file, config.as_deref());
+    }
+
+    let listener = UnixListener::bind(&socket)
+        .with_context(|| format!("failed to listen on {}", socket.display()))?;
+    listener.set_nonblocking(true)?;
+    let dir = file
+        .parent()
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let managed = jobs
+        .iter()
+        .filter(|j| j.job.watch || j.every.is_some())
+        .count();
+    // The dashboard owns the terminal; the log shows up in it instead.
+    QUIET.store(opts.tui, Ordering::Relaxed);
+    log(&format!(
+        "agent started with {} job(s) from {} ({} watched or scheduled); control it with `syncz ctl`",
+        jobs.len(),
+        file.display(),
+        managed
+    ));
+
+    let mut agent = move || {
+        let (tx, rx) = mpsc::channel();
+        let result = serve(
+            &listener,
+            &mut jobs,
+            &exe,
+            &dir,
+            config.as_deref(),
+            &tx,
+            &rx,
+        );
+        for job in &mut jobs {
+            stop_watcher(job);
+        }
+        log("agent stopped");
+        result
+    };
+    let result = if opts.tui {
+        // The agent serves the dashboard like any other client, and stops
+        // with it.
+        let agent = std::thread::spawn(agent);
+        let shown = crate::dashboard::show(&socket, true);
+        let _ = send(&socket, &Request::Stop);
+        let stopped = agent
+            .join()
+            .unwrap_or_else(|_| Err(anyhow!("the agent panicked")));
+        shown.and(stopped)
+    } else {
+        agent()
+    };
+    let _ = fs::remove_file(&socket);
+    result
+}
+
+// Starts the agent again as its own process group, so closing the terminal
+// or pressing Ctrl-C in it leaves it running, with output in agent.log.
+fn detach(file: &Path, config: Option<&Path>) -> Result<()> {
+    let log_path = data_dir()?.join("agent.log");
+    let log_file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&log_path)
+        .with_context(|| format!("failed to open {}", log_path.display()))?;
+    let mut argv = vec!["agent".to_string(), "--file".to_string()];
+    argv.push(file.display().to_string());
+    if let Some(config) = config {
+        argv.push("--config".to_string());
+        argv.push(config.display().to_string());
+    }
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let child = Command::new(exe)
+        .args(&argv)
+        .stdin(Stdio::null())
+        .stdout(log_file.try_clone()?)
+        .stderr(log_file)
+        .process_group(0)
+        .spawn()
+        .with_context(|| "failed to start the agent")?;
+    println!(
+        "Agent started (pid {}); log in {}",
+        child.id(),
+        log_path.display()
+    );
+    Ok(())
+}
+
+fn serve(
+    listener: &UnixListener,
+    jobs: &mut [AgentJob],
+    exe: &Path,
+    dir: &Path,
+    config: Option<&Path>,
+    tx: &Sender<Update>,
+    rx: &Receiver<Update>,
+) -> Result<()> {
+    loop {
+        while let Ok((stream, _)) = listener.accept() {
+            match answer(stream, jobs) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => log(&format!("control request failed: {:#}", e)),
+            }
+        }
+        while let Ok(update) = rx.try_recv() {
+            match update {
+                Update::Finished(i, result) => {
+                    let job = &mut jobs[i];
+                    job.running = false;
+                    log(&format!(
+                        "{}: {}",
+                        job.job.name,
+                        describe(&result.outcome, result.duration)
+                    ));
+                    job.last = Some(LastRun::new(result.outcome.bytes, result.outcome.error));
+                }
+                Update::Event(i, line) => jobs[i].observe(&line),
+            }
+        }
+        let now = Instant::now();
+        for (i, job) in jobs.iter_mut().enumerate() {
+            if job.job.watch {
+                tend_watcher(i, job, exe, dir, config, tx, now);
+            }
+            let due = job.due.is_some_and(|due| due <= now) && !job.paused;
+            if job.running || !(due || job.triggered) {
+                continue;
+            }
+            if due {
+                job.due = job.every.map(|secs| now + Duration::from_secs(secs));
+            }
+            job.triggered = false;
+            job.running = true;
+            log(&format!("{}: starting", job.job.name));
+            let (tx, exe, dir) = (tx.clone(), exe.to_path_buf(), dir.to_path_buf());
+            let (config, one_off) = (config.map(Path::to_path_buf), job.job.clone());
+            std::thread::spawn(move || {
+                let bar = ProgressBar::hidden();
+                let on_event = |line: &str| {
+                    let _ = tx.send(Update::Event(i, line.to_string()));
+                };
+                let result = run_job(&exe, &dir, &one_off, config.as_deref(), &bar, &on_event);
+                let _ = tx.send(Update::Finished(i, result));
+            });
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+// Reads one request and writes the reply. Returns whether the agent should
+// stop.
+fn answer(stream: UnixStream, jobs: &mut [AgentJob]) -> Result<bool> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut line = String::new();
+    BufReader::new(&stream).read_line(&mut line)?;
+    let reply = match serde_json::from_str::<Request>(&line) {
+        Ok(request) => {
+            let reply = handle(jobs, &request, Instant::now());
+            if !matches!(request, Request::Status) && reply["ok"] == true {
+                log(&format!("control: {}", line.trim()));
+            }
+            writeln!(&stream, "{}", reply)?;
+            return Ok(matches!(request, Request::Stop));
+        }
+        Err(e) => json!({ "ok": false, "error": format!("bad request: {}", e) }),
+    };
+    writeln!(&stream, "{}", reply)?;
+    Ok(false)
+}
+
+// Keeps a watch job's `syncz watch` running while the job isn't paused,
+// restarting it a little after it exits.
+fn tend_watcher(
+    i: usize,
+    job: &mut AgentJob,
+    exe: &Path,
+    dir: &Path,
+    config: Option<&Path>,
+    tx: &Sender<Update>,
+    now: Instant,
+) {
+    if job.paused {
+        stop_watcher(job);
+        return;
+    }
+    if let Some(child) = &mut job.watcher {
+        match child.try_wait() {
+            Ok(None) => return,
+            Ok(Some(status)) => {
+                log(&format!("{}: watcher exited ({})", job.job.name, status));
+                if !status.success() {
+                    job.last = Some(LastRun::new(
+                        0,
+                        Some(format!("watcher exited ({})", status)),
+                    ));
+                }
+            }
+            Err(e) => log(&format!("{}: lost the watcher: {}", job.job.name, e)),
+        }
+        job.watcher = None;
+        job.restart_at = Some(now + WATCH_RESTART_DELAY);
+    }
+    if job.restart_at.is_some_and(|at| at > now) {
+        return;
+    }
+    let spawned = Command::new(exe)
+        .args(watch_argv(&job.job, config))
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn();
+    match spawned {
+        Ok(mut child) => {
+            log(&format!("{}: watching", job.job.name));
+            if let Some(stdout) = child.stdout.take() {
+                let tx = tx.clone();
+                std::thread::spawn(move || {
+                    for line in BufReader::new(stdout).lines().map_while(|l| l.ok()) {
+                        let _ = tx.send(Update::Event(i, line));
+                    }
+                });
+            }
+            job.watcher = Some(child);
+            job.restart_at = None;
+        }
+        Err(e) => {
+            log(&format!(
+                "{}: failed to start watching: {}",
+                job.job.name, e
+            ));
+            job.restart_at = Some(now + WATCH_RESTART_DELAY);
+        }
+    }
+}
+
+fn stop_watcher(job: &mut AgentJob) {
+    if let Some(mut child) = job.watcher.take() {
+        let _ = child.kill();
+        let _ = child.wait();
+        log(&format!("{}: stopped watching", job.job.name));
+    }
+}
+
+// `syncz watch` for a watch job: its one-off command with `watch` in place
+// of `push`.
+pub(crate) fn watch_argv(job: &Job, config: Option<&Path>) -> Vec<String> {
+    let mut argv = job_argv(job, config);
+    argv[0] = "watch".to_string();
+    argv
+}
+
+fn describe(outcome: &JobOutcome, duration: Duration) -> String {
+    match &outcome.error {
+        None => format!("ok, {} in {:.2?}", format_size(outcome.bytes), duration),
+        Some(e) => format!("failed: {}", e),
+    }
+}
+
+fn log(message: &str) {
+    let line = format!(
+        "{} {}",
+        chrono::Local::now().format("%Y-%m-%d %H:%M:%S"),
+        message
+    );
+    if !QUIET.load(Ordering::Relaxed) {
+        println!("{}", line);
+    }
+    if let Ok(mut recent) = RECENT_LOG.lock() {
+        if recent.len() == RECENT_LOG_LINES {
+            recent.pop_front();
+        }
+        recent.push_back(line);
+    }
+}
+
+// Sends one request to the running agent and returns its reply.
+pub(crate) fn send(socket: &Path, request: &Request) -> Result<serde_json::Value> {
+    let stream = UnixStream::connect(socket)
+        .map_err(|_| anyhow!("no agent is running; start one with `syncz agent`"))?;
+    stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+    writeln!(&stream, "{}", serde_json::to_string(request)?)?;
+    let mut line = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut line)
+        .with_context(|| "no answer from the agent")?;
+    let reply: serde_json::Value =
+        serde_json::from_str(&line).with_context(|| "unreadable answer from the agent")?;
+    if reply["ok"] != true {
+        bail!("{}", reply["error"].as_str().unwrap_or("the agent refused"));
+    }
+    Ok(reply)
+}
+
+pub(crate) fn ctl(opts: &CtlArgs) -> Result<()> {
+    let request = match &opts.command {
+        CtlCommand::Status => Request::Status,
+        CtlCommand::Pause { job } => Request::Pause { job: job.clone() },
+        CtlCommand::Resume { job } => Request::Resume { job: job.clone() },
+        CtlCommand::Trigger { job } => Request::Trigger { job: job.clone() },
+        CtlCommand::Stop => Request::Stop,
+    };
+    let reply = send(&socket_path()?, &request)?;
+    if opts.json {
+        println!("{}", reply);
+        return Ok(());
+    }
+    match request {
+        Request::Status => println!("{}", format_status(&reply["jobs"])),
+        Request::Pause { job } => println!("Paused {}", job.as_deref().unwrap_or("all jobs")),
+        Request::Resume { job } => println!("Resumed {}", job.as_deref().unwrap_or("all jobs")),
+        Request::Trigger { job } => println!("Started {}", job),
+        Request::Stop => println!("Agent stopping"),
+    }
+    Ok(())
+}
+
+// "✅ 10:00 1.20 MB (next in 42m 10s)" for a job in a status reply.
+pub(crate) fn last_run_text(job: &serde_json::Value) -> String {
+    let last = &job["last_run"];
+    let mut text = match last["ok"].as_bool() {
+        None => "never".to_string(),
+        Some(true) => format!(
+            "✅ {} {}",
+            short_time(last["finished"].as_str().unwrap_or("")),
+            format_size(last["bytes"].as_u64().unwrap_or(0))
+        ),
+        Some(false) => format!(
+            "❌ {} {}",
+            short_time(last["finished"].as_str().unwrap_or("")),
+            last["error"].as_str().unwrap_or("")
+        ),
+    };
+    if let Some(secs) = job["next_run_secs"].as_u64() {
+        text.push_str(&format!(
+            " (next in {})",
+            crate::format_duration(secs * 1000)
+        ));
+    }
+    text
+}
+
+pub(crate) fn format_status(jobs: &serde_json::Value) -> String {
+    let rows: Vec<[String; 4]> = jobs
+        .as_array()
+        .map(Vec::as_slice)
+        .unwrap_or_default()
+        .iter()
+        .map(|job| {
+            let text = |key: &str| job[key].as_str().unwrap_or("").to_string();
+            let last_run = last_run_text(job);
+            [text("job"), text("mode"), text("state"), last_run]
+        })
+        .collect();
+    if rows.is_empty() {
+        return "No jobs".to_string();
+    }
+    let header = ["JOB", "MODE", "STATE", "LAST RUN"].map(String::from);
+    let widths: Vec<usize> = (0..3)
+        .map(|c| {
+            std::iter::once(&header)
+                .chain(&rows)
+                .map(|r| r[c].chars().count())
+                .max()
+                .unwrap_or(0)
+        })
+        .collect();
+    std::iter::once(&header)
+        .chain(&rows)
+        .map(|r| {
+            format!(
+                "{:w0$}  {:w1$}  {:w2$}  {}",
+                r[0],
+                r[1],
+                r[2],
+                r[3],
+                w0 = widths[0],
+                w1 = widths[1],
+                w2 = widths[2]
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+// "2024-05-01T10:00:00+02:00" -> "10:00".
+fn short_time(rfc3339: &str) -> String {
+    chrono::DateTime::parse_from_rfc3339(rfc3339)
+        .map(|t| t.format("%H:%M").to_string())
+        .unwrap_or_default()
+}
@@ -0,0 +1,347 @@
+//! `syncz bench`: times rsync against a host with a few option sets on a
+//! synthetic payload (half text, half random bytes), first as new files and
+//! then as files with small changes, and suggests the host settings that
+//! came out fastest. The results are kept so later syncs can use them.
+
+use crate::{
+    data_dir, emit, format_rate, format_size, remote_rsync, shell_escape, ssh_args, ssh_command,
+    Args, Backend, CommandRunner,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Size of each of the two payload files.
+const FILE_BYTES: usize = 16 << 20;
+
+// The edit made to the changed copy: this many bytes every MiB.
+const EDIT_BYTES: usize = 64;
+
+// A setting is only worth recommending when it beats the default by this much.
+const MARGIN: f64 = 1.1;
+
+pub(crate) struct Variant {
+    pub(crate) label: &'static str,
+    pub(crate) flags: &'static [&'static str],
+    // Sent over an existing copy with small changes rather than as new files.
+    pub(crate) update: bool,
+}
+
+// The first variant of each phase is what a sync does by default.
+pub(crate) const VARIANTS: &[Variant] = &[
+    Variant {
+        label: "new files, -z",
+        flags: &["-z"],
+        update: false,
+    },
+    Variant {
+        label: "new files, no -z",
+        flags: &[],
+        update: false,
+    },
+    Variant {
+        label: "changed files, -z",
+        flags: &["-z"],
+        update: true,
+    },
+    Variant {
+        label: "changed files, no -z",
+        flags: &[],
+        update: true,
+    },
+    Variant {
+        label: "changed files, --block-size=128K",
+        flags: &["--block-size=131072"],
+        update: true,
+    },
+    Variant {
+        label: "changed files, --whole-file",
+        flags: &["--whole-file"],
+        update: true,
+    },
+];
+
+// What `bench` measured for a host, kept in the data directory.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct BenchRecord {
+    pub(crate) timestamp: String,
+    // New files sent with and without -z, in bytes per second.
+    pub(crate) compressed_rate: u64,
+    pub(crate) uncompressed_rate: u64,
+    pub(crate) recommended: Vec<String>,
+}
+
+pub(crate) fn bench_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("bench.json"))
+}
+
+pub(crate) fn load_records(path: &Path) -> Result<BTreeMap<String, BenchRecord>> {
+    if !path.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn save_record(host: &str, record: BenchRecord) -> Result<()> {
+    let path = bench_path()?;
+    let mut records = load_records(&path)?;
+    records.insert(host.to_string(), record);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(&path, serde_json::to_string_pretty(&records)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+pub(crate) fn run(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<()> {
+    if args.backend.is_some_and(|b| b != Backend::Rsync) {
+        bail!("bench measures rsync; it doesn't support --backend");
+    }
+    let local = std::env::temp_dir().join(format!("syncz-bench-{}", std::process::id()));
+    if !args.json {
+        eprintln!(
+            "Benchmarking {} with {} of text and random data...",
+            host,
+            format_size(2 * FILE_BYTES as u64)
+        );
+    }
+    let remote = remote_dir(runner, host, args)?;
+    let result = write_payload(&local).and_then(|()| measure(runner, host, &local, &remote, args));
+    let _ = fs::remove_dir_all(&local);
+    let _ = remote_command(
+        runner,
+        host,
+        args,
+        &format!("rm -rf {}", shell_escape(&remote)),
+    );
+    let timings = result?;
+
+    let payload = 2 * FILE_BYTES as u64;
+    let rate = |elapsed: Duration| payload as f64 / elapsed.as_secs_f64().max(0.001);
+    let recommended = recommend(&timings);
+    if args.json {
+        let results: Vec<serde_json::Value> = VARIANTS
+            .iter()
+            .zip(&timings)
+            .map(|(variant, elapsed)| {
+                json!({
+                    "label": variant.label,
+                    "flags": variant.flags,
+                    "ms": elapsed.as_millis() as u64,
+                    "bytes_per_sec": rate(*elapsed) as u64,
+                })
+            })
+            .collect();
+        emit(json!({
+            "event": "bench",
+            "host": host,
+            "payload_bytes": payload,
+            "results": results,
+            "recommended": recommended,
+        }));
+    } else {
+        for (variant, elapsed) in VARIANTS.iter().zip(&timings) {
+            println!(
+                "  {:<34}{:>8.2}s  {}",
+                variant.label,
+                elapsed.as_secs_f64(),
+                format_rate(rate(*elapsed))
+            );
+        }
+        if recommended.is_empty() {
+            println!("The defaults are the fastest settings for {}", host);
+        } else {
+            println!("Recommended settings for {} (in the config file):", host);
+            println!("[hosts.{}]", host);
+            for line in config_lines(&recommended) {
+                println!("{}", line);
+            }
+        }
+    }
+    save_record(
+        host,
+        BenchRecord {
+            timestamp: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+            compressed_rate: rate(timings[0]) as u64,
+            uncompressed_rate: rate(timings[1]) as u64,
+            recommended,
+        },
+    )
+}
+
+// Both copies of the payload, `a/` and `b/`, differ only in a few bytes per
+// MiB. The text file compresses about as well as source code; the random one
+// not at all.
+fn write_payload(dir: &Path) -> Result<()> {
+    let mut text = String::with_capacity(FILE_BYTES + 64);
+    let mut i = 0;
+    while text.len() < FILE_BYTES {
+        text.push_str(&format!(
+            "{:>8} INFO worker {} finished batch {} in {} ms\n",
+            i,
+            i % 16,
+            i / 16,
+            i * 7 % 1000
+        ));
+        i += 1;
+    }
+    text.truncate(FILE_BYTES);
+    let mut random = vec![0; FILE_BYTES];
+    blake3::Hasher::new()
+        .update(b"syncz bench")
+        .finalize_xof()
+        .fill(&mut random);
+
+    for (name, data) in [("text.dat", text.into_bytes()), ("random.dat", random)] {
+        for (version, contents) in [("a", data.clone()), ("b", edited(&data))] {
+            let path = dir.join(version).join(name);
+            fs::create_dir_all(dir.join(version))
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            fs::write(&path, contents)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn edited(data: &[u8]) -> Vec<u8> {
+    let mut copy = data.to_vec();
+    for chunk in copy.chunks_mut(1 << 20) {
+        let n = EDIT_BYTES.min(chunk.len());
+        for byte in &mut chunk[..n] {
+            *byte = !*byte;
+        }
+    }
+    copy
+}
+
+// New files go to a fresh directory per variant. Changed files all go to the
+// first one, alternating between the two copies so each run has the same
+// edits to send.
+fn measure(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local: &Path,
+    remote: &str,
+    args: &Args,
+) -> Result<Vec<Duration>> {
+    let mut timings = Vec::new();
+    let mut updates = 0;
+    for (i, variant) in VARIANTS.iter().enumerate() {
+        let (source, dest) = if variant.update {
+            updates += 1;
+            let version = if updates % 2 == 1 { "b" } else { "a" };
+            (local.join(version), format!("{}/0/", remote))
+        } else {
+            (local.join("a"), format!("{}/{}/", remote, i))
+        };
+        let mut cmd_args = vec!["-a".to_string()];
+        if variant.update {
+            // Both copies have the same size and possibly the same mtime.
+            cmd_args.push("--ignore-times".to_string());
+        }
+        cmd_args.extend(variant.flags.iter().map(|f| f.to_string()));
+        cmd_args.push("-e".to_string());
+        cmd_args.push(ssh_command(args));
+        if let Some(program) = remote_rsync(args) {
+            cmd_args.push(format!("--rsync-path={}", program));
+        }
+        cmd_args.push(format!("{}/", source.display()));
+        cmd_args.push(format!("{}:{}", host, dest));
+        if !args.json {
+            eprintln!("Timing {}...", variant.label);
+        }
+        let start = Instant::now();
+        let output = runner
+            .output("rsync", &cmd_args)
+            .with_context(|| "failed to run rsync")?;
+        let elapsed = start.elapsed();
+        if !output.status.success() {
+            bail!(
+                "rsync to {} failed: {}",
+                host,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        timings.push(elapsed);
+    }
+    Ok(timings)
+}
+
+// The flags that beat the defaults by a clear margin: compression from the
+// new files, delta tuning from the changed ones sent without -z.
+pub(crate) fn recommend(timings: &[Duration]) -> Vec<String> {
+    let faster = |a: Duration, b: Duration| a.as_secs_f64() * MARGIN < b.as_secs_f64();
+    let mut flags = Vec::new();
+    if faster(timings[1], timings[0]) {
+        flags.push("--no-compress".to_string());
+    }
+    let best = VARIANTS[4..]
+        .iter()
+        .zip(&timings[4..])
+        .min_by(|a, b| a.1.cmp(b.1))
+        .filter(|(_, elapsed)| faster(**elapsed, timings[3]));
+    if let Some((variant, _)) = best {
+        flags.extend(variant.flags.iter().map(|f| f.to_string()));
+    }
+    flags
+}
+
+// The `[hosts.HOST]` lines that make a sync use `flags`.
+pub(crate) fn config_lines(flags: &[String]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut rsync_args = Vec::new();
+    for flag in flags {
+        match flag.as_str() {
+            "--no-compress" => lines.push("compress = false".to_string()),
+            "--whole-file" => lines.push("whole_file = true".to_string()),
+            _ if flag.starts_with("--block-size=") => {
+                lines.push(format!("block_size = {}", &flag["--block-size=".len()..]))
+            }
+            _ => rsync_args.push(format!("\"{}\"", flag)),
+        }
+    }
+    if !rsync_args.is_empty() {
+        lines.push(format!("rsync_args = [{}]", rsync_args.join(", ")));
+    }
+    lines
+}
+
+fn remote_dir(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<String> {
+    let output = remote_command(
+        runner,
+        host,
+        args,
+        "mktemp -d \"${TMPDIR:-/tmp}/syncz-bench.XXXXXX\"",
+    )?;
+    let dir = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || dir.is_empty() {
+        bail!(
+            "couldn't create a scratch directory on {}: {}",
+            host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(dir)
+}
+
+fn remote_command(
+    runner: &dyn CommandRunner,
+    host: &str,
+    args: &Args,
+    command: &str,
+) -> Result<std::process::Output> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(command.to_string());
+    runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")
+}
@@ -0,0 +1,265 @@
+//! `syncz agent --tui`: a full-screen view of the agent's jobs (state,
+//! queued changes, live transfer speed, last run), conflict alerts and the
+//! recent log. It is a client of the control socket like `syncz ctl`, so it
+//! works the same for an agent it started and for one already running.
+
+use crate::agent::{last_run_text, send, Request};
+use anyhow::Result;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Cell, Paragraph, Row, Table, TableState};
+use ratatui::{DefaultTerminal, Frame};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+const REFRESH: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+pub(crate) struct View {
+    // The agent's last status reply.
+    pub(crate) reply: serde_json::Value,
+    pub(crate) selected: usize,
+    // Outcome of the last key press, or why the agent can't be reached.
+    pub(crate) message: Option<String>,
+    // Quitting also stops the agent.
+    pub(crate) owned: bool,
+}
+
+impl View {
+    fn jobs(&self) -> &[serde_json::Value] {
+        self.reply["jobs"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    fn selected_job(&self) -> Option<&serde_json::Value> {
+        self.jobs().get(self.selected)
+    }
+}
+
+pub(crate) fn show(socket: &Path, owned: bool) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = run(&mut terminal, socket, owned);
+    ratatui::restore();
+    result
+}
+
+fn run(terminal: &mut DefaultTerminal, socket: &Path, owned: bool) -> Result<()> {
+    let mut view = View {
+        owned,
+        ..View::default()
+    };
+    let mut refreshed: Option<Instant> = None;
+    loop {
+        if refreshed.is_none_or(|t| t.elapsed() >= REFRESH) {
+            match send(socket, &Request::Status) {
+                Ok(reply) => view.reply = reply,
+                Err(e) => view.message = Some(format!("{:#}", e)),
+            }
+            view.selected = view.selected.min(view.jobs().len().saturating_sub(1));
+            refreshed = Some(Instant::now());
+        }
+        terminal.draw(|frame| draw(frame, &view))?;
+        if !event::poll(Duration::from_millis(100))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let name = view
+            .selected_job()
+            .and_then(|job| job["job"].as_str())
+            .map(str::to_string);
+        let action = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            KeyCode::Up | KeyCode::Char('k') => {
+                view.selected = view.selected.saturating_sub(1);
+                None
+            }
+            KeyCode::Down | KeyCode::Char('j') => {
+                view.selected = (view.selected + 1).min(view.jobs().len().saturating_sub(1));
+                None
+            }
+            KeyCode::Char('p') => name.map(|job| {
+                let paused = view.selected_job().is_some_and(|j| j["state"] == "paused");
+                if paused {
+                    (
+                        Request::Resume {
+                            job: Some(job.clone()),
+                        },
+                        format!("Resumed {}", job),
+                    )
+                } else {
+                    (
+                        Request::Pause {
+                            job: Some(job.clone()),
+                        },
+                        format!("Paused {}", job),
+                    )
+                }
+            }),
+            KeyCode::Char('t') => name.map(|job| {
+                (
+                    Request::Trigger { job: job.clone() },
+                    format!("Started {}", job),
+                )
+            }),
+            _ => None,
+        };
+        if let Some((action, done)) = action {
+            view.message = Some(match send(socket, &action) {
+                Ok(_) => done,
+                Err(e) => format!("{:#}", e),
+            });
+            refreshed = None;
+        }
+    }
+}
+
+pub(crate) fn draw(frame: &mut Frame, view: &View) {
+    let jobs = view.jobs();
+    let alerts: Vec<Line> = jobs
+        .iter()
+        .filter_map(|job| {
+            let conflicts: Vec<&str> = job["conflicts"]
+                .as_array()?
+                .iter()
+                .filter_map(|p| p.as_str())
+                .collect();
+            (!conflicts.is_empty()).then(|| {
+                Line::from(format!(
+                    "⚠ {}: {} file(s) changed on both sides: {}",
+                    job["job"].as_str().unwrap_or(""),
+                    conflicts.len(),
+                    conflicts.join(", ")
+                ))
+                .style(Style::new().fg(Color::Red))
+            })
+        })
+        .collect();
+    let [jobs_area, alerts_area, log_area, help_area] = Layout::vertical([
+        Constraint::Length(jobs.len().max(1) as u16 + 3),
+        Constraint::Length(alerts.len().max(1) as u16 + 2),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let rows = jobs.iter().map(|job| {
+        let text = |key: &str| job[key].as_str().unwrap_or("").to_string();
+        let state = text("state");
+        let state_style = match state.as_str() {
+            "running" => Style::new().fg(Color::Yellow),
+            "watching" => Style::new().fg(Color::Green),
+            "paused" => Style::new().fg(Color::DarkGray),
+            _ => Style::new(),
+        };
+        let queued = job["queued"].as_u64().unwrap_or(0);
+        let transfer = match (job["percent"].as_u64(), job["speed"].as_str()) {
+            (Some(percent), Some(speed)) => format!("{}% {}", percent, speed),
+            _ => "-".to_string(),
+        };
+        let failed = job["last_run"]["ok"] == false;
+        Row::new([
+            Cell::from(text("job")),
+            Cell::from(text("mode")),
+            Cell::from(state).style(state_style),
+            Cell::from(if queued > 0 {
+                queued.to_string()
+            } else {
+                "-".to_string()
+            }),
+            Cell::from(transfer),
+            Cell::from(last_run_text(job)).style(if failed {
+                Style::new().fg(Color::Red)
+            } else {
+                Style::new()
+            }),
+        ])
+    });
+    let table = Table::new(
+        rows,
+        [
+            Constraint::Length(16),
+            Constraint::Length(12),
+            Constraint::Length(9),
+            Constraint::Length(7),
+            Constraint::Length(18),
+            Constraint::Fill(1),
+        ],
+    )
+    .header(
+        Row::new(["JOB", "MODE", "STATE", "QUEUED", "TRANSFER", "LAST RUN"])
+            .style(Style::new().add_modifier(Modifier::BOLD)),
+    )
+    .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+    .block(Block::bordered().title(" syncz agent "));
+    let mut state =
+        TableState::default().with_selected((!jobs.is_empty()).then_some(view.selected));
+    frame.render_stateful_widget(table, jobs_area, &mut state);
+
+    let alerts = if alerts.is_empty() {
+        vec![Line::from("No conflicts").style(Style::new().fg(Color::DarkGray))]
+    } else {
+        alerts
+    };
+    frame.render_widget(
+        Paragraph::new(alerts).block(Block::bordered().title(" Alerts ")),
+        alerts_area,
+    );
+
+    // The newest lines that fit.
+    let log: Vec<Line> = recent_log(view)
+        .into_iter()
+        .rev()
+        .take(log_area.height.saturating_sub(2) as usize)
+        .rev()
+        .map(Line::from)
+        .collect();
+    frame.render_widget(
+        Paragraph::new(log).block(Block::bordered().title(" Log ")),
+        log_area,
+    );
+
+    let quit = if view.owned {
+        "quit (stops the agent)"
+    } else {
+        "quit"
+    };
+    let mut help = vec![
+        Span::styled(" ↑/↓", Style::new().add_modifier(Modifier::BOLD)),
+        Span::raw(" select  "),
+        Span::styled("p", Style::new().add_modifier(Modifier::BOLD)),
+        Span::raw(" pause/resume  "),
+        Span::styled("t", Style::new().add_modifier(Modifier::BOLD)),
+        Span::raw(" run now  "),
+        Span::styled("q", Style::new().add_modifier(Modifier::BOLD)),
+        Span::raw(format!(" {}", quit)),
+    ];
+    if let Some(message) = &view.message {
+        help.push(Span::styled(
+            format!("   {}", message),
+            Style::new().fg(Color::Cyan),
+        ));
+    }
+    frame.render_widget(Line::from(help), help_area);
+}
+
+fn recent_log(view: &View) -> Vec<String> {
+    view.reply["log"]
+        .as_array()
+        .map(|lines| {
+            lines
+                .iter()
+                .filter_map(|l| l.as_str().map(str::to_string))
+                .collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,165 @@
+//! The rsync algorithm, for `--backend native`, using `fast_rsync`: the side
+//! that has an old copy sends a signature of its blocks, the side with the
+//! new copy answers with a delta of block references and literal bytes, and
+//! the old side rebuilds the new file from that.
+//!
+//! Files are handled in windows of `WINDOW` bytes, so neither side holds a
+//! whole file in memory. Each window of the new file is diffed against the
+//! same window of the old one, which only costs a few blocks of literals
+//! where an edit shifts data across a window boundary.
+
+use anyhow::{anyhow, bail, Context, Result};
+use fast_rsync::{Signature, SignatureOptions};
+use std::io::{self, Read, Write};
+
+pub(crate) const WINDOW: usize = 8 << 20;
+
+// Bytes of each block's MD4 hash kept in a signature; the whole window is
+// checked with blake3 after patching anyway.
+const HASH_LEN: u32 = 8;
+
+// A delta frame is never much larger than the window it rebuilds.
+const MAX_FRAME: u64 = 2 * WINDOW as u64;
+
+// About the square root of the window size, like rsync, within sane bounds.
+fn block_size(len: usize) -> u32 {
+    (((len as f64).sqrt() as usize).clamp(700, 128 << 10) & !7) as u32
+}
+
+fn options(len: usize) -> SignatureOptions {
+    SignatureOptions {
+        block_size: block_size(len),
+        crypto_hash_size: HASH_LEN,
+    }
+}
+
+// Fills `buf` with the next window of `reader`; empty at the end.
+fn next_window(reader: &mut impl Read, buf: &mut Vec<u8>) -> io::Result<usize> {
+    buf.clear();
+    reader.take(WINDOW as u64).read_to_end(buf)
+}
+
+// Signatures of each window of the receiver's copy, length-prefixed.
+pub(crate) fn signature(mut old: impl Read) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut window = Vec::new();
+    while next_window(&mut old, &mut window)? > 0 {
+        let signature = Signature::calculate(&window, options(window.len()));
+        let bytes = signature.serialized();
+        out.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        out.extend_from_slice(bytes);
+    }
+    Ok(out)
+}
+
+pub(crate) fn decode_signature(mut bytes: &[u8]) -> Result<Vec<Signature>> {
+    let mut signatures = Vec::new();
+    while let Some((len, rest)) = bytes.split_first_chunk::<4>() {
+        let len = u32::from_le_bytes(*len) as usize;
+        if rest.len() < len {
+            bail!("truncated signature");
+        }
+        let signature = Signature::deserialize(rest[..len].to_vec())
+            .map_err(|_| anyhow!("corrupt signature"))?;
+        signatures.push(signature);
+        bytes = &rest[len..];
+    }
+    if !bytes.is_empty() {
+        bail!("truncated signature");
+    }
+    Ok(signatures)
+}
+
+// Writes a frame per window of `new`: its length, the delta against the same
+// window of the signature, and the blake3 hash of the window. An empty frame
+// ends the file. Returns the bytes written.
+pub(crate) fn send_delta(
+    signatures: &[Signature],
+    mut new: impl Read,
+    out: &mut impl Write,
+) -> Result<u64> {
+    let empty = Signature::calculate(&[], options(0));
+    let mut window = Vec::new();
+    let mut frame = Vec::new();
+    let mut sent = 0;
+    for index in 0.. {
+        if next_window(&mut new, &mut window)? == 0 {
+            break;
+        }
+        let signature = signatures.get(index).unwrap_or(&empty);
+        frame.clear();
+        fast_rsync::diff(&signature.index(), &window, &mut frame)
+            .map_err(|e| anyhow!("failed to compute delta: {}", e))?;
+        out.write_all(&(frame.len() as u64).to_le_bytes())?;
+        out.write_all(&frame)?;
+        out.write_all(blake3::hash(&window).as_bytes())?;
+        sent += 8 + frame.len() as u64 + 32;
+    }
+    out.write_all(&0u64.to_le_bytes())?;
+    Ok(sent + 8)
+}
+
+// Rebuilds a file into `out` from the frames in `input`, reading the old copy
+// the signature was made from window by window. All frames are consumed even
+// when one fails, so the connection stays usable. Returns the bytes received
+// and the size of the rebuilt file.
+pub(crate) fn receive_delta(
+    mut base: impl Read,
+    input: &mut impl Read,
+    out: &mut impl Write,
+) -> Result<(u64, u64)> {
+    let mut received = 0;
+    let mut written = 0;
+    let mut frame = Vec::new();
+    let mut old = Vec::new();
+    let mut new = Vec::new();
+    while let Some(hash) = read_frame(input, &mut frame)? {
+        received += 8 + frame.len() as u64 + 32;
+        let applied = next_window(&mut base, &mut old)
+            .map_err(anyhow::Error::from)
+            .and_then(|_| {
+                new.clear();
+                fast_rsync::apply_limited(&old, &frame, &mut new, WINDOW)
+                    .map_err(|e| anyhow!("corrupt delta: {}", e))?;
+                if blake3::hash(&new).as_bytes() != &hash {
+                    bail!("delta didn't reproduce the file");
+                }
+                out.write_all(&new)?;
+                Ok(())
+            });
+        if let Err(err) = applied {
+            skip(input)?;
+            return Err(err);
+        }
+        written += new.len() as u64;
+    }
+    Ok((received + 8, written))
+}
+
+// Discards the rest of a file's frames.
+pub(crate) fn skip(input: &mut impl Read) -> Result<()> {
+    let mut frame = Vec::new();
+    while read_frame(input, &mut frame)?.is_some() {}
+    Ok(())
+}
+
+fn read_frame(input: &mut impl Read, frame: &mut Vec<u8>) -> Result<Option<[u8; 32]>> {
+    let mut len = [0u8; 8];
+    input
+        .read_exact(&mut len)
+        .with_context(|| "connection closed in the middle of a transfer")?;
+    let len = u64::from_le_bytes(len);
+    if len == 0 {
+        return Ok(None);
+    }
+    if len > MAX_FRAME {
+        bail!("delta frame too large ({} bytes)", len);
+    }
+    frame.clear();
+    input.take(len).read_to_end(frame)?;
+    let mut hash = [0u8; 32];
+    if frame.len() as u64 != len || input.read_exact(&mut hash).is_err() {
+        bail!("connection closed in the middle of a transfer");
+    }
+    Ok(Some(hash))
+}
@@ -0,0 +1,492 @@
+//! `--encrypt age:RECIPIENT`: client-side encryption for remotes that
+//! shouldn't see the data. Files are encrypted with the `age` CLI into blobs
+//! before a push and decrypted after a pull, so the remote directory only
+//! holds blobs and an encrypted copy of the manifest that maps paths to
+//! them. A local manifest remembers what was last synced, so unchanged files
+//! aren't encrypted or downloaded again.
+
+use crate::sftp::{local_entries, LocalEntry};
+use crate::{
+    data_dir, ensure_remote_parent, privileged, remote_shell_path, report_dry_run, run_rsync,
+    ssh_args, write_files_from, Args, Backend, Change, CommandRunner, DryRunSummary,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+// The encrypted manifest, next to the blobs on the remote.
+const REMOTE_MANIFEST: &str = ".syncz-manifest.age";
+
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    pub(crate) files: BTreeMap<String, ManifestEntry>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct ManifestEntry {
+    pub(crate) blob: String,
+    pub(crate) size: u64,
+    pub(crate) mtime: i64,
+    // blake3 of the plaintext, which tells whether the two sides differ.
+    pub(crate) hash: String,
+}
+
+pub(crate) fn recipient(spec: &str) -> Result<&str> {
+    match spec.split_once(':') {
+        Some(("age", recipient)) if !recipient.trim().is_empty() => Ok(recipient.trim()),
+        _ => bail!("unsupported --encrypt value '{}'; use age:RECIPIENT", spec),
+    }
+}
+
+// Features that would need to read or compare the plaintext on the remote.
+pub(crate) fn unsupported(args: &Args) -> Option<&'static str> {
+    if args.backend.is_some_and(|b| b != Backend::Rsync) {
+        Some("--backend")
+    } else if args.delete {
+        Some("--delete")
+    } else if args.both {
+        Some("--both")
+    } else if args.watch {
+        Some("--watch")
+    } else if args.status {
+        Some("status")
+    } else if args.snapshot {
+        Some("snapshot")
+    } else if args.undo {
+        Some("undo")
+    } else if args.select || args.interactive {
+        Some("--select")
+    } else if args.since.is_some() {
+        Some("--since")
+    } else if args.git_tracked {
+        Some("--git-tracked")
+    } else if args.listed_files.is_some() {
+        Some("--files-from (or several paths)")
+    } else if args.parallel.is_some() {
+        Some("--parallel")
+    } else if args.bootstrap {
+        Some("--bootstrap")
+    } else if args.verify {
+        Some("--verify")
+    } else {
+        None
+    }
+}
+
+// Blobs keep the directory layout (`src/main.rs.age`) unless names are
+// obfuscated. A hidden name is a hash of the path and the recipient, so the
+// same file doesn't get the same name in everyone's backups.
+pub(crate) fn blob_name(path: &str, recipient: &str, obfuscate: bool) -> String {
+    if obfuscate {
+        let hash = blake3::hash(format!("{}\0{}", recipient, path).as_bytes()).to_hex();
+        format!("{}.age", &hash[..32])
+    } else {
+        format!("{}.age", path)
+    }
+}
+
+pub(crate) fn push(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let recipient = recipient(args.encrypt.as_deref().unwrap_or_default())?;
+    if !local_path.is_dir() {
+        bail!("--encrypt syncs directories, got {}", local_path.display());
+    }
+    let manifest_file = manifest_path(host, local_path)?;
+    let previous = load_manifest(&manifest_file)?;
+    let entries = local_entries(local_path, args)?;
+    let (manifest, changed) = plan_push(
+        local_path,
+        &entries,
+        &previous,
+        recipient,
+        args.obfuscate_names,
+    )?;
+
+    if args.dry_run {
+        let changes = changed
+            .iter()
+            .map(|path| change(path, !previous.files.contains_key(path)))
+            .collect();
+        report_dry_run(&summary(changes), host, args, false);
+        return Ok(());
+    }
+    if changed.is_empty() && manifest == previous {
+        if !args.json {
+            println!("Nothing to encrypt; {} is up to date", host);
+        }
+        return Ok(());
+    }
+
+    let staging = staging_dir()?;
+    let result = (|| {
+        for path in &changed {
+            let blob = staging.join(&manifest.files[path].blob);
+            encrypt_file(runner, recipient, &local_path.join(path), &blob)?;
+        }
+        let plain = staging.join("manifest.json");
+        fs::write(&plain, serde_json::to_vec(&manifest)?)
+            .with_context(|| format!("failed to write {}", plain.display()))?;
+        encrypt_file(runner, recipient, &plain, &staging.join(REMOTE_MANIFEST))?;
+        fs::remove_file(&plain)?;
+        ensure_remote_parent(runner, host, remote_path, args)?;
+        run_rsync(
+            host,
+            &staging,
+            remote_path,
+            false,
+            &blob_args(args),
+            false,
+            None,
+        )
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    result?;
+    save_manifest(&manifest_file, &manifest)
+}
+
+pub(crate) fn pull(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    recipient(args.encrypt.as_deref().unwrap_or_default())?;
+    let identity = match args.age_identity.as_deref() {
+        Some(identity) => identity,
+        // A push with no direction given also pulls; that half needs the key.
+        None if !args.pull => {
+            if !args.json {
+                println!("Skipped pulling: decrypting needs --age-identity FILE");
+            }
+            return Ok(());
+        }
+        None => bail!("decrypting needs the age identity: pass --age-identity FILE"),
+    };
+    let manifest_file = manifest_path(host, local_path)?;
+    let previous = load_manifest(&manifest_file)?;
+    let staging = staging_dir()?;
+    let result = (|| -> Result<Option<Manifest>> {
+        let remote = fetch_manifest(runner, host, remote_path, identity, &staging, args)?;
+        let (fetch, conflicts) = plan_pull(local_path, &remote, &previous, args.force)?;
+        for path in &conflicts {
+            eprintln!(
+                "⚠️  {} changed both here and on {}; kept the local copy (--force to overwrite)",
+                path, host
+            );
+        }
+        if args.dry_run {
+            let changes = fetch
+                .iter()
+                .map(|path| change(path, !local_path.join(path).exists()))
+                .collect();
+            report_dry_run(&summary(changes), host, args, true);
+            return Ok(None);
+        }
+        if !fetch.is_empty() {
+            let blobs: Vec<String> = fetch
+                .iter()
+                .map(|path| remote.files[path].blob.clone())
+                .collect();
+            let list = write_files_from(&blobs)?;
+            let transferred = run_rsync(
+                host,
+                &staging,
+                remote_path,
+                false,
+                &blob_args(args),
+                true,
+                Some(&list),
+            );
+            let _ = fs::remove_file(&list);
+            transferred?;
+            for path in &fetch {
+                let entry = &remote.files[path];
+                let target = local_path.join(path);
+                if let Some(parent) = target.parent() {
+                    fs::create_dir_all(parent)
+                        .with_context(|| format!("failed to create {}", parent.display()))?;
+                }
+                decrypt_file(runner, identity, &staging.join(&entry.blob), &target)?;
+                set_mtime(&target, entry.mtime)?;
+            }
+        } else if !args.json {
+            println!("Nothing to decrypt; {} is up to date", local_path.display());
+        }
+        // What is now the same on both sides; conflicts keep their old entry.
+        let mut manifest = previous.clone();
+        manifest
+            .files
+            .retain(|path, _| remote.files.contains_key(path));
+        for (path, entry) in remote.files {
+            if !conflicts.contains(&path) {
+                manifest.files.insert(path, entry);
+            }
+        }
+        Ok(Some(manifest))
+    })();
+    let _ = fs::remove_dir_all(&staging);
+    match result? {
+        Some(manifest) => save_manifest(&manifest_file, &manifest),
+        None => Ok(()),
+    }
+}
+
+// The manifest after a push and the files to encrypt: new ones and those
+// whose contents changed. A file that was only touched keeps its blob.
+pub(crate) fn plan_push(
+    local_path: &Path,
+    entries: &[LocalEntry],
+    previous: &Manifest,
+    recipient: &str,
+    obfuscate: bool,
+) -> Result<(Manifest, Vec<String>)> {
+    let mut manifest = Manifest::default();
+    let mut changed = Vec::new();
+    for entry in entries.iter().filter(|e| !e.is_dir) {
+        let file = local_path.join(&entry.path);
+        let meta =
+            fs::metadata(&file).with_context(|| format!("failed to read {}", file.display()))?;
+        let mtime = mtime(&meta);
+        let blob = blob_name(&entry.path, recipient, obfuscate);
+        let known = previous.files.get(&entry.path).filter(|e| e.blob == blob);
+        let hash = match known {
+            Some(known) if known.size == meta.len() && known.mtime == mtime => known.hash.clone(),
+            _ => file_hash(&file)?,
+        };
+        if known.is_none_or(|known| known.hash != hash) {
+            changed.push(entry.path.clone());
+        }
+        manifest.files.insert(
+            entry.path.clone(),
+            ManifestEntry {
+                blob,
+                size: meta.len(),
+                mtime,
+                hash,
+            },
+        );
+    }
+    Ok((manifest, changed))
+}
+
+// Files to download, and conflicts: files changed on the remote since the
+// last sync that were also edited here. Those are left alone unless forced,
+// or when both sides happen to hold the same contents.
+pub(crate) fn plan_pull(
+    local_path: &Path,
+    remote: &Manifest,
+    previous: &Manifest,
+    force: bool,
+) -> Result<(Vec<String>, Vec<String>)> {
+    let mut fetch = Vec::new();
+    let mut conflicts = Vec::new();
+    for (path, entry) in &remote.files {
+        let known = previous.files.get(path);
+        if known.is_some_and(|known| known.hash == entry.hash) {
+            continue;
+        }
+        let file = local_path.join(path);
+        let edited = match (fs::metadata(&file), known) {
+            (Err(_), _) => false,
+            (Ok(meta), Some(known)) => meta.len() != known.size || mtime(&meta) != known.mtime,
+            (Ok(_), None) => true,
+        };
+        if !edited || force {
+            fetch.push(path.clone());
+        } else if file_hash(&file)? != entry.hash {
+            conflicts.push(path.clone());
+        }
+    }
+    Ok((fetch, conflicts))
+}
+
+// The blobs are copied as they are: excludes and size limits were applied
+// to the plaintext already.
+fn blob_args(args: &Args) -> Args {
+    Args {
+        all: true,
+        large: true,
+        max_size: None,
+        include: Vec::new(),
+        exclude: Vec::new(),
+        only: Vec::new(),
+        filters: Vec::new(),
+        ..args.clone()
+    }
+}
+
+fn fetch_manifest(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    identity: &Path,
+    staging: &Path,
+    args: &Args,
+) -> Result<Manifest> {
+    let remote = format!("{}/{}", remote_path.trim_end_matches('/'), REMOTE_MANIFEST);
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("cat -- {}", remote_shell_path(&remote)),
+    ));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh cat")?;
+    if !output.status.success() {
+        bail!(
+            "no encrypted files at {}:{}; push with --encrypt first ({})",
+            host,
+            remote_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let encrypted = staging.join(REMOTE_MANIFEST);
+    fs::write(&encrypted, &output.stdout)
+        .with_context(|| format!("failed to write {}", encrypted.display()))?;
+    let plain = staging.join("manifest.json");
+    decrypt_file(runner, identity, &encrypted, &plain)?;
+    let contents = fs::read_to_string(&plain)
+        .with_context(|| format!("failed to read {}", plain.display()))?;
+    serde_json::from_str(&contents).with_context(|| "invalid encrypted manifest")
+}
+
+fn encrypt_file(
+    runner: &dyn CommandRunner,
+    recipient: &str,
+    input: &Path,
+    output: &Path,
+) -> Result<()> {
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let cmd_args = vec![
+        "-r".to_string(),
+        recipient.to_string(),
+        "-o".to_string(),
+        output.to_string_lossy().to_string(),
+        input.to_string_lossy().to_string(),
+    ];
+    let status = runner
+        .status("age", &cmd_args)
+        .with_context(|| "failed to run age; is it installed?")?;
+    if !status.success() {
+        bail!("age failed to encrypt {} ({})", input.display(), status);
+    }
+    Ok(())
+}
+
+fn decrypt_file(
+    runner: &dyn CommandRunner,
+    identity: &Path,
+    input: &Path,
+    output: &Path,
+) -> Result<()> {
+    let cmd_args = vec![
+        "-d".to_string(),
+        "-i".to_string(),
+        identity.to_string_lossy().to_string(),
+        "-o".to_string(),
+        output.to_string_lossy().to_string(),
+        input.to_string_lossy().to_string(),
+    ];
+    let status = runner
+        .status("age", &cmd_args)
+        .with_context(|| "failed to run age; is it installed?")?;
+    if !status.success() {
+        bail!("age failed to decrypt {} ({})", input.display(), status);
+    }
+    Ok(())
+}
+
+fn manifest_path(host: &str, local_path: &Path) -> Result<PathBuf> {
+    let key = format!("{}@{}", host, local_path.display());
+    let hash = blake3::hash(key.as_bytes()).to_hex();
+    Ok(data_dir()?
+        .join("encrypt")
+        .join(format!("{}.json", &hash[..16])))
+}
+
+fn load_manifest(path: &Path) -> Result<Manifest> {
+    if !path.exists() {
+        return Ok(Manifest::default());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    serde_json::from_str(&contents).with_context(|| format!("failed to parse {}", path.display()))
+}
+
+fn save_manifest(path: &Path, manifest: &Manifest) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    fs::write(path, serde_json::to_string_pretty(manifest)?)
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// One per push or pull: a sync to several hosts runs them in parallel.
+pub(crate) fn staging_dir() -> Result<PathBuf> {
+    let dir = std::env::temp_dir().join(format!(
+        "syncz-encrypt-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+fn file_hash(path: &Path) -> Result<String> {
+    let contents = fs::read(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(blake3::hash(&contents).to_hex().to_string())
+}
+
+fn mtime(meta: &fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+// Decrypted files get the mtime they had when pushed, which the manifest
+// then matches.
+fn set_mtime(path: &Path, secs: i64) -> Result<()> {
+    let time = UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64);
+    fs::File::options()
+        .write(true)
+        .open(path)
+        .and_then(|file| file.set_modified(time))
+        .with_context(|| format!("failed to set the modification time of {}", path.display()))
+}
+
+// New files are shown as rsync would itemize them, everything else as changed.
+fn change(path: &str, new: bool) -> Change {
+    Change {
+        code: if new { ">f+++++++++" } else { ">f.st......" }.to_string(),
+        path: path.to_string(),
+        size: None,
+    }
+}
+
+fn summary(changes: Vec<Change>) -> DryRunSummary {
+    DryRunSummary {
+        transferred_line: None,
+        transferred_bytes: None,
+        deletions: Vec::new(),
+        changes,
+        renames: Vec::new(),
+    }
+}
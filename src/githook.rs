@@ -0,0 +1,149 @@
+//! `syncz githook`: a post-commit (or pre-push) hook that pushes the
+//! repository with syncz, for a lightweight deploy-on-commit workflow. The
+//! host is fixed when the hook is installed, so a later sync of the same
+//! directory to another host doesn't change where commits go.
+
+use crate::{
+    find_project, load_recent_hosts, recent_host, recent_hosts_path, shell_escape, CommandRunner,
+    GithookArgs, GithookCommand, GithookInstallArgs, HookEvent,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Marks hooks syncz wrote, so they can be replaced and removed.
+const MARKER: &str = "# Installed by `syncz githook install`";
+
+impl HookEvent {
+    fn hook_name(self) -> &'static str {
+        match self {
+            HookEvent::Commit => "post-commit",
+            HookEvent::Push => "pre-push",
+        }
+    }
+}
+
+pub(crate) fn run(runner: &dyn CommandRunner, opts: &GithookArgs) -> Result<()> {
+    let cwd = std::env::current_dir().with_context(|| "failed to read the current directory")?;
+    match &opts.command {
+        GithookCommand::Install(install_opts) => install(runner, &cwd, install_opts),
+        GithookCommand::Remove { on } => remove(runner, &cwd, *on),
+    }
+}
+
+fn install(runner: &dyn CommandRunner, cwd: &Path, opts: &GithookInstallArgs) -> Result<()> {
+    let (root, hooks) = locate(runner, cwd)?;
+    // Like a sync: an explicit host, then the project file's (left to the
+    // hook, which reads the project file itself), then the last one used here.
+    let host = match &opts.host {
+        Some(host) => Some(host.clone()),
+        None if find_project(&root)?.is_some_and(|p| p.config.host.is_some()) => None,
+        None => {
+            let recent = load_recent_hosts(&recent_hosts_path()?)?;
+            let host = recent_host(&recent, &root).ok_or_else(|| {
+                anyhow!(
+                    "no host remembered for {}; pass a HOST or sync the repository once",
+                    root.display()
+                )
+            })?;
+            Some(host.to_string())
+        }
+    };
+    let path = hooks.join(opts.on.hook_name());
+    if let Ok(existing) = fs::read_to_string(&path) {
+        if !existing.contains(MARKER) {
+            bail!(
+                "{} already exists and wasn't installed by syncz; remove it or call syncz from it",
+                path.display()
+            );
+        }
+    }
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let exe = exe.canonicalize().unwrap_or(exe);
+    let script = hook_script(
+        &exe.display().to_string(),
+        host.as_deref(),
+        opts.profile.as_deref(),
+    );
+    fs::create_dir_all(&hooks).with_context(|| format!("failed to create {}", hooks.display()))?;
+    fs::write(&path, script).with_context(|| format!("failed to write {}", path.display()))?;
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("failed to make {} executable", path.display()))?;
+    }
+    let when = match opts.on {
+        HookEvent::Commit => "after each commit",
+        HookEvent::Push => "before each git push",
+    };
+    println!(
+        "🪝 {} pushes {} to {} {}",
+        path.display(),
+        root.display(),
+        host.as_deref().unwrap_or("the project file's host"),
+        when
+    );
+    Ok(())
+}
+
+fn remove(runner: &dyn CommandRunner, cwd: &Path, on: HookEvent) -> Result<()> {
+    let (_, hooks) = locate(runner, cwd)?;
+    let path = hooks.join(on.hook_name());
+    let existing = fs::read_to_string(&path).unwrap_or_default();
+    if !existing.contains(MARKER) {
+        bail!("no syncz {} hook in {}", on.hook_name(), hooks.display());
+    }
+    fs::remove_file(&path).with_context(|| format!("failed to remove {}", path.display()))?;
+    println!("Removed {}", path.display());
+    Ok(())
+}
+
+// The work tree root and the hooks directory, which core.hooksPath or a
+// linked worktree can move away from .git/hooks.
+fn locate(runner: &dyn CommandRunner, cwd: &Path) -> Result<(PathBuf, PathBuf)> {
+    let args = vec![
+        "-C".to_string(),
+        cwd.to_string_lossy().to_string(),
+        "rev-parse".to_string(),
+        "--show-toplevel".to_string(),
+        "--git-path".to_string(),
+        "hooks".to_string(),
+    ];
+    let output = runner
+        .output("git", &args)
+        .with_context(|| "failed to run git rev-parse")?;
+    if !output.status.success() {
+        bail!(
+            "{} isn't inside a git work tree: {}",
+            cwd.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut lines = stdout.lines();
+    match (lines.next(), lines.next()) {
+        (Some(root), Some(hooks)) => Ok((PathBuf::from(root), cwd.join(hooks))),
+        _ => bail!("unexpected git rev-parse output: {}", stdout.trim()),
+    }
+}
+
+// Git runs hooks from the top of the work tree. A failed sync is reported
+// but never fails the commit or blocks the push.
+pub(crate) fn hook_script(exe: &str, host: Option<&str>, profile: Option<&str>) -> String {
+    let mut command = format!(
+        "{} push \"$(git rev-parse --show-toplevel)\"",
+        shell_escape(exe)
+    );
+    if let Some(host) = host {
+        command.push(' ');
+        command.push_str(&shell_escape(host));
+    }
+    if let Some(profile) = profile {
+        command.push_str(" --profile ");
+        command.push_str(&shell_escape(profile));
+    }
+    format!(
+        "#!/bin/sh\n{}; remove with `syncz githook remove`.\n{} --non-interactive || true\n",
+        MARKER, command
+    )
+}
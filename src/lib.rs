@@ -0,0 +1,13410 @@
+//! Path mapping and rsync/ssh orchestration behind the `syncz` CLI.
+//!
+//! ```no_run
+//! use syncz::{Endpoint, SyncOptions, SyncSession};
+//!
+//! let local = std::path::Path::new("/home/me/projects/app");
+//! let session = SyncSession::new(
+//!     local,
+//!     Endpoint::mirror("gpu-box", local)?,
+//!     SyncOptions::default(),
+//! )?;
+//! let report = session.dry_run(syncz::Direction::Push)?;
+//! println!("{} file(s) would change", report.files.len());
+//! session.push()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{ArgAction, Parser, Subcommand, ValueEnum};
+use dialoguer::{theme::ColorfulTheme, Confirm, FuzzySelect, MultiSelect};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{BufRead, BufReader, IsTerminal, Write};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+mod agent;
+mod bench;
+mod dashboard;
+mod delta;
+mod encrypt;
+mod githook;
+mod local;
+mod native;
+mod rclone;
+mod schedule;
+mod session;
+mod sftp;
+mod sums;
+
+pub use session::{Direction, DryRunReport, Endpoint, FileChange, SyncOptions, SyncSession};
+
+trait CommandRunner: Sync {
+    fn status(&self, program: &str, args: &[String]) -> Result<std::process::ExitStatus>;
+    fn output(&self, program: &str, args: &[String]) -> Result<std::process::Output>;
+}
+
+struct RealRunner;
+
+impl CommandRunner for RealRunner {
+    fn status(&self, program: &str, args: &[String]) -> Result<std::process::ExitStatus> {
+        info!(command = %command_line(program, args), "running");
+        let start = Instant::now();
+        let status = Command::new(program).args(args).status()?;
+        info!(program, %status, elapsed_ms = start.elapsed().as_millis() as u64, "finished");
+        Ok(status)
+    }
+
+    fn output(&self, program: &str, args: &[String]) -> Result<std::process::Output> {
+        info!(command = %command_line(program, args), "running");
+        let start = Instant::now();
+        let output = Command::new(program).args(args).output()?;
+        info!(
+            program,
+            status = %output.status,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "finished"
+        );
+        if !output.stderr.is_empty() {
+            debug!(program, stderr = %String::from_utf8_lossy(&output.stderr).trim_end());
+        }
+        Ok(output)
+    }
+}
+
+// A command as it could be pasted into a shell, for the log.
+fn command_line(program: &str, args: &[String]) -> String {
+    let mut line = program.to_string();
+    for arg in args {
+        line.push(' ');
+        let plain = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./=:,@%+~".contains(c));
+        if plain {
+            line.push_str(arg);
+        } else {
+            line.push_str(&shell_escape(arg));
+        }
+    }
+    line
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "syncz: rsync + ssh with smart pathing")]
+#[command(args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Push local -> remote (same as the bare form with --push)
+    Push(Args),
+    /// Pull remote -> local (same as the bare form with --pull)
+    Pull(Args),
+    /// Watch for local changes and push them (same as the bare form with --watch)
+    Watch(Args),
+    /// Show local-only, remote-only and differing files without syncing
+    Status(Args),
+    /// Push, then run a command on the remote inside the mapped directory
+    Exec(ExecArgs),
+    /// Open an interactive ssh session in the mapped remote directory
+    Shell(Args),
+    /// Show how a local file differs from its remote counterpart
+    Diff(Args),
+    /// Pull a remote directory into a new local one bound to it by a project file
+    Clone(CloneArgs),
+    /// Add, list or remove the project's named remotes (targeted with --to)
+    Remote(RemoteArgs),
+    /// Print the remote path (and rsync endpoints) a path maps to, without syncing
+    Which(Args),
+    /// Record SHA-256 checksums of a directory's files next to its remote copy
+    Manifest(Args),
+    /// Check the remote copy against the checksums recorded by `syncz manifest`
+    Verify(Args),
+    /// Estimate how much a sync would transfer and how long it would take
+    Estimate(Args),
+    /// Time rsync option sets against HOST and recommend per-host settings
+    Bench(Args),
+    /// Show which config and project files apply to a path
+    Config(ConfigArgs),
+    /// List past syncs, newest first
+    History(HistoryArgs),
+    /// Re-run the most recent sync command
+    Last,
+    /// Run the sync jobs defined in a job file, one after another or N at a time
+    Run(RunArgs),
+    /// Run a job from a job file periodically with a systemd user timer or launchd agent
+    Schedule(ScheduleArgs),
+    /// Keep the watch and scheduled jobs of a job file running, controlled with `syncz ctl`
+    Agent(AgentArgs),
+    /// Show, pause, resume or trigger the jobs of a running agent
+    Ctl(CtlArgs),
+    /// Install a git hook in the current repository that pushes it on commit or push
+    Githook(GithookArgs),
+    /// Push into a new timestamped directory, hard-linking files unchanged since the last one
+    Snapshot(Args),
+    /// Delete snapshots that fall outside keep_daily / keep_weekly
+    Prune(Args),
+    /// Put back the files the last --backup sync of a path overwrote or deleted
+    Undo(Args),
+    /// Open a shared ssh connection to HOST that later syncs reuse until `disconnect`
+    Connect(Args),
+    /// Close the shared ssh connection opened by `connect`
+    Disconnect(Args),
+    /// Serve a --backend native session on stdin/stdout (started over ssh)
+    #[command(hide = true)]
+    Server(ServerArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct ExecArgs {
+    #[command(flatten)]
+    args: Args,
+
+    /// Command to run on the remote, after `--`
+    #[arg(last = true, required = true, value_name = "COMMAND")]
+    command: Vec<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct CloneArgs {
+    /// Host to pull from
+    host: String,
+
+    /// Remote directory to pull
+    remote_path: String,
+
+    /// Where to put it (default: the remote directory's name, in the current directory)
+    local_path: Option<String>,
+
+    /// Sync everything (disable default smart excludes and size limits)
+    #[arg(short = 'a', long, action = ArgAction::SetTrue)]
+    all: bool,
+
+    /// Allow large files (disables the default 10MB size limit)
+    #[arg(short = 'l', long, action = ArgAction::SetTrue)]
+    large: bool,
+
+    /// Use a named profile from the config file
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RemoteArgs {
+    #[command(subcommand)]
+    command: RemoteCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum RemoteCommand {
+    /// Add (or replace) a remote in the project file, creating .syncz.toml if needed
+    Add {
+        /// Name to target it with, as in `--to NAME`
+        name: String,
+        /// Host to sync with
+        host: String,
+        /// Remote directory of the project (default: the project's remote_path or the mirrored path)
+        #[arg(long, value_name = "PATH")]
+        remote_path: Option<String>,
+    },
+    /// Show the project's remotes
+    List,
+    /// Delete a remote from the project file
+    Remove {
+        /// Name of the remote
+        name: String,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ConfigArgs {
+    /// Local path whose project file to look up. Defaults to current directory.
+    path: Option<String>,
+
+    /// Config file to inspect (default: ~/.config/syncz/config.toml)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct RunArgs {
+    /// Job file with one [[jobs]] table per sync
+    file: PathBuf,
+
+    /// Only run these jobs (default: all, in file order)
+    #[arg(value_name = "JOB")]
+    names: Vec<String>,
+
+    /// Number of jobs to run at once
+    #[arg(short = 'j', long, value_name = "N", default_value_t = 1,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    jobs: u32,
+
+    /// Config file for every job (default: ~/.config/syncz/config.toml)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Print one JSON object per finished job instead of progress and a table
+    #[arg(long, action = ArgAction::SetTrue)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ScheduleArgs {
+    #[command(subcommand)]
+    command: ScheduleCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum ScheduleCommand {
+    /// Install (or replace) the timer that runs JOB
+    Install(ScheduleInstallArgs),
+    /// Show the scheduled jobs and how often they run
+    List,
+    /// Stop running JOB and delete its timer
+    Remove {
+        /// Name of the scheduled job
+        job: String,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct ScheduleInstallArgs {
+    /// Name of a job in the job file
+    job: String,
+
+    /// How often to run it, e.g. 30m, 1h or 1d
+    #[arg(long, value_name = "INTERVAL")]
+    every: String,
+
+    /// Job file defining JOB (default: ~/.config/syncz/jobs.toml)
+    #[arg(long, value_name = "PATH")]
+    file: Option<PathBuf>,
+}
+
+#[derive(clap::Args, Debug)]
+struct GithookArgs {
+    #[command(subcommand)]
+    command: GithookCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum GithookCommand {
+    /// Install (or replace) the hook
+    Install(GithookInstallArgs),
+    /// Delete a hook installed by `githook install`
+    Remove {
+        /// Which hook to remove
+        #[arg(long, value_enum, default_value = "commit")]
+        on: HookEvent,
+    },
+}
+
+#[derive(clap::Args, Debug)]
+struct GithookInstallArgs {
+    /// Host to push to (default: the project file's, or the last one used for the repository)
+    host: Option<String>,
+
+    /// Push after each commit (post-commit hook) or before each git push (pre-push hook)
+    #[arg(long, value_enum, default_value = "commit")]
+    on: HookEvent,
+
+    /// Named profile from the config file for the hook's pushes
+    #[arg(long)]
+    profile: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum HookEvent {
+    Commit,
+    Push,
+}
+
+#[derive(clap::Args, Debug)]
+struct AgentArgs {
+    /// Job file to manage (default: ~/.config/syncz/jobs.toml)
+    #[arg(long, value_name = "PATH")]
+    file: Option<PathBuf>,
+
+    /// Config file for every job (default: ~/.config/syncz/config.toml)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Run in the background, logging to ~/.local/share/syncz/agent.log
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "tui")]
+    detach: bool,
+
+    /// Show a live dashboard of the jobs; attaches to the agent if one is already running
+    #[arg(long, action = ArgAction::SetTrue)]
+    tui: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct CtlArgs {
+    #[command(subcommand)]
+    command: CtlCommand,
+
+    /// Print the agent's reply as JSON
+    #[arg(long, global = true, action = ArgAction::SetTrue)]
+    json: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum CtlCommand {
+    /// Show every job with its state and last run
+    Status,
+    /// Stop watching and scheduled runs of JOB (default: all jobs)
+    Pause { job: Option<String> },
+    /// Undo `pause` for JOB (default: all jobs)
+    Resume { job: Option<String> },
+    /// Run JOB once now, even while paused
+    Trigger { job: String },
+    /// Stop the agent and its watchers
+    Stop,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServerArgs {
+    /// Directory or file the session syncs with
+    path: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    /// Only show syncs of this local path or a directory below it
+    path: Option<String>,
+
+    /// Only show syncs with this host
+    #[arg(long, value_name = "HOST")]
+    host: Option<String>,
+
+    /// Only show failed syncs
+    #[arg(long, action = ArgAction::SetTrue)]
+    failed: bool,
+
+    /// Number of entries to show
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+    limit: usize,
+
+    /// Print the entries as JSON lines
+    #[arg(long, action = ArgAction::SetTrue)]
+    json: bool,
+}
+
+#[derive(clap::Args, Debug, Default, Clone)]
+struct Args {
+    /// Local path to sync (push) or path to pull into (pull). Defaults to current directory.
+    path: Option<String>,
+
+    /// Host to sync with; if omitted, the last used host or a picker is used.
+    /// More local paths may come first: `syncz src/ configs/ HOST`.
+    host: Option<String>,
+
+    /// More hosts to push to
+    extra_hosts: Vec<String>,
+
+    /// Without a HOST, use the last host for this directory instead of asking
+    #[arg(long, action = ArgAction::SetTrue)]
+    last: bool,
+
+    /// Comma-separated hosts to push to (e.g. gpu1,gpu2,gpu3)
+    #[arg(long, value_name = "HOSTS", value_delimiter = ',')]
+    hosts: Vec<String>,
+
+    /// Comma-separated hosts to try in order, syncing with the first reachable one
+    #[arg(
+        long,
+        value_name = "HOSTS",
+        value_delimiter = ',',
+        conflicts_with = "hosts"
+    )]
+    failover: Vec<String>,
+
+    /// Push local -> remote (default is bidirectional)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "pull")]
+    push: bool,
+
+    /// Pull remote -> local (default is bidirectional)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "push")]
+    pull: bool,
+
+    /// Watch for file changes and sync automatically (defaults to Push mode)
+    #[arg(short = 'w', long, action = ArgAction::SetTrue)]
+    watch: bool,
+
+    /// Sync everything (disable default smart excludes and size limits)
+    #[arg(short = 'a', long, action = ArgAction::SetTrue)]
+    all: bool,
+
+    /// Allow large files (disables the default 10MB size limit)
+    #[arg(short = 'l', long, action = ArgAction::SetTrue)]
+    large: bool,
+
+    /// Use .gitignore to exclude files
+    #[arg(short = 'g', long, action = ArgAction::SetTrue)]
+    gitignore: bool,
+
+    /// Override max size limit (e.g. 100M, 1G)
+    #[arg(long)]
+    max_size: Option<String>,
+
+    /// Move files a sync overwrites or deletes to ~/.syncz-trash/<time> on the destination (see `undo`)
+    #[arg(short = 'b', long, action = ArgAction::SetTrue)]
+    backup: bool,
+
+    /// Dry run: show a tree-style diff and transfer size
+    #[arg(short = 'd', long, action = ArgAction::SetTrue)]
+    dry_run: bool,
+
+    /// Show only N levels of the dry-run tree, summarizing what's below
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    tree_depth: Option<u32>,
+
+    /// List the N largest files a dry run would transfer, after the tree
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    top: Option<u32>,
+
+    /// Print a dry-run preview directly even when it doesn't fit on the terminal
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_pager: bool,
+
+    /// Skip syncing permissions (useful for macOS/Linux UID/GID clashes)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_perms: bool,
+
+    /// Push only files changed since a git ref (implies --push)
+    #[arg(long, value_name = "GITREF", conflicts_with = "pull")]
+    since: Option<String>,
+
+    /// Push only files changed relative to a git ref, HEAD unless given as --git-diff=REF
+    /// (like --since, implies --push)
+    #[arg(
+        long,
+        value_name = "REF",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "HEAD",
+        conflicts_with_all = ["pull", "since", "both", "delete", "bootstrap", "git_tracked"]
+    )]
+    git_diff: Option<String>,
+
+    /// With --since or --git-diff, also delete files removed since the ref on the remote
+    #[arg(long, action = ArgAction::SetTrue)]
+    delete_removed: bool,
+
+    /// Push only files tracked by git (`git ls-files`), skipping untracked ones (implies --push)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["pull", "since", "both", "delete", "bootstrap"])]
+    git_tracked: bool,
+
+    /// Sync only the paths listed in FILE, one per line (or NUL-separated); `-` reads stdin
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = ["since", "git_diff", "git_tracked", "both", "delete", "bootstrap", "watch", "parallel"]
+    )]
+    files_from: Option<String>,
+
+    /// The --files-from paths, relative to the synced directory
+    #[arg(skip)]
+    listed_files: Option<Vec<String>>,
+
+    /// Sync both ways, skipping files changed on both sides since the last --both run
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["push", "pull", "since", "delete"])]
+    both: bool,
+
+    /// Mirror mode: delete destination files that don't exist on the source
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "since")]
+    delete: bool,
+
+    /// Pick which changed files to sync from the dry-run tree
+    #[arg(short = 's', long, action = ArgAction::SetTrue, conflicts_with_all = ["delete", "watch", "both"])]
+    select: bool,
+
+    /// Show the dry-run tree and transfer size, then ask before syncing
+    #[arg(short = 'i', long, action = ArgAction::SetTrue, conflicts_with_all = ["yes", "dry_run", "watch"])]
+    interactive: bool,
+
+    /// Never prompt: fail instead of showing the host picker and answer "no" to
+    /// confirmations unless --yes is given (implied when stdin is not a terminal)
+    #[arg(long, action = ArgAction::SetTrue)]
+    non_interactive: bool,
+
+    /// Skip files that are newer on the receiving side (the default; conflicts with --force)
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["force", "ignore_existing", "existing"])]
+    update: bool,
+
+    /// Only send files missing on the receiving side, never touching existing ones
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with = "existing")]
+    ignore_existing: bool,
+
+    /// Only update files that already exist on the receiving side, creating no new ones
+    #[arg(long, action = ArgAction::SetTrue)]
+    existing: bool,
+
+    /// Sync even when the receiving side has newer copies of some files (overwriting them)
+    /// or seems to lack the free space
+    #[arg(long, action = ArgAction::SetTrue)]
+    force: bool,
+
+    /// Sync even when the remote path is (or contains) one of the protected_paths
+    #[arg(long, action = ArgAction::SetTrue)]
+    allow_protected: bool,
+
+    /// Remote paths never to sync with, from the config (see DEFAULT_PROTECTED_PATHS)
+    #[arg(skip)]
+    protected_paths: Option<Vec<String>>,
+
+    /// Wait for another syncz syncing the same directory with the same host to finish,
+    /// instead of stopping
+    #[arg(long, action = ArgAction::SetTrue)]
+    wait: bool,
+
+    /// Show a desktop notification when the sync finishes or fails
+    #[arg(long, action = ArgAction::SetTrue)]
+    notify: bool,
+
+    /// Don't ask for confirmation before deleting files
+    #[arg(short = 'y', long, action = ArgAction::SetTrue)]
+    yes: bool,
+
+    /// Disable SSH connection multiplexing (ControlMaster)
+    #[arg(long, action = ArgAction::SetTrue)]
+    no_multiplex: bool,
+
+    /// SSH port, overriding ~/.ssh/config (a HOST written as host:port wins)
+    #[arg(long, value_name = "N")]
+    port: Option<u16>,
+
+    /// Transfer with rsync (default), sftp (whole files, no rsync on the remote), rclone (HOST
+    /// is an rclone remote such as s3) or the experimental native delta engine (needs syncz on
+    /// the remote); sftp, rclone and native push/pull only
+    #[arg(long, value_enum, value_name = "BACKEND")]
+    backend: Option<Backend>,
+
+    /// Encrypt files locally before pushing and decrypt them after pulling, so the remote
+    /// only stores age-encrypted blobs (e.g. age:age1... or age:"ssh-ed25519 AAAA...")
+    #[arg(long, value_name = "age:RECIPIENT")]
+    encrypt: Option<String>,
+
+    /// With --encrypt, also hide file names on the remote behind hashes
+    #[arg(long, action = ArgAction::SetTrue, requires = "encrypt")]
+    obfuscate_names: bool,
+
+    /// age identity file that decrypts --encrypt pulls
+    #[arg(long, value_name = "FILE")]
+    age_identity: Option<PathBuf>,
+
+    /// rsync binary on the remote, when it isn't on the PATH (e.g. /opt/homebrew/bin/rsync)
+    #[arg(long, value_name = "PATH")]
+    rsync_path: Option<String>,
+
+    /// Run rsync, mkdir and the other file commands on the remote through sudo
+    #[arg(long, action = ArgAction::SetTrue)]
+    remote_sudo: bool,
+
+    /// Reach the host through this bastion (ssh -J, e.g. me@gateway or a,b for a chain)
+    #[arg(long, value_name = "HOST")]
+    jump: Option<String>,
+
+    /// SSH private key to authenticate with (ssh -i)
+    #[arg(long, value_name = "KEY")]
+    identity: Option<PathBuf>,
+
+    /// Extra ssh arguments, e.g. '-o StrictHostKeyChecking=accept-new' (repeatable)
+    #[arg(long = "ssh-opt", value_name = "OPTS", action = ArgAction::Append, allow_hyphen_values = true)]
+    ssh_opts: Vec<String>,
+
+    /// Print machine-readable JSON events (one per line) instead of progress output
+    #[arg(long, action = ArgAction::SetTrue)]
+    json: bool,
+
+    /// Use a named profile from the config file
+    #[arg(long, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Sync with a named remote of the project (see `syncz remote`), or with a local
+    /// directory when given a path (e.g. /mnt/usb/app); --pull copies back from it
+    #[arg(long, value_name = "REMOTE|PATH")]
+    to: Option<String>,
+
+    /// Also offer the machines of your tailnet (`tailscale`) or those advertising ssh on
+    /// the local network (`lan`) in the host picker; comma-separated for both
+    #[arg(long, value_enum, value_name = "SOURCE", value_delimiter = ',')]
+    discover: Vec<Discover>,
+
+    /// Config file to read defaults from (default: ~/.config/syncz/config.toml)
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Log verbosity; logs go to --log-file, or stderr without one [default: info]
+    #[arg(long, value_enum, value_name = "LEVEL")]
+    log_level: Option<LogLevel>,
+
+    /// Append a log of every ssh/rsync command, its timing and transfer stats to PATH
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Compare files by checksum instead of size and mtime (slower, also used for dry runs)
+    #[arg(short = 'c', long, action = ArgAction::SetTrue)]
+    checksum: bool,
+
+    /// After pushing, compare the files with the remote copies by checksum and fail on any
+    /// mismatch
+    #[arg(long, action = ArgAction::SetTrue)]
+    verify: bool,
+
+    /// Abort (or ask) when a sync would transfer more than SIZE (e.g. 2G)
+    #[arg(long, value_name = "SIZE")]
+    max_total_size: Option<ByteSize>,
+
+    /// Split the transfer into N concurrent rsync processes (helps with many small files)
+    #[arg(long, value_name = "N", conflicts_with = "delete")]
+    parallel: Option<usize>,
+
+    /// Re-run rsync up to N times after a transient failure (dropped connection, timeout)
+    #[arg(long, value_name = "N")]
+    retries: Option<u32>,
+
+    /// Give up on an ssh connection after SECS, and on a transfer that stalls for SECS
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    timeout: Option<u64>,
+
+    /// Seconds to wait before the first retry; doubles after each attempt [default: 2]
+    #[arg(long, value_name = "SECS", requires = "retries")]
+    retry_delay: Option<u64>,
+
+    /// First push into a missing or empty remote directory: stream a tar archive over ssh
+    /// instead of running rsync (much faster for many small files); later pushes use rsync
+    #[arg(long, action = ArgAction::SetTrue, conflicts_with_all = ["pull", "since", "select"])]
+    bootstrap: bool,
+
+    /// Limit transfer bandwidth (rsync --bwlimit, e.g. 5000 for KB/s or 5M)
+    #[arg(long, value_name = "RATE")]
+    bwlimit: Option<String>,
+
+    /// Compression level for rsync -z (rsync --compress-level); forces compression on
+    #[arg(long, value_name = "N")]
+    compress_level: Option<u32>,
+
+    /// Compression algorithm for rsync -z (rsync --compress-choice); forces compression on
+    #[arg(long, value_name = "ALGO", value_parser = ["zstd", "lz4", "zlibx", "zlib"])]
+    compress_choice: Option<String>,
+
+    /// More file extensions not to compress, on top of the built-in list (repeatable)
+    #[arg(long, value_name = "EXT", action = ArgAction::Append, value_delimiter = ',')]
+    skip_compress: Vec<String>,
+
+    /// Whether rsync compresses, once decided for the host (config or link detection)
+    #[arg(skip)]
+    compress: Option<bool>,
+
+    /// Send changed files whole instead of as deltas (rsync --whole-file); faster on a fast LAN
+    #[arg(short = 'W', long, action = ArgAction::SetTrue, conflicts_with = "block_size")]
+    whole_file: bool,
+
+    /// Block size in bytes for rsync's delta algorithm (rsync --block-size)
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u32).range(1..=131072))]
+    block_size: Option<u32>,
+
+    /// Look for a similar file to base a new one on, e.g. a renamed one (rsync --fuzzy)
+    #[arg(long, action = ArgAction::SetTrue)]
+    fuzzy: bool,
+
+    /// Local command to run in the synced directory before syncing (e.g. "cargo build")
+    #[arg(long, value_name = "CMD")]
+    pre_cmd: Option<String>,
+
+    /// Remote command to run in the remote directory after syncing (e.g. "systemctl restart app")
+    #[arg(long, value_name = "CMD")]
+    post_cmd: Option<String>,
+
+    /// What to do when a hook fails [default: abort]
+    #[arg(long, value_enum, value_name = "POLICY")]
+    on_hook_error: Option<OnHookError>,
+
+    /// After a successful sync, POST a JSON report to this URL, or pipe it to this local command
+    #[arg(long, value_name = "URL|CMD")]
+    on_success: Option<String>,
+
+    /// After a failed sync, POST a JSON report to this URL, or pipe it to this local command
+    #[arg(long, value_name = "URL|CMD")]
+    on_failure: Option<String>,
+
+    /// Exclude files matching PATTERN (repeatable)
+    #[arg(long, value_name = "PATTERN", action = ArgAction::Append)]
+    exclude: Vec<String>,
+
+    /// Exclude the patterns listed in FILE, one per line (repeatable)
+    #[arg(long, value_name = "FILE", action = ArgAction::Append)]
+    exclude_from: Vec<String>,
+
+    /// Always include files matching PATTERN, even if excluded by default (repeatable)
+    #[arg(long, value_name = "PATTERN", action = ArgAction::Append)]
+    include: Vec<String>,
+
+    /// Only sync files matching GLOB, or everything under it if it ends in `/` (repeatable)
+    #[arg(long, value_name = "GLOB", action = ArgAction::Append)]
+    only: Vec<String>,
+
+    /// Extra flags passed verbatim to rsync (from the config file)
+    #[arg(skip)]
+    rsync_args: Vec<String>,
+
+    /// rsync filter rules translated from .gitignore files
+    #[arg(skip)]
+    filters: Vec<String>,
+
+    /// Remote path to sync with instead of mirroring the local path under ~
+    #[arg(long, value_name = "PATH")]
+    remote_path: Option<String>,
+
+    /// Progress display shared by concurrent transfers
+    #[arg(skip)]
+    progress: Option<MultiProgress>,
+
+    /// Label for this transfer's bar on a shared display (defaults to the host)
+    #[arg(skip)]
+    progress_label: Option<String>,
+
+    /// Report differences instead of syncing (the `status` subcommand)
+    #[arg(skip)]
+    status: bool,
+
+    /// Remote command to run after pushing (the `exec` subcommand)
+    #[arg(skip)]
+    exec: Vec<String>,
+
+    /// Open a remote shell instead of syncing (the `shell` subcommand)
+    #[arg(skip)]
+    shell: bool,
+
+    /// Diff one file against the remote copy instead of syncing (the `diff` subcommand)
+    #[arg(skip)]
+    diff: bool,
+
+    /// Print where a path maps to instead of syncing (the `which` subcommand)
+    #[arg(skip)]
+    which: bool,
+
+    /// Record checksums next to the remote copy (the `manifest` subcommand)
+    #[arg(skip)]
+    manifest: bool,
+
+    /// Check the remote copy against its recorded checksums (the `verify` subcommand)
+    #[arg(skip)]
+    verify_sums: bool,
+
+    /// Report the size and duration of a sync instead of running it (the `estimate` subcommand)
+    #[arg(skip)]
+    estimate: bool,
+
+    /// Measure rsync throughput with a few option sets (the `bench` subcommand)
+    #[arg(skip)]
+    bench: bool,
+
+    /// Write a project file binding the path to the remote after pulling (the `clone` subcommand)
+    #[arg(skip)]
+    clone: bool,
+
+    /// Push into a new timestamped snapshot (the `snapshot` subcommand)
+    #[arg(skip)]
+    snapshot: bool,
+
+    /// Delete old snapshots (the `prune` subcommand)
+    #[arg(skip)]
+    prune: bool,
+
+    /// Restore the last backup set (the `undo` subcommand)
+    #[arg(skip)]
+    undo: bool,
+
+    /// Open or close a persistent master connection (`connect` / `disconnect`)
+    #[arg(skip)]
+    connect: bool,
+
+    #[arg(skip)]
+    disconnect: bool,
+
+    /// Where --backup moves replaced files for this transfer
+    #[arg(skip)]
+    backup_dir: Option<String>,
+
+    /// What the preflight probe found on the remote side
+    #[arg(skip)]
+    remote_probe: Option<RemoteProbe>,
+
+    /// Directory on the remote that holds one snapshot directory per synced path [default: ~/backups]
+    #[arg(long, value_name = "DIR")]
+    snapshot_root: Option<String>,
+
+    /// With `prune`, keep the newest snapshot of each of the last N days
+    #[arg(long, value_name = "N")]
+    keep_daily: Option<usize>,
+
+    /// With `prune`, keep the newest snapshot of each of the last N weeks
+    #[arg(long, value_name = "N")]
+    keep_weekly: Option<usize>,
+}
+
+// A byte count written like rsync sizes: plain bytes or a K/M/G/T suffix
+// (powers of 1024).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+struct ByteSize(u64);
+
+impl std::str::FromStr for ByteSize {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let trimmed = s.trim();
+        let lower = trimmed.to_ascii_lowercase();
+        let number = lower
+            .trim_end_matches('b')
+            .trim_end_matches('i')
+            .trim_end_matches(['k', 'm', 'g', 't']);
+        let unit = lower[number.len()..].chars().next();
+        let shift = match unit {
+            None | Some('b') => 0,
+            Some('k') => 10,
+            Some('m') => 20,
+            Some('g') => 30,
+            Some('t') => 40,
+            Some(_) => bail!("invalid size '{}'", trimmed),
+        };
+        let value: f64 = number
+            .trim()
+            .parse()
+            .map_err(|_| anyhow!("invalid size '{}' (expected e.g. 500M or 2G)", trimmed))?;
+        if value < 0.0 {
+            bail!("invalid size '{}'", trimmed);
+        }
+        Ok(ByteSize((value * (1u64 << shift) as f64) as u64))
+    }
+}
+
+impl TryFrom<String> for ByteSize {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum OnHookError {
+    #[default]
+    Abort,
+    Warn,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Backend {
+    #[default]
+    Rsync,
+    Sftp,
+    Rclone,
+    Native,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+enum Discover {
+    Tailscale,
+    Lan,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for tracing::Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Error => tracing::Level::ERROR,
+            LogLevel::Warn => tracing::Level::WARN,
+            LogLevel::Info => tracing::Level::INFO,
+            LogLevel::Debug => tracing::Level::DEBUG,
+            LogLevel::Trace => tracing::Level::TRACE,
+        }
+    }
+}
+
+// Logging is off unless asked for, so the progress display stays clean.
+fn init_logging(args: &Args) -> Result<()> {
+    if args.log_level.is_none() && args.log_file.is_none() {
+        return Ok(());
+    }
+    let level = tracing::Level::from(args.log_level.unwrap_or(LogLevel::Info));
+    let builder = tracing_subscriber::fmt().with_max_level(level);
+    match &args.log_file {
+        Some(path) => {
+            if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+                fs::create_dir_all(parent)?;
+            }
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("failed to open log file {}", path.display()))?;
+            builder
+                .with_ansi(false)
+                .with_writer(Mutex::new(file))
+                .init();
+        }
+        None => builder.with_writer(std::io::stderr).init(),
+    }
+    Ok(())
+}
+
+impl Args {
+    fn is_push(&self) -> bool {
+        self.push || !self.pull
+    }
+
+    fn is_pull(&self) -> bool {
+        self.pull || (!self.push && self.since.is_none() && !self.git_tracked)
+    }
+
+    // The direction rules live here rather than in clap because subcommands
+    // pick the direction after parsing.
+    fn validate(&self) -> Result<()> {
+        if self.push && self.pull {
+            bail!("--push and --pull can't be used together");
+        }
+        if self.both && (self.push || self.pull) {
+            bail!("--both can't be combined with a push or pull");
+        }
+        if self.pull && self.since.is_some() {
+            bail!("--since only applies to pushes");
+        }
+        if self.delete_removed && self.since.is_none() {
+            bail!("--delete-removed needs --since or --git-diff");
+        }
+        if self.pull && self.git_tracked {
+            bail!("--git-tracked only applies to pushes");
+        }
+        if self.non_interactive && (self.select || self.interactive) {
+            bail!("--select and --interactive need a terminal; drop them or run interactively");
+        }
+        if self.delete && !self.push && !self.pull {
+            bail!("--delete needs a direction: use `syncz push`/`syncz pull` or --push/--pull");
+        }
+        Ok(())
+    }
+}
+
+// Entry point of the `syncz` binary.
+pub fn cli_main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut args = match cli.command {
+        None => cli.args,
+        Some(Commands::Push(mut args)) => {
+            args.push = true;
+            args
+        }
+        Some(Commands::Pull(mut args)) => {
+            args.pull = true;
+            args
+        }
+        Some(Commands::Watch(mut args)) => {
+            args.watch = true;
+            args
+        }
+        Some(Commands::Status(mut args)) => {
+            args.status = true;
+            args
+        }
+        Some(Commands::Shell(mut args)) => {
+            args.shell = true;
+            args
+        }
+        Some(Commands::Diff(mut args)) => {
+            args.diff = true;
+            args
+        }
+        Some(Commands::Manifest(mut args)) => {
+            args.manifest = true;
+            args
+        }
+        Some(Commands::Verify(mut args)) => {
+            args.verify_sums = true;
+            args
+        }
+        Some(Commands::Estimate(mut args)) => {
+            args.estimate = true;
+            args
+        }
+        Some(Commands::Bench(mut args)) => {
+            args.bench = true;
+            args
+        }
+        Some(Commands::Which(args)) => which_args(args),
+        Some(Commands::Snapshot(mut args)) => {
+            args.push = true;
+            args.snapshot = true;
+            args
+        }
+        Some(Commands::Prune(mut args)) => {
+            args.push = true;
+            args.prune = true;
+            args
+        }
+        Some(Commands::Undo(mut args)) => {
+            args.push = true;
+            args.undo = true;
+            args
+        }
+        Some(Commands::Connect(mut args)) => {
+            args.connect = true;
+            args
+        }
+        Some(Commands::Disconnect(mut args)) => {
+            args.disconnect = true;
+            args
+        }
+        Some(Commands::Clone(opts)) => clone_args(&opts)?,
+        Some(Commands::Exec(ExecArgs { mut args, command })) => {
+            args.push = true;
+            args.exec = command;
+            args
+        }
+        Some(Commands::Remote(opts)) => return manage_remotes(&opts),
+        Some(Commands::Config(opts)) => return show_config(&opts),
+        Some(Commands::History(opts)) => return show_history(&opts),
+        Some(Commands::Last) => return rerun_last(),
+        Some(Commands::Run(opts)) => return run_jobs(&opts),
+        Some(Commands::Schedule(opts)) => return schedule::run(&RealRunner, &opts),
+        Some(Commands::Agent(opts)) => return agent::run(&opts),
+        Some(Commands::Ctl(opts)) => return agent::ctl(&opts),
+        Some(Commands::Githook(opts)) => return githook::run(&RealRunner, &opts),
+        Some(Commands::Server(opts)) => {
+            let stdin = std::io::stdin();
+            return native::serve(&opts.path, stdin.lock(), std::io::stdout().lock());
+        }
+    };
+    // --git-diff is --since with HEAD as the default ref.
+    if let Some(gitref) = args.git_diff.take() {
+        args.since = Some(gitref);
+    }
+    if !std::io::stdin().is_terminal() {
+        args.non_interactive = true;
+    }
+    let json = args.json;
+    install_interrupt_handler();
+    let result = init_logging(&args)
+        .and_then(|()| {
+            let argv: Vec<String> = std::env::args().skip(1).collect();
+            info!(version = env!("CARGO_PKG_VERSION"), ?argv, "start");
+            args.validate()
+        })
+        .and_then(|()| run(args));
+    if let Err(e) = &result {
+        let code = exit_code(e);
+        tracing::error!(exit_code = code, "{:#}", e);
+        if json {
+            emit(json!({ "event": "error", "message": format!("{:#}", e), "exit_code": code }));
+            std::process::exit(code);
+        }
+        if code != 1 {
+            eprintln!("Error: {:?}", e);
+            std::process::exit(code);
+        }
+    }
+    result
+}
+
+// rsync failures end the process with rsync's own exit code; every other
+// error exits with 1.
+fn exit_code(err: &anyhow::Error) -> i32 {
+    if err.downcast_ref::<Interrupted>().is_some() {
+        return 130;
+    }
+    err.downcast_ref::<RsyncError>()
+        .map_or(1, RsyncError::exit_code)
+}
+
+// Set by Ctrl-C while an rsync transfer runs; each transfer then stops its
+// rsync and reports how far it got.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+static ACTIVE_TRANSFERS: AtomicUsize = AtomicUsize::new(0);
+
+// Outside a transfer (or on a second Ctrl-C) there is nothing to clean up,
+// so the process just ends the way it would have without a handler.
+fn install_interrupt_handler() {
+    let _ = ctrlc::set_handler(|| {
+        if ACTIVE_TRANSFERS.load(Ordering::SeqCst) == 0 || INTERRUPTED.swap(true, Ordering::SeqCst)
+        {
+            std::process::exit(130);
+        }
+    });
+}
+
+// Counts a transfer as running for as long as it's alive.
+struct ActiveTransfer;
+
+impl ActiveTransfer {
+    fn start() -> Self {
+        ACTIVE_TRANSFERS.fetch_add(1, Ordering::SeqCst);
+        ActiveTransfer
+    }
+}
+
+impl Drop for ActiveTransfer {
+    fn drop(&mut self) {
+        ACTIVE_TRANSFERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+struct Interrupted {
+    sent: u64,
+}
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "interrupted after transferring {}; run the same command again to resume \
+             (--partial keeps partly sent files)",
+            format_size(self.sent)
+        )
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+fn run(mut args: Args) -> Result<()> {
+    let runner = RealRunner;
+
+    let config = load_config(args.config.as_deref())?;
+
+    if let (Some(src), Some(dst)) = (
+        args.path.as_deref().and_then(remote_spec),
+        args.host.as_deref().and_then(remote_spec),
+    ) {
+        if let Some(name) = args.profile.clone() {
+            config.profile(&name)?.apply(&mut args);
+        }
+        config.apply(&mut args);
+        args.no_multiplex = true;
+        let (src, dst) = if args.pull && !args.push {
+            (dst, src)
+        } else {
+            (src, dst)
+        };
+        if let Some(host_config) = config.hosts.get(host_alias(&src.0)) {
+            host_config.apply(&mut args);
+        }
+        return remote_to_remote(&runner, &src, &dst, &args);
+    }
+
+    if let Some(p) = &args.path {
+        if !Path::new(p).exists() {
+            if let Some((host, remote)) = remote_spec(p).filter(|_| args.host.is_none()) {
+                // `syncz --pull host:~/path`: the local side is where that
+                // remote path would be mapped from.
+                let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+                let local = local_for_remote(&config, &host, &remote, &home);
+                args.path = Some(local.to_string_lossy().to_string());
+                args.remote_path = Some(remote);
+                args.host = Some(host);
+            } else {
+                if let Some(host) = args.host.take() {
+                    args.extra_hosts.insert(0, host);
+                }
+                args.host = args.path.take();
+                args.path = Some(".".to_string());
+            }
+        }
+    }
+    let extra_paths = match &args.path {
+        Some(p) if Path::new(p).exists() => take_extra_paths(&mut args),
+        _ => Vec::new(),
+    };
+    if !extra_paths.is_empty() {
+        let first = args.path.take().unwrap_or_default();
+        combine_paths(&mut args, &[vec![first], extra_paths].concat())?;
+    }
+    if args.host.is_none() && !args.hosts.is_empty() {
+        args.host = Some(args.hosts.remove(0));
+    }
+    // `syncz . host:path`, e.g. `s3:bucket/app` for an rclone remote.
+    if let Some((host, remote)) = args.host.as_deref().and_then(remote_spec) {
+        if args.remote_path.is_none() {
+            args.remote_path = Some(remote);
+        }
+        args.host = Some(host);
+    }
+    expand_host_aliases(&mut args)?;
+
+    if args.connect || args.disconnect {
+        let spec = args
+            .host
+            .clone()
+            .ok_or_else(|| anyhow!("no host given; pass the HOST to connect to"))?;
+        config.apply(&mut args);
+        let (host, port) = split_host_spec(&spec)?;
+        if port.is_some() {
+            args.port = port;
+        }
+        if let Some(host_config) = config.hosts.get(host_alias(&host)) {
+            host_config.apply(&mut args);
+        }
+        return if args.connect {
+            connect_master(&runner, &host, &args)
+        } else {
+            disconnect_master(&runner, &host, &args)
+        };
+    }
+
+    let path_str = args.path.as_deref().unwrap_or(".");
+    let local_path = expand_path(path_str)?;
+    let local_path = normalize_path(&local_path)?;
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+    if let Some(source) = args.files_from.clone() {
+        args.listed_files = Some(read_file_list(&source, &local_path)?);
+    }
+    if args.listed_files.is_some() {
+        // Listed directories are sent whole, as without a list.
+        args.rsync_args.push("--recursive".to_string());
+    }
+    for file in args.exclude_from.clone() {
+        let file = expand_path(&file)?;
+        let contents = fs::read_to_string(&file)
+            .with_context(|| format!("failed to read {}", file.display()))?;
+        args.exclude.extend(exclude_patterns(&contents));
+    }
+    args.filters = syncignore_filters(&local_path)?;
+
+    if let (Some(host), false) = (&args.host, args.failover.is_empty()) {
+        bail!("pass either a HOST ({}) or --failover, not both", host);
+    }
+    let mut project = find_project(&local_path)?;
+    if let (Some(name), Some(_)) = (&args.to, &args.host) {
+        bail!("pass either a HOST or --to {}, not both", name);
+    }
+    let local_dest = match args.to.as_deref().filter(|to| local::is_path(to)) {
+        Some(to) => Some(normalize_path(&expand_path(to)?)?),
+        None => None,
+    };
+    if let Some(name) = args.to.as_ref().filter(|_| local_dest.is_none()) {
+        let project = project.as_mut().ok_or_else(|| {
+            anyhow!(
+                "--to needs a project file with remotes; add one with `syncz remote add {} HOST`",
+                name
+            )
+        })?;
+        args.host = Some(project.select_remote(name)?);
+    }
+
+    // Precedence: CLI flags, then the selected profile, the project file and
+    // finally the global config.
+    if let Some(name) = args.profile.clone() {
+        config.profile(&name)?.apply(&mut args);
+    }
+    if let Some(project) = &project {
+        project.config.apply(&mut args);
+    }
+    config.apply(&mut args);
+    expand_host_groups(&mut args, &config.groups)?;
+    if let Some(dest) = &local_dest {
+        return local::sync(&runner, &local_path, dest, &args);
+    }
+
+    if !args.no_multiplex {
+        let control_dir = home.join(".ssh");
+        if let Some(problem) = control_dir_problem(&control_dir, probe_dir, create_private_dir) {
+            eprintln!(
+                "⚠️  SSH multiplexing disabled: {} (every connection will do a full handshake)",
+                problem
+            );
+            args.no_multiplex = true;
+        }
+    }
+    // Ahead of a host from the profile, project file or config.
+    if !args.failover.is_empty() {
+        args.host = Some(pick_failover_host(&runner, &args, &config)?);
+    }
+
+    let recent_path = recent_hosts_path()?;
+    let mut recent = load_recent_hosts(&recent_path)?;
+    let host = match &args.host {
+        Some(h) => h.clone(),
+        None => match remembered_host(&recent, &local_path, &args)? {
+            Some(h) => h,
+            None => {
+                pick_host_from_ssh_config(&runner, load_last_host()?.as_deref(), &args.discover)?
+            }
+        },
+    };
+    if !args.which {
+        save_last_host(&host)?;
+        remember_host(&mut recent, &local_path, &host);
+        save_recent_hosts(&recent_path, &recent)?;
+    }
+
+    if args.gitignore && local_path.is_dir() {
+        args.filters
+            .extend(gitignore_filters(&local_path, args.all)?);
+    }
+
+    let remote_for = |host: &str| {
+        resolve_remote_path(
+            &runner,
+            host,
+            &local_path,
+            &home,
+            &args,
+            project.as_ref(),
+            &config,
+        )
+    };
+
+    let target_for = |spec: &str| -> Result<Target> {
+        let (host, port) = split_host_spec(spec)?;
+        let mut host_args = args.clone();
+        if port.is_some() {
+            host_args.port = port;
+        }
+        if let Some(host_config) = config.hosts.get(host_alias(&host)) {
+            host_config.apply(&mut host_args);
+        }
+        host_args.compress = Some(use_compression(&runner, &host, &host_args));
+        let remote_path = remote_for(&host)?;
+        check_protected(&remote_path, &host_args)?;
+        check_overlap(&host, &local_path, &remote_path, &home)?;
+        Ok(Target {
+            remote_path,
+            host,
+            args: host_args,
+        })
+    };
+
+    let mut hosts = vec![host.clone()];
+    for extra in args.extra_hosts.iter().chain(args.hosts.iter()) {
+        if !hosts.contains(extra) {
+            hosts.push(extra.clone());
+        }
+    }
+    if hosts.len() > 1 {
+        if args.pull
+            || args.both
+            || args.watch
+            || args.status
+            || args.select
+            || args.interactive
+            || args.shell
+            || args.diff
+            || args.which
+            || args.manifest
+            || args.verify_sums
+            || args.estimate
+            || args.bench
+            || args.snapshot
+            || args.prune
+            || args.undo
+            || !args.exec.is_empty()
+        {
+            bail!("multiple hosts are only supported for pushes");
+        }
+        let targets = hosts
+            .iter()
+            .map(|h| target_for(h))
+            .collect::<Result<Vec<_>>>()?;
+        let _locks = targets
+            .iter()
+            .map(|t| lock_sync(&t.host, &local_path, &t.args))
+            .collect::<Result<Vec<_>>>()?;
+        let started = Instant::now();
+        let result = (|| {
+            if !args.dry_run {
+                run_pre_hook(&runner, &local_path, &args)?;
+            }
+            push_many(&runner, targets, &local_path, &args)
+        })();
+        record_run(&runner, &args, &hosts, &local_path, None, started, &result);
+        return result;
+    }
+    let Target {
+        host,
+        remote_path,
+        args,
+    } = target_for(&host)?;
+
+    if args.which {
+        show_which(&host, &local_path, &remote_path, &args);
+        return Ok(());
+    }
+    if args.watch && args.delete && !args.yes {
+        bail!("--watch with --delete needs --yes, since every sync could delete files");
+    }
+
+    let _lock = lock_sync(&host, &local_path, &args)?;
+    let started = Instant::now();
+    let result = dispatch(&runner, &host, &local_path, &remote_path, &args).and_then(|()| {
+        if args.clone && !args.dry_run {
+            write_clone_project(&local_path, &host, &remote_path)
+        } else {
+            Ok(())
+        }
+    });
+    record_run(
+        &runner,
+        &args,
+        &hosts,
+        &local_path,
+        Some(&remote_path),
+        started,
+        &result,
+    );
+    result
+}
+
+fn dispatch(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    // An rclone remote isn't reached over ssh.
+    if !args.no_multiplex && args.backend != Some(Backend::Rclone) {
+        let reused = master_running(runner, host, args);
+        info!(host, reused, "ssh connection");
+        if args.json {
+            emit(json!({ "event": "connection", "host": host, "reused": reused }));
+        } else if reused {
+            println!("🔗 Reusing the open connection to {}", host);
+        }
+    }
+    if !args.shell && !args.diff && !args.verify_sums {
+        check_remote(runner, host, args)?;
+    }
+    if args.shell {
+        open_shell(runner, host, local_path, remote_path, args)?;
+    } else if args.diff {
+        diff_remote(runner, host, local_path, remote_path, args)?;
+    } else if args.manifest {
+        sums::write_manifest(runner, host, local_path, remote_path, args)?;
+    } else if args.verify_sums {
+        sums::verify(runner, host, remote_path, args)?;
+    } else if args.estimate {
+        estimate(runner, host, local_path, remote_path, args)?;
+    } else if args.bench {
+        bench::run(runner, host, args)?;
+    } else if args.watch {
+        if !args.json {
+            println!("👀 Watching for changes in {}...", local_path.display());
+        }
+        watch_loop(runner, host, local_path, remote_path, args)?;
+    } else if args.status {
+        status(runner, host, local_path, remote_path, args)?;
+    } else if args.snapshot {
+        with_hooks(runner, host, local_path, remote_path, args, || {
+            snapshot(runner, host, local_path, args)
+        })?;
+    } else if args.prune {
+        prune(runner, host, local_path, args)?;
+    } else if args.undo {
+        undo(runner, host, local_path, args)?;
+    } else if !args.exec.is_empty() {
+        with_hooks(runner, host, local_path, remote_path, args, || {
+            push(runner, host, local_path, remote_path, args, "")
+        })?;
+        if !args.dry_run {
+            let tty = std::io::stdin().is_terminal();
+            exec_remote(runner, host, local_path, remote_path, args, tty)?;
+        }
+    } else if args.both {
+        with_hooks(runner, host, local_path, remote_path, args, || {
+            sync_both(runner, host, local_path, remote_path, args)
+        })?;
+    } else {
+        with_hooks(runner, host, local_path, remote_path, args, || {
+            if args.is_push() {
+                let context = if args.is_pull() { "[Upstream]" } else { "" };
+                push(runner, host, local_path, remote_path, args, context)?;
+            }
+
+            if args.is_pull() {
+                let context = if args.is_push() { "[Downstream]" } else { "" };
+                pull(runner, host, local_path, remote_path, args, context)?;
+            }
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    host: Option<String>,
+    all: Option<bool>,
+    large: Option<bool>,
+    gitignore: Option<bool>,
+    max_size: Option<String>,
+    backup: Option<bool>,
+    no_perms: Option<bool>,
+    no_multiplex: Option<bool>,
+    notify: Option<bool>,
+    verify: Option<bool>,
+    discover: Vec<Discover>,
+    exclude: Vec<String>,
+    skip_compress: Vec<String>,
+    rsync_args: Vec<String>,
+    remote_path: Option<String>,
+    remote_paths: BTreeMap<String, String>,
+    pre_cmd: Option<String>,
+    post_cmd: Option<String>,
+    on_hook_error: Option<OnHookError>,
+    on_success: Option<String>,
+    on_failure: Option<String>,
+    max_total_size: Option<ByteSize>,
+    retries: Option<u32>,
+    retry_delay: Option<u64>,
+    timeout: Option<u64>,
+    snapshot_root: Option<String>,
+    protected_paths: Option<Vec<String>>,
+    keep_daily: Option<usize>,
+    keep_weekly: Option<usize>,
+    hosts: BTreeMap<String, HostConfig>,
+    groups: BTreeMap<String, Vec<String>>,
+    profiles: BTreeMap<String, Config>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct HostConfig {
+    remote_paths: BTreeMap<String, String>,
+    bwlimit: Option<String>,
+    checksum: Option<bool>,
+    identity: Option<String>,
+    ssh_opts: Vec<String>,
+    jump: Option<String>,
+    rsync_path: Option<String>,
+    backend: Option<Backend>,
+    encrypt: Option<String>,
+    obfuscate_names: Option<bool>,
+    age_identity: Option<String>,
+    compress: Option<bool>,
+    compress_level: Option<u32>,
+    compress_choice: Option<String>,
+    whole_file: Option<bool>,
+    block_size: Option<u32>,
+    fuzzy: Option<bool>,
+    rsync_args: Vec<String>,
+}
+
+impl HostConfig {
+    fn apply(&self, args: &mut Args) {
+        if args.bwlimit.is_none() {
+            args.bwlimit = self.bwlimit.clone();
+        }
+        args.checksum |= self.checksum.unwrap_or(false);
+        if let (None, Some(identity)) = (&args.identity, &self.identity) {
+            args.identity = Some(expand_path(identity).unwrap_or_else(|_| identity.into()));
+        }
+        args.ssh_opts.extend(self.ssh_opts.iter().cloned());
+        if args.jump.is_none() {
+            args.jump = self.jump.clone();
+        }
+        if args.rsync_path.is_none() {
+            args.rsync_path = self.rsync_path.clone();
+        }
+        if args.backend.is_none() {
+            args.backend = self.backend;
+        }
+        if args.encrypt.is_none() {
+            args.encrypt = self.encrypt.clone();
+        }
+        args.obfuscate_names |= self.obfuscate_names.unwrap_or(false);
+        if let (None, Some(identity)) = (&args.age_identity, &self.age_identity) {
+            args.age_identity = Some(expand_path(identity).unwrap_or_else(|_| identity.into()));
+        }
+        if args.compress.is_none() {
+            args.compress = self.compress;
+        }
+        if args.compress_level.is_none() {
+            args.compress_level = self.compress_level;
+        }
+        if args.compress_choice.is_none() {
+            args.compress_choice = self.compress_choice.clone();
+        }
+        // An explicit --block-size asks for deltas, whatever the host's default.
+        args.whole_file |= self.whole_file.unwrap_or(false) && args.block_size.is_none();
+        if args.block_size.is_none() && !args.whole_file {
+            args.block_size = self.block_size;
+        }
+        args.fuzzy |= self.fuzzy.unwrap_or(false);
+        args.rsync_args.extend(self.rsync_args.iter().cloned());
+    }
+}
+
+// One host to sync with, with its settings resolved.
+struct Target {
+    host: String,
+    remote_path: String,
+    args: Args,
+}
+
+impl Config {
+    fn profile(&self, name: &str) -> Result<&Config> {
+        self.profiles.get(name).ok_or_else(|| {
+            let known: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            if known.is_empty() {
+                anyhow!("unknown profile '{}' (no profiles configured)", name)
+            } else {
+                anyhow!(
+                    "unknown profile '{}' (available: {})",
+                    name,
+                    known.join(", ")
+                )
+            }
+        })
+    }
+
+    // Flags given on the command line win; boolean flags can only be turned
+    // on from the CLI, so a config `true` sticks.
+    fn apply(&self, args: &mut Args) {
+        if args.host.is_none() {
+            args.host = self.host.clone();
+        }
+        if args.max_size.is_none() {
+            args.max_size = self.max_size.clone();
+        }
+        args.all |= self.all.unwrap_or(false);
+        args.large |= self.large.unwrap_or(false);
+        args.gitignore |= self.gitignore.unwrap_or(false);
+        args.backup |= self.backup.unwrap_or(false);
+        args.no_perms |= self.no_perms.unwrap_or(false);
+        args.no_multiplex |= self.no_multiplex.unwrap_or(false);
+        args.notify |= self.notify.unwrap_or(false);
+        args.verify |= self.verify.unwrap_or(false);
+        if args.discover.is_empty() {
+            args.discover = self.discover.clone();
+        }
+        args.exclude.extend(self.exclude.iter().cloned());
+        args.skip_compress
+            .extend(self.skip_compress.iter().cloned());
+        args.rsync_args.extend(self.rsync_args.iter().cloned());
+        if args.remote_path.is_none() {
+            args.remote_path = self.remote_path.clone();
+        }
+        if args.pre_cmd.is_none() {
+            args.pre_cmd = self.pre_cmd.clone();
+        }
+        if args.post_cmd.is_none() {
+            args.post_cmd = self.post_cmd.clone();
+        }
+        if args.on_hook_error.is_none() {
+            args.on_hook_error = self.on_hook_error;
+        }
+        if args.on_success.is_none() {
+            args.on_success = self.on_success.clone();
+        }
+        if args.on_failure.is_none() {
+            args.on_failure = self.on_failure.clone();
+        }
+        if args.max_total_size.is_none() {
+            args.max_total_size = self.max_total_size;
+        }
+        if args.retries.is_none() {
+            args.retries = self.retries;
+        }
+        if args.retry_delay.is_none() {
+            args.retry_delay = self.retry_delay;
+        }
+        if args.timeout.is_none() {
+            args.timeout = self.timeout;
+        }
+        if args.snapshot_root.is_none() {
+            args.snapshot_root = self.snapshot_root.clone();
+        }
+        if args.protected_paths.is_none() {
+            args.protected_paths = self.protected_paths.clone();
+        }
+        if args.keep_daily.is_none() {
+            args.keep_daily = self.keep_daily;
+        }
+        if args.keep_weekly.is_none() {
+            args.keep_weekly = self.keep_weekly;
+        }
+    }
+}
+
+fn default_config_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+    Ok(home.join(".config").join("syncz").join("config.toml"))
+}
+
+fn default_jobs_path() -> Result<PathBuf> {
+    Ok(default_config_path()?.with_file_name("jobs.toml"))
+}
+
+fn load_config(explicit: Option<&Path>) -> Result<Config> {
+    let path = match explicit {
+        Some(p) => p.to_path_buf(),
+        None => {
+            let p = default_config_path()?;
+            if !p.exists() {
+                return Ok(Config::default());
+            }
+            p
+        }
+    };
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?;
+    parse_config(&contents).with_context(|| format!("invalid config {}", path.display()))
+}
+
+fn parse_config(contents: &str) -> Result<Config> {
+    let config: Config = toml::from_str(contents)?;
+    if config.remote_path.is_some() {
+        bail!("remote_path is only allowed inside a [profiles.<name>] table");
+    }
+    for (name, profile) in &config.profiles {
+        if !profile.profiles.is_empty() {
+            bail!("profile '{}' cannot contain nested profiles", name);
+        }
+        if !profile.remote_paths.is_empty() || !profile.hosts.is_empty() {
+            bail!(
+                "profile '{}' cannot contain remote_paths or hosts; use remote_path instead",
+                name
+            );
+        }
+        if !profile.groups.is_empty() {
+            bail!("profile '{}' cannot contain groups", name);
+        }
+    }
+    Ok(config)
+}
+
+fn show_config(opts: &ConfigArgs) -> Result<()> {
+    let path = match &opts.config {
+        Some(p) => p.clone(),
+        None => default_config_path()?,
+    };
+    let config = load_config(opts.config.as_deref())?;
+    if path.exists() {
+        println!("Config file:  {}", path.display());
+    } else {
+        println!("Config file:  {} (not found)", path.display());
+    }
+    if config.profiles.is_empty() {
+        println!("Profiles:     none");
+    } else {
+        let names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+        println!("Profiles:     {}", names.join(", "));
+    }
+
+    let local_path = normalize_path(&expand_path(opts.path.as_deref().unwrap_or("."))?)?;
+    match find_project(&local_path)? {
+        Some(project) => println!(
+            "Project file: {}",
+            project.root.join(PROJECT_FILE).display()
+        ),
+        None => println!("Project file: none"),
+    }
+    Ok(())
+}
+
+const PROJECT_FILE: &str = ".syncz.toml";
+
+// `syncz which` is meant for scripts: it fails instead of showing the host
+// picker, and doesn't count as a use of the host.
+fn which_args(mut args: Args) -> Args {
+    args.which = true;
+    args.non_interactive = true;
+    args
+}
+
+// `syncz clone` is a pull into a new (or empty) directory, created here so
+// the rest of the run sees an existing local path.
+fn clone_args(opts: &CloneArgs) -> Result<Args> {
+    let local = match &opts.local_path {
+        Some(path) => path.clone(),
+        None => clone_dir_name(&opts.remote_path)
+            .ok_or_else(|| {
+                anyhow!(
+                    "can't name a directory after '{}'; pass a local path",
+                    opts.remote_path
+                )
+            })?
+            .to_string(),
+    };
+    let local_path = expand_path(&local)?;
+    let occupied = fs::read_dir(&local_path).is_ok_and(|mut entries| entries.next().is_some());
+    if occupied || local_path.is_file() {
+        bail!(
+            "{} already exists and isn't an empty directory",
+            local_path.display()
+        );
+    }
+    fs::create_dir_all(&local_path)
+        .with_context(|| format!("failed to create {}", local_path.display()))?;
+    Ok(Args {
+        path: Some(local_path.to_string_lossy().to_string()),
+        host: Some(opts.host.clone()),
+        remote_path: Some(opts.remote_path.clone()),
+        all: opts.all,
+        large: opts.large,
+        profile: opts.profile.clone(),
+        pull: true,
+        clone: true,
+        ..Default::default()
+    })
+}
+
+// Like `git clone`, the last component of the remote path.
+fn clone_dir_name(remote_path: &str) -> Option<&str> {
+    let name = remote_path.trim_end_matches('/').rsplit('/').next()?;
+    (!name.is_empty() && name != "~" && name != "." && name != "..").then_some(name)
+}
+
+// The file excludes itself, so it doesn't travel back to the remote.
+fn clone_project_file(host: &str, remote_path: &str) -> String {
+    format!(
+        "# Written by `syncz clone`: `syncz` in this directory syncs with {}:{}\n\
+         host = {}\n\
+         remote_path = {}\n\
+         exclude = [{}]\n",
+        host,
+        remote_path,
+        toml::Value::String(host.to_string()),
+        toml::Value::String(remote_path.to_string()),
+        toml::Value::String(PROJECT_FILE.to_string()),
+    )
+}
+
+fn write_clone_project(local_path: &Path, host: &str, remote_path: &str) -> Result<()> {
+    let path = local_path.join(PROJECT_FILE);
+    if path.exists() {
+        eprintln!(
+            "⚠️  The remote directory has its own {}; left it as is, so later syncs follow it",
+            PROJECT_FILE
+        );
+        return Ok(());
+    }
+    fs::write(&path, clone_project_file(host, remote_path))
+        .with_context(|| format!("failed to write {}", path.display()))?;
+    println!(
+        "📌 {} is bound to {}:{} ({})",
+        local_path.display(),
+        host,
+        remote_path,
+        PROJECT_FILE
+    );
+    Ok(())
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ProjectConfig {
+    host: Option<String>,
+    remote_path: Option<String>,
+    exclude: Vec<String>,
+    pre_cmd: Option<String>,
+    post_cmd: Option<String>,
+    remotes: BTreeMap<String, ProjectRemote>,
+}
+
+// A `[remotes.<name>]` table, targeted with `--to <name>`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProjectRemote {
+    host: String,
+    // Replaces the project's remote_path for this remote.
+    remote_path: Option<String>,
+}
+
+impl ProjectConfig {
+    fn apply(&self, args: &mut Args) {
+        if args.host.is_none() {
+            args.host = self.host.clone();
+        }
+        if args.pre_cmd.is_none() {
+            args.pre_cmd = self.pre_cmd.clone();
+        }
+        if args.post_cmd.is_none() {
+            args.post_cmd = self.post_cmd.clone();
+        }
+        args.exclude.extend(self.exclude.iter().cloned());
+    }
+}
+
+struct Project {
+    root: PathBuf,
+    config: ProjectConfig,
+}
+
+impl Project {
+    // The remote's host; its remote_path (if any) becomes the project's.
+    fn select_remote(&mut self, name: &str) -> Result<String> {
+        let Some(remote) = self.config.remotes.get(name) else {
+            let known: Vec<&str> = self.config.remotes.keys().map(String::as_str).collect();
+            bail!(
+                "no remote named '{}' in {} (known: {})",
+                name,
+                self.root.join(PROJECT_FILE).display(),
+                if known.is_empty() {
+                    "none".to_string()
+                } else {
+                    known.join(", ")
+                }
+            );
+        };
+        let host = remote.host.clone();
+        if let Some(path) = &remote.remote_path {
+            self.config.remote_path = Some(path.clone());
+        }
+        Ok(host)
+    }
+
+    fn remote_path(&self, local_path: &Path, home: &Path) -> String {
+        let Some(base) = &self.config.remote_path else {
+            return map_to_remote(local_path, home);
+        };
+        match local_path.strip_prefix(&self.root) {
+            Ok(rel) if rel.as_os_str().is_empty() => base.clone(),
+            Ok(rel) => format!("{}/{}", base.trim_end_matches('/'), rel.to_string_lossy()),
+            Err(_) => map_to_remote(local_path, home),
+        }
+    }
+}
+
+// `syncz remote`: edits the project file as text, so its comments and
+// layout survive. Remotes are `[remotes.<name>]` tables at the end.
+fn manage_remotes(opts: &RemoteArgs) -> Result<()> {
+    let cwd = std::env::current_dir().with_context(|| "failed to read the current directory")?;
+    let project = find_project(&cwd)?;
+    let path = project
+        .as_ref()
+        .map_or_else(|| cwd.join(PROJECT_FILE), |p| p.root.join(PROJECT_FILE));
+    let contents = if path.is_file() {
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?
+    } else {
+        String::new()
+    };
+    match &opts.command {
+        RemoteCommand::Add {
+            name,
+            host,
+            remote_path,
+        } => {
+            check_remote_name(name)?;
+            let mut updated = remove_remote_table(&contents, name).unwrap_or(contents);
+            let table = remote_table(name, host, remote_path.as_deref());
+            if updated.trim().is_empty() {
+                updated = table.trim_start().to_string();
+            } else {
+                if !updated.ends_with('\n') {
+                    updated.push('\n');
+                }
+                updated.push_str(&table);
+            }
+            // Catches a remote written some other way (an inline table) that
+            // the text edit didn't replace.
+            toml::from_str::<ProjectConfig>(&updated)
+                .with_context(|| format!("can't add remote '{}' to {}", name, path.display()))?;
+            fs::write(&path, updated)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            println!("Added remote '{}' ({}) to {}", name, host, path.display());
+        }
+        RemoteCommand::List => {
+            let remotes = project.map(|p| p.config.remotes).unwrap_or_default();
+            if remotes.is_empty() {
+                println!("No remotes; add one with `syncz remote add NAME HOST`");
+                return Ok(());
+            }
+            let width = remotes.keys().map(String::len).max().unwrap_or(0);
+            for (name, remote) in &remotes {
+                match &remote.remote_path {
+                    Some(dir) => println!("{:width$}  {}:{}", name, remote.host, dir),
+                    None => println!("{:width$}  {}", name, remote.host),
+                }
+            }
+        }
+        RemoteCommand::Remove { name } => {
+            let updated = remove_remote_table(&contents, name)
+                .ok_or_else(|| anyhow!("no [remotes.{}] table in {}", name, path.display()))?;
+            fs::write(&path, updated)
+                .with_context(|| format!("failed to write {}", path.display()))?;
+            println!("Removed remote '{}' from {}", name, path.display());
+        }
+    }
+    Ok(())
+}
+
+// Names are bare TOML keys, so the table header needs no quoting.
+fn check_remote_name(name: &str) -> Result<()> {
+    let valid = !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!(
+            "invalid remote name '{}'; use letters, digits, '-' and '_'",
+            name
+        );
+    }
+    Ok(())
+}
+
+fn remote_table(name: &str, host: &str, remote_path: Option<&str>) -> String {
+    let mut table = format!(
+        "\n[remotes.{}]\nhost = {}\n",
+        name,
+        toml::Value::String(host.to_string())
+    );
+    if let Some(path) = remote_path {
+        table.push_str(&format!(
+            "remote_path = {}\n",
+            toml::Value::String(path.to_string())
+        ));
+    }
+    table
+}
+
+// Drops the `[remotes.<name>]` table, up to the next table header; None if
+// there is none.
+fn remove_remote_table(contents: &str, name: &str) -> Option<String> {
+    let header = format!("[remotes.{}]", name);
+    let mut out = String::new();
+    let mut skipping = false;
+    let mut found = false;
+    for line in contents.split_inclusive('\n') {
+        let code = line.split('#').next().unwrap_or("").trim();
+        if code.starts_with('[') {
+            skipping = code == header;
+            found |= skipping;
+        }
+        if !skipping {
+            out.push_str(line);
+        }
+    }
+    if !found {
+        return None;
+    }
+    let mut out = out.trim_end().to_string();
+    if !out.is_empty() {
+        out.push('\n');
+    }
+    Some(out)
+}
+
+fn find_project(local_path: &Path) -> Result<Option<Project>> {
+    for dir in local_path.ancestors() {
+        let candidate = dir.join(PROJECT_FILE);
+        if !candidate.is_file() {
+            continue;
+        }
+        let contents = fs::read_to_string(&candidate)
+            .with_context(|| format!("failed to read {}", candidate.display()))?;
+        let config: ProjectConfig = toml::from_str(&contents)
+            .with_context(|| format!("invalid project config {}", candidate.display()))?;
+        return Ok(Some(Project {
+            root: dir.to_path_buf(),
+            config,
+        }));
+    }
+    Ok(None)
+}
+
+fn get_state_path() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+    Ok(home.join(".syncz_state"))
+}
+
+fn save_last_host(host: &str) -> Result<()> {
+    let path = get_state_path()?;
+    fs::write(path, host).context("failed to save last host")?;
+    Ok(())
+}
+
+fn load_last_host() -> Result<Option<String>> {
+    let path = get_state_path()?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let host = fs::read_to_string(path)?.trim().to_string();
+    if host.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(host))
+    }
+}
+
+// Most recently used first: (local path, host).
+type RecentHosts = Vec<(PathBuf, String)>;
+
+const MAX_RECENT_HOSTS: usize = 200;
+
+// One line of history.jsonl. `argv` and `cwd` are kept so `syncz last` can
+// run the same command again.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct HistoryEntry {
+    timestamp: String,
+    direction: String,
+    hosts: Vec<String>,
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    remote_path: Option<String>,
+    bytes: u64,
+    duration_ms: u64,
+    exit_code: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    cwd: PathBuf,
+    argv: Vec<String>,
+}
+
+// Bytes moved by every rsync run in this process (sent plus received), for
+// the history entry.
+static TRANSFERRED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// A --backup transfer: the files it replaced in `dest_dir` were moved to
+// `backup_dir` on the destination side (the local machine for pulls).
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct BackupSet {
+    timestamp: String,
+    host: String,
+    path: PathBuf,
+    pulled: bool,
+    dest_dir: String,
+    backup_dir: String,
+}
+
+fn backups_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("backups.jsonl"))
+}
+
+fn append_backup_set(path: &Path, set: &BackupSet) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let line = serde_json::to_string(set)?;
+    writeln!(file, "{}", line).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn load_backup_sets(path: &Path) -> Result<Vec<BackupSet>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+// Backups go to ~/.syncz-trash/<time> on whichever side is written to. rsync
+// takes --backup-dir literally, so the remote home is looked up first.
+fn new_backup_set(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    pulling: bool,
+    args: &Args,
+) -> Result<BackupSet> {
+    let timestamp = chrono::Local::now().format("%Y-%m-%dT%H-%M-%S").to_string();
+    let (home, dest_dir) = if pulling {
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+        let dest = if is_file {
+            local_path.parent().unwrap_or(local_path)
+        } else {
+            local_path
+        };
+        (
+            home.to_string_lossy().to_string(),
+            dest.to_string_lossy().to_string(),
+        )
+    } else {
+        let home = remote_home(runner, host, args)?;
+        let dest = if is_file {
+            parent_of_remote(remote_path)
+        } else {
+            remote_path.to_string()
+        };
+        let dest = match dest.strip_prefix('~') {
+            Some(rest) => format!("{}{}", home, rest),
+            None => dest,
+        };
+        (home, dest)
+    };
+    Ok(BackupSet {
+        backup_dir: format!("{}/.syncz-trash/{}", home.trim_end_matches('/'), timestamp),
+        timestamp,
+        host: host.to_string(),
+        path: local_path.to_path_buf(),
+        pulled: pulling,
+        dest_dir,
+    })
+}
+
+fn remote_home(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<String> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push("printf %s \"$HOME\"".to_string());
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    let home = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || home.is_empty() {
+        bail!("could not find the home directory on {}", host);
+    }
+    Ok(home)
+}
+
+// Index of the newest backup set of `local_path` with `host`.
+fn latest_backup_set(sets: &[BackupSet], host: &str, local_path: &Path) -> Option<usize> {
+    sets.iter()
+        .rposition(|set| set.host == host && set.path == local_path)
+}
+
+// Copies a backup set back over its destination. Returns false when the set
+// is empty: rsync only creates the backup directory once it moves a file.
+fn restore_backup_set(runner: &dyn CommandRunner, set: &BackupSet, args: &Args) -> Result<bool> {
+    let rsync_args = vec![
+        "-a".to_string(),
+        format!("{}/", set.backup_dir),
+        format!("{}/", set.dest_dir),
+    ];
+    if set.pulled {
+        if !Path::new(&set.backup_dir).is_dir() {
+            return Ok(false);
+        }
+        let status = runner
+            .status("rsync", &rsync_args)
+            .with_context(|| "failed to run rsync")?;
+        if !status.success() {
+            return Err(RsyncError::new(status.code(), "", false).into());
+        }
+        return Ok(true);
+    }
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(set.host.clone());
+    cmd_args.push(privileged(
+        args,
+        &format!("test -d {}", shell_escape(&set.backup_dir)),
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh test -d")?;
+    if status.code() == Some(255) {
+        bail!("could not connect to {} over ssh", set.host);
+    }
+    if !status.success() {
+        return Ok(false);
+    }
+    let program = remote_rsync(args).unwrap_or_else(|| "rsync".to_string());
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(set.host.clone());
+    cmd_args.push(format!(
+        "{} {}",
+        program,
+        rsync_args
+            .iter()
+            .map(|a| if a.starts_with('-') {
+                a.clone()
+            } else {
+                shell_escape(a)
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh rsync")?;
+    if !status.success() {
+        return Err(RsyncError::new(status.code(), "", false).into());
+    }
+    Ok(true)
+}
+
+fn undo(runner: &dyn CommandRunner, host: &str, local_path: &Path, args: &Args) -> Result<()> {
+    let path = backups_path()?;
+    let mut sets = load_backup_sets(&path)?;
+    let Some(index) = latest_backup_set(&sets, host, local_path) else {
+        bail!(
+            "no --backup syncs of {} with {} to undo",
+            local_path.display(),
+            host
+        );
+    };
+    let set = sets[index].clone();
+    let side = if set.pulled { "local" } else { host };
+    if args.dry_run {
+        println!(
+            "Would restore {} ({}) from {} into {}",
+            set.timestamp, side, set.backup_dir, set.dest_dir
+        );
+        return Ok(());
+    }
+    let prompt = format!(
+        "Restore the files the {} sync replaced in {} ({})?",
+        set.timestamp, set.dest_dir, side
+    );
+    if !confirm(&prompt, args)? {
+        bail!("undo cancelled");
+    }
+    let restored = restore_backup_set(runner, &set, args)?;
+    sets.remove(index);
+    let mut contents = String::new();
+    for set in &sets {
+        contents.push_str(&serde_json::to_string(set)?);
+        contents.push('\n');
+    }
+    fs::write(&path, contents).with_context(|| format!("failed to write {}", path.display()))?;
+    if args.json {
+        emit(json!({
+            "event": "undo",
+            "host": host,
+            "timestamp": set.timestamp,
+            "restored": restored,
+        }));
+    } else if restored {
+        println!("↩️  Restored files from the {} sync", set.timestamp);
+    } else {
+        println!("The {} sync didn't replace any files", set.timestamp);
+    }
+    Ok(())
+}
+
+fn history_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("history.jsonl"))
+}
+
+fn history_direction(args: &Args) -> &'static str {
+    if args.both {
+        "both"
+    } else if !args.exec.is_empty() {
+        "exec"
+    } else if args.is_push() && args.is_pull() {
+        "sync"
+    } else if args.is_pull() {
+        "pull"
+    } else {
+        "push"
+    }
+}
+
+// Records a finished sync and reports it: with --notify to the desktop, and
+// to the on_success / on_failure hook. Previews, watch sessions, status
+// checks and shells move no data and are left out; neither a history write
+// error nor a failing report ever fails the sync.
+fn record_run(
+    runner: &dyn CommandRunner,
+    args: &Args,
+    hosts: &[String],
+    local_path: &Path,
+    remote_path: Option<&str>,
+    started: Instant,
+    result: &Result<()>,
+) {
+    if args.dry_run
+        || args.watch
+        || args.status
+        || args.shell
+        || args.diff
+        || args.verify_sums
+        || args.estimate
+        || args.bench
+        || args.prune
+        || args.undo
+    {
+        return;
+    }
+    let entry = HistoryEntry {
+        timestamp: chrono::Local::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, false),
+        direction: history_direction(args).to_string(),
+        hosts: hosts.to_vec(),
+        path: local_path.to_path_buf(),
+        remote_path: remote_path.map(str::to_string),
+        bytes: TRANSFERRED_BYTES.load(Ordering::Relaxed),
+        duration_ms: started.elapsed().as_millis() as u64,
+        exit_code: result.as_ref().err().map_or(0, exit_code),
+        error: result.as_ref().err().map(|e| format!("{:#}", e)),
+        cwd: std::env::current_dir().unwrap_or_default(),
+        argv: std::env::args().skip(1).collect(),
+    };
+    if let Err(e) = history_path().and_then(|path| append_history(&path, &entry)) {
+        warn!("failed to record history: {:#}", e);
+    }
+    if args.notify {
+        let (title, body) = desktop_notification(&entry);
+        send_desktop_notification(&title, &body);
+    }
+    let hook = if result.is_ok() {
+        &args.on_success
+    } else {
+        &args.on_failure
+    };
+    if let Some(hook) = hook {
+        if let Err(e) = run_result_hook(runner, hook, &result_payload(&entry)) {
+            warn!("{:#}", e);
+            if !args.json {
+                eprintln!("⚠️  {:#}", e);
+            }
+        }
+    }
+}
+
+// What on_success / on_failure receive. `text` is a one-line summary, so the
+// payload can go straight to a Slack or Mattermost incoming webhook.
+fn result_payload(entry: &HistoryEntry) -> serde_json::Value {
+    let (title, body) = desktop_notification(entry);
+    json!({
+        "status": if entry.error.is_some() { "failure" } else { "success" },
+        "host": entry.hosts.join(","),
+        "hosts": entry.hosts,
+        "path": entry.path,
+        "remote_path": entry.remote_path,
+        "direction": entry.direction,
+        "bytes": entry.bytes,
+        "duration_ms": entry.duration_ms,
+        "exit_code": entry.exit_code,
+        "error": entry.error,
+        "timestamp": entry.timestamp,
+        "text": format!("{}\n{}", title, body),
+    })
+}
+
+// A URL gets the payload POSTed with curl; anything else runs as a local
+// shell command with the payload on stdin.
+fn run_result_hook(
+    runner: &dyn CommandRunner,
+    hook: &str,
+    payload: &serde_json::Value,
+) -> Result<()> {
+    let body = payload.to_string();
+    let output = if hook.starts_with("http://") || hook.starts_with("https://") {
+        let mut curl_args: Vec<String> = [
+            "-fsS",
+            "-m",
+            "10",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+        ]
+        .map(String::from)
+        .to_vec();
+        curl_args.push(body);
+        curl_args.push(hook.to_string());
+        runner
+            .output("curl", &curl_args)
+            .with_context(|| format!("failed to run curl for {}", hook))?
+    } else {
+        let script = format!("printf '%s\\n' {} | {}", shell_escape(&body), hook);
+        runner
+            .output("sh", &["-c".to_string(), script])
+            .with_context(|| format!("failed to run '{}'", hook))?
+    };
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        bail!(
+            "result hook '{}' failed ({}): {}",
+            hook,
+            output.status,
+            stderr.trim()
+        );
+    }
+    Ok(())
+}
+
+fn desktop_notification(entry: &HistoryEntry) -> (String, String) {
+    let outcome = if entry.error.is_some() {
+        "failed"
+    } else {
+        "finished"
+    };
+    let preposition = match entry.direction.as_str() {
+        "push" | "exec" => "to",
+        "pull" => "from",
+        _ => "with",
+    };
+    let title = format!(
+        "syncz: {} {} {} {}",
+        entry.direction,
+        preposition,
+        entry.hosts.join(", "),
+        outcome
+    );
+    let name = entry.path.file_name().map_or_else(
+        || entry.path.display().to_string(),
+        |n| n.to_string_lossy().into_owned(),
+    );
+    let duration = format_duration(entry.duration_ms);
+    let body = match &entry.error {
+        Some(error) => format!("{}: {} (after {})", name, error, duration),
+        None => format!("{}: {} in {}", name, format_size(entry.bytes), duration),
+    };
+    (title, body)
+}
+
+// terminal-notifier on macOS when installed; otherwise notify-rust, which
+// talks to the notification daemon over D-Bus on Linux and to Notification
+// Center on macOS without any external program. Best effort: a failure only
+// logs a warning.
+fn send_desktop_notification(title: &str, body: &str) {
+    if cfg!(target_os = "macos") {
+        let status = Command::new("terminal-notifier")
+            .args(["-title", title, "-message", body])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status();
+        match status {
+            Ok(status) if status.success() => return,
+            Ok(status) => warn!(%status, "terminal-notifier failed"),
+            Err(e) => debug!(error = %e, "terminal-notifier not available"),
+        }
+    }
+    if let Err(e) = notify_rust::Notification::new()
+        .appname("syncz")
+        .summary(title)
+        .body(body)
+        .show()
+    {
+        warn!(error = %e, "could not show a desktop notification");
+    }
+}
+
+// "42s", "3m 05s", "1h 02m".
+fn format_duration(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs % 3600 / 60),
+    }
+}
+
+fn append_history(path: &Path, entry: &HistoryEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let line = serde_json::to_string(entry)?;
+    writeln!(file, "{}", line).with_context(|| format!("failed to write {}", path.display()))
+}
+
+// Oldest first, as written. Lines that don't parse (e.g. from a newer
+// version) are skipped.
+fn load_history(path: &Path) -> Result<Vec<HistoryEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+fn filter_history<'a>(
+    entries: &'a [HistoryEntry],
+    opts: &HistoryArgs,
+    path: Option<&Path>,
+) -> Vec<&'a HistoryEntry> {
+    entries
+        .iter()
+        .rev()
+        .filter(|e| path.is_none_or(|p| e.path.starts_with(p)))
+        .filter(|e| opts.host.as_ref().is_none_or(|h| e.hosts.contains(h)))
+        .filter(|e| !opts.failed || e.exit_code != 0)
+        .take(opts.limit)
+        .collect()
+}
+
+fn show_history(opts: &HistoryArgs) -> Result<()> {
+    let entries = load_history(&history_path()?)?;
+    let path = match &opts.path {
+        Some(p) => Some(normalize_path(&expand_path(p)?)?),
+        None => None,
+    };
+    let shown = filter_history(&entries, opts, path.as_deref());
+    if opts.json {
+        for entry in shown {
+            println!("{}", serde_json::to_string(entry)?);
+        }
+        return Ok(());
+    }
+    if shown.is_empty() {
+        println!("No syncs recorded yet.");
+        return Ok(());
+    }
+    for entry in shown {
+        let when = chrono::DateTime::parse_from_rfc3339(&entry.timestamp)
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+            .unwrap_or_else(|_| entry.timestamp.clone());
+        let outcome = if entry.exit_code == 0 {
+            "✅".to_string()
+        } else {
+            format!("❌ {}", entry.exit_code)
+        };
+        println!(
+            "{}  {:<5} {:<4} {}  {} -> {}  {} in {:.1}s",
+            when,
+            entry.direction,
+            outcome,
+            entry.path.display(),
+            entry.hosts.join(","),
+            entry.remote_path.as_deref().unwrap_or("-"),
+            format_size(entry.bytes),
+            entry.duration_ms as f64 / 1000.0,
+        );
+    }
+    Ok(())
+}
+
+// Runs the most recent recorded command again from the directory it was
+// started in, and exits with its status.
+fn rerun_last() -> Result<()> {
+    let entry = load_history(&history_path()?)?
+        .pop()
+        .ok_or_else(|| anyhow!("no syncs recorded yet"))?;
+    eprintln!(
+        "↻ {} (in {})",
+        command_line("syncz", &entry.argv),
+        entry.cwd.display()
+    );
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let status = Command::new(exe)
+        .args(&entry.argv)
+        .current_dir(&entry.cwd)
+        .status()
+        .with_context(|| format!("failed to run syncz in {}", entry.cwd.display()))?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct JobFile {
+    jobs: Vec<Job>,
+}
+
+// One sync in a job file: the command line it stands for is built by
+// job_argv, so a job accepts everything the CLI does through `flags`.
+// `watch` and `every` only matter to `syncz agent`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Job {
+    name: String,
+    path: String,
+    host: Option<String>,
+    #[serde(default)]
+    direction: JobDirection,
+    remote_path: Option<String>,
+    #[serde(default)]
+    exclude: Vec<String>,
+    #[serde(default)]
+    flags: Vec<String>,
+    #[serde(default)]
+    watch: bool,
+    every: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum JobDirection {
+    Push,
+    Pull,
+    // Push, then pull (the bare `syncz` form).
+    #[default]
+    Sync,
+    // --both: reconcile against the last synced state.
+    Both,
+}
+
+fn parse_job_file(contents: &str) -> Result<JobFile> {
+    let file: JobFile = toml::from_str(contents)?;
+    let mut seen = BTreeSet::new();
+    for job in &file.jobs {
+        if !seen.insert(job.name.as_str()) {
+            bail!("job '{}' is defined twice", job.name);
+        }
+        if job.watch && !matches!(job.direction, JobDirection::Push | JobDirection::Sync) {
+            bail!(
+                "job '{}': watch jobs push, so they can't have a direction",
+                job.name
+            );
+        }
+        if let Some(every) = &job.every {
+            schedule::parse_interval(every).with_context(|| format!("job '{}'", job.name))?;
+        }
+    }
+    Ok(file)
+}
+
+// Jobs run as `syncz ... --json` children, which keeps their locks, history
+// entries and byte counts apart and lets run_jobs follow them by event.
+fn job_argv(job: &Job, config: Option<&Path>) -> Vec<String> {
+    let mut argv = Vec::new();
+    match job.direction {
+        _ if job.watch => argv.push("push".to_string()),
+        JobDirection::Push => argv.push("push".to_string()),
+        JobDirection::Pull => argv.push("pull".to_string()),
+        JobDirection::Sync | JobDirection::Both => {}
+    }
+    argv.push(job.path.clone());
+    match &job.host {
+        Some(host) => argv.push(host.clone()),
+        None => argv.push("--last".to_string()),
+    }
+    if job.direction == JobDirection::Both {
+        argv.push("--both".to_string());
+    }
+    if let Some(remote_path) = &job.remote_path {
+        argv.push("--remote-path".to_string());
+        argv.push(remote_path.clone());
+    }
+    for pattern in &job.exclude {
+        argv.push("--exclude".to_string());
+        argv.push(pattern.clone());
+    }
+    argv.extend(job.flags.iter().cloned());
+    if let Some(config) = config {
+        argv.push("--config".to_string());
+        argv.push(config.display().to_string());
+    }
+    argv.push("--json".to_string());
+    argv
+}
+
+// What a job's event stream said about it.
+#[derive(Debug, Default)]
+struct JobOutcome {
+    bytes: u64,
+    error: Option<String>,
+}
+
+impl JobOutcome {
+    // Returns a progress message for the events worth showing.
+    fn observe(&mut self, line: &str) -> Option<String> {
+        let event: serde_json::Value = serde_json::from_str(line).ok()?;
+        match event["event"].as_str()? {
+            "connection" => Some(format!("connected to {}", event["host"].as_str()?)),
+            "progress" => Some(format!(
+                "{}% {} {}",
+                event["percent"].as_u64()?,
+                format_size(event["bytes"].as_u64()?),
+                event["speed"].as_str()?
+            )),
+            "transfer" => {
+                let stats = &event["stats"];
+                self.bytes += match stats["sent_bytes"].as_u64() {
+                    Some(sent) => sent + stats["received_bytes"].as_u64().unwrap_or(0),
+                    None => event["sent_bytes"].as_u64().unwrap_or(0),
+                };
+                Some(format!("{} transferred", format_size(self.bytes)))
+            }
+            "retry" => Some("retrying".to_string()),
+            "error" => {
+                self.error = event["message"].as_str().map(str::to_string);
+                None
+            }
+            _ => None,
+        }
+    }
+}
+
+struct JobResult {
+    name: String,
+    outcome: JobOutcome,
+    duration: Duration,
+}
+
+// `syncz run FILE`: every job (or the named ones) as its own syncz process,
+// at most --jobs at once, each with a status line, then a summary table.
+fn run_jobs(opts: &RunArgs) -> Result<()> {
+    let contents = fs::read_to_string(&opts.file)
+        .with_context(|| format!("failed to read {}", opts.file.display()))?;
+    let file = parse_job_file(&contents)
+        .with_context(|| format!("invalid job file {}", opts.file.display()))?;
+    for name in &opts.names {
+        if !file.jobs.iter().any(|j| &j.name == name) {
+            bail!("no job named '{}' in {}", name, opts.file.display());
+        }
+    }
+    let jobs: Vec<&Job> = file
+        .jobs
+        .iter()
+        .filter(|j| opts.names.is_empty() || opts.names.contains(&j.name))
+        .collect();
+    if jobs.is_empty() {
+        bail!("{} defines no jobs", opts.file.display());
+    }
+    // Relative job paths are relative to the job file, not to wherever
+    // `syncz run` happens to be started.
+    let dir = opts
+        .file
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .map_or_else(|| PathBuf::from("."), Path::to_path_buf);
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let config = opts
+        .config
+        .as_deref()
+        .map(std::path::absolute)
+        .transpose()?;
+
+    let progress = if opts.json {
+        MultiProgress::with_draw_target(ProgressDrawTarget::hidden())
+    } else {
+        MultiProgress::new()
+    };
+    let width = jobs.iter().map(|j| j.name.len()).max().unwrap_or(0);
+    let bars: Vec<ProgressBar> = jobs
+        .iter()
+        .map(|job| {
+            let bar = progress.add(ProgressBar::new_spinner());
+            bar.set_style(
+                ProgressStyle::with_template("{spinner} {prefix} {msg} {elapsed:.dim}").unwrap(),
+            );
+            bar.set_prefix(format!("{:width$}", job.name, width = width));
+            bar.set_message("queued");
+            bar
+        })
+        .collect();
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<(usize, JobResult)>> = Mutex::new(Vec::new());
+    std::thread::scope(|scope| {
+        for _ in 0..(opts.jobs as usize).min(jobs.len()) {
+            scope.spawn(|| loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                let Some(job) = jobs.get(i) else {
+                    break;
+                };
+                let result = run_job(&exe, &dir, job, config.as_deref(), &bars[i], &|_| {});
+                results.lock().expect("job results").push((i, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().expect("job results");
+    results.sort_by_key(|(i, _)| *i);
+    let results: Vec<JobResult> = results.into_iter().map(|(_, r)| r).collect();
+
+    if opts.json {
+        for r in &results {
+            emit(json!({
+                "event": "job_result",
+                "job": r.name,
+                "ok": r.outcome.error.is_none(),
+                "bytes": r.outcome.bytes,
+                "error": r.outcome.error,
+                "duration_ms": r.duration.as_millis() as u64,
+            }));
+        }
+    } else {
+        println!("{}", format_job_results(&results));
+    }
+    let failed = results.iter().filter(|r| r.outcome.error.is_some()).count();
+    if failed > 0 {
+        bail!("{} of {} jobs failed", failed, results.len());
+    }
+    Ok(())
+}
+
+// `on_event` sees every line the child prints, for callers that track more
+// than the outcome.
+fn run_job(
+    exe: &Path,
+    dir: &Path,
+    job: &Job,
+    config: Option<&Path>,
+    bar: &ProgressBar,
+    on_event: &dyn Fn(&str),
+) -> JobResult {
+    let start = Instant::now();
+    bar.enable_steady_tick(Duration::from_millis(100));
+    bar.set_message("starting");
+    let argv = job_argv(job, config);
+    info!(job = %job.name, command = %command_line("syncz", &argv), "running job");
+    let mut outcome = JobOutcome::default();
+    let spawned = Command::new(exe)
+        .args(&argv)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn();
+    let status = spawned.map_err(anyhow::Error::from).and_then(|mut child| {
+        // Drained on the side so a chatty stderr can't block the child.
+        let stderr = child.stderr.take().map(|stderr| {
+            std::thread::spawn(move || {
+                BufReader::new(stderr)
+                    .lines()
+                    .map_while(|l| l.ok())
+                    .filter(|l| !l.trim().is_empty())
+                    .last()
+            })
+        });
+        if let Some(stdout) = child.stdout.take() {
+            for line in BufReader::new(stdout).lines().map_while(|l| l.ok()) {
+                if let Some(message) = outcome.observe(&line) {
+                    bar.set_message(message);
+                }
+                on_event(&line);
+            }
+        }
+        let status = child.wait()?;
+        let last_stderr = stderr.and_then(|h| h.join().ok()).flatten();
+        if !status.success() && outcome.error.is_none() {
+            outcome.error = Some(last_stderr.unwrap_or_else(|| format!("exited with {}", status)));
+        }
+        Ok(status)
+    });
+    if let Err(e) = status {
+        outcome.error = Some(format!("failed to start syncz: {:#}", e));
+    }
+    let duration = start.elapsed();
+    match &outcome.error {
+        None => bar.finish_with_message(format!(
+            "✅ {} in {:.2?}",
+            format_size(outcome.bytes),
+            duration
+        )),
+        Some(e) => bar.finish_with_message(format!("❌ {}", e)),
+    }
+    JobResult {
+        name: job.name.clone(),
+        outcome,
+        duration,
+    }
+}
+
+fn format_job_results(results: &[JobResult]) -> String {
+    let width = results.iter().map(|r| r.name.len()).max().unwrap_or(0);
+    let mut lines = vec!["Jobs:".to_string()];
+    for r in results {
+        lines.push(match &r.outcome.error {
+            None => format!(
+                "  ✅ {:width$}  {:>10}  {:.2?}",
+                r.name,
+                format_size(r.outcome.bytes),
+                r.duration,
+                width = width
+            ),
+            Some(e) => format!("  ❌ {:width$}  {}", r.name, e, width = width),
+        });
+    }
+    lines.join("\n")
+}
+
+fn recent_hosts_path() -> Result<PathBuf> {
+    Ok(data_dir()?.join("recent_hosts.tsv"))
+}
+
+fn load_recent_hosts(path: &Path) -> Result<RecentHosts> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(contents
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .map(|(host, dir)| (PathBuf::from(dir), host.to_string()))
+        .collect())
+}
+
+fn save_recent_hosts(path: &Path, recent: &RecentHosts) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut contents = String::new();
+    for (dir, host) in recent {
+        contents.push_str(&format!("{}\t{}\n", host, dir.display()));
+    }
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+// The host to use when none is given, or None to ask with the picker. A
+// script or CI job only gets the host used for this directory: the last host
+// from anywhere else could be the wrong machine entirely.
+fn remembered_host(recent: &RecentHosts, local_path: &Path, args: &Args) -> Result<Option<String>> {
+    if let Some(h) = recent_host(recent, local_path) {
+        if !args.json {
+            eprintln!("Using {} (last used for this directory)", h);
+        }
+        return Ok(Some(h.to_string()));
+    }
+    if args.last {
+        return match load_last_host()? {
+            Some(h) => Ok(Some(h)),
+            None => bail!("no host given and none used before; pass a HOST"),
+        };
+    }
+    if args.non_interactive {
+        bail!(
+            "no host given and none used before for {}; pass a HOST (the picker is disabled without a terminal)",
+            local_path.display()
+        );
+    }
+    Ok(None)
+}
+
+// The host used for this path, or for the closest parent that has one.
+fn recent_host<'a>(recent: &'a RecentHosts, local_path: &Path) -> Option<&'a str> {
+    local_path.ancestors().find_map(|dir| {
+        recent
+            .iter()
+            .find(|(path, _)| path == dir)
+            .map(|(_, host)| host.as_str())
+    })
+}
+
+fn remember_host(recent: &mut RecentHosts, local_path: &Path, host: &str) {
+    recent.retain(|(path, _)| path != local_path);
+    recent.insert(0, (local_path.to_path_buf(), host.to_string()));
+    recent.truncate(MAX_RECENT_HOSTS);
+}
+
+fn watch_loop(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = RecommendedWatcher::new(tx, notify::Config::default())?;
+
+    watcher.watch(local_path, RecursiveMode::Recursive)?;
+
+    let quiet_period = Duration::from_millis(500);
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut pending = Some(
+            rx.recv()
+                .map_err(|_| anyhow!("file watcher stopped unexpectedly"))?,
+        );
+        // Keep collecting until the tree has been quiet for a moment so a burst
+        // of saves (or a git checkout) turns into a single push.
+        while let Some(event) = pending.take() {
+            match event {
+                Ok(event) => {
+                    for path in event.paths {
+                        if let Ok(rel) = path.strip_prefix(local_path) {
+                            if !rel.as_os_str().is_empty() && !path_excluded(rel, args) {
+                                changed.insert(rel.to_string_lossy().to_string());
+                            }
+                        }
+                    }
+                }
+                Err(e) => eprintln!("❌ Watch error: {:?}", e),
+            }
+            pending = rx.recv_timeout(quiet_period).ok();
+        }
+
+        if changed.is_empty() {
+            continue;
+        }
+
+        if args.json {
+            emit(json!({ "event": "watch", "changed": changed }));
+            let synced = with_hooks(runner, host, local_path, remote_path, args, || {
+                push(runner, host, local_path, remote_path, args, "")
+            });
+            if let Err(e) = synced {
+                if e.downcast_ref::<Interrupted>().is_some() {
+                    return Err(e);
+                }
+                emit(json!({ "event": "error", "message": format!("{:#}", e) }));
+            }
+            continue;
+        }
+
+        println!(
+            "[{}] 🔄 {} changed, syncing...",
+            chrono::Local::now().format("%H:%M:%S"),
+            describe_changes(&changed)
+        );
+        let start = Instant::now();
+        let synced = with_hooks(runner, host, local_path, remote_path, args, || {
+            push(runner, host, local_path, remote_path, args, "[Watch]")
+        });
+        if synced
+            .as_ref()
+            .is_err_and(|e| e.downcast_ref::<Interrupted>().is_some())
+        {
+            return synced;
+        }
+        match synced {
+            Ok(()) => println!(
+                "[{}] ✅ Synced in {:.1?}",
+                chrono::Local::now().format("%H:%M:%S"),
+                start.elapsed()
+            ),
+            Err(e) => eprintln!(
+                "[{}] ❌ Sync failed: {}",
+                chrono::Local::now().format("%H:%M:%S"),
+                e
+            ),
+        }
+    }
+}
+
+// Dry runs never trigger hooks.
+fn with_hooks(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    sync: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    if args.dry_run {
+        return sync();
+    }
+    run_pre_hook(runner, local_path, args)?;
+    sync()?;
+    run_post_hook(runner, host, local_path, remote_path, args)
+}
+
+fn run_pre_hook(runner: &dyn CommandRunner, local_path: &Path, args: &Args) -> Result<()> {
+    let Some(cmd) = &args.pre_cmd else {
+        return Ok(());
+    };
+    let dir = if local_path.is_dir() {
+        local_path
+    } else {
+        local_path.parent().unwrap_or(local_path)
+    };
+    let script = format!("cd {} && {}", shell_escape(&dir.to_string_lossy()), cmd);
+    let status = runner
+        .status("sh", &["-c".to_string(), script])
+        .with_context(|| "failed to run pre-sync hook")?;
+    check_hook("pre-sync", cmd, status, args)
+}
+
+fn run_post_hook(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let Some(cmd) = &args.post_cmd else {
+        return Ok(());
+    };
+    let dir = remote_workdir(local_path, remote_path);
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!("cd {} && {}", remote_shell_path(&dir), cmd));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run post-sync hook")?;
+    check_hook("post-sync", cmd, status, args)
+}
+
+// The remote directory commands run in: the synced directory, or the parent
+// of a synced file.
+fn remote_workdir(local_path: &Path, remote_path: &str) -> String {
+    if local_path.is_file() {
+        parent_of_remote(remote_path)
+    } else {
+        remote_path.to_string()
+    }
+}
+
+// Like ssh itself, the command words are joined with spaces and left for the
+// remote shell to interpret.
+fn exec_remote(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    tty: bool,
+) -> Result<()> {
+    let dir = remote_workdir(local_path, remote_path);
+    let mut cmd_args = ssh_args(args);
+    if tty {
+        // Lets Ctrl-C reach the remote command instead of leaving it running.
+        cmd_args.push("-t".to_string());
+    }
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!(
+        "cd {} && {}",
+        remote_shell_path(&dir),
+        args.exec.join(" ")
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run remote command")?;
+    if !status.success() {
+        bail!("remote command failed on {} ({})", host, status);
+    }
+    Ok(())
+}
+
+fn open_shell(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let dir = remote_workdir(local_path, remote_path);
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push("-t".to_string());
+    cmd_args.push(host.to_string());
+    // Still open the shell (in the home directory) if the path hasn't been
+    // synced yet.
+    cmd_args.push(format!(
+        "cd {} || echo 'syncz: staying in the home directory' >&2; exec \"$SHELL\" -l",
+        remote_shell_path(&dir)
+    ));
+    runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    Ok(())
+}
+
+// `syncz diff`: the remote file is copied to a temporary file and compared
+// with `diff -u` (remote first, so `+` lines are what a push would write), or
+// handed to $DIFFTOOL as `$DIFFTOOL REMOTE LOCAL`.
+fn diff_remote(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    if !local_path.is_file() {
+        bail!(
+            "diff compares a single file, got {}; use `syncz status` for directories",
+            local_path.display()
+        );
+    }
+    let copy = fetch_remote_file(runner, host, local_path, remote_path, args)?;
+    let result = match std::env::var("DIFFTOOL").ok().filter(|t| !t.is_empty()) {
+        Some(tool) if !args.json => {
+            let cmd_args = vec![
+                "-c".to_string(),
+                format!("{} \"$1\" \"$2\"", tool),
+                "sh".to_string(),
+                copy.to_string_lossy().to_string(),
+                local_path.to_string_lossy().to_string(),
+            ];
+            runner
+                .status("sh", &cmd_args)
+                .with_context(|| format!("failed to run $DIFFTOOL ({})", tool))
+                .map(|_| ())
+        }
+        _ => unified_diff(runner, host, local_path, remote_path, &copy).map(|diff| {
+            if args.json {
+                emit(json!({
+                    "event": "diff",
+                    "host": host,
+                    "remote_path": remote_path,
+                    "identical": diff.is_none(),
+                    "diff": diff.as_deref().unwrap_or(""),
+                }));
+            } else if let Some(diff) = diff {
+                let diff = if use_color(args) {
+                    color_diff(&diff)
+                } else {
+                    diff
+                };
+                print_paged(diff.trim_end(), args);
+            } else {
+                println!("✨ {} is identical on {}", local_path.display(), host);
+            }
+        }),
+    };
+    let _ = fs::remove_file(&copy);
+    result
+}
+
+fn fetch_remote_file(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<PathBuf> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("cat -- {}", remote_shell_path(remote_path)),
+    ));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh cat")?;
+    if !output.status.success() {
+        bail!(
+            "failed to read {}:{}: {}",
+            host,
+            remote_path,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    // Keeping the file name keeps the extension, which difftools use to
+    // pick syntax highlighting.
+    let name = local_path
+        .file_name()
+        .map(|n| n.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let copy = std::env::temp_dir().join(format!("syncz-diff-{}-{}", std::process::id(), name));
+    fs::write(&copy, &output.stdout)
+        .with_context(|| format!("failed to write {}", copy.display()))?;
+    Ok(copy)
+}
+
+// None when the files are the same.
+fn unified_diff(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    copy: &Path,
+) -> Result<Option<String>> {
+    let cmd_args = vec![
+        "-u".to_string(),
+        "--label".to_string(),
+        format!("{}:{}", host, remote_path),
+        "--label".to_string(),
+        local_path.to_string_lossy().to_string(),
+        copy.to_string_lossy().to_string(),
+        local_path.to_string_lossy().to_string(),
+    ];
+    let output = runner
+        .output("diff", &cmd_args)
+        .with_context(|| "failed to run diff")?;
+    match output.status.code() {
+        Some(0) => Ok(None),
+        Some(1) => Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned())),
+        _ => bail!(
+            "diff failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ),
+    }
+}
+
+fn color_diff(diff: &str) -> String {
+    diff.lines()
+        .map(|line| {
+            let color = if line.starts_with("+++") || line.starts_with("---") {
+                "1"
+            } else if line.starts_with('+') {
+                "32"
+            } else if line.starts_with('-') {
+                "31"
+            } else if line.starts_with("@@") {
+                "36"
+            } else {
+                return format!("{}\n", line);
+            };
+            format!("\x1b[{}m{}\x1b[0m\n", color, line)
+        })
+        .collect()
+}
+
+fn check_hook(stage: &str, cmd: &str, status: std::process::ExitStatus, args: &Args) -> Result<()> {
+    if status.success() {
+        return Ok(());
+    }
+    match args.on_hook_error.unwrap_or_default() {
+        OnHookError::Abort => bail!("{} hook `{}` failed ({})", stage, cmd, status),
+        OnHookError::Warn => {
+            eprintln!(
+                "⚠️  {} hook `{}` failed ({}), continuing",
+                stage, cmd, status
+            );
+            Ok(())
+        }
+    }
+}
+
+fn describe_changes(changed: &BTreeSet<String>) -> String {
+    const SHOWN: usize = 3;
+    let names: Vec<&str> = changed.iter().take(SHOWN).map(String::as_str).collect();
+    if changed.len() > SHOWN {
+        format!("{} (+{} more)", names.join(", "), changed.len() - SHOWN)
+    } else {
+        names.join(", ")
+    }
+}
+
+// Mirrors the name-based exclude rules handed to rsync, for the places where
+// syncz looks at the local tree itself (watch events, sync state).
+fn path_excluded(rel: &Path, args: &Args) -> bool {
+    let components: Vec<String> = rel
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().to_string())
+        .collect();
+    let matches = |pattern: &str| {
+        let (name_pattern, dir_only) = match pattern.strip_suffix('/') {
+            Some(p) => (p, true),
+            None => (pattern, false),
+        };
+        if name_pattern.contains('/') {
+            return false;
+        }
+        components.iter().enumerate().any(|(idx, name)| {
+            let is_last = idx + 1 == components.len();
+            (!dir_only || !is_last) && glob_match(name_pattern, name)
+        })
+    };
+
+    if args.include.iter().any(|p| matches(p)) {
+        return false;
+    }
+    let defaults: &[&str] = if args.all { &[] } else { DEFAULT_EXCLUDES };
+    components.first().map(String::as_str) == Some(".git")
+        || defaults.iter().any(|p| matches(p))
+        || args.exclude.iter().any(|p| matches(p))
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            backtrack = Some((pi, ti));
+            pi += 1;
+        } else if let Some((star, matched)) = backtrack {
+            pi = star + 1;
+            ti = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+fn sync_both(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    if !local_path.is_dir() {
+        bail!(
+            "--both requires a local directory, got {}",
+            local_path.display()
+        );
+    }
+    let state_path = sync_state_path(host, local_path)?;
+    let previous = load_sync_state(&state_path)?;
+
+    let local = local_fingerprints(local_path, args)?;
+    let remote = remote_fingerprints(runner, host, remote_path, args)?;
+    let candidates = conflict_candidates(previous.as_ref(), &local, &remote);
+
+    let conflicts: Vec<String> = if candidates.is_empty() {
+        Vec::new()
+    } else {
+        // Both sides changed, but they may have been changed to the same thing
+        // (e.g. resolved with an explicit --push); only differing files conflict.
+        let mut differing = BTreeSet::new();
+        for pulling in [false, true] {
+            let summary = run_dry_run(
+                runner,
+                host,
+                local_path,
+                remote_path,
+                false,
+                args,
+                pulling,
+                None,
+            )?;
+            differing.extend(
+                summary
+                    .changes
+                    .into_iter()
+                    .filter(|change| !change.is_deletion() && !change.path.ends_with('/'))
+                    .map(|change| change.path),
+            );
+        }
+        candidates
+            .into_iter()
+            .filter(|path| differing.contains(path))
+            .collect()
+    };
+
+    let mut pass_args = args.clone();
+    for path in &conflicts {
+        pass_args.exclude.push(format!("/{}", path));
+    }
+
+    push(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        &pass_args,
+        "[Upstream]",
+    )?;
+    pull(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        &pass_args,
+        "[Downstream]",
+    )?;
+
+    if args.json && !conflicts.is_empty() {
+        emit(json!({ "event": "conflicts", "paths": conflicts }));
+    } else if !conflicts.is_empty() {
+        eprintln!(
+            "⚠️  {} conflict(s): changed on both sides since the last sync, left untouched:",
+            conflicts.len()
+        );
+        for path in &conflicts {
+            eprintln!("  ! {}", path);
+        }
+        eprintln!("Resolve each with --push or --pull, then run --both again.");
+    }
+
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let local = local_fingerprints(local_path, args)?;
+    let remote = remote_fingerprints(runner, host, remote_path, args)?;
+    let mut state = SyncState::new();
+    for path in local.keys().chain(remote.keys()) {
+        let entry = if conflicts.contains(path) {
+            // Keep the pre-conflict baseline so the conflict is reported until resolved.
+            match previous.as_ref().and_then(|prev| prev.get(path)) {
+                Some(entry) => entry.clone(),
+                None => continue,
+            }
+        } else {
+            (
+                local.get(path).cloned().unwrap_or_default(),
+                remote.get(path).cloned().unwrap_or_default(),
+            )
+        };
+        state.insert(path.clone(), entry);
+    }
+    save_sync_state(&state_path, &state)
+}
+
+// Per path: (local fingerprint, remote fingerprint) as of the last --both run.
+// An empty fingerprint means the file did not exist on that side.
+type SyncState = BTreeMap<String, (String, String)>;
+
+fn sync_state_path(host: &str, local_path: &Path) -> Result<PathBuf> {
+    let key = format!("{}@{}", host, local_path.display())
+        .replace('%', "%25")
+        .replace('/', "%2F");
+    Ok(data_dir()?.join("both").join(format!("{}.tsv", key)))
+}
+
+// Held while syncing a directory with a host, so that two syncz processes (say
+// a forgotten --watch and a manual push) never write the same files at once;
+// interleaved --inplace updates corrupt them. The OS releases the lock when
+// the file is closed, even if syncz is killed.
+struct SyncLock {
+    _file: fs::File,
+}
+
+// Dry runs, status, shells and diffs change nothing, so they don't take the lock.
+fn lock_sync(host: &str, local_path: &Path, args: &Args) -> Result<Option<SyncLock>> {
+    if args.dry_run || args.status || args.shell || args.diff || args.verify_sums || args.estimate {
+        return Ok(None);
+    }
+    let path = lock_path(&data_dir()?.join("locks"), host, local_path);
+    let what = format!("{} with {}", local_path.display(), host);
+    acquire_lock(&path, &what, args.wait, args.json).map(Some)
+}
+
+fn lock_path(dir: &Path, host: &str, local_path: &Path) -> PathBuf {
+    let key = format!("{}@{}", host, local_path.display());
+    let hash = blake3::hash(key.as_bytes()).to_hex();
+    dir.join(format!("{}.lock", &hash[..16]))
+}
+
+fn acquire_lock(path: &Path, what: &str, wait: bool, json: bool) -> Result<SyncLock> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    match file.try_lock() {
+        Ok(()) => {}
+        Err(fs::TryLockError::WouldBlock) => {
+            let mut holder = String::new();
+            let _ = std::io::Read::read_to_string(&mut file, &mut holder);
+            let holder = match holder.trim() {
+                "" => String::new(),
+                pid => format!(" (pid {})", pid),
+            };
+            if !wait {
+                bail!(
+                    "another syncz is already syncing {}{}; pass --wait to wait for it to finish",
+                    what,
+                    holder
+                );
+            }
+            if !json {
+                eprintln!(
+                    "⏳ Waiting for the other sync of {}{} to finish...",
+                    what, holder
+                );
+            }
+            file.lock()
+                .with_context(|| format!("failed to lock {}", path.display()))?;
+        }
+        Err(fs::TryLockError::Error(err)) => {
+            return Err(err).with_context(|| format!("failed to lock {}", path.display()));
+        }
+    }
+    file.set_len(0)?;
+    std::io::Seek::rewind(&mut file)?;
+    write!(file, "{}", std::process::id())?;
+    Ok(SyncLock { _file: file })
+}
+
+fn data_dir() -> Result<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+    Ok(home.join(".local").join("share").join("syncz"))
+}
+
+fn load_sync_state(path: &Path) -> Result<Option<SyncState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut state = SyncState::new();
+    for line in contents.lines() {
+        let mut fields = line.split('\t');
+        if let (Some(name), Some(local), Some(remote)) =
+            (fields.next(), fields.next(), fields.next())
+        {
+            state.insert(name.to_string(), (local.to_string(), remote.to_string()));
+        }
+    }
+    Ok(Some(state))
+}
+
+fn save_sync_state(path: &Path, state: &SyncState) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .with_context(|| format!("failed to create {}", parent.display()))?;
+    }
+    let mut contents = String::new();
+    for (name, (local, remote)) in state {
+        contents.push_str(&format!("{}\t{}\t{}\n", name, local, remote));
+    }
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn conflict_candidates(
+    previous: Option<&SyncState>,
+    local: &BTreeMap<String, String>,
+    remote: &BTreeMap<String, String>,
+) -> Vec<String> {
+    // Without a baseline there is no way to tell who changed what; the first
+    // run falls back to newer-wins and records one.
+    let Some(previous) = previous else {
+        return Vec::new();
+    };
+    let empty = String::new();
+    let paths: BTreeSet<&String> = local.keys().chain(remote.keys()).collect();
+    paths
+        .into_iter()
+        .filter(|path| {
+            let (prev_local, prev_remote) = previous
+                .get(*path)
+                .map(|(l, r)| (l, r))
+                .unwrap_or((&empty, &empty));
+            let now_local = local.get(*path).unwrap_or(&empty);
+            let now_remote = remote.get(*path).unwrap_or(&empty);
+            now_local != prev_local && now_remote != prev_remote
+        })
+        .cloned()
+        .collect()
+}
+
+fn local_fingerprints(root: &Path, args: &Args) -> Result<BTreeMap<String, String>> {
+    let mut out = BTreeMap::new();
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let entries =
+            fs::read_dir(&dir).with_context(|| format!("failed to read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let rel = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            if path_excluded(&rel, args) {
+                continue;
+            }
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                stack.push(path);
+            } else if file_type.is_file() {
+                let meta = entry.metadata()?;
+                let mtime = meta
+                    .modified()
+                    .ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default();
+                out.insert(
+                    rel.to_string_lossy().to_string(),
+                    format!("{}:{}", meta.len(), mtime),
+                );
+            }
+        }
+    }
+    Ok(out)
+}
+
+fn remote_fingerprints(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Result<BTreeMap<String, String>> {
+    let mut cmd_args = base_rsync_args(args, true);
+    cmd_args.push("--list-only".to_string());
+    cmd_args.push(format!("{}:{}/", host, remote_path));
+    let output = runner
+        .output("rsync", &cmd_args)
+        .with_context(|| "failed to run rsync --list-only")?;
+    if !output.status.success() {
+        // A missing remote directory simply has no files yet.
+        return Ok(BTreeMap::new());
+    }
+    Ok(parse_list_only(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Parses `rsync --list-only` lines such as
+// `-rw-r--r--          1,234 2024/06/01 12:00:00 src/main.rs`, keeping regular
+// files only. The timestamp is in the remote's timezone, which is fine since it
+// is only ever compared against earlier listings of the same host.
+fn parse_list_only(output: &str) -> BTreeMap<String, String> {
+    let mut out = BTreeMap::new();
+    for line in output.lines() {
+        let mut rest = line;
+        let mut fields = Vec::with_capacity(4);
+        for _ in 0..4 {
+            let trimmed = rest.trim_start();
+            let Some(end) = trimmed.find(' ') else {
+                break;
+            };
+            fields.push(&trimmed[..end]);
+            rest = &trimmed[end..];
+        }
+        let name = rest.trim_start();
+        if fields.len() != 4 || name.is_empty() {
+            continue;
+        }
+        let (perms, size, date, time) = (fields[0], fields[1], fields[2], fields[3]);
+        if perms.len() != 10 || !perms.starts_with('-') {
+            continue;
+        }
+        out.insert(
+            name.to_string(),
+            format!("{}:{} {}", size.replace(',', ""), date, time),
+        );
+    }
+    out
+}
+
+fn expand_path(raw: &str) -> Result<PathBuf> {
+    if raw.starts_with('~') {
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+        let mut expanded = home;
+        let rest = raw.trim_start_matches('~');
+        expanded.push(rest.trim_start_matches('/'));
+        Ok(expanded)
+    } else {
+        Ok(PathBuf::from(raw))
+    }
+}
+
+fn normalize_path(path: &Path) -> Result<PathBuf> {
+    let p = if path.is_relative() {
+        std::env::current_dir()?.join(path)
+    } else {
+        path.to_path_buf()
+    };
+    Ok(clean_path(&p))
+}
+
+fn clean_path(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                out.pop();
+            }
+            Component::CurDir => {}
+            other => out.push(other.as_os_str()),
+        }
+    }
+    out
+}
+
+fn map_to_remote(local: &Path, home: &Path) -> String {
+    if let Ok(rel) = local.strip_prefix(home) {
+        let mut remote = PathBuf::from("~");
+        remote.push(rel);
+        remote.to_string_lossy().to_string()
+    } else {
+        local.to_string_lossy().to_string()
+    }
+}
+// An explicit remote path typed as `~/x` reaches us already expanded by the
+// local shell; map it back so it lands in the remote home.
+fn normalize_remote_path(path: &str, home: &Path) -> String {
+    let trimmed = match path.trim_end_matches('/') {
+        "" => "/",
+        p => p,
+    };
+    match Path::new(trimmed).strip_prefix(home) {
+        Ok(rel) if rel.as_os_str().is_empty() => "~".to_string(),
+        Ok(rel) => format!("~/{}", rel.to_string_lossy()),
+        Err(_) => trimmed.to_string(),
+    }
+}
+
+// Applies `[remote_paths]` rules (local prefix -> remote path); the most
+// specific local prefix wins.
+fn mapped_remote_path(
+    rules: &BTreeMap<String, String>,
+    local_path: &Path,
+    home: &Path,
+) -> Option<String> {
+    rules
+        .iter()
+        .filter_map(|(local, remote)| {
+            let prefix = match local.strip_prefix('~') {
+                Some(rest) => home.join(rest.trim_start_matches('/')),
+                None => PathBuf::from(local),
+            };
+            let rel = local_path.strip_prefix(&prefix).ok()?;
+            Some((prefix.components().count(), remote, rel))
+        })
+        .max_by_key(|(depth, _, _)| *depth)
+        .map(|(_, remote, rel)| {
+            let base = normalize_remote_path(remote, home);
+            if rel.as_os_str().is_empty() {
+                base
+            } else {
+                format!("{}/{}", base.trim_end_matches('/'), rel.to_string_lossy())
+            }
+        })
+}
+
+// The inverse of the mapping below, for a sync given only a remote path: the
+// host's [hosts.<name>.remote_paths], the global [remote_paths] (rules with
+// $USER can't be inverted and are skipped), then the home-relative mirror.
+fn local_for_remote(config: &Config, host: &str, remote: &str, home: &Path) -> PathBuf {
+    let remote = normalize_remote_path(remote, home);
+    config
+        .hosts
+        .get(host_alias(host))
+        .and_then(|h| unmapped_local_path(&h.remote_paths, &remote, home))
+        .or_else(|| unmapped_local_path(&config.remote_paths, &remote, home))
+        .unwrap_or_else(|| match remote.strip_prefix('~') {
+            Some(rest) => home.join(rest.trim_start_matches('/')),
+            None if remote.starts_with('/') => PathBuf::from(&remote),
+            // Relative remote paths are relative to the remote home.
+            None => home.join(&remote),
+        })
+}
+
+// The most specific remote path wins.
+fn unmapped_local_path(
+    rules: &BTreeMap<String, String>,
+    remote: &str,
+    home: &Path,
+) -> Option<PathBuf> {
+    rules
+        .iter()
+        .filter(|(_, base)| !base.contains("$USER") && !base.contains("${USER}"))
+        .filter_map(|(local, base)| {
+            let base = normalize_remote_path(base, home);
+            let rel = if remote == base {
+                ""
+            } else if base == "/" {
+                remote.strip_prefix('/')?
+            } else {
+                remote.strip_prefix(base.as_str())?.strip_prefix('/')?
+            };
+            let prefix = match local.strip_prefix('~') {
+                Some(rest) => home.join(rest.trim_start_matches('/')),
+                None => PathBuf::from(local),
+            };
+            Some((base.len(), prefix, rel))
+        })
+        .max_by_key(|(len, _, _)| *len)
+        .map(|(_, prefix, rel)| {
+            if rel.is_empty() {
+                prefix
+            } else {
+                prefix.join(rel)
+            }
+        })
+}
+
+// Precedence: --remote-path (or a profile's remote_path), the project file,
+// the host's [hosts.<name>.remote_paths], the global [remote_paths], and
+// finally the home-relative mirror.
+fn resolve_remote_path(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    home: &Path,
+    args: &Args,
+    project: Option<&Project>,
+    config: &Config,
+) -> Result<String> {
+    if let Some(path) = &args.remote_path {
+        return Ok(normalize_remote_path(path, home));
+    }
+    if let Some(project) = project.filter(|p| p.config.remote_path.is_some()) {
+        return Ok(project.remote_path(local_path, home));
+    }
+    let mapped = config
+        .hosts
+        .get(host_alias(host))
+        .and_then(|h| mapped_remote_path(&h.remote_paths, local_path, home))
+        .or_else(|| mapped_remote_path(&config.remote_paths, local_path, home));
+    match mapped {
+        Some(path) if path.contains("$USER") || path.contains("${USER}") => {
+            let user = remote_user(runner, host)?;
+            Ok(path.replace("${USER}", &user).replace("$USER", &user))
+        }
+        Some(path) => Ok(path),
+        None => Ok(map_to_remote(local_path, home)),
+    }
+}
+
+// Whether two paths are the same or one lies inside the other.
+fn paths_overlap(a: &Path, b: &Path) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+// The destination of a sync with this machine (`localhost`, a loopback
+// address or its own hostname) must not overlap the source: rsync would copy
+// files onto themselves or recurse into its own output.
+fn check_overlap(host: &str, local_path: &Path, remote_path: &str, home: &Path) -> Result<()> {
+    let name = split_host_spec(host).map_or(host.to_string(), |(h, _)| h);
+    let name = host_alias(&name);
+    let this_host = fs::read_to_string("/etc/hostname").unwrap_or_default();
+    let is_local = matches!(name, "localhost" | "127.0.0.1" | "::1" | "[::1]")
+        || (!this_host.trim().is_empty() && name == this_host.trim());
+    if !is_local {
+        return Ok(());
+    }
+    let remote = match remote_path.strip_prefix('~') {
+        Some(rest) => home.join(rest.trim_start_matches('/')),
+        None => PathBuf::from(remote_path),
+    };
+    if paths_overlap(local_path, &remote) {
+        bail!(
+            "{} on {} overlaps the local path {}; syncing a directory with itself or a directory around it would copy files onto themselves",
+            remote_path,
+            host,
+            local_path.display()
+        );
+    }
+    Ok(())
+}
+
+const DEFAULT_PROTECTED_PATHS: &[&str] = &["~", "/", "/etc"];
+
+// Refuses a remote path that is a protected path or an ancestor of one: a
+// mapping gone wrong there (worse with --delete) would clobber the whole home
+// directory or system.
+fn check_protected(remote_path: &str, args: &Args) -> Result<()> {
+    if args.allow_protected {
+        return Ok(());
+    }
+    fn normalize(path: &str) -> String {
+        let parts: Vec<&str> = path
+            .split('/')
+            .filter(|p| !p.is_empty() && *p != ".")
+            .collect();
+        let joined = parts.join("/");
+        if path.starts_with('/') {
+            format!("/{}", joined)
+        } else {
+            joined
+        }
+    }
+    let dest = normalize(remote_path);
+    let protected: Vec<String> = match &args.protected_paths {
+        Some(paths) => paths.iter().map(|p| normalize(p)).collect(),
+        None => DEFAULT_PROTECTED_PATHS
+            .iter()
+            .map(|p| normalize(p))
+            .collect(),
+    };
+    for path in &protected {
+        let contains = path == &dest
+            || (dest == "/" && path.starts_with('/'))
+            || path.starts_with(&format!("{}/", dest));
+        if contains {
+            let what = if path == &dest {
+                "is".to_string()
+            } else {
+                format!("contains {}, which is", path)
+            };
+            bail!(
+                "the remote path {} {} protected; pass --allow-protected if you really mean it",
+                remote_path,
+                what
+            );
+        }
+    }
+    Ok(())
+}
+
+// A `host:path` argument, as in `syncz gpu1:~/data gpu2:~/data`. `host:2222`
+// is a port and anything with a `/` before the colon a local path.
+fn remote_spec(arg: &str) -> Option<(String, String)> {
+    let (host, path) = arg.split_once(':')?;
+    if host.is_empty()
+        || host.contains('/')
+        || path.is_empty()
+        || path.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+    Some((host.to_string(), path.to_string()))
+}
+
+// Copies between two remotes by running rsync on the source host, which
+// connects to the destination itself; the local ssh agent is forwarded so
+// it can log in with the same keys. The connection options (--port,
+// --identity, --jump, ...) only apply to the source host.
+fn remote_to_remote(
+    runner: &dyn CommandRunner,
+    (src_host, src_path): &(String, String),
+    (dst_host, dst_path): &(String, String),
+    args: &Args,
+) -> Result<()> {
+    if args.both || args.watch || args.status || args.shell || args.diff || !args.exec.is_empty() {
+        bail!("remote-to-remote syncs only copy one way; use --push (default) or --pull");
+    }
+    if args.select || args.interactive || args.since.is_some() || args.git_tracked || args.bootstrap
+    {
+        bail!("--select, --interactive, --since, --git-tracked and --bootstrap need a local side");
+    }
+    if args.backend.is_some_and(|b| b != Backend::Rsync) {
+        bail!("remote-to-remote syncs need rsync on the source host");
+    }
+    if args.encrypt.is_some() {
+        bail!("--encrypt needs a local side to encrypt and decrypt on");
+    }
+    // Home-relative paths are compared under a stand-in home directory.
+    let home = Path::new("/~");
+    if src_host == dst_host
+        && paths_overlap(
+            &home.join(sftp::sftp_path(src_path)),
+            &home.join(sftp::sftp_path(dst_path)),
+        )
+    {
+        bail!(
+            "{} and {} overlap on {}; pick a destination outside the source",
+            src_path,
+            dst_path,
+            src_host
+        );
+    }
+    let is_file = remote_is_file(runner, src_host, src_path, args).unwrap_or(false);
+    let dst_args = Args {
+        remote_sudo: args.remote_sudo,
+        no_multiplex: true,
+        ..Default::default()
+    };
+    ensure_remote_parent(runner, dst_host, &parent_of_remote(dst_path), &dst_args)?;
+
+    let mut rsync = base_rsync_args(args, args.dry_run);
+    // The -e ssh command names local control sockets and keys.
+    if let Some(e) = rsync.iter().position(|a| a == "-e") {
+        rsync.drain(e..e + 2);
+    }
+    if args.dry_run {
+        rsync.push("--dry-run".to_string());
+        rsync.push("--itemize-changes".to_string());
+    }
+    let slash = if is_file { "" } else { "/" };
+    let mut command = command_line("rsync", &rsync);
+    command.push(' ');
+    command.push_str(&shell_escape(&format!(
+        "{}{}",
+        sftp::sftp_path(src_path),
+        slash
+    )));
+    command.push(' ');
+    command.push_str(&shell_escape(&format!(
+        "{}:{}{}",
+        dst_host,
+        sftp::sftp_path(dst_path),
+        slash
+    )));
+    if !args.json {
+        println!(
+            "{}:{} -> {}:{} (rsync runs on {})",
+            src_host, src_path, dst_host, dst_path, src_host
+        );
+    }
+    let mut cmd_args = vec!["-A".to_string()];
+    cmd_args.extend(ssh_args(args));
+    cmd_args.push(src_host.clone());
+    cmd_args.push(command);
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    if !status.success() {
+        return Err(RsyncError::new(status.code(), "", args.dry_run).into());
+    }
+    Ok(())
+}
+
+// Splits a HOST argument written as `host:port` or `user@host:port`.
+// Anything else (including IPv6 addresses) is passed to ssh untouched.
+fn split_host_spec(spec: &str) -> Result<(String, Option<u16>)> {
+    match spec.rsplit_once(':') {
+        Some((host, port))
+            if !host.is_empty()
+                && !host.contains(':')
+                && port.chars().all(|c| c.is_ascii_digit()) =>
+        {
+            let port = port
+                .parse()
+                .map_err(|_| anyhow!("invalid port in '{}'", spec))?;
+            Ok((host.to_string(), Some(port)))
+        }
+        _ => Ok((spec.to_string(), None)),
+    }
+}
+
+// The ssh config alias of `user@host`, used to look up per-host settings.
+fn host_alias(host: &str) -> &str {
+    host.rsplit_once('@').map_or(host, |(_, alias)| alias)
+}
+
+// Whether rsync should compress for `host`: as asked or configured, else
+// not when `syncz bench` found the link faster without it, nor over a LAN
+// or loopback link, where -z costs more CPU time than it saves on the wire.
+fn use_compression(runner: &dyn CommandRunner, host: &str, args: &Args) -> bool {
+    if args.compress_level.is_some() || args.compress_choice.is_some() {
+        return true;
+    }
+    if let Some(compress) = args.compress {
+        return compress;
+    }
+    let benched = bench::bench_path()
+        .and_then(|path| bench::load_records(&path))
+        .ok()
+        .and_then(|records| records.get(host).cloned());
+    let compress = match benched {
+        Some(record) => record.compressed_rate >= record.uncompressed_rate,
+        None => !on_local_network(runner, host, args),
+    };
+    info!(host, compress, "compression");
+    compress
+}
+
+// A host ssh reaches directly at a loopback, private or link-local address.
+// Through a jump host, a private address is private to the far network.
+fn on_local_network(runner: &dyn CommandRunner, host: &str, args: &Args) -> bool {
+    if args.jump.is_some() {
+        return false;
+    }
+    let Ok(output) = runner.output("ssh", &["-G".to_string(), host.to_string()]) else {
+        return false;
+    };
+    if !output.status.success() {
+        return false;
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let option = |key: &str| {
+        stdout.lines().find_map(|line| {
+            line.strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix(' '))
+                .map(str::trim)
+        })
+    };
+    if option("proxyjump").is_some_and(|v| v != "none")
+        || option("proxycommand").is_some_and(|v| v != "none")
+    {
+        return false;
+    }
+    let Some(hostname) = option("hostname") else {
+        return false;
+    };
+    if hostname == "localhost" || hostname.ends_with(".local") {
+        return true;
+    }
+    let addresses: Vec<std::net::IpAddr> = match hostname.parse() {
+        Ok(ip) => vec![ip],
+        Err(_) => std::net::ToSocketAddrs::to_socket_addrs(&(hostname, 22))
+            .map(|addrs| addrs.map(|a| a.ip()).collect())
+            .unwrap_or_default(),
+    };
+    !addresses.is_empty() && addresses.into_iter().all(is_lan_address)
+}
+
+fn is_lan_address(ip: std::net::IpAddr) -> bool {
+    match ip {
+        std::net::IpAddr::V4(v4) => v4.is_loopback() || v4.is_private() || v4.is_link_local(),
+        std::net::IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            // Unique local (fc00::/7) and link-local (fe80::/10) addresses.
+            v6.is_loopback() || first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+        }
+    }
+}
+
+// The login user ssh would use for `host`, honouring ~/.ssh/config.
+fn remote_user(runner: &dyn CommandRunner, host: &str) -> Result<String> {
+    let output = runner
+        .output("ssh", &["-G".to_string(), host.to_string()])
+        .with_context(|| "failed to run ssh -G")?;
+    if !output.status.success() {
+        bail!("ssh -G {} failed", host);
+    }
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("user "))
+        .map(|user| user.trim().to_string())
+        .ok_or_else(|| anyhow!("could not determine the ssh user for {}", host))
+}
+
+fn pick_host_from_ssh_config(
+    runner: &dyn CommandRunner,
+    preferred: Option<&str>,
+    discover: &[Discover],
+) -> Result<String> {
+    let mut hosts = read_ssh_hosts()?;
+    for source in discover {
+        match source {
+            Discover::Tailscale => match tailscale_peers(runner) {
+                Ok(peers) => merge_tailnet_peers(&mut hosts, peers),
+                Err(e) => eprintln!("⚠️  Tailscale discovery failed: {:#}", e),
+            },
+            Discover::Lan => match lan_hosts(runner) {
+                Ok(found) => merge_lan_hosts(&mut hosts, found),
+                Err(e) => eprintln!("⚠️  LAN discovery failed: {:#}", e),
+            },
+        }
+    }
+    if let Some(preferred) = preferred {
+        let position = hosts.iter().position(|h| h.alias == preferred);
+        let host = match position {
+            Some(i) => hosts.remove(i),
+            None => SshHost {
+                alias: preferred.to_string(),
+                ..Default::default()
+            },
+        };
+        hosts.insert(0, host);
+    }
+    if hosts.is_empty() {
+        bail!("no hosts found in ~/.ssh/config and no host provided");
+    }
+
+    let last_used = last_used_by_host(&load_history(&history_path()?)?);
+    let rows = format_host_rows(&hosts, &last_used, chrono::Local::now().into());
+    let selection = FuzzySelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Select SSH host")
+        .items(&rows)
+        .default(0)
+        .interact()?;
+
+    Ok(hosts[selection].alias.clone())
+}
+
+// --failover: the first host that answers over ssh. Only a failed connection
+// moves on to the next one; a host that turns down our credentials is the
+// right machine, so that is an error.
+fn pick_failover_host(runner: &dyn CommandRunner, args: &Args, config: &Config) -> Result<String> {
+    let mut skipped = Vec::new();
+    for spec in &args.failover {
+        let (host, port) = split_host_spec(spec)?;
+        let mut host_args = args.clone();
+        if port.is_some() {
+            host_args.port = port;
+        }
+        if let Some(host_config) = config.hosts.get(host_alias(&host)) {
+            host_config.apply(&mut host_args);
+        }
+        let mut cmd_args = preflight_ssh_args(&host_args);
+        cmd_args.push(host.clone());
+        cmd_args.push("true".to_string());
+        let output = runner
+            .output("ssh", &cmd_args)
+            .with_context(|| "failed to run ssh")?;
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if output.status.success() {
+            if args.json {
+                emit(json!({ "event": "failover", "host": spec, "skipped": skipped }));
+            } else if skipped.is_empty() {
+                eprintln!("Using {} (first reachable of --failover)", spec);
+            } else {
+                eprintln!("↪️  Using {} after: {}", spec, skipped.join("; "));
+            }
+            return Ok(spec.clone());
+        }
+        if SSH_AUTH_ERRORS.iter().any(|s| stderr.contains(s)) {
+            return Err(ssh_failure(&host, &stderr));
+        }
+        skipped.push(ssh_failure(&host, &stderr).to_string());
+    }
+    bail!(
+        "none of the --failover hosts is reachable: {}",
+        skipped.join("; ")
+    )
+}
+
+// `@name` stands for the hosts of the `[groups]` entry `name`, which turns
+// the sync into a multi-host push.
+fn expand_host_groups(args: &mut Args, groups: &BTreeMap<String, Vec<String>>) -> Result<()> {
+    let expand = |host: &String| -> Result<Vec<String>> {
+        let Some(name) = host.strip_prefix('@') else {
+            return Ok(vec![host.clone()]);
+        };
+        match groups.get(name) {
+            Some(members) if !members.is_empty() => Ok(members.clone()),
+            Some(_) => bail!("host group '@{}' is empty", name),
+            None if groups.is_empty() => {
+                bail!("unknown host group '@{}' (no [groups] configured)", name)
+            }
+            None => {
+                let known: Vec<&str> = groups.keys().map(String::as_str).collect();
+                bail!(
+                    "unknown host group '@{}' (available: {})",
+                    name,
+                    known.join(", ")
+                )
+            }
+        }
+    };
+    let expand_all = |hosts: &[String]| -> Result<Vec<String>> {
+        Ok(hosts
+            .iter()
+            .map(expand)
+            .collect::<Result<Vec<_>>>()?
+            .concat())
+    };
+    args.extra_hosts = expand_all(&args.extra_hosts)?;
+    args.hosts = expand_all(&args.hosts)?;
+    args.failover = expand_all(&args.failover)?;
+    if let Some(host) = args.host.take() {
+        let mut members = expand(&host)?.into_iter();
+        args.host = members.next();
+        args.extra_hosts.splice(0..0, members);
+    }
+    Ok(())
+}
+
+// Replaces hosts given on the command line that aren't ssh config aliases
+// with the one alias they abbreviate ("gpu3" -> "lab-gpu3").
+fn expand_host_aliases(args: &mut Args) -> Result<()> {
+    if args.host.is_none() && args.extra_hosts.is_empty() {
+        return Ok(());
+    }
+    let aliases: Vec<String> = read_ssh_hosts()?.into_iter().map(|h| h.alias).collect();
+    let json = args.json;
+    for host in args
+        .host
+        .iter_mut()
+        .chain(args.extra_hosts.iter_mut())
+        .chain(args.hosts.iter_mut())
+    {
+        if let Some(alias) = match_host_alias(host, &aliases)? {
+            if !json {
+                eprintln!("Using {} (matched '{}')", alias, host);
+            }
+            *host = alias;
+        }
+    }
+    Ok(())
+}
+
+// The alias `host` stands for, if it isn't one itself: the only alias it
+// prefixes, else the only one containing it, else the only one containing
+// its characters in order. Names that look like real hostnames or addresses
+// are left alone, as are names that match nothing.
+fn match_host_alias(host: &str, aliases: &[String]) -> Result<Option<String>> {
+    if host.contains(['.', ':', '@']) || aliases.iter().any(|a| a == host) {
+        return Ok(None);
+    }
+    let needle = host.to_ascii_lowercase();
+    let is_subsequence = |alias: &str| {
+        let mut chars = alias.chars();
+        needle.chars().all(|c| chars.any(|a| a == c))
+    };
+    let tiers: [&dyn Fn(&str) -> bool; 3] = [
+        &|alias| alias.starts_with(&needle),
+        &|alias| alias.contains(&needle),
+        &is_subsequence,
+    ];
+    for matches in tiers {
+        let candidates: Vec<&String> = aliases
+            .iter()
+            .filter(|a| matches(&a.to_ascii_lowercase()))
+            .collect();
+        match candidates.as_slice() {
+            [] => continue,
+            [only] => return Ok(Some(only.to_string())),
+            many => {
+                let names: Vec<&str> = many.iter().map(|a| a.as_str()).collect();
+                bail!(
+                    "host '{}' is ambiguous; it matches {}",
+                    host,
+                    names.join(", ")
+                );
+            }
+        }
+    }
+    Ok(None)
+}
+
+// A `Host` alias from the ssh config with the options that apply to it. As
+// in ssh, the first value seen for an option wins, and wildcard blocks such
+// as `Host *` contribute to every alias they match.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SshHost {
+    alias: String,
+    hostname: Option<String>,
+    user: Option<String>,
+    port: Option<u16>,
+    /// Every option that applies, keyed by lowercase name.
+    options: BTreeMap<String, String>,
+    /// Whether the machine is online, for hosts that are in the tailnet.
+    tailnet: Option<bool>,
+    /// Whether the machine advertises ssh on the local network.
+    lan: bool,
+}
+
+struct TailnetPeer {
+    // The MagicDNS name, or the Tailscale IP without MagicDNS.
+    name: String,
+    ip: Option<String>,
+    online: bool,
+}
+
+fn tailscale_peers(runner: &dyn CommandRunner) -> Result<Vec<TailnetPeer>> {
+    let output = runner
+        .output("tailscale", &["status".to_string(), "--json".to_string()])
+        .with_context(|| "failed to run tailscale")?;
+    if !output.status.success() {
+        bail!(
+            "tailscale status failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_tailscale_status(&String::from_utf8_lossy(&output.stdout))
+}
+
+fn parse_tailscale_status(json: &str) -> Result<Vec<TailnetPeer>> {
+    let status: serde_json::Value =
+        serde_json::from_str(json).with_context(|| "unexpected tailscale status output")?;
+    let mut peers: Vec<TailnetPeer> = status["Peer"]
+        .as_object()
+        .into_iter()
+        .flat_map(|peers| peers.values())
+        .filter_map(|peer| {
+            let ip = peer["TailscaleIPs"][0].as_str().map(str::to_string);
+            let dns = peer["DNSName"].as_str().unwrap_or("").trim_end_matches('.');
+            let name = if dns.is_empty() {
+                ip.clone()?
+            } else {
+                dns.to_string()
+            };
+            Some(TailnetPeer {
+                name,
+                ip,
+                online: peer["Online"].as_bool().unwrap_or(false),
+            })
+        })
+        .collect();
+    peers.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(peers)
+}
+
+// Peers already in ssh_config (by alias, HostName, short name or IP) are
+// marked there; the rest follow, online ones first.
+fn merge_tailnet_peers(hosts: &mut Vec<SshHost>, peers: Vec<TailnetPeer>) {
+    let mut added = Vec::new();
+    for peer in peers {
+        let short = peer.name.split('.').next().unwrap_or(&peer.name);
+        let known = hosts.iter_mut().find(|h| {
+            [Some(&h.alias), h.hostname.as_ref()]
+                .into_iter()
+                .flatten()
+                .any(|n| *n == peer.name || n == short || Some(n) == peer.ip.as_ref())
+        });
+        match known {
+            Some(host) => host.tailnet = Some(peer.online),
+            None => added.push(SshHost {
+                alias: peer.name,
+                hostname: peer.ip,
+                tailnet: Some(peer.online),
+                ..Default::default()
+            }),
+        }
+    }
+    added.sort_by_key(|h| h.tailnet != Some(true));
+    hosts.extend(added);
+}
+
+struct LanHost {
+    // The mDNS name, e.g. `raspberrypi.local`.
+    name: String,
+    address: String,
+    port: u16,
+}
+
+// How long to listen for mDNS answers without avahi.
+const MDNS_BROWSE_TIME: Duration = Duration::from_secs(2);
+
+// Machines advertising `_ssh._tcp` over mDNS. On Linux avahi's daemon
+// usually owns the mDNS port and already knows them; elsewhere, or without
+// avahi-utils, syncz browses the network itself.
+fn lan_hosts(runner: &dyn CommandRunner) -> Result<Vec<LanHost>> {
+    if !cfg!(target_os = "linux") {
+        return browse_mdns(MDNS_BROWSE_TIME);
+    }
+    match avahi_hosts(runner) {
+        Ok(hosts) => Ok(hosts),
+        Err(e) => {
+            debug!(error = %format!("{:#}", e), "avahi-browse unavailable; browsing mDNS directly");
+            browse_mdns(MDNS_BROWSE_TIME)
+        }
+    }
+}
+
+fn browse_mdns(timeout: Duration) -> Result<Vec<LanHost>> {
+    let daemon = mdns_sd::ServiceDaemon::new().with_context(|| "failed to start mDNS browsing")?;
+    let events = daemon
+        .browse("_ssh._tcp.local.")
+        .with_context(|| "failed to browse mDNS for ssh hosts")?;
+    let deadline = Instant::now() + timeout;
+    let mut found = BTreeMap::new();
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        match events.recv_timeout(left) {
+            Ok(mdns_sd::ServiceEvent::ServiceResolved(info)) => {
+                let addresses = info.get_addresses().iter().copied();
+                if let Some(host) = lan_host(info.get_hostname(), info.get_port(), addresses) {
+                    found.insert(host.name.clone(), host);
+                }
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    let _ = daemon.shutdown();
+    Ok(found.into_values().collect())
+}
+
+// A resolved service: the host name without mDNS's trailing dot, and an
+// IPv4 address when there is one, as with avahi.
+fn lan_host(
+    hostname: &str,
+    port: u16,
+    addresses: impl Iterator<Item = std::net::IpAddr>,
+) -> Option<LanHost> {
+    let address = addresses.min_by_key(|ip| (!ip.is_ipv4(), *ip))?;
+    Some(LanHost {
+        name: hostname.trim_end_matches('.').to_string(),
+        address: address.to_string(),
+        port,
+    })
+}
+
+fn avahi_hosts(runner: &dyn CommandRunner) -> Result<Vec<LanHost>> {
+    let args: Vec<String> = [
+        "--resolve",
+        "--terminate",
+        "--parsable",
+        "--no-db-lookup",
+        "_ssh._tcp",
+    ]
+    .iter()
+    .map(|a| a.to_string())
+    .collect();
+    let output = runner
+        .output("avahi-browse", &args)
+        .with_context(|| "failed to run avahi-browse")?;
+    if !output.status.success() {
+        bail!(
+            "avahi-browse failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_avahi_browse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+// Resolved services are `=;iface;protocol;name;type;domain;host;address;port;txt`.
+// A machine shows up once per interface and protocol; IPv4 wins.
+fn parse_avahi_browse(output: &str) -> Vec<LanHost> {
+    let mut found: BTreeMap<String, (bool, LanHost)> = BTreeMap::new();
+    for line in output.lines() {
+        let fields: Vec<&str> = line.split(';').collect();
+        if fields.len() < 9 || fields[0] != "=" {
+            continue;
+        }
+        let Ok(port) = fields[8].parse() else {
+            continue;
+        };
+        let ipv4 = fields[2] == "IPv4";
+        let host = LanHost {
+            name: fields[6].to_string(),
+            address: fields[7].to_string(),
+            port,
+        };
+        match found.get(&host.name) {
+            Some((seen_ipv4, _)) if *seen_ipv4 || !ipv4 => {}
+            _ => {
+                found.insert(host.name.clone(), (ipv4, host));
+            }
+        }
+    }
+    found.into_values().map(|(_, host)| host).collect()
+}
+
+// Like tailnet peers: machines already in ssh_config are marked there, the
+// rest follow. An unusual port goes into the name so picking it connects.
+fn merge_lan_hosts(hosts: &mut Vec<SshHost>, found: Vec<LanHost>) {
+    for lan in found {
+        let short = lan.name.split('.').next().unwrap_or(&lan.name);
+        let known = hosts.iter_mut().find(|h| {
+            [Some(&h.alias), h.hostname.as_ref()]
+                .into_iter()
+                .flatten()
+                .any(|n| *n == lan.name || n == short || *n == lan.address)
+        });
+        match known {
+            Some(host) => host.lan = true,
+            None => hosts.push(SshHost {
+                alias: if lan.port == 22 {
+                    lan.name
+                } else {
+                    format!("{}:{}", lan.name, lan.port)
+                },
+                hostname: Some(lan.address),
+                port: Some(lan.port),
+                lan: true,
+                ..Default::default()
+            }),
+        }
+    }
+}
+
+fn read_ssh_hosts() -> Result<Vec<SshHost>> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+    let ssh_dir = home.join(".ssh");
+    let config_path = ssh_dir.join("config");
+    if !config_path.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut options = Vec::new();
+    read_ssh_config(&config_path, &ssh_dir, &home, 0, &mut options)?;
+    Ok(parse_ssh_hosts(&options))
+}
+
+// The patterns of a `Host` line and the options (lowercase key) under it.
+type SshBlock<'a> = (Vec<&'a str>, Vec<(String, &'a str)>);
+
+// Groups the flattened config into `Host` blocks and resolves each concrete
+// alias (patterns and negations are only used for matching). Options before
+// the first `Host` apply everywhere; `Match` blocks are skipped since they
+// depend on more than the alias.
+fn parse_ssh_hosts(options: &[(String, String)]) -> Vec<SshHost> {
+    let mut blocks: Vec<SshBlock> = vec![(vec!["*"], Vec::new())];
+    for (key, value) in options {
+        if key.eq_ignore_ascii_case("Host") {
+            blocks.push((value.split_whitespace().collect(), Vec::new()));
+        } else if key.eq_ignore_ascii_case("Match") {
+            blocks.push((Vec::new(), Vec::new()));
+        } else if let Some((_, block)) = blocks.last_mut() {
+            block.push((key.to_ascii_lowercase(), value.as_str()));
+        }
+    }
+
+    let mut aliases: Vec<&str> = blocks
+        .iter()
+        .flat_map(|(patterns, _)| patterns.iter().copied())
+        .filter(|p| !p.contains(['*', '?', '!']))
+        .collect();
+    aliases.sort();
+    aliases.dedup();
+
+    aliases
+        .into_iter()
+        .map(|alias| {
+            let mut resolved = BTreeMap::new();
+            for (patterns, block) in &blocks {
+                if !ssh_host_matches(patterns, alias) {
+                    continue;
+                }
+                for (key, value) in block {
+                    resolved
+                        .entry(key.clone())
+                        .or_insert_with(|| value.to_string());
+                }
+            }
+            SshHost {
+                alias: alias.to_string(),
+                hostname: resolved.get("hostname").cloned(),
+                user: resolved.get("user").cloned(),
+                port: resolved.get("port").and_then(|p| p.parse().ok()),
+                options: resolved,
+                tailnet: None,
+                lan: false,
+            }
+        })
+        .collect()
+}
+
+// A `Host` line matches when any pattern does and no `!pattern` does.
+fn ssh_host_matches(patterns: &[&str], alias: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns {
+        match pattern.strip_prefix('!') {
+            Some(negated) if ssh_pattern_matches(negated, alias) => return false,
+            Some(_) => {}
+            None => matched |= ssh_pattern_matches(pattern, alias),
+        }
+    }
+    matched
+}
+
+// `*` and `?` wildcards, compared case-insensitively like ssh does.
+fn ssh_pattern_matches(pattern: &str, text: &str) -> bool {
+    fn matches(pattern: &[u8], text: &[u8]) -> bool {
+        match pattern.split_first() {
+            None => text.is_empty(),
+            Some((b'*', rest)) => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+            Some((b'?', rest)) => !text.is_empty() && matches(rest, &text[1..]),
+            Some((c, rest)) => text
+                .split_first()
+                .is_some_and(|(t, text)| t.eq_ignore_ascii_case(c) && matches(rest, text)),
+        }
+    }
+    matches(pattern.as_bytes(), text.as_bytes())
+}
+
+// The newest history timestamp for each host.
+fn last_used_by_host(history: &[HistoryEntry]) -> BTreeMap<String, String> {
+    let mut last_used = BTreeMap::new();
+    for entry in history {
+        for host in &entry.hosts {
+            last_used.insert(host.clone(), entry.timestamp.clone());
+        }
+    }
+    last_used
+}
+
+// Picker rows: alias, user@hostname[:port], online/offline for tailnet
+// machines (or LAN for ones found there) and when syncz last synced with it, in aligned columns.
+fn format_host_rows(
+    hosts: &[SshHost],
+    last_used: &BTreeMap<String, String>,
+    now: chrono::DateTime<chrono::FixedOffset>,
+) -> Vec<String> {
+    let targets: Vec<String> = hosts
+        .iter()
+        .map(|h| {
+            let mut target = match (&h.hostname, &h.user, h.port) {
+                (Some(hostname), _, _) => hostname.clone(),
+                // Without a HostName, ssh connects to the alias itself.
+                (None, None, None) => String::new(),
+                (None, _, _) => h.alias.clone(),
+            };
+            if let Some(user) = &h.user {
+                target = format!("{}@{}", user, target);
+            }
+            if let Some(port) = h.port.filter(|&p| p != 22) {
+                target = format!("{}:{}", target, port);
+            }
+            target
+        })
+        .collect();
+    let alias_width = hosts.iter().map(|h| h.alias.len()).max().unwrap_or(0);
+    let target_width = targets.iter().map(String::len).max().unwrap_or(0);
+    let discovered = hosts.iter().any(|h| h.tailnet.is_some() || h.lan);
+    hosts
+        .iter()
+        .zip(&targets)
+        .map(|(host, target)| {
+            let used = last_used
+                .get(&host.alias)
+                .and_then(|t| chrono::DateTime::parse_from_rfc3339(t).ok())
+                .map(|t| format!("synced {}", format_age(now - t)))
+                .unwrap_or_default();
+            let state = match (host.lan, host.tailnet) {
+                (true, _) => "● LAN     ",
+                (false, Some(true)) => "● online  ",
+                (false, Some(false)) => "○ offline ",
+                (false, None) if discovered => "          ",
+                (false, None) => "",
+            };
+            format!(
+                "{:alias_width$}  {:target_width$}  {}{}",
+                host.alias,
+                target,
+                state,
+                used,
+                alias_width = alias_width,
+                target_width = target_width
+            )
+            .trim_end()
+            .to_string()
+        })
+        .collect()
+}
+
+fn format_age(age: chrono::TimeDelta) -> String {
+    let minutes = age.num_minutes();
+    if minutes < 1 {
+        "just now".to_string()
+    } else if minutes < 60 {
+        format!("{}m ago", minutes)
+    } else if minutes < 60 * 24 {
+        format!("{}h ago", minutes / 60)
+    } else {
+        format!("{}d ago", minutes / (60 * 24))
+    }
+}
+
+// ssh gives up on Include chains deeper than this, which also stops cycles.
+const MAX_SSH_INCLUDE_DEPTH: usize = 16;
+
+// Collects the `key value` options of an ssh config file in order, with
+// `Include` directives replaced by the options of the files they name.
+// Relative includes are looked up in ~/.ssh and globs expand in sorted
+// order, as ssh does; patterns that match nothing are skipped.
+fn read_ssh_config(
+    path: &Path,
+    ssh_dir: &Path,
+    home: &Path,
+    depth: usize,
+    options: &mut Vec<(String, String)>,
+) -> Result<()> {
+    if depth > MAX_SSH_INCLUDE_DEPTH {
+        bail!(
+            "ssh config includes nested too deeply at {}",
+            path.display()
+        );
+    }
+    let contents =
+        fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+    for line in contents.lines() {
+        let Some((key, value)) = split_ssh_option(line) else {
+            continue;
+        };
+        if !key.eq_ignore_ascii_case("Include") {
+            options.push((key.to_string(), value.to_string()));
+            continue;
+        }
+        for pattern in value.split_whitespace() {
+            let pattern = match pattern.strip_prefix("~/") {
+                Some(rest) => home.join(rest),
+                None => ssh_dir.join(pattern),
+            };
+            let Ok(matches) = glob::glob(&pattern.to_string_lossy()) else {
+                continue;
+            };
+            let mut files: Vec<PathBuf> = matches.filter_map(Result::ok).collect();
+            files.sort();
+            for file in files.iter().filter(|f| f.is_file()) {
+                read_ssh_config(file, ssh_dir, home, depth + 1, options)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Splits `Key value` or `Key=value`, skipping blanks and comments.
+fn split_ssh_option(line: &str) -> Option<(&str, &str)> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return None;
+    }
+    let end = line.find(|c: char| c.is_whitespace() || c == '=')?;
+    let (key, rest) = line.split_at(end);
+    let value = rest.trim_start();
+    let value = value.strip_prefix('=').unwrap_or(value).trim();
+    Some((key, value))
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct StatusReport {
+    local_only: Vec<(String, Option<u64>)>,
+    remote_only: Vec<(String, Option<u64>)>,
+    differ: Vec<(String, Option<u64>)>,
+}
+
+impl StatusReport {
+    // `outgoing` is a push dry run and `incoming` a pull dry run. Files new on
+    // the receiving side only exist on the sender; anything else differs. With
+    // --update a differing file only shows up in the direction it would move.
+    fn from_changes(outgoing: &[Change], incoming: &[Change]) -> Self {
+        let mut report = StatusReport::default();
+        let mut differ = BTreeMap::new();
+        for (changes, only) in [
+            (outgoing, &mut report.local_only),
+            (incoming, &mut report.remote_only),
+        ] {
+            for change in changes {
+                if change.is_deletion() || change.path.ends_with('/') {
+                    continue;
+                }
+                if change.is_new() {
+                    only.push((change.path.clone(), change.size));
+                } else {
+                    differ.entry(change.path.clone()).or_insert(change.size);
+                }
+            }
+        }
+        report.differ = differ.into_iter().collect();
+        report
+    }
+
+    fn is_clean(&self) -> bool {
+        self.local_only.is_empty() && self.remote_only.is_empty() && self.differ.is_empty()
+    }
+}
+
+fn status(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let is_file = local_path.is_file()
+        || (!local_path.exists() && remote_is_file(runner, host, remote_path, args)?);
+    let outgoing = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        false,
+        None,
+    )?;
+    let incoming = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        true,
+        None,
+    )?;
+    let report = StatusReport::from_changes(&outgoing.changes, &incoming.changes);
+
+    if args.json {
+        let files = |entries: &[(String, Option<u64>)]| -> Vec<serde_json::Value> {
+            entries
+                .iter()
+                .map(|(path, size)| json!({ "path": path, "size": size }))
+                .collect()
+        };
+        emit(json!({
+            "event": "status",
+            "host": host,
+            "local": local_path.display().to_string(),
+            "remote": remote_path,
+            "local_only": files(&report.local_only),
+            "remote_only": files(&report.remote_only),
+            "differ": files(&report.differ),
+        }));
+        return Ok(());
+    }
+
+    println!("{} <-> {}:{}", local_path.display(), host, remote_path);
+    if report.is_clean() {
+        println!("Up to date.");
+        return Ok(());
+    }
+    for (title, marker, entries) in [
+        ("Local only", "+", &report.local_only),
+        ("Remote only", "-", &report.remote_only),
+        ("Differ", "~", &report.differ),
+    ] {
+        if entries.is_empty() {
+            continue;
+        }
+        println!("{} ({}):", title, entries.len());
+        for (path, size) in entries {
+            match size {
+                Some(size) => println!("  {} {}  {}", marker, path, format_size(*size)),
+                None => println!("  {} {}", marker, path),
+            }
+        }
+    }
+    Ok(())
+}
+
+fn push_many(
+    runner: &dyn CommandRunner,
+    mut targets: Vec<Target>,
+    local_path: &Path,
+    args: &Args,
+) -> Result<()> {
+    let hosts: Vec<String> = targets.iter().map(|t| t.host.clone()).collect();
+    let results: Vec<(String, Result<()>, Duration)> = if args.dry_run {
+        targets
+            .iter()
+            .map(|t| {
+                let start = Instant::now();
+                let context = format!("[{}]", t.host);
+                let result = check_remote(runner, &t.host, &t.args).and_then(|()| {
+                    push(
+                        runner,
+                        &t.host,
+                        local_path,
+                        &t.remote_path,
+                        &t.args,
+                        &context,
+                    )
+                });
+                (t.host.clone(), result, start.elapsed())
+            })
+            .collect()
+    } else {
+        let progress = MultiProgress::new();
+        for target in &mut targets {
+            target.args.progress = Some(progress.clone());
+        }
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = targets
+                .iter()
+                .map(|t| {
+                    scope.spawn(move || {
+                        let start = Instant::now();
+                        let result = check_remote(runner, &t.host, &t.args)
+                            .and_then(|()| {
+                                push(runner, &t.host, local_path, &t.remote_path, &t.args, "")
+                            })
+                            .and_then(|()| {
+                                run_post_hook(runner, &t.host, local_path, &t.remote_path, &t.args)
+                            });
+                        (t.host.clone(), result, start.elapsed())
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .zip(&hosts)
+                .map(|(handle, host)| {
+                    handle.join().unwrap_or_else(|_| {
+                        (host.clone(), Err(anyhow!("push panicked")), Duration::ZERO)
+                    })
+                })
+                .collect()
+        })
+    };
+
+    let failed = results
+        .iter()
+        .filter(|(_, result, _)| result.is_err())
+        .count();
+    if args.json {
+        for (host, result, duration) in &results {
+            emit(json!({
+                "event": "host_result",
+                "host": host,
+                "ok": result.is_ok(),
+                "error": result.as_ref().err().map(|e| format!("{:#}", e)),
+                "duration_ms": duration.as_millis() as u64,
+            }));
+        }
+    } else {
+        print_host_results(&hosts, &results);
+    }
+    if failed > 0 {
+        bail!("{} of {} hosts failed", failed, results.len());
+    }
+    Ok(())
+}
+
+fn print_host_results(hosts: &[String], results: &[(String, Result<()>, Duration)]) {
+    println!("Hosts:");
+    let width = hosts.iter().map(String::len).max().unwrap_or(0);
+    for (host, result, duration) in results {
+        match result {
+            Ok(()) => println!("  ✅ {:width$}  {:.2?}", host, duration, width = width),
+            Err(e) => println!("  ❌ {:width$}  {}", host, e, width = width),
+        }
+    }
+}
+
+fn push(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    context: &str,
+) -> Result<()> {
+    if args.encrypt.is_some() {
+        return encrypt::push(runner, host, local_path, remote_path, args);
+    }
+    match args.backend {
+        Some(Backend::Sftp) => return sftp::push(runner, host, local_path, remote_path, args),
+        Some(Backend::Rclone) => return rclone::push(runner, host, local_path, remote_path, args),
+        Some(Backend::Native) => return native::push(runner, host, local_path, remote_path, args),
+        _ => {}
+    }
+    let is_file = local_path.is_file();
+    let remote_parent = parent_of_remote(remote_path);
+
+    let probe = probe_remote(runner, host, remote_path, Some(&remote_parent), false, args)?;
+    let args = &Args {
+        remote_probe: Some(probe),
+        ..args.clone()
+    };
+
+    announce(host, local_path, remote_path, is_file, args, false, context);
+
+    if args.bootstrap
+        && !is_file
+        && !args.dry_run
+        && remote_is_empty(runner, host, remote_path, args)?
+    {
+        return bootstrap(host, local_path, remote_path, args);
+    }
+
+    let changes = match &args.since {
+        Some(gitref) => {
+            if is_file {
+                bail!("--since requires a directory, got {}", local_path.display());
+            }
+            let changes = git_changed_files(runner, local_path, gitref)?;
+            if changes.changed.is_empty() && changes.deleted.is_empty() {
+                if !args.json {
+                    println!("No changes since {}", gitref);
+                }
+                return Ok(());
+            }
+            Some(changes)
+        }
+        None if args.git_tracked => {
+            if is_file {
+                bail!(
+                    "--git-tracked requires a directory, got {}",
+                    local_path.display()
+                );
+            }
+            let changes = git_tracked_files(runner, local_path)?;
+            if changes.changed.is_empty() {
+                if !args.json {
+                    println!("No files tracked by git in {}", local_path.display());
+                }
+                return Ok(());
+            }
+            Some(changes)
+        }
+        None => match &args.listed_files {
+            Some(listed) => {
+                if is_file {
+                    bail!(
+                        "--files-from requires a directory, got {}",
+                        local_path.display()
+                    );
+                }
+                if listed.is_empty() {
+                    if !args.json {
+                        println!("No paths listed in {}", listed_source(args));
+                    }
+                    return Ok(());
+                }
+                Some(GitChanges {
+                    changed: listed.clone(),
+                    deleted: Vec::new(),
+                })
+            }
+            None => None,
+        },
+    };
+
+    // With --since, a change set made only of deletions has nothing to transfer.
+    let skip_transfer = matches!(&changes, Some(c) if c.changed.is_empty());
+    if !skip_transfer {
+        let mut files_from = match &changes {
+            Some(changes) => Some(write_files_from(&changes.changed)?),
+            None => None,
+        };
+        let mut nothing_selected = false;
+        if args.select && !is_file {
+            let picked = pick_changes(
+                runner,
+                host,
+                local_path,
+                remote_path,
+                args,
+                false,
+                files_from.as_deref(),
+            );
+            if let Some(path) = files_from.take() {
+                let _ = fs::remove_file(path);
+            }
+            let picked = picked?;
+            nothing_selected = picked.is_empty();
+            if !nothing_selected {
+                files_from = Some(write_files_from(&picked)?);
+            }
+        }
+        let list = files_from.as_deref();
+        let result = if nothing_selected {
+            Ok(())
+        } else if args.dry_run {
+            run_dry_run(
+                runner,
+                host,
+                local_path,
+                remote_path,
+                is_file,
+                args,
+                false,
+                list,
+            )
+            .map(|summary| report_dry_run(&summary, host, args, false))
+        } else {
+            preflight(
+                runner,
+                host,
+                local_path,
+                remote_path,
+                is_file,
+                args,
+                false,
+                list,
+            )
+            .and_then(|proceed| {
+                if !proceed {
+                    return Ok(());
+                }
+                transfer(
+                    runner,
+                    host,
+                    local_path,
+                    remote_path,
+                    is_file,
+                    args,
+                    false,
+                    list,
+                )?;
+                if args.verify {
+                    verify_transfer(runner, host, local_path, remote_path, is_file, args, list)?;
+                }
+                Ok(())
+            })
+        };
+        if let Some(path) = &files_from {
+            let _ = fs::remove_file(path);
+        }
+        result?;
+    }
+
+    if let Some(changes) = &changes {
+        report_removed(runner, host, remote_path, &changes.deleted, args)?;
+    }
+    Ok(())
+}
+
+const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H:%M";
+
+// Where the snapshots of `local_path` live: one directory per snapshot under
+// <snapshot root>/<directory name>.
+fn snapshot_dir(local_path: &Path, args: &Args) -> String {
+    let root = args.snapshot_root.as_deref().unwrap_or("~/backups");
+    let name = local_path
+        .file_name()
+        .map_or_else(|| "root".into(), |n| n.to_string_lossy());
+    format!("{}/{}", root.trim_end_matches('/'), name)
+}
+
+// Existing snapshot names, oldest first. Anything else in the directory
+// (such as the `latest` link) is ignored.
+fn list_snapshots(
+    runner: &dyn CommandRunner,
+    host: &str,
+    dir: &str,
+    args: &Args,
+) -> Result<Vec<String>> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("ls -1 {} 2>/dev/null", remote_shell_path(dir)),
+    ));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh ls")?;
+    if output.status.code() == Some(255) {
+        bail!("could not list snapshots on {}", host);
+    }
+    let mut names: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|name| chrono::NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT).is_ok())
+        .map(str::to_string)
+        .collect();
+    names.sort();
+    Ok(names)
+}
+
+fn snapshot(runner: &dyn CommandRunner, host: &str, local_path: &Path, args: &Args) -> Result<()> {
+    if !local_path.is_dir() {
+        bail!("snapshots need a directory, got {}", local_path.display());
+    }
+    let dir = snapshot_dir(local_path, args);
+    let existing = list_snapshots(runner, host, &dir, args)?;
+    let name = chrono::Local::now().format(SNAPSHOT_FORMAT).to_string();
+    if existing.contains(&name) {
+        bail!(
+            "snapshot {}/{} already exists; try again in a minute",
+            dir,
+            name
+        );
+    }
+    let mut snap_args = args.clone();
+    snap_args.delete = false;
+    if let Some(previous) = existing.last() {
+        // Relative to the new snapshot directory.
+        snap_args
+            .rsync_args
+            .push(format!("--link-dest=../{}", previous));
+    }
+    let target = format!("{}/{}", dir, name);
+    push(runner, host, local_path, &target, &snap_args, "")?;
+    if args.dry_run {
+        return Ok(());
+    }
+
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!(
+            "ln -sfn {} {}",
+            shell_escape(&name),
+            remote_shell_path(&format!("{}/latest", dir))
+        ),
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh ln")?;
+    if !status.success() {
+        warn!(host, "could not update the latest snapshot link");
+    }
+    if args.json {
+        emit(json!({
+            "event": "snapshot",
+            "host": host,
+            "path": target,
+            "previous": existing.last(),
+        }));
+    } else {
+        println!("📸 Snapshot {}:{}", host, target);
+    }
+    Ok(())
+}
+
+// Snapshot names (oldest first) that the retention policy doesn't keep. The
+// newest snapshot of each of the last `keep_daily` days and `keep_weekly`
+// ISO weeks is kept, and so is the newest snapshot overall.
+fn snapshots_to_prune(
+    names: &[String],
+    keep_daily: Option<usize>,
+    keep_weekly: Option<usize>,
+) -> Vec<String> {
+    use chrono::Datelike;
+    let mut keep = BTreeSet::new();
+    let mut days = Vec::new();
+    let mut weeks = Vec::new();
+    for (i, name) in names.iter().enumerate().rev() {
+        let Ok(time) = chrono::NaiveDateTime::parse_from_str(name, SNAPSHOT_FORMAT) else {
+            continue;
+        };
+        if i + 1 == names.len() {
+            keep.insert(name);
+        }
+        let day = time.date();
+        if !days.contains(&day) && days.len() < keep_daily.unwrap_or(0) {
+            days.push(day);
+            keep.insert(name);
+        }
+        let week = time.iso_week();
+        if !weeks.contains(&week) && weeks.len() < keep_weekly.unwrap_or(0) {
+            weeks.push(week);
+            keep.insert(name);
+        }
+    }
+    names
+        .iter()
+        .filter(|name| !keep.contains(name))
+        .cloned()
+        .collect()
+}
+
+fn prune(runner: &dyn CommandRunner, host: &str, local_path: &Path, args: &Args) -> Result<()> {
+    if args.keep_daily.is_none() && args.keep_weekly.is_none() {
+        bail!("no retention policy: set keep_daily/keep_weekly in the config or pass --keep-daily/--keep-weekly");
+    }
+    let dir = snapshot_dir(local_path, args);
+    let names = list_snapshots(runner, host, &dir, args)?;
+    let doomed = snapshots_to_prune(&names, args.keep_daily, args.keep_weekly);
+    if args.json {
+        emit(json!({
+            "event": "prune",
+            "host": host,
+            "dry_run": args.dry_run,
+            "kept": names.len() - doomed.len(),
+            "removed": doomed,
+        }));
+    } else if doomed.is_empty() {
+        println!("Nothing to prune ({} snapshot(s) kept)", names.len());
+    } else {
+        println!(
+            "{} {} of {} snapshot(s) in {}:{}:",
+            if args.dry_run {
+                "Would remove"
+            } else {
+                "Removing"
+            },
+            doomed.len(),
+            names.len(),
+            host,
+            dir
+        );
+        for name in &doomed {
+            println!("  {}", name);
+        }
+    }
+    if doomed.is_empty() || args.dry_run {
+        return Ok(());
+    }
+    if !confirm(&format!("Delete {} snapshot(s)?", doomed.len()), args)? {
+        bail!("prune cancelled");
+    }
+    let paths: Vec<String> = doomed
+        .iter()
+        .map(|name| remote_shell_path(&format!("{}/{}", dir, name)))
+        .collect();
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(args, &format!("rm -rf {}", paths.join(" "))));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh rm")?;
+    if !status.success() {
+        bail!("failed to remove snapshots on {} ({})", host, status);
+    }
+    Ok(())
+}
+
+#[derive(Debug, Default, PartialEq)]
+struct GitChanges {
+    changed: Vec<String>,
+    deleted: Vec<String>,
+}
+
+fn git_changed_files(runner: &dyn CommandRunner, root: &Path, gitref: &str) -> Result<GitChanges> {
+    let args = vec![
+        "-C".to_string(),
+        root.to_string_lossy().to_string(),
+        "diff".to_string(),
+        "--name-only".to_string(),
+        "-z".to_string(),
+        "--no-renames".to_string(),
+        "--relative".to_string(),
+        gitref.to_string(),
+    ];
+    let output = runner
+        .output("git", &args)
+        .with_context(|| "failed to run git diff")?;
+    if !output.status.success() {
+        bail!(
+            "git diff against {} failed: {}",
+            gitref,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(parse_git_changes(&stdout, |rel| root.join(rel).exists()))
+}
+
+// Tracked files deleted from the working tree are left out: there is nothing
+// to send, and --git-tracked never deletes on the remote.
+fn git_tracked_files(runner: &dyn CommandRunner, root: &Path) -> Result<GitChanges> {
+    let args = vec![
+        "-C".to_string(),
+        root.to_string_lossy().to_string(),
+        "ls-files".to_string(),
+        "-z".to_string(),
+    ];
+    let output = runner
+        .output("git", &args)
+        .with_context(|| "failed to run git ls-files")?;
+    if !output.status.success() {
+        bail!(
+            "git ls-files in {} failed: {}",
+            root.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(GitChanges {
+        changed: parse_tracked_files(&stdout, |rel| root.join(rel).exists()),
+        deleted: Vec::new(),
+    })
+}
+
+// `-z` output, so names with newlines or quotes come through unescaped.
+fn parse_tracked_files(output: &str, exists: impl Fn(&str) -> bool) -> Vec<String> {
+    output
+        .split('\0')
+        .filter(|path| !path.is_empty() && exists(path))
+        .map(str::to_string)
+        .collect()
+}
+
+// `--no-renames` makes a rename show up as a delete plus an add. `-z`
+// output, as with ls-files: without it git quotes unusual names, which then
+// don't exist locally and would count as deletions.
+fn parse_git_changes(output: &str, exists: impl Fn(&str) -> bool) -> GitChanges {
+    let mut changes = GitChanges::default();
+    for path in output.split('\0') {
+        if path.is_empty() {
+            continue;
+        }
+        if exists(path) {
+            changes.changed.push(path.to_string());
+        } else {
+            changes.deleted.push(path.to_string());
+        }
+    }
+    changes
+}
+
+// The HOST positional and the extra hosts after it are more paths while they
+// are written like paths (`src/`, `./gpu`) and exist locally, so `syncz . gpu
+// gpu2` still pushes to two hosts when there is a local gpu directory.
+fn take_extra_paths(args: &mut Args) -> Vec<String> {
+    let mut positionals: Vec<String> = args
+        .host
+        .take()
+        .into_iter()
+        .chain(args.extra_hosts.drain(..))
+        .collect();
+    let mut paths = Vec::new();
+    while let Some(first) = positionals.first() {
+        if !local::is_path(first) || !Path::new(first).exists() {
+            break;
+        }
+        paths.push(positionals.remove(0));
+    }
+    let mut rest = positionals.into_iter();
+    args.host = rest.next();
+    args.extra_hosts = rest.collect();
+    paths
+}
+
+// Several paths sync as one list under their closest common directory, in a
+// single rsync run: each keeps its place relative to that directory, so the
+// remote side mirrors the local layout the same way a sync of the whole
+// directory would.
+fn combine_paths(args: &mut Args, paths: &[String]) -> Result<()> {
+    let flag = if args.files_from.is_some() {
+        Some("--files-from")
+    } else if args.since.is_some() {
+        Some("--since")
+    } else if args.git_tracked {
+        Some("--git-tracked")
+    } else if args.both {
+        Some("--both")
+    } else if args.delete {
+        Some("--delete")
+    } else if args.bootstrap {
+        Some("--bootstrap")
+    } else if args.watch {
+        Some("--watch")
+    } else if args.parallel.is_some() {
+        // Listed directories go whole, so a worker would resend the others' files.
+        Some("--parallel")
+    } else {
+        None
+    };
+    if let Some(flag) = flag {
+        bail!("{} can't be combined with several paths", flag);
+    }
+    let mut full = Vec::new();
+    for path in paths {
+        full.push(normalize_path(&expand_path(path)?)?);
+    }
+    let (root, listed) = common_root(&full);
+    args.path = Some(root.to_string_lossy().to_string());
+    args.listed_files = Some(listed);
+    Ok(())
+}
+
+// The deepest directory holding every path, and the paths relative to it.
+fn common_root(paths: &[PathBuf]) -> (PathBuf, Vec<String>) {
+    let mut root = paths[0].clone();
+    while !paths.iter().all(|p| p.starts_with(&root)) || root.is_file() {
+        if !root.pop() {
+            break;
+        }
+    }
+    let mut listed = Vec::new();
+    for path in paths {
+        let rel = path
+            .strip_prefix(&root)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string();
+        let rel = if rel.is_empty() { ".".to_string() } else { rel };
+        if !listed.contains(&rel) {
+            listed.push(rel);
+        }
+    }
+    (root, listed)
+}
+
+// Reads a --files-from list, from stdin for `-`. Lines may also be separated
+// by NULs (`fd -0`, `git ls-files -z`). Paths are relative to the current
+// directory, like the output of the tools that make them, unless absolute;
+// either way they have to lie inside the synced directory.
+fn read_file_list(source: &str, root: &Path) -> Result<Vec<String>> {
+    let contents = if source == "-" {
+        let mut contents = String::new();
+        std::io::Read::read_to_string(&mut std::io::stdin(), &mut contents)
+            .with_context(|| "failed to read the file list from stdin")?;
+        contents
+    } else {
+        let path = expand_path(source)?;
+        fs::read_to_string(&path).with_context(|| format!("failed to read {}", path.display()))?
+    };
+    let cwd = std::env::current_dir().with_context(|| "failed to read the current directory")?;
+    parse_file_list(&contents, root, &cwd)
+}
+
+fn parse_file_list(contents: &str, root: &Path, cwd: &Path) -> Result<Vec<String>> {
+    let separator = if contents.contains('\0') { '\0' } else { '\n' };
+    let mut paths = Vec::new();
+    for line in contents.split(separator) {
+        let line = line.trim_end_matches('\r');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let path = clean_path(&cwd.join(line));
+        let rel = path.strip_prefix(root).map_err(|_| {
+            anyhow!(
+                "{} (from --files-from) is outside {}",
+                path.display(),
+                root.display()
+            )
+        })?;
+        let rel = match rel.to_string_lossy() {
+            r if r.is_empty() => ".".to_string(),
+            r => r.to_string(),
+        };
+        if !paths.contains(&rel) {
+            paths.push(rel);
+        }
+    }
+    Ok(paths)
+}
+
+fn listed_source(args: &Args) -> &str {
+    match args.files_from.as_deref() {
+        Some("-") | None => "stdin",
+        Some(file) => file,
+    }
+}
+
+fn write_files_from(paths: &[String]) -> Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "syncz-files-{}-{}.txt",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    // NUL-terminated (rsync's --from0), so any file name survives.
+    let mut contents = paths.join("\0");
+    contents.push('\0');
+    fs::write(&path, contents).with_context(|| format!("failed to write {}", path.display()))?;
+    Ok(path)
+}
+
+fn report_removed(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    deleted: &[String],
+    args: &Args,
+) -> Result<()> {
+    if deleted.is_empty() {
+        return Ok(());
+    }
+    if args.json {
+        let deleting = args.delete_removed && !args.dry_run;
+        if deleting {
+            delete_remote_files(runner, host, remote_path, deleted, args)?;
+        }
+        emit(json!({ "event": "removed", "paths": deleted, "deleted": deleting }));
+        return Ok(());
+    }
+    if !args.delete_removed {
+        println!(
+            "{} file(s) removed locally; pass --delete-removed to delete them on {}",
+            deleted.len(),
+            host
+        );
+        return Ok(());
+    }
+    if args.dry_run {
+        println!("Would delete on {}:", host);
+        for path in deleted {
+            println!("  {}", path);
+        }
+        return Ok(());
+    }
+    delete_remote_files(runner, host, remote_path, deleted, args)?;
+    println!("Deleted {} file(s) on {}", deleted.len(), host);
+    Ok(())
+}
+
+fn delete_remote_files(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    paths: &[String],
+    args: &Args,
+) -> Result<()> {
+    let targets: Vec<String> = paths.iter().map(|p| shell_escape(p)).collect();
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!(
+        "cd {} && {}",
+        remote_shell_path(remote_path),
+        privileged(args, &format!("rm -f -- {}", targets.join(" ")))
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh rm")?;
+    if !status.success() {
+        bail!("failed to delete removed files on {}", host);
+    }
+    Ok(())
+}
+
+fn pull(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    context: &str,
+) -> Result<()> {
+    if args.encrypt.is_some() {
+        return encrypt::pull(runner, host, local_path, remote_path, args);
+    }
+    match args.backend {
+        Some(Backend::Sftp) => return sftp::pull(runner, host, local_path, remote_path, args),
+        Some(Backend::Rclone) => return rclone::pull(runner, host, local_path, remote_path, args),
+        Some(Backend::Native) => return native::pull(runner, host, local_path, remote_path, args),
+        _ => {}
+    }
+    // The remote size is only needed for the free space check.
+    let with_size = !args.force && !args.dry_run;
+    let probe = probe_remote(runner, host, remote_path, None, with_size, args)?;
+    let is_file = probe.kind == RemoteKind::File;
+    let args = &Args {
+        remote_probe: Some(probe),
+        ..args.clone()
+    };
+    let local_parent = local_path
+        .parent()
+        .ok_or_else(|| anyhow!("unable to resolve local parent"))?;
+
+    fs::create_dir_all(local_parent)
+        .with_context(|| format!("failed to create {}", local_parent.display()))?;
+
+    announce(host, local_path, remote_path, is_file, args, true, context);
+    let files_from = if args.select && !is_file {
+        let picked = pick_changes(runner, host, local_path, remote_path, args, true, None)?;
+        if picked.is_empty() {
+            return Ok(());
+        }
+        Some(write_files_from(&picked)?)
+    } else if let Some(listed) = &args.listed_files {
+        if is_file {
+            bail!(
+                "--files-from requires a directory, got {}:{}",
+                host,
+                remote_path
+            );
+        }
+        if listed.is_empty() {
+            if !args.json {
+                println!("No paths listed in {}", listed_source(args));
+            }
+            return Ok(());
+        }
+        Some(write_files_from(listed)?)
+    } else {
+        None
+    };
+    let list = files_from.as_deref();
+    let result = if args.dry_run {
+        run_dry_run(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            true,
+            list,
+        )
+        .map(|summary| report_dry_run(&summary, host, args, true))
+    } else {
+        preflight(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            true,
+            list,
+        )
+        .and_then(|proceed| {
+            if proceed {
+                transfer(
+                    runner,
+                    host,
+                    local_path,
+                    remote_path,
+                    is_file,
+                    args,
+                    true,
+                    list,
+                )
+            } else {
+                Ok(())
+            }
+        })
+    };
+    if let Some(path) = &files_from {
+        let _ = fs::remove_file(path);
+    }
+    result
+}
+
+struct SelectItem {
+    // Directories end with '/'.
+    path: String,
+    depth: usize,
+    size: Option<u64>,
+}
+
+// Lays the changed files out as a tree: each directory is listed once, before
+// the files under it.
+fn selection_items(changes: &[Change]) -> Vec<SelectItem> {
+    let files: BTreeMap<&str, Option<u64>> = changes
+        .iter()
+        .filter(|c| !c.is_deletion() && !c.path.ends_with('/'))
+        .map(|c| (c.path.as_str(), c.size))
+        .collect();
+    let mut items = Vec::new();
+    let mut seen_dirs = BTreeSet::new();
+    for (path, size) in files {
+        let parts: Vec<&str> = path.split('/').collect();
+        for depth in 1..parts.len() {
+            let dir = format!("{}/", parts[..depth].join("/"));
+            if seen_dirs.insert(dir.clone()) {
+                items.push(SelectItem {
+                    path: dir,
+                    depth: depth - 1,
+                    size: None,
+                });
+            }
+        }
+        items.push(SelectItem {
+            path: path.to_string(),
+            depth: parts.len() - 1,
+            size,
+        });
+    }
+    items
+}
+
+// A file is synced if it is checked itself or sits under a checked directory.
+fn resolve_selection(items: &[SelectItem], chosen: &[usize]) -> Vec<String> {
+    let chosen: BTreeSet<usize> = chosen.iter().copied().collect();
+    let dirs: Vec<&str> = items
+        .iter()
+        .enumerate()
+        .filter(|(i, item)| chosen.contains(i) && item.path.ends_with('/'))
+        .map(|(_, item)| item.path.as_str())
+        .collect();
+    items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| !item.path.ends_with('/'))
+        .filter(|(i, item)| chosen.contains(i) || dirs.iter().any(|d| item.path.starts_with(d)))
+        .map(|(_, item)| item.path.clone())
+        .collect()
+}
+
+fn pick_changes(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<Vec<String>> {
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        false,
+        args,
+        pulling,
+        files_from,
+    )?;
+    let items = selection_items(&preview.changes);
+    if items.is_empty() {
+        println!("Nothing to {}.", direction_name(pulling));
+        return Ok(Vec::new());
+    }
+    let labels: Vec<String> = items
+        .iter()
+        .map(|item| {
+            let name = item
+                .path
+                .trim_end_matches('/')
+                .rsplit('/')
+                .next()
+                .unwrap_or("");
+            let indent = "  ".repeat(item.depth);
+            match item.size {
+                Some(size) => format!("{}{}  ({})", indent, name, format_size(size)),
+                None => format!("{}{}/", indent, name),
+            }
+        })
+        .collect();
+    let defaults: Vec<bool> = items.iter().map(|item| !item.path.ends_with('/')).collect();
+    let chosen = MultiSelect::with_theme(&ColorfulTheme::default())
+        .with_prompt("Files to sync (space toggles, a directory selects everything in it)")
+        .items(&labels)
+        .defaults(&defaults)
+        .interact()?;
+    let picked = resolve_selection(&items, &chosen);
+    if picked.is_empty() {
+        println!("Nothing selected.");
+    }
+    Ok(picked)
+}
+// The sftp and native backends need no remote rsync, but can't do everything
+// rsync does.
+fn check_remote(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<()> {
+    if let Some(flag) = args
+        .encrypt
+        .as_ref()
+        .and_then(|_| encrypt::unsupported(args))
+    {
+        bail!("{} isn't supported with --encrypt", flag);
+    }
+    let name = match args.backend.unwrap_or_default() {
+        Backend::Rsync => return check_remote_rsync(runner, host, args).map(|_| ()),
+        Backend::Sftp => "sftp",
+        Backend::Rclone => return rclone::check(runner, host, args),
+        Backend::Native if args.dry_run => bail!("--dry-run isn't supported with --backend native"),
+        Backend::Native => "native",
+    };
+    if let Some(flag) = sftp::unsupported(args) {
+        bail!("{} isn't supported with --backend {}", flag, name);
+    }
+    // sftp and the native session would otherwise hang on a dead host before
+    // failing with whatever the ssh subprocess printed.
+    let mut cmd_args = preflight_ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push("true".to_string());
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    if !output.status.success() {
+        return Err(ssh_failure(host, &String::from_utf8_lossy(&output.stderr)));
+    }
+    Ok(())
+}
+
+// How long the preflight waits for the host to answer when --timeout isn't
+// set, instead of the system's TCP timeout (minutes on some networks).
+const PREFLIGHT_TIMEOUT: u32 = 10;
+
+fn preflight_ssh_args(args: &Args) -> Vec<String> {
+    let mut list = ssh_args(args);
+    if args.timeout.is_none() {
+        list.push("-o".to_string());
+        list.push(format!("ConnectTimeout={}", PREFLIGHT_TIMEOUT));
+    }
+    list
+}
+
+const SSH_AUTH_ERRORS: [&str; 4] = [
+    "Permission denied",
+    "Too many authentication failures",
+    "Authentication failed",
+    "Host key verification failed",
+];
+
+// Tells "can't reach the machine" apart from "reached it but it won't let us
+// in", from the last thing ssh printed.
+fn ssh_failure(host: &str, stderr: &str) -> anyhow::Error {
+    let reason = stderr
+        .lines()
+        .rfind(|l| !l.trim().is_empty())
+        .unwrap_or("")
+        .trim();
+    const NETWORK: [&str; 7] = [
+        "Connection refused",
+        "Connection timed out",
+        "Operation timed out",
+        "No route to host",
+        "Network is unreachable",
+        "Could not resolve hostname",
+        "Name or service not known",
+    ];
+    if SSH_AUTH_ERRORS.iter().any(|s| reason.contains(s)) {
+        anyhow!(
+            "authentication failed for {}: {}; check --identity, your ssh agent and ~/.ssh/config",
+            host,
+            reason
+        )
+    } else if NETWORK.iter().any(|s| reason.contains(s)) {
+        anyhow!("host unreachable: {}: {}", host, reason)
+    } else {
+        anyhow!("could not connect to {} over ssh: {}", host, reason)
+    }
+}
+
+// Makes sure the remote can run rsync before anything is transferred, so a
+// missing binary is reported as such instead of as a protocol error.
+// Returns the remote rsync version.
+fn check_remote_rsync(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<String> {
+    let program = args.rsync_path.as_deref().unwrap_or("rsync");
+    let mut cmd_args = preflight_ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!("{} --version", program));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    match output.status.code() {
+        Some(0) => {}
+        Some(255) => return Err(ssh_failure(host, &String::from_utf8_lossy(&output.stderr))),
+        _ => bail!(
+            "{} not found on {}; install rsync there, point --rsync-path at it or use --backend sftp",
+            program,
+            host
+        ),
+    }
+    let version = parse_rsync_version(&String::from_utf8_lossy(&output.stdout))
+        .unwrap_or_else(|| "unknown".to_string());
+    info!(host, version, "remote rsync");
+    Ok(version)
+}
+
+// "rsync  version 3.2.7  protocol version 31" -> "3.2.7". openrsync (macOS)
+// prints its own line first, then "rsync version 2.6.9 compatible".
+fn parse_rsync_version(output: &str) -> Option<String> {
+    let line = output.lines().find(|l| l.starts_with("rsync"))?;
+    let mut words = line.split_whitespace().skip_while(|w| *w != "version");
+    words.nth(1).map(str::to_string)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum RemoteKind {
+    #[default]
+    Missing,
+    File,
+    Dir,
+    Other,
+}
+
+// What a sync needs to know about the remote path before it starts, gathered
+// in one ssh round trip instead of one per question.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct RemoteProbe {
+    kind: RemoteKind,
+    // Free bytes on the filesystem holding the path or its nearest existing
+    // parent, when df could tell.
+    free: Option<u64>,
+    // Disk usage of the path, when asked for.
+    size: Option<u64>,
+}
+
+// Looks at `remote_path`, creating `parent` first when given (as
+// ensure_remote_parent does), and optionally measures it with du.
+fn probe_remote(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    parent: Option<&str>,
+    with_size: bool,
+    args: &Args,
+) -> Result<RemoteProbe> {
+    let mut script = vec![
+        format!("p={}", remote_shell_path(remote_path)),
+        format!(
+            "if {}; then echo type=file; elif {}; then echo type=dir; \
+             elif {}; then echo type=other; else echo type=missing; fi",
+            privileged(args, "test -f \"$p\""),
+            privileged(args, "test -d \"$p\""),
+            privileged(args, "test -e \"$p\"")
+        ),
+    ];
+    if let Some(parent) = parent {
+        script.push(format!(
+            "{} || echo mkdir=failed",
+            privileged(args, &format!("mkdir -p {}", remote_shell_path(parent)))
+        ));
+    }
+    script.push("d=$p; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done".to_string());
+    script.push("echo \"free=$(df -Pk \"$d\" 2>/dev/null | awk 'NR==2 {print $4}')\"".to_string());
+    if with_size {
+        script.push("echo \"size=$(du -sk \"$p\" 2>/dev/null | cut -f1)\"".to_string());
+    }
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(script.join("; "));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    if output.status.code() == Some(255) {
+        bail!(
+            "could not connect to {} over ssh: {}",
+            host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let (probe, parent_created) = parse_probe(&String::from_utf8_lossy(&output.stdout));
+    if let (Some(parent), false) = (parent, parent_created) {
+        bail!("failed to create remote directory {}", parent);
+    }
+    debug!(host, ?probe, "remote probe");
+    Ok(probe)
+}
+
+// Returns the probe and whether creating the parent went fine.
+fn parse_probe(output: &str) -> (RemoteProbe, bool) {
+    let mut probe = RemoteProbe::default();
+    let mut parent_created = true;
+    for line in output.lines() {
+        match line.trim().split_once('=') {
+            Some(("type", "file")) => probe.kind = RemoteKind::File,
+            Some(("type", "dir")) => probe.kind = RemoteKind::Dir,
+            Some(("type", "other")) => probe.kind = RemoteKind::Other,
+            Some(("mkdir", _)) => parent_created = false,
+            Some(("free", kb)) => probe.free = kb.parse::<u64>().ok().map(|kb| kb * 1024),
+            Some(("size", kb)) => probe.size = kb.parse::<u64>().ok().map(|kb| kb * 1024),
+            _ => {}
+        }
+    }
+    (probe, parent_created)
+}
+
+fn remote_is_file(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Result<bool> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("test -f {}", remote_shell_path(remote_path)),
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh test -f")?;
+    Ok(status.success())
+}
+
+// Whether the remote directory is missing or has nothing in it, i.e. a push
+// there would be an initial copy.
+fn remote_is_empty(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Result<bool> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    // A directory that can't be listed counts as not empty.
+    let dir = remote_shell_path(remote_path);
+    cmd_args.push(format!(
+        "test ! -e {dir} || (out=$(ls -A {dir}) && test -z \"$out\")"
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh ls")?;
+    Ok(status.success())
+}
+
+// `tar -c` of the listed files locally and `tar -x` into the remote directory,
+// as the argument lists of the two ends of the pipe.
+fn bootstrap_commands(
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    list: &Path,
+    args: &Args,
+) -> (Vec<String>, Vec<String>) {
+    let tar_args = vec![
+        "-czf".to_string(),
+        "-".to_string(),
+        "-C".to_string(),
+        local_path.display().to_string(),
+        "--no-recursion".to_string(),
+        "--null".to_string(),
+        "-T".to_string(),
+        list.display().to_string(),
+    ];
+    let dir = remote_shell_path(remote_path);
+    let mut ssh = ssh_args(args);
+    ssh.push(host.to_string());
+    ssh.push(format!(
+        "{} && {}",
+        privileged(args, &format!("mkdir -p {}", dir)),
+        privileged(args, &format!("tar -xzf - -C {}", dir))
+    ));
+    (tar_args, ssh)
+}
+
+// The initial copy for --bootstrap. It sends what the rsync push would
+// (default excludes, --exclude/--include and the size limit), except that
+// .gitignore files aren't consulted.
+fn bootstrap(host: &str, local_path: &Path, remote_path: &str, args: &Args) -> Result<()> {
+    let entries = sftp::local_entries(local_path, args)?;
+    let files = entries.iter().filter(|e| !e.is_dir).count();
+    let bytes: u64 = entries.iter().map(|e| e.size).sum();
+    if !args.json {
+        println!(
+            "Remote directory is empty; copying {} file(s) ({}) with tar",
+            files,
+            format_size(bytes)
+        );
+    }
+    let paths: Vec<String> = entries.into_iter().map(|e| e.path).collect();
+    let list = write_files_from(&paths)?;
+    let (tar_args, ssh) = bootstrap_commands(host, local_path, remote_path, &list, args);
+    info!(
+        command = %format!("{} | {}", command_line("tar", &tar_args), command_line("ssh", &ssh)),
+        "running"
+    );
+    let start = Instant::now();
+    let result = (|| {
+        let mut tar = Command::new("tar")
+            .args(&tar_args)
+            .stdout(Stdio::piped())
+            .spawn()
+            .with_context(|| "failed to run tar")?;
+        let stdout = tar.stdout.take().expect("piped stdout");
+        let ssh_status = Command::new("ssh")
+            .args(&ssh)
+            .stdin(stdout)
+            .status()
+            .with_context(|| "failed to run ssh");
+        let tar_status = tar.wait()?;
+        let ssh_status = ssh_status?;
+        if !tar_status.success() {
+            bail!("tar failed ({})", tar_status);
+        }
+        if !ssh_status.success() {
+            bail!("unpacking on {} failed ({})", host, ssh_status);
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_file(&list);
+    result?;
+    info!(elapsed_ms = start.elapsed().as_millis() as u64, "finished");
+    TRANSFERRED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    if args.json {
+        emit(json!({
+            "event": "transfer",
+            "direction": "push",
+            "host": host,
+            "backend": "tar",
+            "files": files,
+            "sent_bytes": bytes,
+        }));
+    } else {
+        println!("Copied {} file(s) to {}", files, host);
+    }
+    Ok(())
+}
+
+fn parent_of_remote(remote_path: &str) -> String {
+    let path = Path::new(remote_path);
+    if let Some(parent) = path.parent() {
+        parent.to_string_lossy().to_string()
+    } else {
+        remote_path.to_string()
+    }
+}
+
+fn ensure_remote_parent(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_parent: &str,
+    args: &Args,
+) -> Result<()> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("mkdir -p {}", remote_shell_path(remote_parent)),
+    ));
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh mkdir -p")?;
+    if !status.success() {
+        bail!("failed to create remote directory {}", remote_parent);
+    }
+    Ok(())
+}
+
+// A remote file command, prefixed with sudo under --remote-sudo. Paths like
+// "$HOME/..." are still expanded by the login user's shell.
+fn privileged(args: &Args, command: &str) -> String {
+    if args.remote_sudo {
+        format!("sudo {}", command)
+    } else {
+        command.to_string()
+    }
+}
+
+// The program the remote side runs for rsync (rsync's --rsync-path), when it
+// isn't plain `rsync`.
+fn remote_rsync(args: &Args) -> Option<String> {
+    let program = args.rsync_path.as_deref();
+    match (args.remote_sudo, program) {
+        (true, program) => Some(format!("sudo {}", program.unwrap_or("rsync"))),
+        (false, Some(program)) => Some(program.to_string()),
+        (false, None) => None,
+    }
+}
+
+fn emit(event: serde_json::Value) {
+    println!("{}", event);
+}
+
+// The whole change list of a dry run, for tools that review or check a sync
+// before it happens. `total_size` counts the files that would be sent whole;
+// `transferred_bytes` is what rsync expects to send.
+fn dry_run_json(summary: &DryRunSummary, host: &str, pulling: bool) -> serde_json::Value {
+    let files: Vec<serde_json::Value> = summary
+        .changes
+        .iter()
+        .map(|change| {
+            json!({
+                "path": change.path,
+                "change": change.code,
+                "kind": change.kind(),
+                "is_dir": change.path.ends_with('/'),
+                "size": change.size,
+            })
+        })
+        .collect();
+    let total_size: u64 = summary
+        .changes
+        .iter()
+        .filter(|c| !c.is_deletion() && !c.path.ends_with('/'))
+        .filter_map(|c| c.size)
+        .sum();
+    json!({
+        "event": "dry_run",
+        "direction": direction_name(pulling),
+        "host": host,
+        "files": files,
+        "deletions": summary.deletions,
+        "renames": summary.renames,
+        "total_size": total_size,
+        "transferred_bytes": summary.transferred_bytes,
+    })
+}
+
+// `syncz estimate`: the dry run's transfer size over a rate taken from
+// recent syncs with the host, or from a short timed transfer when there are
+// none.
+fn estimate(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let pulling = args.pull;
+    let is_file = local_path.is_file();
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        pulling,
+        None,
+    )?;
+    let bytes = preview.transferred_bytes.unwrap_or(0);
+    let direction = direction_name(pulling);
+    let (rate, source) = match history_rate(&load_history(&history_path()?)?, host, direction) {
+        Some((rate, runs)) => (rate, format!("from {} recent {}(es)", runs, direction)),
+        None => (
+            probe_rate(host, args, pulling)?,
+            "measured just now".to_string(),
+        ),
+    };
+    let secs = (bytes as f64 / rate.max(1.0)).ceil() as u64;
+    if args.json {
+        emit(json!({
+            "event": "estimate",
+            "host": host,
+            "direction": direction,
+            "transfer_bytes": bytes,
+            "files": preview
+                .changes
+                .iter()
+                .filter(|c| !c.is_deletion() && !c.path.ends_with('/'))
+                .count(),
+            "bytes_per_sec": rate as u64,
+            "eta_ms": secs * 1000,
+        }));
+    } else if bytes == 0 {
+        println!("Nothing to transfer; {} is up to date", host);
+    } else {
+        println!(
+            "~{} to transfer, ETA ≈ {} at {} ({})",
+            format_size(bytes),
+            format_duration(secs * 1000),
+            format_rate(rate),
+            source
+        );
+    }
+    Ok(())
+}
+
+// Recent syncs that moved at least this much say more about bandwidth than
+// about latency.
+const RATE_MIN_BYTES: u64 = 1 << 20;
+
+// Bytes per second over the last few successful syncs with the host in this
+// direction, and how many there were. Their durations include scanning, so
+// the rate errs on the slow side.
+fn history_rate(entries: &[HistoryEntry], host: &str, direction: &str) -> Option<(f64, usize)> {
+    let runs: Vec<&HistoryEntry> = entries
+        .iter()
+        .rev()
+        .filter(|e| {
+            e.exit_code == 0
+                && e.direction == direction
+                && e.hosts.iter().any(|h| h == host)
+                && e.bytes >= RATE_MIN_BYTES
+                && e.duration_ms > 0
+        })
+        .take(5)
+        .collect();
+    if runs.is_empty() {
+        return None;
+    }
+    let bytes: u64 = runs.iter().map(|e| e.bytes).sum();
+    let ms: u64 = runs.iter().map(|e| e.duration_ms).sum();
+    Some((bytes as f64 * 1000.0 / ms as f64, runs.len()))
+}
+
+const PROBE_BYTES: usize = 4 << 20;
+
+// Times sending (or receiving) a few MB of random-looking data over ssh,
+// minus the time an empty command takes, which is the connection overhead.
+fn probe_rate(host: &str, args: &Args, pulling: bool) -> Result<f64> {
+    let run = |command: &str, input: Option<&[u8]>| -> Result<Duration> {
+        let mut cmd_args = ssh_args(args);
+        cmd_args.push(host.to_string());
+        cmd_args.push(command.to_string());
+        info!(command = %command_line("ssh", &cmd_args), "running");
+        let start = Instant::now();
+        let mut child = Command::new("ssh")
+            .args(&cmd_args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            })
+            .stdout(Stdio::null())
+            .spawn()
+            .with_context(|| "failed to run ssh")?;
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), input) {
+            stdin.write_all(input)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            bail!("measuring the connection to {} failed ({})", host, status);
+        }
+        Ok(start.elapsed())
+    };
+    let overhead = run("true", None)?;
+    let elapsed = if pulling {
+        run(&format!("head -c {} /dev/urandom", PROBE_BYTES), None)?
+    } else {
+        // Incompressible, so ssh compression can't flatter the link.
+        let mut payload = vec![0; PROBE_BYTES];
+        blake3::Hasher::new()
+            .update(b"syncz estimate")
+            .finalize_xof()
+            .fill(&mut payload);
+        run("cat > /dev/null", Some(&payload))?
+    };
+    let secs = elapsed.saturating_sub(overhead).as_secs_f64().max(0.001);
+    Ok(PROBE_BYTES as f64 / secs)
+}
+
+// Network speeds are quoted in bits: "45 Mbit/s".
+fn format_rate(bytes_per_sec: f64) -> String {
+    let bits = bytes_per_sec * 8.0;
+    if bits >= 1e9 {
+        format!("{:.1} Gbit/s", bits / 1e9)
+    } else if bits >= 1e6 {
+        format!("{:.0} Mbit/s", bits / 1e6)
+    } else {
+        format!("{:.0} kbit/s", bits / 1e3)
+    }
+}
+
+// The remote path alone goes to stdout, for `ssh host vim $(syncz which
+// file)`; the rsync endpoints go to stderr.
+fn show_which(host: &str, local_path: &Path, remote_path: &str, args: &Args) {
+    if args.json {
+        emit(which_event(host, local_path, remote_path));
+        return;
+    }
+    let [(push_src, push_dst), (pull_src, pull_dst)] =
+        which_endpoints(host, local_path, remote_path);
+    println!("{}", remote_path);
+    eprintln!("push: rsync {} {}", push_src, push_dst);
+    eprintln!("pull: rsync {} {}", pull_src, pull_dst);
+}
+
+// The rsync source and destination of a push, then of a pull.
+fn which_endpoints(host: &str, local_path: &Path, remote_path: &str) -> [(String, String); 2] {
+    let is_file = local_path.is_file();
+    [false, true].map(|pulling| sync_endpoints(host, local_path, remote_path, is_file, pulling))
+}
+
+fn which_event(host: &str, local_path: &Path, remote_path: &str) -> serde_json::Value {
+    let [(push_src, push_dst), (pull_src, pull_dst)] =
+        which_endpoints(host, local_path, remote_path);
+    json!({
+        "event": "which",
+        "host": host,
+        "local_path": local_path.to_string_lossy(),
+        "remote_path": remote_path,
+        "push": { "source": push_src, "destination": push_dst },
+        "pull": { "source": pull_src, "destination": pull_dst },
+    })
+}
+
+fn direction_name(pulling: bool) -> &'static str {
+    if pulling {
+        "pull"
+    } else {
+        "push"
+    }
+}
+
+fn announce(
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    context: &str,
+) {
+    if args.json {
+        let (src, dst) = sync_endpoints(host, local_path, remote_path, is_file, pulling);
+        emit(json!({
+            "event": "endpoints",
+            "direction": direction_name(pulling),
+            "host": host,
+            "source": src,
+            "destination": dst,
+        }));
+    } else if !context.is_empty() {
+        println!("{}", context);
+    }
+}
+
+struct DryRunSummary {
+    transferred_line: Option<String>,
+    transferred_bytes: Option<u64>,
+    deletions: Vec<String>,
+    changes: Vec<Change>,
+    // Deletions paired with new files of the same contents.
+    renames: Vec<Rename>,
+}
+
+#[derive(Clone)]
+struct Change {
+    code: String,
+    path: String,
+    size: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ChangeKind {
+    New,
+    Modified,
+    Deleted,
+    // Only permissions, owner, group, ACLs or xattrs differ.
+    Perms,
+}
+
+impl ChangeKind {
+    fn color(self) -> &'static str {
+        match self {
+            ChangeKind::New => "32",
+            ChangeKind::Modified => "33",
+            ChangeKind::Deleted => "31",
+            ChangeKind::Perms => "36",
+        }
+    }
+}
+
+impl Change {
+    fn kind(&self) -> ChangeKind {
+        let attrs = self.code.get(2..).unwrap_or("");
+        if self.is_deletion() {
+            ChangeKind::Deleted
+        } else if self.is_new() {
+            ChangeKind::New
+        } else if !attrs.contains(['c', 's', 't']) && attrs.contains(['p', 'o', 'g', 'a', 'x']) {
+            ChangeKind::Perms
+        } else {
+            ChangeKind::Modified
+        }
+    }
+
+    fn is_deletion(&self) -> bool {
+        self.code.starts_with("*deleting")
+    }
+
+    // rsync marks items missing on the receiver with `+` in every attribute column.
+    fn is_new(&self) -> bool {
+        self.code
+            .get(2..)
+            .is_some_and(|attrs| attrs.starts_with('+'))
+    }
+}
+
+fn report_dry_run(summary: &DryRunSummary, host: &str, args: &Args, pulling: bool) {
+    if args.json {
+        let mut event = dry_run_json(summary, host, pulling);
+        if let Some(n) = args.top {
+            event["largest"] = largest_files(&summary.changes, n as usize)
+                .iter()
+                .map(|c| json!({ "path": c.path, "size": c.size }))
+                .collect();
+        }
+        emit(event);
+        return;
+    }
+    let renamed: BTreeSet<&str> = summary
+        .renames
+        .iter()
+        .flat_map(|r| [r.from.as_str(), r.to.as_str()])
+        .collect();
+    let changes: Vec<Change> = summary
+        .changes
+        .iter()
+        .filter(|c| !renamed.contains(c.path.as_str()))
+        .cloned()
+        .collect();
+    let mut lines = vec![render_tree(&changes, TreeView::new(args))];
+    for rename in &summary.renames {
+        lines.push(format!(
+            "renamed {} → {} ({})",
+            rename.from,
+            rename.to,
+            format_size(rename.size)
+        ));
+    }
+    if !summary.renames.is_empty() && !args.fuzzy {
+        lines.push(
+            "rsync sends renamed files again in full; with --fuzzy it starts from the old copy of a file renamed within its directory"
+                .to_string(),
+        );
+    }
+    if let Some(n) = args.top {
+        let largest = largest_files(&changes, n as usize);
+        if !largest.is_empty() {
+            lines.push(format!("Largest {} file(s):", largest.len()));
+            for change in largest {
+                lines.push(format!(
+                    "{:>12}  {}",
+                    format_size(change.size.unwrap_or(0)),
+                    change.path
+                ));
+            }
+        }
+    }
+    if let Some(line) = &summary.transferred_line {
+        lines.push(line.clone());
+    }
+    let deletions = summary.deletions.len() - summary.renames.len();
+    if deletions > 0 {
+        lines.push(format!("Would delete {} path(s)", deletions));
+    }
+    print_paged(&lines.join("\n"), args);
+}
+
+// The files whose contents would be sent, biggest first. Directories,
+// deletions and permission-only changes move no data.
+fn largest_files(changes: &[Change], n: usize) -> Vec<&Change> {
+    let mut files: Vec<&Change> = changes
+        .iter()
+        .filter(|c| !c.is_deletion() && !c.path.ends_with('/') && c.kind() != ChangeKind::Perms)
+        .filter(|c| c.size.is_some())
+        .collect();
+    files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    files.truncate(n);
+    files
+}
+
+// Output taller than the terminal goes through $PAGER instead of scrolling
+// past; anything else, or output that isn't to a terminal, is printed as is.
+fn print_paged(text: &str, args: &Args) {
+    let fits = console::Term::stdout()
+        .size_checked()
+        .is_none_or(|(rows, _)| text.lines().count() < rows as usize);
+    if args.no_pager || fits || !std::io::stdout().is_terminal() {
+        println!("{}", text);
+        return;
+    }
+    let pager = pager_command(std::env::var("PAGER").ok());
+    match Command::new("sh")
+        .arg("-c")
+        .arg(&pager)
+        .stdin(Stdio::piped())
+        .spawn()
+    {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                // The user may quit the pager before reading everything.
+                let _ = writeln!(stdin, "{}", text);
+            }
+            let _ = child.wait();
+        }
+        Err(err) => {
+            debug!(%err, pager, "pager failed to start");
+            println!("{}", text);
+        }
+    }
+}
+
+// `less -R` passes the tree's colors through.
+fn pager_command(env: Option<String>) -> String {
+    env.filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| "less -R".to_string())
+}
+
+// Checks that need a dry run before the real transfer: --interactive,
+// --delete and --max-total-size share a single preview. Returns false when
+// there is nothing to do.
+#[allow(clippy::too_many_arguments)]
+fn preflight(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<bool> {
+    if !pulling && !args.force && !args.ignore_existing && !args.is_pull() && !args.watch {
+        guard_newer_remote(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            files_from,
+        )?;
+    }
+    if !args.force {
+        check_free_space(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            pulling,
+            files_from,
+        )?;
+    }
+    if !args.interactive && !args.delete && args.max_total_size.is_none() {
+        return Ok(true);
+    }
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        pulling,
+        files_from,
+    )?;
+    if !args.interactive {
+        confirm_mirror(&preview, host, local_path, remote_path, args, pulling)?;
+        guard_transfer_size(&preview, args, pulling)?;
+        return Ok(true);
+    }
+
+    report_dry_run(&preview, host, args, pulling);
+    if preview.changes.is_empty() {
+        println!("Nothing to {}.", direction_name(pulling));
+        return Ok(false);
+    }
+    if let Some(ByteSize(cap)) = args.max_total_size {
+        if preview.transferred_bytes.unwrap_or(0) > cap {
+            eprintln!(
+                "⚠️  over the --max-total-size limit of {}",
+                format_size(cap)
+            );
+        }
+    }
+    if !confirm("Proceed?", args)? {
+        bail!("aborted, nothing was transferred");
+    }
+    Ok(true)
+}
+
+// Stops a push-only sync when files were changed on the remote after the
+// local copy, e.g. edits made directly on the server. A pull dry run with
+// --update lists exactly those.
+#[allow(clippy::too_many_arguments)]
+fn guard_newer_remote(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    // Nothing to protect when the remote copy doesn't exist yet, which is
+    // also when this dry run fails.
+    let Ok(preview) = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        true,
+        files_from,
+    ) else {
+        return Ok(());
+    };
+    let newer = newer_on_remote(&preview.changes);
+    if newer.is_empty() {
+        return Ok(());
+    }
+    if args.json {
+        emit(json!({ "event": "newer_on_remote", "host": host, "files": newer }));
+    } else {
+        eprintln!("⚠️  Newer on {} than locally:", host);
+        for path in newer.iter().take(20) {
+            eprintln!("  {}", path);
+        }
+        if newer.len() > 20 {
+            eprintln!("  ... and {} more", newer.len() - 20);
+        }
+    }
+    bail!(
+        "{} file(s) changed on {} after the local copy; pull them first, or pass --force to overwrite them",
+        newer.len(),
+        host
+    )
+}
+
+// Aborts a sync that can't fit on the receiving filesystem. The size of the
+// source is an upper bound for the transfer, so the (slower) dry run that
+// measures the real delta only happens when that bound doesn't fit.
+#[allow(clippy::too_many_arguments)]
+fn check_free_space(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    let (available, place) = if pulling {
+        (
+            local_free_space(runner, local_path),
+            format!("this machine for {}", local_path.display()),
+        )
+    } else {
+        let free = match &args.remote_probe {
+            Some(probe) => probe.free,
+            None => remote_free_space(runner, host, remote_path, args),
+        };
+        (free, format!("{} for {}", host, remote_path))
+    };
+    let Some(available) = available else {
+        return Ok(());
+    };
+    let remote_size = match args.remote_probe.as_ref().and_then(|p| p.size) {
+        Some(size) => Some(size),
+        None if pulling => remote_size(runner, host, remote_path, args),
+        None => None,
+    };
+    let upper = if pulling {
+        match remote_size {
+            Some(size) => size,
+            None => return Ok(()),
+        }
+    } else if is_file {
+        fs::metadata(local_path)?.len()
+    } else {
+        sftp::local_entries(local_path, args)?
+            .iter()
+            .map(|e| e.size)
+            .sum()
+    };
+    if upper <= available {
+        return Ok(());
+    }
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        pulling,
+        files_from,
+    )?;
+    let needed = preview.transferred_bytes.unwrap_or(upper);
+    if needed > available {
+        bail!(
+            "this {} needs {} but only {} is free on {}; free up space or pass --force",
+            direction_name(pulling),
+            format_size(needed),
+            format_size(available),
+            place
+        );
+    }
+    Ok(())
+}
+
+// Free bytes where `local_path` (or its nearest existing parent) lives.
+fn local_free_space(runner: &dyn CommandRunner, local_path: &Path) -> Option<u64> {
+    let dir = local_path.ancestors().find(|p| p.exists())?;
+    let output = runner
+        .output("df", &["-Pk".to_string(), dir.display().to_string()])
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+// Disk usage of `remote_path` on the host, an upper bound for a pull.
+fn remote_size(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Option<u64> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!("du -sk {}", remote_shell_path(remote_path)));
+    let output = runner.output("ssh", &cmd_args).ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let kb: u64 = String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    Some(kb * 1024)
+}
+
+// Free bytes on the filesystem that holds `remote_path` (or its nearest
+// existing parent). None when df can't tell, in which case nothing is checked.
+fn remote_free_space(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Option<u64> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(format!(
+        "d={}; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; df -Pk \"$d\"",
+        remote_shell_path(remote_path)
+    ));
+    let output = runner.output("ssh", &cmd_args).ok()?;
+    if !output.status.success() {
+        debug!(host, "df failed; skipping the free space check");
+        return None;
+    }
+    parse_df_available(&String::from_utf8_lossy(&output.stdout))
+}
+
+// The Available column of `df -Pk`, in bytes.
+fn parse_df_available(output: &str) -> Option<u64> {
+    let line = output.lines().nth(1)?;
+    let kb: u64 = line.split_whitespace().nth(3)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+// Files a pull would update rather than create, given --update semantics.
+fn newer_on_remote(changes: &[Change]) -> Vec<&str> {
+    changes
+        .iter()
+        .filter(|c| !c.is_deletion() && !c.is_new() && c.code.get(1..2) == Some("f"))
+        .map(|c| c.path.as_str())
+        .collect()
+}
+
+// --verify: a checksum dry run over what was just pushed. A file it would
+// still send has different contents on the remote.
+fn verify_transfer(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    let check_args = Args {
+        checksum: true,
+        force: true,
+        ignore_existing: false,
+        existing: false,
+        delete: false,
+        ..args.clone()
+    };
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        &check_args,
+        false,
+        files_from,
+    )?;
+    let mismatched = verify_mismatches(&preview);
+    let target = rsync_endpoint(host, remote_path);
+    if args.json {
+        emit(json!({
+            "event": "verify",
+            "host": host,
+            "ok": mismatched.is_empty(),
+            "mismatched": mismatched,
+        }));
+    } else if mismatched.is_empty() {
+        println!("🔒 Verified by checksum: {} matches", target);
+    } else {
+        for path in &mismatched {
+            eprintln!("  ✗ {}", path);
+        }
+    }
+    if !mismatched.is_empty() {
+        bail!(
+            "{} file(s) differ from {} after the transfer",
+            mismatched.len(),
+            target
+        );
+    }
+    Ok(())
+}
+
+// Files the checksum run would transfer; attribute-only differences (`.f`)
+// aren't content mismatches.
+fn verify_mismatches(preview: &DryRunSummary) -> Vec<String> {
+    preview
+        .changes
+        .iter()
+        .filter(|c| c.code.starts_with("<f") || c.code.starts_with(">f"))
+        .map(|c| c.path.clone())
+        .collect()
+}
+
+fn confirm_mirror(
+    preview: &DryRunSummary,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+    pulling: bool,
+) -> Result<()> {
+    if !args.delete || preview.deletions.is_empty() {
+        return Ok(());
+    }
+    let target = if pulling {
+        local_path.display().to_string()
+    } else {
+        rsync_endpoint(host, remote_path)
+    };
+    if args.json {
+        emit(json!({ "event": "pending_deletions", "target": target, "paths": preview.deletions }));
+    } else {
+        println!(
+            "The following {} path(s) will be deleted from {}:",
+            preview.deletions.len(),
+            target
+        );
+        for path in &preview.deletions {
+            println!("  - {}", path);
+        }
+    }
+    if !confirm("Delete these files?", args)? {
+        bail!("aborted, nothing was deleted");
+    }
+    Ok(())
+}
+
+fn guard_transfer_size(preview: &DryRunSummary, args: &Args, pulling: bool) -> Result<()> {
+    let Some(ByteSize(cap)) = args.max_total_size else {
+        return Ok(());
+    };
+    let size = preview.transferred_bytes.unwrap_or(0);
+    if size <= cap {
+        return Ok(());
+    }
+    let message = format!(
+        "this {} would transfer {}, over the --max-total-size limit of {}",
+        direction_name(pulling),
+        format_size(size),
+        format_size(cap)
+    );
+    if args.json {
+        emit(json!({ "event": "size_limit", "transfer_bytes": size, "limit_bytes": cap }));
+    } else {
+        eprintln!("⚠️  {}", message);
+    }
+    if !confirm("Transfer anyway?", args)? {
+        bail!("aborted: {}", message);
+    }
+    Ok(())
+}
+
+fn confirm(prompt: &str, args: &Args) -> Result<bool> {
+    if args.yes {
+        return Ok(true);
+    }
+    if args.non_interactive {
+        eprintln!("{} no (non-interactive; pass --yes to accept)", prompt);
+        return Ok(false);
+    }
+    Ok(Confirm::with_theme(&ColorfulTheme::default())
+        .with_prompt(prompt)
+        .default(false)
+        .interact()?)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_dry_run(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<DryRunSummary> {
+    let (src, dst) = sync_endpoints(host, local_path, remote_path, is_file, pulling);
+
+    let mut cmd_args = base_rsync_args(args, true);
+    if let Some(list) = files_from {
+        cmd_args.push(format!("--files-from={}", list.display()));
+        cmd_args.push("--from0".to_string());
+    }
+    cmd_args.push("--dry-run".to_string());
+    cmd_args.push("--itemize-changes".to_string());
+    cmd_args.push("--out-format=%i|%n|%l".to_string());
+    cmd_args.push(src);
+    cmd_args.push(dst);
+    let output = runner
+        .output("rsync", &cmd_args)
+        .with_context(|| "failed to run rsync --dry-run")?;
+
+    if !output.status.success() {
+        return Err(RsyncError::new(
+            output.status.code(),
+            &String::from_utf8_lossy(&output.stderr),
+            true,
+        )
+        .into());
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let deletions = parse_deletions(&stdout);
+    let changes = parse_changes(&stdout);
+    // The itemize codes say why each file is listed (size, time, checksum...).
+    for change in &changes {
+        debug!(code = %change.code, path = %change.path, size = ?change.size, "dry run change");
+    }
+
+    // `--stats` prints to stdout; stderr is checked too for older rsyncs.
+    let stats = String::from_utf8_lossy(&output.stderr);
+    let transferred_line = stdout
+        .lines()
+        .chain(stats.lines())
+        .find(|line| line.starts_with("Total transferred file size:"))
+        .map(|line| line.trim().to_string());
+
+    info!(
+        host,
+        files = changes.len(),
+        deletions = deletions.len(),
+        transferred = ?transferred_line,
+        "dry run"
+    );
+    // Only a preview shows renames; it's worth the extra hashing there.
+    let renames = if args.dry_run && !is_file && !deletions.is_empty() {
+        let (sender, receiver) = if pulling {
+            (Side::new(host, remote_path), Side::local(local_path))
+        } else {
+            (Side::local(local_path), Side::new(host, remote_path))
+        };
+        detect_renames(runner, &sender, &receiver, &changes, &deletions, args).unwrap_or_else(
+            |err| {
+                debug!(%err, "rename detection failed");
+                Vec::new()
+            },
+        )
+    } else {
+        Vec::new()
+    };
+    Ok(DryRunSummary {
+        transferred_bytes: transferred_line
+            .as_deref()
+            .and_then(parse_transferred_bytes),
+        transferred_line,
+        deletions,
+        changes,
+        renames,
+    })
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+struct Rename {
+    from: String,
+    to: String,
+    size: u64,
+}
+
+// Past this many new or deleted files, hashing them isn't worth the wait.
+const RENAME_CANDIDATES: usize = 500;
+
+// One end of a transfer: a directory on a host, or a local one.
+struct Side {
+    host: String,
+    dir: String,
+}
+
+impl Side {
+    fn new(host: &str, dir: &str) -> Self {
+        Side {
+            host: host.to_string(),
+            dir: dir.to_string(),
+        }
+    }
+
+    fn local(dir: &Path) -> Self {
+        Side::new("", &dir.to_string_lossy())
+    }
+}
+
+// rsync sends a renamed file again in full, as a deletion on the receiver and
+// a new file from the sender. The deleted files whose size matches a new one
+// are hashed where they are, then the new files whose size matches one of
+// those; equal size and SHA-256 make a rename.
+fn detect_renames(
+    runner: &dyn CommandRunner,
+    sender: &Side,
+    receiver: &Side,
+    changes: &[Change],
+    deletions: &[String],
+    args: &Args,
+) -> Result<Vec<Rename>> {
+    let added: Vec<&Change> = changes
+        .iter()
+        .filter(|c| c.is_new() && !c.path.ends_with('/') && c.size.is_some_and(|s| s > 0))
+        .collect();
+    let deleted: Vec<String> = deletions
+        .iter()
+        .filter(|p| !p.ends_with('/'))
+        .cloned()
+        .collect();
+    if added.is_empty() || added.len() > RENAME_CANDIDATES || deleted.len() > RENAME_CANDIDATES {
+        return Ok(Vec::new());
+    }
+    let sizes: BTreeSet<u64> = added.iter().filter_map(|c| c.size).collect();
+    let removed = sized_sums(runner, receiver, &sizes, &deleted, args)?;
+    if removed.is_empty() {
+        return Ok(Vec::new());
+    }
+    let sizes: BTreeSet<u64> = removed.iter().map(|(size, _, _)| *size).collect();
+    let candidates: Vec<String> = added
+        .iter()
+        .filter(|c| c.size.is_some_and(|s| sizes.contains(&s)))
+        .map(|c| c.path.clone())
+        .collect();
+    let sent = sized_sums(runner, sender, &sizes, &candidates, args)?;
+    Ok(pair_renames(&removed, &sent))
+}
+
+// `SIZE HASH  PATH` for each of `paths` under the side's directory whose size
+// is one of `sizes`.
+fn sized_sums(
+    runner: &dyn CommandRunner,
+    side: &Side,
+    sizes: &BTreeSet<u64>,
+    paths: &[String],
+    args: &Args,
+) -> Result<Vec<(u64, String, String)>> {
+    let script = sized_sums_script();
+    let sizes: Vec<String> = sizes.iter().map(u64::to_string).collect();
+    let output = if side.host.is_empty() {
+        let mut cmd_args = vec![
+            "-c".to_string(),
+            script,
+            "sh".to_string(),
+            sizes.join(" "),
+            side.dir.clone(),
+        ];
+        cmd_args.extend(paths.iter().cloned());
+        runner
+            .output("sh", &cmd_args)
+            .with_context(|| "failed to run sh")?
+    } else {
+        let mut command = format!(
+            "sh -c {} sh {} {}",
+            shell_escape(&script),
+            shell_escape(&sizes.join(" ")),
+            remote_shell_path(&side.dir)
+        );
+        for path in paths {
+            command.push(' ');
+            command.push_str(&shell_escape(path));
+        }
+        let mut cmd_args = ssh_args(args);
+        cmd_args.push(side.host.clone());
+        cmd_args.push(privileged(args, &command));
+        runner
+            .output("ssh", &cmd_args)
+            .with_context(|| "failed to run ssh")?
+    };
+    if !output.status.success() {
+        bail!(
+            "hashing files in {} failed: {}",
+            side.dir,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(parse_sized_sums(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn sized_sums_script() -> String {
+    format!(
+        "{}; sizes=\" $1 \"; cd \"$2\" || exit 3; shift 2; for f; do [ -f \"$f\" ] || continue; \
+         n=$(wc -c < \"$f\" | tr -d ' '); case $sizes in *\" $n \"*) printf '%s ' \"$n\"; sha -- \"$f\";; esac; done",
+        sums::SHA256
+    )
+}
+
+fn parse_sized_sums(stdout: &str) -> Vec<(u64, String, String)> {
+    stdout
+        .lines()
+        .filter_map(|line| {
+            let (size, rest) = line.split_once(' ')?;
+            let (hash, path) = rest.split_once("  ")?;
+            Some((size.parse().ok()?, hash.to_string(), path.to_string()))
+        })
+        .collect()
+}
+
+// Each deleted file pairs with the first new file of the same size and hash
+// that isn't taken yet.
+fn pair_renames(removed: &[(u64, String, String)], sent: &[(u64, String, String)]) -> Vec<Rename> {
+    let mut taken = vec![false; sent.len()];
+    let mut renames = Vec::new();
+    for (size, hash, from) in removed {
+        let found = sent
+            .iter()
+            .enumerate()
+            .find(|(i, (s, h, _))| !taken[*i] && s == size && h == hash);
+        if let Some((i, (_, _, to))) = found {
+            taken[i] = true;
+            renames.push(Rename {
+                from: from.clone(),
+                to: to.clone(),
+                size: *size,
+            });
+        }
+    }
+    renames
+}
+
+fn parse_transferred_bytes(line: &str) -> Option<u64> {
+    let rest = line.strip_prefix("Total transferred file size:")?.trim();
+    parse_bytes(rest.split_whitespace().next()?)
+}
+
+// Parses `%i|%n|%l` itemize lines from a dry run.
+fn parse_changes(output: &str) -> Vec<Change> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split('|');
+            let code = parts.next()?.trim();
+            let path = parts.next()?.trim_start_matches("./");
+            if code.is_empty() || path.is_empty() || path == "." {
+                return None;
+            }
+            Some(Change {
+                code: code.to_string(),
+                path: path.to_string(),
+                size: parts.next().and_then(parse_bytes),
+            })
+        })
+        .collect()
+}
+
+fn parse_deletions(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.strip_prefix("*deleting")?;
+            let name = match rest.split('|').nth(1) {
+                Some(name) => name,
+                None => rest,
+            };
+            let name = name.trim();
+            (!name.is_empty()).then(|| name.to_string())
+        })
+        .collect()
+}
+// Runs the transfer, split across `--parallel` rsync processes when asked.
+// The chunks come from a dry run and share the ControlMaster connection that
+// the earlier ssh checks opened.
+#[allow(clippy::too_many_arguments)]
+fn transfer(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    if args.backup && args.backup_dir.is_none() {
+        let set = new_backup_set(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            pulling,
+            args,
+        )?;
+        let mut args = args.clone();
+        args.backup_dir = Some(set.backup_dir.clone());
+        transfer(
+            runner,
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            &args,
+            pulling,
+            files_from,
+        )?;
+        return append_backup_set(&backups_path()?, &set);
+    }
+    let workers = args.parallel.unwrap_or(1);
+    if workers <= 1 || is_file {
+        return run_rsync(
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            pulling,
+            files_from,
+        );
+    }
+    let preview = run_dry_run(
+        runner,
+        host,
+        local_path,
+        remote_path,
+        is_file,
+        args,
+        pulling,
+        files_from,
+    )?;
+    let chunks = split_into_chunks(&preview.changes, workers);
+    if chunks.len() <= 1 {
+        return run_rsync(
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            pulling,
+            files_from,
+        );
+    }
+
+    let lists = chunks
+        .iter()
+        .map(|chunk| write_files_from(chunk))
+        .collect::<Result<Vec<_>>>()?;
+    let progress = args.progress.clone().unwrap_or_default();
+    let total = lists.len();
+    let results: Vec<Result<()>> = std::thread::scope(|scope| {
+        let handles: Vec<_> = lists
+            .iter()
+            .enumerate()
+            .map(|(i, list)| {
+                let mut chunk_args = args.clone();
+                chunk_args.progress = Some(progress.clone());
+                chunk_args.progress_label = Some(format!("{} [{}/{}]", host, i + 1, total));
+                scope.spawn(move || {
+                    run_rsync(
+                        host,
+                        local_path,
+                        remote_path,
+                        is_file,
+                        &chunk_args,
+                        pulling,
+                        Some(list),
+                    )
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| Err(anyhow!("rsync worker panicked")))
+            })
+            .collect()
+    });
+    for list in &lists {
+        let _ = fs::remove_file(list);
+    }
+    let failed = results.iter().filter(|r| r.is_err()).count();
+    if let Some(Err(e)) = results.into_iter().find(|r| r.is_err()) {
+        return Err(e.context(format!("{} of {} parallel transfers failed", failed, total)));
+    }
+    Ok(())
+}
+
+// Spreads the changed files over up to `workers` lists of similar total size,
+// largest files first. Directory entries go in the first list so new (even
+// empty) directories are still created.
+fn split_into_chunks(changes: &[Change], workers: usize) -> Vec<Vec<String>> {
+    let mut files: Vec<&Change> = changes
+        .iter()
+        .filter(|c| !c.is_deletion() && !c.path.ends_with('/'))
+        .collect();
+    files.sort_by(|a, b| b.size.cmp(&a.size).then_with(|| a.path.cmp(&b.path)));
+    let workers = workers.min(files.len()).max(1);
+    let mut chunks: Vec<(u64, Vec<String>)> = vec![(0, Vec::new()); workers];
+    for file in files {
+        let (total, list) = chunks
+            .iter_mut()
+            .min_by_key(|(total, _)| *total)
+            .expect("at least one chunk");
+        *total += file.size.unwrap_or(0);
+        list.push(file.path.clone());
+    }
+    let mut chunks: Vec<Vec<String>> = chunks.into_iter().map(|(_, list)| list).collect();
+    chunks[0].extend(
+        changes
+            .iter()
+            .filter(|c| !c.is_deletion() && c.path.ends_with('/'))
+            .map(|c| c.path.clone()),
+    );
+    for chunk in &mut chunks {
+        chunk.sort();
+    }
+    chunks.retain(|chunk| !chunk.is_empty());
+    chunks
+}
+
+// A failed rsync run, kept as a typed error so callers can look at the exit
+// code. `detail` is the most useful line rsync (or ssh) printed to stderr.
+#[derive(Debug)]
+struct RsyncError {
+    code: Option<i32>,
+    dry_run: bool,
+    detail: Option<String>,
+}
+
+impl std::fmt::Display for RsyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let what = if self.dry_run {
+            "rsync dry run"
+        } else {
+            "rsync"
+        };
+        match self.code {
+            Some(code) => write!(
+                f,
+                "{} failed: {} (exit code {})",
+                what,
+                rsync_exit_cause(code),
+                code
+            )?,
+            None => write!(f, "{} was terminated by a signal", what)?,
+        }
+        if let Some(detail) = &self.detail {
+            write!(f, ": {}", detail)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for RsyncError {}
+
+impl RsyncError {
+    fn new(code: Option<i32>, stderr: &str, dry_run: bool) -> Self {
+        // rsync ends with a generic "rsync error: ... (code N)" summary; the
+        // line before it usually names the actual problem.
+        let detail = stderr
+            .lines()
+            .rev()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with("rsync error:"))
+            .find(|line| parse_progress_percent(line).is_none())
+            .map(str::to_string);
+        RsyncError {
+            code,
+            dry_run,
+            detail,
+        }
+    }
+
+    // The exit status syncz itself should end with; rsync's codes are passed
+    // through so scripts can tell e.g. a partial transfer from a dead link.
+    fn exit_code(&self) -> i32 {
+        self.code.filter(|&code| code != 0).unwrap_or(1)
+    }
+
+    // Failures worth another attempt: the connection dropped or stalled, so a
+    // rerun picks up where `--partial` left off. Anything else (bad arguments,
+    // missing files, permissions) would just fail again.
+    fn is_retryable(&self) -> bool {
+        matches!(self.code, Some(10 | 12 | 30 | 35 | 255))
+    }
+}
+
+const DEFAULT_RETRY_DELAY_SECS: u64 = 2;
+const MAX_RETRY_DELAY_SECS: u64 = 300;
+
+fn retry_delay(base_secs: u64, attempt: u32) -> Duration {
+    let secs = base_secs.saturating_mul(1u64 << attempt.min(16));
+    Duration::from_secs(secs.min(MAX_RETRY_DELAY_SECS))
+}
+
+// What rsync's exit codes mean (see EXIT VALUES in rsync(1)); 255 and 127
+// come from ssh rather than rsync itself.
+fn rsync_exit_cause(code: i32) -> &'static str {
+    match code {
+        1 => "syntax or usage error",
+        2 => "protocol incompatibility",
+        3 => "errors selecting input/output files or directories",
+        4 => "requested action not supported by the remote rsync",
+        5 => "error starting client-server protocol",
+        10 => "socket I/O error (connection lost)",
+        11 => "file I/O error",
+        12 => "protocol data stream error (connection dropped or remote rsync died)",
+        13 => "errors with program diagnostics",
+        14 => "error in IPC code",
+        20 => "interrupted",
+        21 => "waitpid() error",
+        22 => "out of memory",
+        23 => "partial transfer: some files could not be transferred",
+        24 => "partial transfer: source files vanished during the sync",
+        25 => "the --max-delete limit stopped deletions",
+        30 => "timeout sending or receiving data",
+        35 => "timeout waiting for the daemon connection",
+        127 => "rsync not found on the remote host",
+        255 => "ssh connection failed (refused, unreachable or authentication)",
+        _ => "unknown error",
+    }
+}
+
+// Runs rsync, re-running it after transient failures when `--retries` is set.
+fn run_rsync(
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    let retries = args.retries.unwrap_or(0);
+    let base_delay = args.retry_delay.unwrap_or(DEFAULT_RETRY_DELAY_SECS);
+    let mut attempt = 0;
+    loop {
+        let result = run_rsync_once(
+            host,
+            local_path,
+            remote_path,
+            is_file,
+            args,
+            pulling,
+            files_from,
+        );
+        let retryable = match &result {
+            Err(e) => e
+                .downcast_ref::<RsyncError>()
+                .is_some_and(RsyncError::is_retryable),
+            Ok(()) => false,
+        };
+        if !retryable || attempt >= retries {
+            return result;
+        }
+        let delay = retry_delay(base_delay, attempt);
+        attempt += 1;
+        if let Err(e) = &result {
+            warn!(
+                host,
+                attempt,
+                retries,
+                delay_ms = delay.as_millis() as u64,
+                "retrying: {}",
+                e
+            );
+            if args.json {
+                emit(json!({
+                    "event": "retry",
+                    "host": host,
+                    "attempt": attempt,
+                    "retries": retries,
+                    "delay_ms": delay.as_millis() as u64,
+                    "message": e.to_string(),
+                }));
+            } else {
+                eprintln!(
+                    "⚠️  {} with {}; retrying in {}s ({}/{})",
+                    e,
+                    host,
+                    delay.as_secs(),
+                    attempt,
+                    retries
+                );
+            }
+        }
+        std::thread::sleep(delay);
+    }
+}
+
+fn run_rsync_once(
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+    files_from: Option<&Path>,
+) -> Result<()> {
+    let (src, dst) = sync_endpoints(host, local_path, remote_path, is_file, pulling);
+
+    let mut cmd = Command::new("rsync");
+    let mut base_args = base_rsync_args(args, false);
+    if let Some(list) = files_from {
+        base_args.push(format!("--files-from={}", list.display()));
+        base_args.push("--from0".to_string());
+    }
+    if !base_args.iter().any(|a| a == "--itemize-changes") {
+        base_args.push("--itemize-changes".to_string());
+    }
+    base_args.push(src);
+    base_args.push(dst);
+    info!(command = %command_line("rsync", &base_args), "running");
+    cmd.args(base_args);
+    cmd.stdout(Stdio::piped());
+    cmd.stderr(Stdio::piped());
+
+    let mut child = cmd.spawn().with_context(|| "failed to spawn rsync")?;
+
+    // When several transfers share one display, each bar is labelled with its host.
+    let (mp, label) = match &args.progress {
+        Some(mp) => (
+            mp.clone(),
+            args.progress_label
+                .clone()
+                .unwrap_or_else(|| host.to_string()),
+        ),
+        None if args.json => (
+            MultiProgress::with_draw_target(ProgressDrawTarget::hidden()),
+            String::new(),
+        ),
+        None => (MultiProgress::new(), "Overall".to_string()),
+    };
+
+    let overall = ProgressBar::new(100);
+    overall.set_style(
+        ProgressStyle::with_template("{prefix} {wide_bar} {pos}% {msg}")
+            .unwrap()
+            .progress_chars("=> "),
+    );
+    overall.set_prefix(label.clone());
+
+    let current = ProgressBar::new_spinner();
+    current.set_message("Waiting for files...");
+    current.enable_steady_tick(Duration::from_millis(100));
+
+    let overall = mp.add(overall);
+    let current = mp.add(current);
+
+    let overall = Arc::new(overall);
+    let current = Arc::new(current);
+    let stats_lines = Arc::new(Mutex::new(Vec::new()));
+    let itemized_lines = Arc::new(Mutex::new(Vec::new()));
+    let error_lines = Arc::new(Mutex::new(Vec::new()));
+
+    let stdout = child.stdout.take().ok_or_else(|| anyhow!("no stdout"))?;
+    let stderr = child.stderr.take().ok_or_else(|| anyhow!("no stderr"))?;
+
+    let current_clone = Arc::clone(&current);
+    let overall_progress = Arc::clone(&overall);
+    let itemized_clone = Arc::clone(&itemized_lines);
+    let stdout_stats = Arc::clone(&stats_lines);
+    let sent_bytes = Arc::new(AtomicU64::new(0));
+    let sent_clone = Arc::clone(&sent_bytes);
+    // With --json, progress goes out as an event about once a second instead.
+    let json_progress = args
+        .json
+        .then(|| (host.to_string(), direction_name(pulling)));
+    let stdout_handle = std::thread::spawn(move || {
+        // The file being sent: its name, size and the total bytes sent
+        // before it started, which give its own percentage.
+        let mut file: Option<(String, u64, u64)> = None;
+        let mut sent = 0;
+        let mut started = false;
+        let mut last_event: Option<Instant> = None;
+        read_records(BufReader::new(stdout), |line| {
+            if line.trim().is_empty() {
+                return;
+            }
+            // Building the file list of a big tree takes a while; show that
+            // it's moving until the first change comes through.
+            if !started {
+                if let Some(found) = scanned_files(&line) {
+                    current_clone
+                        .set_message(format!("scanning: {} files found", format_count(found)));
+                }
+            }
+            if let Some(progress) = parse_progress2(&line) {
+                sent = progress.bytes;
+                sent_clone.store(sent, Ordering::Relaxed);
+                if let Some((host, direction)) = &json_progress {
+                    if last_event.is_none_or(|t| t.elapsed() >= Duration::from_secs(1)) {
+                        last_event = Some(Instant::now());
+                        emit(json!({
+                            "event": "progress",
+                            "direction": direction,
+                            "host": host,
+                            "bytes": progress.bytes,
+                            "percent": progress.percent,
+                            "speed": progress.speed,
+                            "eta": progress.eta,
+                        }));
+                    }
+                }
+                overall_progress.set_position(progress.percent as u64);
+                overall_progress.set_message(format!(
+                    "{}  {}  ETA {}",
+                    format_size(progress.bytes),
+                    progress.speed,
+                    progress.eta
+                ));
+                if let Some((name, size, start)) = &file {
+                    let done = (progress.bytes.saturating_sub(*start) * 100 / size).min(100);
+                    current_clone.set_message(format!("{} {}%", name, done));
+                }
+                return;
+            }
+            // `--stats` output goes to stdout alongside the itemized changes.
+            if is_stats_line(&line) {
+                if let Ok(mut guard) = stdout_stats.lock() {
+                    guard.push(line);
+                }
+                return;
+            }
+            if line.contains('|') {
+                started = true;
+                let parts: Vec<&str> = line.split('|').collect();
+                if parts.len() >= 2 {
+                    current_clone.set_message(parts[1].to_string());
+                    let size = parts.get(2).and_then(|s| parse_bytes(s)).unwrap_or(0);
+                    file = (parts[0].get(1..2) == Some("f") && size > 0)
+                        .then(|| (parts[1].to_string(), size, sent));
+                }
+                if let Ok(mut guard) = itemized_clone.lock() {
+                    guard.push(line);
+                }
+            } else if scanned_files(&line).is_none() {
+                current_clone.set_message(line);
+            }
+        });
+    });
+
+    let overall_clone = Arc::clone(&overall);
+    let stats_clone = Arc::clone(&stats_lines);
+    let errors_clone = Arc::clone(&error_lines);
+    let stderr_handle = std::thread::spawn(move || {
+        let reader = BufReader::new(stderr);
+        for line in reader.lines().map_while(Result::ok) {
+            if let Some(percent) = parse_progress_percent(&line) {
+                overall_clone.set_position(percent as u64);
+            } else if is_stats_line(&line) {
+                if let Ok(mut guard) = stats_clone.lock() {
+                    guard.push(line);
+                }
+            } else if let Ok(mut guard) = errors_clone.lock() {
+                warn!(stderr = %line, "rsync");
+                guard.push(line);
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let active = ActiveTransfer::start();
+    let status = loop {
+        if let Some(status) = child
+            .try_wait()
+            .with_context(|| "failed to wait on rsync")?
+        {
+            break status;
+        }
+        if INTERRUPTED.load(Ordering::SeqCst) {
+            let _ = child.kill();
+            break child.wait().with_context(|| "failed to wait on rsync")?;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    };
+    drop(active);
+    let duration = start.elapsed();
+
+    let _ = stdout_handle.join();
+    let _ = stderr_handle.join();
+
+    if INTERRUPTED.load(Ordering::SeqCst) {
+        overall.abandon();
+        current.finish_and_clear();
+        let sent = sent_bytes.load(Ordering::Relaxed);
+        warn!(
+            host,
+            sent,
+            duration_ms = duration.as_millis() as u64,
+            "interrupted"
+        );
+        TRANSFERRED_BYTES.fetch_add(sent, Ordering::Relaxed);
+        return Err(Interrupted { sent }.into());
+    }
+
+    overall.finish();
+    current.finish_with_message("Done");
+
+    if !status.success() {
+        let stderr = error_lines
+            .lock()
+            .map(|lines| lines.join("\n"))
+            .unwrap_or_default();
+        let err = RsyncError::new(status.code(), &stderr, false);
+        warn!(host, duration_ms = duration.as_millis() as u64, "{}", err);
+        return Err(err.into());
+    }
+
+    let stats = stats_lines
+        .lock()
+        .ok()
+        .map(|lines| lines.clone())
+        .unwrap_or_default();
+    if let Ok(lines) = itemized_lines.lock() {
+        for line in lines.iter() {
+            debug!(host, change = %line, "transferred");
+        }
+    }
+    let parsed = parse_stats(&stats);
+    TRANSFERRED_BYTES.fetch_add(
+        parsed.sent_bytes.unwrap_or(0) + parsed.received_bytes.unwrap_or(0),
+        Ordering::Relaxed,
+    );
+    info!(
+        host,
+        files = itemized_lines.lock().map(|lines| lines.len()).unwrap_or(0),
+        sent_bytes = ?parsed.sent_bytes,
+        total_size = ?parsed.total_size,
+        speedup = ?parsed.speedup,
+        duration_ms = duration.as_millis() as u64,
+        "rsync finished"
+    );
+
+    if args.json {
+        let changes: Vec<serde_json::Value> = itemized_lines
+            .lock()
+            .map(|lines| {
+                parse_changes(&lines.join("\n"))
+                    .iter()
+                    .map(|c| json!({ "path": c.path, "change": c.code }))
+                    .collect()
+            })
+            .unwrap_or_default();
+        emit(json!({
+            "event": "transfer",
+            "direction": direction_name(pulling),
+            "host": host,
+            "files": changes,
+            "sent_bytes": parsed.sent_bytes,
+            "total_size": parsed.total_size,
+            "speedup": parsed.speedup,
+            "stats": parsed,
+            "duration_ms": duration.as_millis() as u64,
+        }));
+        return Ok(());
+    }
+
+    let mut report = Vec::new();
+    if args.progress.is_some() {
+        report.push(format!("[{}]", label));
+    }
+    if let Ok(guard) = itemized_lines.lock() {
+        if !guard.is_empty() {
+            report.push("Changes:".to_string());
+            let itemized_blob = guard.join("\n");
+            report.push(render_tree(
+                &parse_changes(&itemized_blob),
+                TreeView::new(args),
+            ));
+        }
+    }
+
+    report.push(format_summary(&stats, duration));
+
+    let report = report.join("\n");
+    if args.progress.is_some() {
+        // Printed in one piece so concurrent transfers don't interleave lines.
+        mp.println(report)?;
+    } else {
+        println!("{}", report);
+    }
+
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    const KB: u64 = 1024;
+    const MB: u64 = KB * 1024;
+    const GB: u64 = MB * 1024;
+
+    if bytes >= GB {
+        format!("{:.2} GB", bytes as f64 / GB as f64)
+    } else if bytes >= MB {
+        format!("{:.2} MB", bytes as f64 / MB as f64)
+    } else if bytes >= KB {
+        format!("{:.2} KB", bytes as f64 / KB as f64)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+fn parse_bytes(s: &str) -> Option<u64> {
+    s.replace(",", "").parse().ok()
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct TransferStats {
+    sent_bytes: Option<u64>,
+    received_bytes: Option<u64>,
+    total_size: Option<u64>,
+    speedup: Option<f64>,
+    files: Option<u64>,
+    files_created: Option<u64>,
+    files_deleted: Option<u64>,
+    files_transferred: Option<u64>,
+    literal_bytes: Option<u64>,
+    matched_bytes: Option<u64>,
+}
+
+// The lines of rsync's `--stats` block, plus the two it always prints at the
+// end.
+fn is_stats_line(line: &str) -> bool {
+    let line = line.trim_start();
+    [
+        "Number of ",
+        "Total ",
+        "Literal data:",
+        "Matched data:",
+        "File list ",
+        "sent ",
+        "total size is ",
+    ]
+    .iter()
+    .any(|prefix| line.starts_with(prefix))
+}
+
+fn parse_stats(stats: &[String]) -> TransferStats {
+    let mut parsed = TransferStats::default();
+
+    for line in stats {
+        let line = line.trim();
+        if let Some((key, value)) = line.split_once(": ") {
+            // "Number of files: 1,234 (reg: 1,000, dir: 234)", "Literal data: 512 bytes"
+            let value = value.split_whitespace().next().and_then(parse_bytes);
+            match key {
+                "Number of files" => parsed.files = value,
+                "Number of created files" => parsed.files_created = value,
+                "Number of deleted files" => parsed.files_deleted = value,
+                "Number of regular files transferred" => parsed.files_transferred = value,
+                "Literal data" => parsed.literal_bytes = value,
+                "Matched data" => parsed.matched_bytes = value,
+                _ => {}
+            }
+        }
+        if let Some(bytes_str) = line.strip_prefix("sent ") {
+            if let Some(end) = bytes_str.find(" bytes") {
+                parsed.sent_bytes = parse_bytes(&bytes_str[..end]);
+            }
+            if let Some(received) = bytes_str.split("received ").nth(1) {
+                parsed.received_bytes = received.split_whitespace().next().and_then(parse_bytes);
+            }
+        }
+        if let Some(rest) = line.strip_prefix("total size is ") {
+            if let Some(end) = rest.find("  ") {
+                parsed.total_size = parse_bytes(&rest[..end]);
+            } else {
+                parsed.total_size = parse_bytes(rest);
+            }
+            if let Some(speedup) = rest.split("speedup is ").nth(1) {
+                parsed.speedup = speedup
+                    .split_whitespace()
+                    .next()
+                    .and_then(|s| s.parse().ok());
+            }
+        }
+    }
+
+    parsed
+}
+
+fn format_summary(stats: &[String], duration: Duration) -> String {
+    let parsed = parse_stats(stats);
+
+    let mut rows = Vec::new();
+    if let Some(files) = parsed.files {
+        let mut counts = vec![format!("{} total", format_count(files))];
+        for (count, what) in [
+            (parsed.files_transferred, "transferred"),
+            (parsed.files_created, "created"),
+            (parsed.files_deleted, "deleted"),
+        ] {
+            if let Some(count) = count {
+                counts.push(format!("{} {}", format_count(count), what));
+            }
+        }
+        rows.push(("files", counts.join(", ")));
+    }
+    if let (Some(literal), Some(matched)) = (parsed.literal_bytes, parsed.matched_bytes) {
+        rows.push((
+            "data",
+            format!(
+                "{} literal, {} matched",
+                format_size(literal),
+                format_size(matched)
+            ),
+        ));
+    }
+    if let Some(bytes) = parsed.sent_bytes {
+        rows.push(("sent", format_size(bytes)));
+    }
+    if let Some(bytes) = parsed.received_bytes {
+        rows.push(("received", format_size(bytes)));
+    }
+    if let Some(bytes) = parsed.total_size {
+        rows.push(("total size", format_size(bytes)));
+    }
+    if let Some(speedup) = parsed.speedup {
+        rows.push(("speedup", format!("{:.2}", speedup)));
+    }
+    rows.push(("duration", format!("{:.2?}", duration)));
+
+    let mut lines = vec!["Summary:".to_string()];
+    for (label, value) in rows {
+        lines.push(format!("  {:<12}{}", format!("{}:", label), value));
+    }
+    lines.join("\n")
+}
+const DEFAULT_EXCLUDES: &[&str] = &[
+    "*.o",
+    "*.obj",
+    "*.a",
+    "*.lib",
+    "*.so",
+    "*.dylib",
+    "*.dll",
+    "*.exe",
+    "__pycache__/",
+    "*.pyc",
+    ".git/",
+    "node_modules/",
+    "target/",
+    ".next/",
+    "dist/",
+    "build/",
+    ".terraform/",
+    ".DS_Store",
+    "Thumbs.db",
+    "*.swp",
+    "*~",
+];
+
+// Already compressed formats, which -z would only spend CPU on.
+const SKIP_COMPRESS: &[&str] = &[
+    "7z", "avi", "br", "bz2", "deb", "flac", "gz", "heic", "iso", "jar", "jpeg", "jpg", "lz4",
+    "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "parquet", "png", "rar", "rpm", "tbz", "tgz",
+    "txz", "webm", "webp", "whl", "xz", "zip", "zst",
+];
+
+fn base_rsync_args(args: &Args, dry_run: bool) -> Vec<String> {
+    let compress = args.compress != Some(false);
+    // -u keeps rsync from replacing files that are newer on the receiver.
+    let mut list = vec![format!(
+        "-av{}{}",
+        if compress { "z" } else { "" },
+        if args.force { "" } else { "u" }
+    )];
+    if compress {
+        let suffixes: Vec<&str> = SKIP_COMPRESS
+            .iter()
+            .copied()
+            .chain(args.skip_compress.iter().map(|e| e.trim_start_matches('.')))
+            .collect();
+        list.push(format!("--skip-compress={}", suffixes.join("/")));
+        if let Some(choice) = &args.compress_choice {
+            list.push(format!("--compress-choice={}", choice));
+        }
+        if let Some(level) = args.compress_level {
+            list.push(format!("--compress-level={}", level));
+        }
+    }
+    if args.ignore_existing {
+        list.push("--ignore-existing".to_string());
+    }
+    if args.existing {
+        list.push("--existing".to_string());
+    }
+    if args.checksum {
+        list.push("--checksum".to_string());
+    }
+    if args.whole_file {
+        list.push("--whole-file".to_string());
+    }
+    if let Some(size) = args.block_size {
+        list.push(format!("--block-size={}", size));
+    }
+    if args.fuzzy {
+        list.push("--fuzzy".to_string());
+    }
+    if !dry_run {
+        list.push("-P".to_string());
+        list.push("--partial".to_string());
+        // Writing in place would modify the hard links a snapshot shares
+        // with the previous one.
+        if !args.snapshot {
+            list.push("--inplace".to_string());
+        }
+        list.push("--info=progress2".to_string());
+        list.push("--out-format=%i|%n|%l".to_string());
+    }
+    if let Some(secs) = args.timeout {
+        list.push(format!("--timeout={}", secs));
+    }
+    list.push("-e".to_string());
+    list.push(ssh_command(args));
+    if let Some(program) = remote_rsync(args) {
+        list.push(format!("--rsync-path={}", program));
+    }
+    list.push("--stats".to_string());
+
+    for pattern in &args.include {
+        list.push(format!("--include={}", pattern));
+    }
+
+    if !args.all {
+        for pattern in DEFAULT_EXCLUDES {
+            list.push(format!("--exclude={}", pattern));
+        }
+    }
+
+    list.extend(args.filters.iter().cloned());
+
+    if let Some(max_size) = &args.max_size {
+        list.push(format!("--max-size={}", max_size));
+    } else if !args.large && !args.all {
+        list.push("--max-size=10m".to_string());
+    }
+
+    if args.backup {
+        list.push("--backup".to_string());
+        if let Some(dir) = &args.backup_dir {
+            list.push(format!("--backup-dir={}", dir));
+        }
+    }
+
+    if args.no_perms {
+        list.push("--no-perms".to_string());
+    }
+
+    if let Some(rate) = &args.bwlimit {
+        list.push(format!("--bwlimit={}", rate));
+    }
+
+    if args.delete {
+        list.push("--delete".to_string());
+        // Deleted files stay until the end, so --fuzzy can use a renamed
+        // file's old copy as its basis.
+        if args.fuzzy {
+            list.push("--delete-delay".to_string());
+        }
+    }
+
+    for pattern in &args.exclude {
+        list.push(format!("--exclude={}", pattern));
+    }
+
+    // Rules match first-come, so anything excluded above stays excluded.
+    // Every directory is searched, but those left empty aren't created.
+    if !args.only.is_empty() {
+        list.push("--include=*/".to_string());
+        for pattern in &args.only {
+            list.push(format!("--include={}", pattern));
+            if pattern.ends_with('/') {
+                list.push(format!("--include={}**", pattern));
+            }
+        }
+        list.push("--exclude=*".to_string());
+        list.push("--prune-empty-dirs".to_string());
+    }
+
+    list.extend(args.rsync_args.iter().cloned());
+
+    list
+}
+
+// `--exclude-from` files: a pattern per line, skipping blank lines and
+// comments, which start with `#` or `;` as in rsync's own exclude files.
+fn exclude_patterns(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .filter(|line| !line.trim().is_empty() && !line.starts_with(['#', ';']))
+        .map(str::to_string)
+        .collect()
+}
+
+const SYNCIGNORE: &str = ".syncignore";
+
+// The rules of a `.syncignore` at the sync root, which uses rsync's filter
+// syntax. They come before the .gitignore rules, so a `+` line can bring
+// back something git ignores.
+fn syncignore_filters(root: &Path) -> Result<Vec<String>> {
+    let file = root.join(SYNCIGNORE);
+    if !root.is_dir() || !file.is_file() {
+        return Ok(Vec::new());
+    }
+    let contents =
+        fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+    Ok(syncignore_rules(&contents)
+        .into_iter()
+        .map(|rule| format!("--filter={}", rule))
+        .collect())
+}
+
+// A line that doesn't start with a filter rule (`- `, `+ `, `exclude `,
+// `merge `, ...) is a plain pattern to exclude, as in an exclude file.
+fn syncignore_rules(contents: &str) -> Vec<String> {
+    const KEYWORDS: [&str; 9] = [
+        "exclude",
+        "include",
+        "merge",
+        "dir-merge",
+        "hide",
+        "show",
+        "protect",
+        "risk",
+        "clear",
+    ];
+    let is_rule = |line: &str| {
+        let head = line.split([' ', '_']).next().unwrap_or("");
+        let mut chars = head.chars();
+        KEYWORDS.contains(&head)
+            || head == "!"
+            || (chars.next().is_some_and(|c| "+-.:HSPR".contains(c))
+                && line.len() > head.len()
+                && chars.all(|c| "/!Cnwe,sxpr".contains(c)))
+    };
+    exclude_patterns(contents)
+        .into_iter()
+        .map(|line| {
+            if is_rule(&line) {
+                line
+            } else {
+                format!("- {}", line)
+            }
+        })
+        .collect()
+}
+
+fn gitignore_filters(root: &Path, all: bool) -> Result<Vec<String>> {
+    let mut files = Vec::new();
+    collect_gitignores(root, "", all, &mut files)?;
+    // Deeper .gitignore files override shallower ones, and rsync stops at the
+    // first matching rule, so the most specific rules go first.
+    files.sort_by_key(|(dir, _)| std::cmp::Reverse(Path::new(dir).components().count()));
+    Ok(files
+        .iter()
+        .flat_map(|(dir, contents)| translate_gitignore(contents, dir))
+        .map(|rule| format!("--filter={}", rule))
+        .collect())
+}
+
+fn collect_gitignores(
+    dir: &Path,
+    rel: &str,
+    all: bool,
+    out: &mut Vec<(String, String)>,
+) -> Result<()> {
+    let ignore_file = dir.join(".gitignore");
+    if ignore_file.is_file() {
+        let contents = fs::read_to_string(&ignore_file)
+            .with_context(|| format!("failed to read {}", ignore_file.display()))?;
+        out.push((rel.to_string(), contents));
+    }
+    let entries = fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if !entry.file_type()?.is_dir() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().to_string();
+        let skipped = name == ".git"
+            || (!all
+                && DEFAULT_EXCLUDES
+                    .iter()
+                    .any(|p| p.strip_suffix('/') == Some(name.as_str())));
+        if skipped {
+            continue;
+        }
+        let child_rel = if rel.is_empty() {
+            name
+        } else {
+            format!("{}/{}", rel, name)
+        };
+        collect_gitignores(&entry.path(), &child_rel, all, out)?;
+    }
+    Ok(())
+}
+
+// Translates one .gitignore (living in `dir`, relative to the sync root) into
+// rsync filter rules. Git lets the last matching pattern win while rsync uses
+// the first, so the rules come out in reverse order.
+fn translate_gitignore(contents: &str, dir: &str) -> Vec<String> {
+    let base = if dir.is_empty() {
+        String::new()
+    } else {
+        format!("/{}", dir)
+    };
+    let mut rules = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim_end();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (action, pattern) = match line.strip_prefix('!') {
+            Some(rest) => ('+', rest),
+            None => ('-', line),
+        };
+        let pattern = pattern.strip_prefix('\\').unwrap_or(pattern);
+        let suffix = if pattern.ends_with('/') { "/" } else { "" };
+        let body = pattern.trim_end_matches('/');
+        let (body, floating) = match body.strip_prefix("**/") {
+            Some(rest) => (rest, true),
+            None => (body, !body.contains('/')),
+        };
+        let body = body.trim_start_matches('/');
+        if body.is_empty() {
+            continue;
+        }
+        if !floating {
+            rules.push(format!("{} {}/{}{}", action, base, body, suffix));
+        } else if dir.is_empty() {
+            rules.push(format!("{} {}{}", action, body, suffix));
+        } else {
+            rules.push(format!("{} {}/**/{}{}", action, base, body, suffix));
+            rules.push(format!("{} {}/{}{}", action, base, body, suffix));
+        }
+    }
+    rules.reverse();
+    rules
+}
+
+fn sync_endpoints(
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    is_file: bool,
+    pulling: bool,
+) -> (String, String) {
+    let (local, remote) = if is_file {
+        (
+            local_path.to_string_lossy().to_string(),
+            remote_path.to_string(),
+        )
+    } else {
+        (
+            format!("{}/", local_path.to_string_lossy()),
+            format!("{}/", remote_path),
+        )
+    };
+
+    let remote = rsync_endpoint(host, &remote);
+    if pulling {
+        (remote, local)
+    } else {
+        (local, remote)
+    }
+}
+
+// `host:path`, or just the path for a local sync (`--to PATH`), which has no host.
+fn rsync_endpoint(host: &str, path: &str) -> String {
+    if host.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}:{}", host, path)
+    }
+}
+
+// Like `lines()`, but a `\r` also ends a record: rsync redraws its progress
+// line in place that way, so updates arrive without a newline.
+fn read_records(mut reader: impl BufRead, mut each: impl FnMut(String)) {
+    let mut record = Vec::new();
+    loop {
+        let buf = match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => buf,
+            _ => break,
+        };
+        let (used, done) = match buf.iter().position(|&b| b == b'\n' || b == b'\r') {
+            Some(end) => {
+                record.extend_from_slice(&buf[..end]);
+                (end + 1, true)
+            }
+            None => {
+                record.extend_from_slice(buf);
+                (buf.len(), false)
+            }
+        };
+        reader.consume(used);
+        if done {
+            each(String::from_utf8_lossy(&record).into_owned());
+            record.clear();
+        }
+    }
+    if !record.is_empty() {
+        each(String::from_utf8_lossy(&record).into_owned());
+    }
+}
+
+// One `--info=progress2` update: bytes so far, overall percentage, current
+// speed and rsync's estimate of the time left.
+#[derive(Debug, PartialEq)]
+struct TransferProgress {
+    bytes: u64,
+    percent: u8,
+    speed: String,
+    eta: String,
+}
+
+fn parse_progress2(line: &str) -> Option<TransferProgress> {
+    let mut tokens = line.split_whitespace();
+    let bytes = parse_bytes(tokens.next()?)?;
+    let percent = tokens.next()?.strip_suffix('%')?.parse().ok()?;
+    let speed = tokens.next()?;
+    let eta = tokens.next()?;
+    if !speed.ends_with("/s") || !eta.contains(':') {
+        return None;
+    }
+    Some(TransferProgress {
+        bytes,
+        percent,
+        speed: speed.to_string(),
+        eta: eta.to_string(),
+    })
+}
+
+// How many files rsync has found so far while it builds the file list: the
+// total in progress2's `ir-chk=left/total` (incremental recursion), or the
+// `N files...` count printed when the whole list is built up front.
+fn scanned_files(line: &str) -> Option<u64> {
+    if let Some((_, rest)) = line.split_once("ir-chk=") {
+        let (_, total) = rest.split_once('/')?;
+        return total.trim_end_matches(')').parse().ok();
+    }
+    parse_bytes(line.trim().strip_suffix(" files...")?)
+}
+
+fn format_count(n: u64) -> String {
+    let digits = n.to_string();
+    let mut out = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i).is_multiple_of(3) {
+            out.push(',');
+        }
+        out.push(digit);
+    }
+    out
+}
+
+fn parse_progress_percent(line: &str) -> Option<u8> {
+    if !line.contains('%') {
+        return None;
+    }
+    let mut pct = None;
+    for token in line.split_whitespace() {
+        if let Some(num) = token.strip_suffix('%') {
+            if let Ok(value) = num.parse::<u8>() {
+                pct = Some(value);
+                break;
+            }
+        }
+    }
+    pct
+}
+
+// Directories with more entries than this are summarized in one line, so a
+// dataset directory doesn't print thousands of them.
+const TREE_MAX_ENTRIES: usize = 100;
+
+// How a tree of changes is drawn.
+#[derive(Clone, Copy, Default)]
+struct TreeView {
+    color: bool,
+    depth: Option<u32>,
+}
+
+impl TreeView {
+    fn new(args: &Args) -> Self {
+        TreeView {
+            color: use_color(args),
+            depth: args.tree_depth,
+        }
+    }
+}
+
+// Lays the changes out as a tree, each entry colored by what happens to it and
+// annotated with its size; directories show the total of what's under them.
+fn render_tree(changes: &[Change], view: TreeView) -> String {
+    let mut root = TreeNode::default();
+    for change in changes {
+        if change.path.starts_with('.') {
+            continue;
+        }
+        let mut node = &mut root;
+        for part in change.path.split('/').filter(|p| !p.is_empty()) {
+            node.is_dir = true;
+            node = node.children.entry(part.to_string()).or_default();
+        }
+        node.kind = Some(change.kind());
+        node.is_dir |= change.path.ends_with('/');
+        // Deleted files aren't transferred, so they don't count.
+        if !node.is_dir && !change.is_deletion() {
+            node.size = change.size;
+        }
+    }
+
+    let mut lines = Vec::new();
+    let mut iter = root.children.iter().peekable();
+    while let Some((name, node)) = iter.next() {
+        render_node(&mut lines, name, node, "", iter.peek().is_none(), 1, view);
+    }
+    lines.join("\n")
+}
+
+#[derive(Default)]
+struct TreeNode {
+    children: BTreeMap<String, TreeNode>,
+    kind: Option<ChangeKind>,
+    size: Option<u64>,
+    is_dir: bool,
+}
+
+impl TreeNode {
+    // A directory's size is the sum of the sizes known below it.
+    fn total(&self) -> Option<u64> {
+        if !self.is_dir {
+            return self.size;
+        }
+        self.children
+            .values()
+            .filter_map(TreeNode::total)
+            .reduce(|a, b| a + b)
+    }
+
+    fn files(&self) -> usize {
+        if !self.is_dir {
+            return 1;
+        }
+        self.children.values().map(TreeNode::files).sum()
+    }
+}
+
+fn render_node(
+    lines: &mut Vec<String>,
+    name: &str,
+    node: &TreeNode,
+    prefix: &str,
+    last: bool,
+    level: u32,
+    view: TreeView,
+) {
+    let color = view.color;
+    let branch = if last { "└── " } else { "├── " };
+    let mut name = if node.is_dir {
+        format!("{}/", name)
+    } else {
+        name.to_string()
+    };
+    if node.kind == Some(ChangeKind::Deleted) {
+        name.push_str(" [deleted]");
+    }
+    let name = match node.kind {
+        Some(kind) if color => format!("\x1b[{}m{}\x1b[0m", kind.color(), name),
+        _ => name,
+    };
+    let size = match node.total() {
+        Some(size) if color => format!(" \x1b[2m({})\x1b[0m", format_size(size)),
+        Some(size) => format!(" ({})", format_size(size)),
+        None => String::new(),
+    };
+    lines.push(format!("{}{}{}{}", prefix, branch, name, size));
+
+    let next_prefix = format!("{}{}", prefix, if last { "    " } else { "│   " });
+    if !node.children.is_empty()
+        && (view.depth.is_some_and(|depth| level >= depth)
+            || node.children.len() > TREE_MAX_ENTRIES)
+    {
+        let mut summary = format!("… {} file(s)", node.files());
+        if let Some(size) = node.total() {
+            summary.push_str(&format!(", {}", format_size(size)));
+        }
+        if color {
+            summary = format!("\x1b[2m{}\x1b[0m", summary);
+        }
+        lines.push(format!("{}└── {}", next_prefix, summary));
+        return;
+    }
+    let mut iter = node.children.iter().peekable();
+    while let Some((child_name, child_node)) = iter.next() {
+        let is_last = iter.peek().is_none();
+        render_node(
+            lines,
+            child_name,
+            child_node,
+            &next_prefix,
+            is_last,
+            level + 1,
+            view,
+        );
+    }
+}
+
+// ANSI colors only on a terminal, and never when NO_COLOR is set.
+fn use_color(args: &Args) -> bool {
+    !args.json && std::env::var_os("NO_COLOR").is_none() && std::io::stdout().is_terminal()
+}
+
+fn shell_escape(value: &str) -> String {
+    let mut out = String::from("'");
+    for ch in value.chars() {
+        if ch == '\'' {
+            out.push_str("'\\''");
+        } else {
+            out.push(ch);
+        }
+    }
+    out.push('\'');
+    out
+}
+
+fn shell_escape_double(value: &str) -> String {
+    let mut out = String::new();
+    for ch in value.chars() {
+        match ch {
+            '\\' | '"' | '$' | '`' => {
+                out.push('\\');
+                out.push(ch);
+            }
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+fn remote_shell_path(path: &str) -> String {
+    if path == "~" {
+        return "\"$HOME\"".to_string();
+    }
+    if let Some(rest) = path.strip_prefix("~/") {
+        return format!("\"$HOME/{}\"", shell_escape_double(rest));
+    }
+    shell_escape(path)
+}
+
+fn ssh_args(args: &Args) -> Vec<String> {
+    let mut list = Vec::new();
+    if let Some(port) = args.port {
+        list.push("-p".to_string());
+        list.push(port.to_string());
+    }
+    if let Some(jump) = &args.jump {
+        list.push("-J".to_string());
+        list.push(jump.clone());
+    }
+    if let Some(identity) = &args.identity {
+        list.push("-i".to_string());
+        list.push(identity.display().to_string());
+    }
+    for opts in &args.ssh_opts {
+        list.extend(split_words(opts));
+    }
+    // After --ssh-opt, since ssh keeps the first value it sees. Three missed
+    // keepalives end a dead connection after about SECS.
+    if let Some(secs) = args.timeout {
+        list.extend([
+            "-o".to_string(),
+            format!("ConnectTimeout={}", secs),
+            "-o".to_string(),
+            format!("ServerAliveInterval={}", (secs / 3).max(1)),
+            "-o".to_string(),
+            "ServerAliveCountMax=3".to_string(),
+        ]);
+    }
+    if !args.no_multiplex {
+        list.extend([
+            "-o".to_string(),
+            "ControlMaster=auto".to_string(),
+            "-o".to_string(),
+            "ControlPersist=60s".to_string(),
+            "-o".to_string(),
+            CONTROL_PATH.to_string(),
+        ]);
+    }
+    list
+}
+
+const CONTROL_PATH: &str = "ControlPath=~/.ssh/cm-%r@%h:%p";
+
+// Whether a master connection to the host is up, so the next ssh skips the
+// handshake. Asks the local control socket; nothing goes over the network.
+fn master_running(runner: &dyn CommandRunner, host: &str, args: &Args) -> bool {
+    master_command(runner, host, args, "check").is_ok_and(|status| status.success())
+}
+
+fn master_command(
+    runner: &dyn CommandRunner,
+    host: &str,
+    args: &Args,
+    command: &str,
+) -> Result<std::process::ExitStatus> {
+    let mut cmd_args = ssh_args(&Args {
+        no_multiplex: false,
+        ..args.clone()
+    });
+    cmd_args.extend(["-O".to_string(), command.to_string(), host.to_string()]);
+    runner
+        .status("ssh", &cmd_args)
+        .with_context(|| format!("failed to run ssh -O {}", command))
+}
+
+// `syncz connect`: a master connection in the background that stays up
+// until `syncz disconnect`, so every sync in between starts without a
+// handshake (and without asking for a password or key twice).
+fn connect_master(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<()> {
+    if master_running(runner, host, args) {
+        println!("Already connected to {}", host);
+        return Ok(());
+    }
+    let mut cmd_args = ssh_args(&Args {
+        no_multiplex: true,
+        ..args.clone()
+    });
+    cmd_args.extend(
+        [
+            "-o",
+            "ControlMaster=yes",
+            "-o",
+            "ControlPersist=yes",
+            "-o",
+            CONTROL_PATH,
+            "-N",
+            "-f",
+            host,
+        ]
+        .map(String::from),
+    );
+    info!(command = %command_line("ssh", &cmd_args), "running");
+    let status = runner
+        .status("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    if !status.success() {
+        bail!("could not connect to {} ({})", host, status);
+    }
+    println!(
+        "🔗 Connected to {}; syncs will reuse this connection until `syncz disconnect {}`",
+        host, host
+    );
+    Ok(())
+}
+
+fn disconnect_master(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<()> {
+    if !master_command(runner, host, args, "exit")?.success() {
+        bail!("no open connection to {}", host);
+    }
+    println!("Disconnected from {}", host);
+    Ok(())
+}
+
+// The ssh invocation for rsync's `-e`; rsync splits it on whitespace but
+// honours quotes, so arguments with spaces survive.
+fn ssh_command(args: &Args) -> String {
+    command_line("ssh", &ssh_args(args))
+}
+
+// Splits an option string into words like a shell would, minus expansions:
+// whitespace separates words unless quoted, and backslash escapes outside
+// single quotes.
+fn split_words(input: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut current = String::new();
+    let mut in_word = false;
+    let mut quote = None;
+    let mut chars = input.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some('\''), c) => current.push(c),
+            (_, '\\') => {
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+                in_word = true;
+            }
+            (Some(_), c) => current.push(c),
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                in_word = true;
+            }
+            (None, c) if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut current));
+                    in_word = false;
+                }
+            }
+            (None, c) => {
+                current.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_word {
+        words.push(current);
+    }
+    words
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DirStatus {
+    Missing,
+    Writable,
+    Unwritable,
+}
+
+fn probe_dir(dir: &Path) -> DirStatus {
+    match fs::metadata(dir) {
+        Err(_) => DirStatus::Missing,
+        Ok(meta) if !meta.is_dir() => DirStatus::Unwritable,
+        Ok(_) => {
+            let probe = dir.join(format!(".syncz-probe-{}", std::process::id()));
+            match fs::write(&probe, b"") {
+                Ok(()) => {
+                    let _ = fs::remove_file(&probe);
+                    DirStatus::Writable
+                }
+                Err(_) => DirStatus::Unwritable,
+            }
+        }
+    }
+}
+
+fn create_private_dir(dir: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(dir)
+}
+
+// Returns why multiplexing has to be disabled, or None if the ControlPath
+// directory is usable (creating it when missing).
+fn control_dir_problem(
+    dir: &Path,
+    probe: impl Fn(&Path) -> DirStatus,
+    create: impl Fn(&Path) -> std::io::Result<()>,
+) -> Option<String> {
+    match probe(dir) {
+        DirStatus::Writable => None,
+        DirStatus::Unwritable => Some(format!("{} is not a writable directory", dir.display())),
+        DirStatus::Missing => match create(dir) {
+            Ok(()) => None,
+            Err(e) => Some(format!("failed to create {}: {}", dir.display(), e)),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::Output;
+
+    struct ExpectedCall {
+        program: String,
+        args: Vec<String>,
+        output: Option<Output>,
+        status: Option<std::process::ExitStatus>,
+    }
+
+    struct FakeRunner {
+        calls: Mutex<VecDeque<ExpectedCall>>,
+    }
+
+    impl FakeRunner {
+        fn new(calls: Vec<ExpectedCall>) -> Self {
+            Self {
+                calls: Mutex::new(VecDeque::from(calls)),
+            }
+        }
+
+        fn next_call(&self) -> ExpectedCall {
+            let mut guard = self.calls.lock().expect("lock calls");
+            guard.pop_front().expect("expected call")
+        }
+    }
+
+    impl CommandRunner for FakeRunner {
+        fn status(&self, program: &str, args: &[String]) -> Result<std::process::ExitStatus> {
+            let call = self.next_call();
+            assert_eq!(call.program, program);
+            assert_eq!(call.args, args);
+            Ok(call.status.expect("expected status"))
+        }
+
+        fn output(&self, program: &str, args: &[String]) -> Result<Output> {
+            let call = self.next_call();
+            assert_eq!(call.program, program);
+            assert_eq!(call.args, args);
+            Ok(call.output.expect("expected output"))
+        }
+    }
+
+    fn ok_status() -> std::process::ExitStatus {
+        std::process::ExitStatus::from_raw(0)
+    }
+
+    #[test]
+    fn args_default_to_bidirectional() {
+        let args = Args::default();
+        assert!(args.is_push());
+        assert!(args.is_pull());
+    }
+
+    #[test]
+    fn args_push_only() {
+        let args = Args {
+            push: true,
+            ..Default::default()
+        };
+        assert!(args.is_push());
+        assert!(!args.is_pull());
+    }
+
+    #[test]
+    fn args_pull_only() {
+        let args = Args {
+            pull: true,
+            ..Default::default()
+        };
+        assert!(!args.is_push());
+        assert!(args.is_pull());
+    }
+
+    #[test]
+    fn test_base_rsync_args_logic() {
+        let mut args = Args {
+            dry_run: true,
+            ..Default::default()
+        };
+
+        let rsync_args = base_rsync_args(&args, true);
+        assert!(rsync_args.iter().any(|a| a == "--max-size=10m"));
+
+        args.large = true;
+        let rsync_args = base_rsync_args(&args, true);
+        assert!(!rsync_args.iter().any(|a| a == "--max-size=10m"));
+
+        args.all = true;
+        let rsync_args = base_rsync_args(&args, true);
+        assert!(!rsync_args.iter().any(|a| a == "--max-size=10m"));
+
+        args.gitignore = true;
+        args.filters = vec!["--filter=- *.log".to_string()];
+        let rsync_args = base_rsync_args(&args, true);
+        assert!(rsync_args.iter().any(|a| a == "--filter=- *.log"));
+
+        args.backup = true;
+        args.backup_dir = Some("/home/me/.syncz-trash/2024-06-01T12-00-00".to_string());
+        let rsync_args = base_rsync_args(&args, true);
+        assert!(rsync_args.iter().any(|a| a == "--backup"));
+        assert!(rsync_args
+            .iter()
+            .any(|a| a == "--backup-dir=/home/me/.syncz-trash/2024-06-01T12-00-00"));
+    }
+
+    #[test]
+    fn remote_is_file_uses_ssh() {
+        let host = "example";
+        let remote = "~/projects/app/file.txt";
+        let mut args = ssh_args(&Args::default());
+        args.push(host.to_string());
+        args.push(format!("test -f {}", remote_shell_path(remote)));
+
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+
+        let is_file =
+            remote_is_file(&runner, host, remote, &Args::default()).expect("remote_is_file");
+        assert!(is_file);
+    }
+
+    #[test]
+    fn ensure_remote_parent_creates_dir() {
+        let host = "example";
+        let remote_parent = "~/projects/app";
+        let mut args = ssh_args(&Args::default());
+        args.push(host.to_string());
+        args.push(format!("mkdir -p {}", remote_shell_path(remote_parent)));
+
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+
+        ensure_remote_parent(&runner, host, remote_parent, &Args::default())
+            .expect("ensure_remote_parent");
+    }
+
+    #[test]
+    fn dry_run_parses_tree_and_stats() {
+        let args = Args {
+            host: Some("example".to_string()),
+            dry_run: true,
+            ..Default::default()
+        };
+        let local_path = Path::new("/home/user/projects/app");
+        let remote_path = "~/projects/app";
+        let (src, dst) = sync_endpoints("example", local_path, remote_path, false, false);
+
+        let mut cmd_args = base_rsync_args(&args, true);
+        cmd_args.push("--dry-run".to_string());
+        cmd_args.push("--itemize-changes".to_string());
+        cmd_args.push("--out-format=%i|%n|%l".to_string());
+        cmd_args.push(src);
+        cmd_args.push(dst);
+
+        let stdout = b"f+++++++++|foo.txt|12\nd+++++++++|dir/|0\nf+++++++++|dir/bar.txt|24\n";
+        let stderr = b"Total transferred file size: 36 bytes\n";
+        let output = Output {
+            status: ok_status(),
+            stdout: stdout.to_vec(),
+            stderr: stderr.to_vec(),
+        };
+
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "rsync".to_string(),
+            args: cmd_args,
+            output: Some(output),
+            status: None,
+        }]);
+
+        let summary = run_dry_run(
+            &runner,
+            "example",
+            local_path,
+            remote_path,
+            false,
+            &args,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(
+            render_tree(&summary.changes, TreeView::default()),
+            "├── dir/ (24 B)\n│   └── bar.txt (24 B)\n└── foo.txt (12 B)"
+        );
+        assert_eq!(
+            summary.transferred_line.as_deref(),
+            Some("Total transferred file size: 36 bytes")
+        );
+    }
+
+    #[test]
+    fn since_parses_git_diff_and_separates_deletions() {
+        let stdout = "src/lib.rs\0README.md\0old/name.rs\0café.txt\0new/name.rs\0";
+        let changes = parse_git_changes(stdout, |p| p != "old/name.rs");
+        assert_eq!(
+            changes.changed,
+            vec!["src/lib.rs", "README.md", "café.txt", "new/name.rs"]
+        );
+        assert_eq!(changes.deleted, vec!["old/name.rs"]);
+    }
+
+    #[test]
+    fn files_from_lists_are_nul_separated() {
+        let paths = vec!["src/lib.rs".to_string(), "notes\nold.txt".to_string()];
+        let list = write_files_from(&paths).unwrap();
+        assert_eq!(
+            fs::read_to_string(&list).unwrap(),
+            "src/lib.rs\0notes\nold.txt\0"
+        );
+        let _ = fs::remove_file(&list);
+    }
+
+    #[test]
+    fn since_implies_push_only() {
+        let args = Args {
+            since: Some("HEAD~1".to_string()),
+            ..Default::default()
+        };
+        assert!(args.is_push());
+        assert!(!args.is_pull());
+    }
+
+    #[test]
+    fn delete_remote_files_runs_rm_in_remote_path() {
+        let host = "example";
+        let remote = "~/projects/app";
+        let mut args = ssh_args(&Args::default());
+        args.push(host.to_string());
+        args.push(format!(
+            "cd {} && rm -f -- 'old/name.rs' 'it'\\''s.txt'",
+            remote_shell_path(remote)
+        ));
+
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+
+        let paths = vec!["old/name.rs".to_string(), "it's.txt".to_string()];
+        delete_remote_files(&runner, host, remote, &paths, &Args::default())
+            .expect("delete_remote_files");
+    }
+
+    #[test]
+    fn control_dir_check_creates_missing_or_disables_multiplexing() {
+        let dir = Path::new("/home/user/.ssh");
+
+        let ok = control_dir_problem(dir, |_| DirStatus::Writable, |_| unreachable!());
+        assert!(ok.is_none());
+
+        let created = control_dir_problem(dir, |_| DirStatus::Missing, |_| Ok(()));
+        assert!(created.is_none());
+
+        let denied = control_dir_problem(
+            dir,
+            |_| DirStatus::Missing,
+            |_| Err(std::io::Error::from(std::io::ErrorKind::PermissionDenied)),
+        );
+        assert!(denied.unwrap().contains("failed to create /home/user/.ssh"));
+
+        let readonly = control_dir_problem(dir, |_| DirStatus::Unwritable, |_| Ok(()));
+        assert!(readonly.unwrap().contains("not a writable directory"));
+
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        assert!(ssh_args(&args).is_empty());
+        assert_eq!(ssh_command(&args), "ssh");
+    }
+
+    #[test]
+    fn config_fills_defaults_without_overriding_cli() {
+        let config = parse_config(
+            r#"
+host = "gpu-box"
+no_perms = true
+max_size = "50M"
+exclude = ["*.ckpt", "wandb/"]
+rsync_args = ["--bwlimit=5000"]
+"#,
+        )
+        .expect("parse config");
+
+        let mut args = Args {
+            host: Some("cli-host".to_string()),
+            ..Default::default()
+        };
+        config.apply(&mut args);
+        assert_eq!(args.host.as_deref(), Some("cli-host"));
+        assert_eq!(args.max_size.as_deref(), Some("50M"));
+        assert!(args.no_perms);
+
+        let rsync_args = base_rsync_args(&args, false);
+        assert!(rsync_args.iter().any(|a| a == "--max-size=50M"));
+        assert!(rsync_args.iter().any(|a| a == "--exclude=*.ckpt"));
+        assert!(rsync_args.iter().any(|a| a == "--exclude=wandb/"));
+        assert!(rsync_args.iter().any(|a| a == "--bwlimit=5000"));
+
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert_eq!(args.host.as_deref(), Some("gpu-box"));
+
+        assert!(parse_config("no_prems = true").is_err());
+    }
+
+    #[test]
+    fn project_file_is_found_from_subdirectories() {
+        let root = std::env::temp_dir().join(format!("syncz-project-{}", std::process::id()));
+        let nested = root.join("src").join("bin");
+        fs::create_dir_all(&nested).expect("create dirs");
+        fs::write(
+            root.join(PROJECT_FILE),
+            "host = \"build-box\"\nremote_path = \"/srv/app/\"\nexclude = [\"data/\"]\n",
+        )
+        .expect("write project file");
+
+        let project = find_project(&nested).expect("find").expect("project");
+        assert_eq!(project.root, root);
+        let home = Path::new("/home/user");
+        assert_eq!(project.remote_path(&nested, home), "/srv/app/src/bin");
+        assert_eq!(project.remote_path(&root, home), "/srv/app/");
+
+        let mut args = Args::default();
+        project.config.apply(&mut args);
+        assert_eq!(args.host.as_deref(), Some("build-box"));
+        assert_eq!(args.exclude, vec!["data/"]);
+
+        fs::remove_dir_all(&root).expect("cleanup");
+    }
+
+    #[test]
+    fn cli_includes_precede_default_excludes() {
+        let args = Args {
+            exclude: vec!["__pycache__/".to_string(), "*.ckpt".to_string()],
+            include: vec!["build/".to_string()],
+            ..Default::default()
+        };
+        let rsync_args = base_rsync_args(&args, true);
+        let position = |flag: &str| rsync_args.iter().position(|a| a == flag).unwrap();
+        assert!(position("--include=build/") < position("--exclude=build/"));
+        assert!(position("--exclude=*.ckpt") > position("--exclude=build/"));
+        assert!(rsync_args.iter().any(|a| a == "--exclude=__pycache__/"));
+    }
+
+    #[test]
+    fn gitignore_translates_negation_and_anchoring() {
+        let rules = translate_gitignore("# build output\n*.log\n!keep.log\n/out/\ndocs/tmp\n", "");
+        assert_eq!(
+            rules,
+            vec!["- /docs/tmp", "- /out/", "+ keep.log", "- *.log"]
+        );
+
+        let nested = translate_gitignore("cache/\n**/scratch\n/local.env\n", "pkg/sub");
+        assert_eq!(
+            nested,
+            vec![
+                "- /pkg/sub/local.env",
+                "- /pkg/sub/scratch",
+                "- /pkg/sub/**/scratch",
+                "- /pkg/sub/cache/",
+                "- /pkg/sub/**/cache/",
+            ]
+        );
+    }
+
+    #[test]
+    fn gitignore_filters_put_nested_files_first() {
+        let root = std::env::temp_dir().join(format!("syncz-gitignore-{}", std::process::id()));
+        fs::create_dir_all(root.join("app").join("node_modules").join("dep")).expect("dirs");
+        fs::write(root.join(".gitignore"), "*.env\n").expect("root ignore");
+        fs::write(root.join("app").join(".gitignore"), "!prod.env\n").expect("app ignore");
+        fs::write(
+            root.join("app")
+                .join("node_modules")
+                .join("dep")
+                .join(".gitignore"),
+            "*\n",
+        )
+        .expect("dep ignore");
+
+        let filters = gitignore_filters(&root, false).expect("filters");
+        assert_eq!(
+            filters,
+            vec![
+                "--filter=+ /app/prod.env",
+                "--filter=+ /app/**/prod.env",
+                "--filter=- *.env",
+            ]
+        );
+
+        fs::remove_dir_all(&root).expect("cleanup");
+    }
+
+    #[test]
+    fn path_excluded_matches_excludes() {
+        let args = Args {
+            exclude: vec!["*.ckpt".to_string()],
+            include: vec!["build/".to_string()],
+            ..Default::default()
+        };
+        assert!(path_excluded(Path::new("node_modules/pkg/index.js"), &args));
+        assert!(path_excluded(Path::new("src/.main.rs.swp"), &args));
+        assert!(path_excluded(Path::new("runs/model.ckpt"), &args));
+        assert!(path_excluded(Path::new(".git/index"), &args));
+        assert!(!path_excluded(Path::new("build/out.txt"), &args));
+        assert!(!path_excluded(Path::new("src/main.rs"), &args));
+        // A file literally named `target` is not the `target/` directory.
+        assert!(!path_excluded(Path::new("docs/target"), &args));
+
+        let all = Args {
+            all: true,
+            ..Default::default()
+        };
+        assert!(!path_excluded(Path::new("node_modules/pkg/index.js"), &all));
+    }
+
+    #[test]
+    fn glob_match_handles_wildcards() {
+        assert!(glob_match("*.pyc", "mod.pyc"));
+        assert!(glob_match("*~", "notes.txt~"));
+        assert!(glob_match("file?.rs", "file1.rs"));
+        assert!(glob_match("a*b*c", "aXXbYc"));
+        assert!(!glob_match("*.pyc", "mod.py"));
+        assert!(!glob_match("file?.rs", "file10.rs"));
+    }
+
+    #[test]
+    fn mirror_dry_run_lists_deletions() {
+        let args = Args {
+            push: true,
+            delete: true,
+            ..Default::default()
+        };
+        let local_path = Path::new("/home/user/projects/app");
+        let remote_path = "~/projects/app";
+        let (src, dst) = sync_endpoints("example", local_path, remote_path, false, false);
+
+        let mut cmd_args = base_rsync_args(&args, true);
+        assert!(cmd_args.iter().any(|a| a == "--delete"));
+        cmd_args.push("--dry-run".to_string());
+        cmd_args.push("--itemize-changes".to_string());
+        cmd_args.push("--out-format=%i|%n|%l".to_string());
+        cmd_args.push(src);
+        cmd_args.push(dst);
+
+        let stdout = b"*deleting  |stale.log|0\nf+++++++++|new.txt|3\n*deleting   old/\n";
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "rsync".to_string(),
+            args: cmd_args,
+            output: Some(Output {
+                status: ok_status(),
+                stdout: stdout.to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+
+        let summary = run_dry_run(
+            &runner,
+            "example",
+            local_path,
+            remote_path,
+            false,
+            &args,
+            false,
+            None,
+        )
+        .unwrap();
+        assert_eq!(summary.deletions, vec!["stale.log", "old/"]);
+    }
+
+    #[test]
+    fn delete_requires_a_direction() {
+        let parse = |argv: &[&str]| Cli::try_parse_from(argv).expect("parse").args;
+        assert!(parse(&["syncz", "--delete"]).validate().is_err());
+        assert!(parse(&["syncz", "--push", "--delete"]).validate().is_ok());
+    }
+
+    #[test]
+    fn both_flags_only_paths_changed_on_both_sides() {
+        let map = |entries: &[(&str, &str)]| -> BTreeMap<String, String> {
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect()
+        };
+        let mut previous = SyncState::new();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            previous.insert(name.to_string(), ("1:100".to_string(), "1:day".to_string()));
+        }
+        let local = map(&[
+            ("a.txt", "2:200"),
+            ("b.txt", "2:200"),
+            ("c.txt", "1:100"),
+            ("new.txt", "5:1"),
+        ]);
+        let remote = map(&[
+            ("a.txt", "3:later"),
+            ("b.txt", "1:day"),
+            ("c.txt", "4:later"),
+            ("new.txt", "6:x"),
+        ]);
+
+        let conflicts = conflict_candidates(Some(&previous), &local, &remote);
+        assert_eq!(conflicts, vec!["a.txt", "new.txt"]);
+        assert!(conflict_candidates(None, &local, &remote).is_empty());
+    }
+
+    #[test]
+    fn list_only_output_is_parsed_into_fingerprints() {
+        let output = "\
+drwxr-xr-x          4,096 2024/06/01 12:00:00 .
+-rw-r--r--          1,234 2024/06/01 12:00:00 src/main.rs
+lrwxrwxrwx             11 2024/06/01 12:00:00 link -> target
+-rw-r--r--             12 2024/06/02 08:30:15 notes with spaces.txt
+
+Number of files: 3
+";
+        let parsed = parse_list_only(output);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed["src/main.rs"], "1234:2024/06/01 12:00:00");
+        assert_eq!(parsed["notes with spaces.txt"], "12:2024/06/02 08:30:15");
+    }
+
+    #[test]
+    fn extra_hosts_parse_from_positionals_and_flag() {
+        let args = Cli::try_parse_from(["syncz", ".", "gpu1", "gpu2", "--hosts", "gpu3,gpu4"])
+            .expect("parse")
+            .args;
+        assert_eq!(args.host.as_deref(), Some("gpu1"));
+        assert_eq!(args.extra_hosts, vec!["gpu2"]);
+        assert_eq!(args.hosts, vec!["gpu3", "gpu4"]);
+    }
+
+    #[test]
+    fn profiles_compose_with_cli_and_global_config() {
+        let config = parse_config(
+            r#"
+exclude = [".venv/"]
+no_perms = true
+
+[profiles.ml]
+host = "gpu-box"
+remote_path = "/scratch/me/app"
+exclude = ["wandb/", "checkpoints/"]
+rsync_args = ["--bwlimit=20000"]
+"#,
+        )
+        .expect("parse config");
+
+        let mut args = Args {
+            exclude: vec!["*.ckpt".to_string()],
+            ..Default::default()
+        };
+        config.profile("ml").expect("profile").apply(&mut args);
+        config.apply(&mut args);
+        assert_eq!(args.host.as_deref(), Some("gpu-box"));
+        assert_eq!(args.remote_path.as_deref(), Some("/scratch/me/app"));
+        assert_eq!(
+            args.exclude,
+            vec!["*.ckpt", "wandb/", "checkpoints/", ".venv/"]
+        );
+        assert_eq!(args.rsync_args, vec!["--bwlimit=20000"]);
+        assert!(args.no_perms);
+
+        let err = config.profile("nope").unwrap_err().to_string();
+        assert!(err.contains("available: ml"));
+        assert!(parse_config("remote_path = \"/srv\"").is_err());
+    }
+
+    #[test]
+    fn stats_and_dry_run_changes_parse_for_json() {
+        let output = "Number of files: 1,234 (reg: 1,000, dir: 234)\n\
+                      Number of created files: 5 (reg: 5)\n\
+                      Number of deleted files: 0\n\
+                      Number of regular files transferred: 12\n\
+                      Total file size: 98,765 bytes\n\
+                      Literal data: 1,000 bytes\n\
+                      Matched data: 234 bytes\n\
+                      File list size: 0\n\
+                      sent 1,234 bytes  received 56 bytes  2,580.00 bytes/sec\n\
+                      total size is 98,765  speedup is 76.56";
+        let stats: Vec<String> = output
+            .lines()
+            .filter(|line| is_stats_line(line))
+            .map(str::to_string)
+            .collect();
+        assert_eq!(stats.len(), 10);
+        assert!(!is_stats_line(">f+++++++++|Number of files.txt|12"));
+        assert_eq!(
+            parse_stats(&stats),
+            TransferStats {
+                sent_bytes: Some(1234),
+                received_bytes: Some(56),
+                total_size: Some(98765),
+                speedup: Some(76.56),
+                files: Some(1234),
+                files_created: Some(5),
+                files_deleted: Some(0),
+                files_transferred: Some(12),
+                literal_bytes: Some(1000),
+                matched_bytes: Some(234),
+            }
+        );
+        assert_eq!(
+            format_summary(&stats, Duration::from_millis(1500)),
+            "Summary:\n  \
+             files:      1,234 total, 12 transferred, 5 created, 0 deleted\n  \
+             data:       1000 B literal, 234 B matched\n  \
+             sent:       1.21 KB\n  \
+             received:   56 B\n  \
+             total size: 96.45 KB\n  \
+             speedup:    76.56\n  \
+             duration:   1.50s"
+        );
+
+        let changes = parse_changes(
+            ".d..t......|./|0\n>f+++++++++|src/main.rs|1,024\n*deleting  |old.txt|0\n",
+        );
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].path, "src/main.rs");
+        assert_eq!(changes[0].code, ">f+++++++++");
+        assert_eq!(changes[0].size, Some(1024));
+        assert!(changes[1].is_deletion());
+
+        assert_eq!(
+            parse_transferred_bytes("Total transferred file size: 36 bytes"),
+            Some(36)
+        );
+    }
+
+    #[test]
+    fn subcommands_and_bare_form_parse() {
+        let cli = Cli::try_parse_from(["syncz", "push", "src", "gpu1", "--delete"]).expect("parse");
+        let Some(Commands::Push(args)) = cli.command else {
+            panic!("expected push subcommand");
+        };
+        assert_eq!(args.path.as_deref(), Some("src"));
+        assert_eq!(args.host.as_deref(), Some("gpu1"));
+        assert!(args.delete);
+
+        let cli = Cli::try_parse_from(["syncz", ".", "gpu1", "--pull"]).expect("parse");
+        assert!(cli.command.is_none());
+        assert!(cli.args.pull);
+
+        let pull_with_push = Args {
+            pull: true,
+            push: true,
+            ..Default::default()
+        };
+        assert!(pull_with_push.validate().is_err());
+        let pull_with_since = Args {
+            pull: true,
+            since: Some("HEAD".to_string()),
+            ..Default::default()
+        };
+        assert!(pull_with_since.validate().is_err());
+    }
+
+    #[test]
+    fn status_report_splits_new_and_differing_files() {
+        let outgoing = parse_changes(
+            ">f+++++++++|new.rs|120\n>f.st......|shared.rs|2,048\ncd+++++++++|dir/|0\n",
+        );
+        let incoming = parse_changes(">f+++++++++|remote.log|9\n>f..t......|other.rs|10\n");
+        let report = StatusReport::from_changes(&outgoing, &incoming);
+        assert_eq!(report.local_only, vec![("new.rs".to_string(), Some(120))]);
+        assert_eq!(
+            report.remote_only,
+            vec![("remote.log".to_string(), Some(9))]
+        );
+        assert_eq!(
+            report.differ,
+            vec![
+                ("other.rs".to_string(), Some(10)),
+                ("shared.rs".to_string(), Some(2048)),
+            ]
+        );
+        assert!(StatusReport::from_changes(&[], &[]).is_clean());
+    }
+
+    #[test]
+    fn non_interactive_needs_a_host_for_this_directory() {
+        let mut recent = RecentHosts::new();
+        remember_host(&mut recent, Path::new("/home/me/site"), "web");
+        let args = Args {
+            non_interactive: true,
+            json: true,
+            ..Default::default()
+        };
+        let err = remembered_host(&recent, Path::new("/home/me/app"), &args).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("none used before for /home/me/app"));
+
+        remember_host(&mut recent, Path::new("/home/me/app"), "gpu");
+        assert_eq!(
+            remembered_host(&recent, Path::new("/home/me/app/src"), &args).unwrap(),
+            Some("gpu".to_string())
+        );
+        let interactive = Args {
+            json: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            remembered_host(&recent, Path::new("/srv"), &interactive).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn which_maps_a_path_without_prompting() {
+        let cli = Cli::try_parse_from(["syncz", "which", "/home/me/app", "gpu"]).unwrap();
+        let Some(Commands::Which(args)) = cli.command else {
+            panic!("expected the which command");
+        };
+        let args = which_args(args);
+        assert!(args.which && args.non_interactive);
+        assert_eq!(args.path.as_deref(), Some("/home/me/app"));
+        assert_eq!(args.host.as_deref(), Some("gpu"));
+
+        let local = Path::new("/home/me/app");
+        assert_eq!(
+            which_endpoints("gpu", local, "~/app"),
+            [
+                ("/home/me/app/".to_string(), "gpu:~/app/".to_string()),
+                ("gpu:~/app/".to_string(), "/home/me/app/".to_string()),
+            ]
+        );
+        assert_eq!(
+            which_event("gpu", local, "~/app"),
+            json!({
+                "event": "which",
+                "host": "gpu",
+                "local_path": "/home/me/app",
+                "remote_path": "~/app",
+                "push": { "source": "/home/me/app/", "destination": "gpu:~/app/" },
+                "pull": { "source": "gpu:~/app/", "destination": "/home/me/app/" },
+            })
+        );
+    }
+
+    #[test]
+    fn recent_hosts_are_keyed_by_directory() {
+        let mut recent = RecentHosts::new();
+        remember_host(&mut recent, Path::new("/home/me/app"), "gpu1");
+        remember_host(&mut recent, Path::new("/home/me/site"), "web");
+        remember_host(&mut recent, Path::new("/home/me/app"), "gpu2");
+
+        assert_eq!(recent.len(), 2);
+        assert_eq!(recent[0].1, "gpu2");
+        assert_eq!(
+            recent_host(&recent, Path::new("/home/me/app")),
+            Some("gpu2")
+        );
+        assert_eq!(
+            recent_host(&recent, Path::new("/home/me/app/src")),
+            Some("gpu2")
+        );
+        assert_eq!(
+            recent_host(&recent, Path::new("/home/me/site")),
+            Some("web")
+        );
+        assert_eq!(recent_host(&recent, Path::new("/home/me")), None);
+
+        let dir = std::env::temp_dir().join(format!("syncz-recent-{}", std::process::id()));
+        let path = dir.join("recent_hosts.tsv");
+        save_recent_hosts(&path, &recent).expect("save");
+        assert_eq!(load_recent_hosts(&path).expect("load"), recent);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn remote_path_override_and_mapping_rules() {
+        let home = Path::new("/home/me");
+        assert_eq!(
+            normalize_remote_path("/home/me/srv/app/", home),
+            "~/srv/app"
+        );
+        assert_eq!(normalize_remote_path("/home/me", home), "~");
+        assert_eq!(normalize_remote_path("/srv/app", home), "/srv/app");
+        assert_eq!(normalize_remote_path("/", home), "/");
+
+        let config = parse_config(
+            r#"
+[remote_paths]
+"~/src" = "/srv/src"
+"~/src/app" = "/srv/app"
+"/opt/data" = "~/data"
+"#,
+        )
+        .expect("config");
+        let map = |local: &str| mapped_remote_path(&config.remote_paths, Path::new(local), home);
+        assert_eq!(map("/home/me/src/app/web").as_deref(), Some("/srv/app/web"));
+        assert_eq!(map("/home/me/src/app").as_deref(), Some("/srv/app"));
+        assert_eq!(map("/home/me/src/lib").as_deref(), Some("/srv/src/lib"));
+        assert_eq!(map("/opt/data/x").as_deref(), Some("~/data/x"));
+        assert_eq!(map("/home/me/notes"), None);
+
+        assert!(parse_config("[profiles.p.remote_paths]\n\"~/a\" = \"/b\"\n").is_err());
+    }
+
+    #[test]
+    fn host_mapping_rules_expand_the_remote_user() {
+        let home = Path::new("/home/me");
+        let config = parse_config(
+            r#"
+[remote_paths]
+"~/work" = "~/work"
+
+[hosts.cluster.remote_paths]
+"~/work" = "/data/$USER/work"
+"#,
+        )
+        .expect("config");
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec!["-G".to_string(), "cluster".to_string()],
+            output: Some(Output {
+                status: ok_status(),
+                stdout: b"hostname 10.0.0.5\nuser jdoe\nport 22\n".to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        let local = Path::new("/home/me/work/exp");
+        let args = Args::default();
+        let resolve = |host| resolve_remote_path(&runner, host, local, home, &args, None, &config);
+        assert_eq!(resolve("cluster").expect("cluster"), "/data/jdoe/work/exp");
+        assert_eq!(resolve("laptop").expect("laptop"), "~/work/exp");
+
+        let explicit = Args {
+            remote_path: Some("/srv/exp".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            resolve_remote_path(&runner, "cluster", local, home, &explicit, None, &config)
+                .expect("explicit"),
+            "/srv/exp"
+        );
+    }
+
+    #[test]
+    fn bwlimit_comes_from_cli_or_host_config() {
+        let config = parse_config("[hosts.home]\nbwlimit = \"2M\"\n").expect("config");
+        let mut args = Args::default();
+        config.hosts["home"].apply(&mut args);
+        assert!(base_rsync_args(&args, false).contains(&"--bwlimit=2M".to_string()));
+
+        let mut args = Args {
+            bwlimit: Some("500".to_string()),
+            ..Default::default()
+        };
+        config.hosts["home"].apply(&mut args);
+        assert!(base_rsync_args(&args, true).contains(&"--bwlimit=500".to_string()));
+        assert!(!base_rsync_args(&Args::default(), false)
+            .iter()
+            .any(|arg| arg.starts_with("--bwlimit")));
+    }
+
+    #[test]
+    fn hooks_wrap_the_sync_and_honour_on_hook_error() {
+        let local = std::env::temp_dir();
+        let pre = format!("cd {} && make", shell_escape(&local.to_string_lossy()));
+        let mut post = ssh_args(&Args::default());
+        post.push("example".to_string());
+        post.push(format!("cd {} && ./restart", remote_shell_path("~/app")));
+        let failed = std::process::ExitStatus::from_raw(1 << 8);
+
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "sh".to_string(),
+                args: vec!["-c".to_string(), pre.clone()],
+                output: None,
+                status: Some(ok_status()),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: post.clone(),
+                output: None,
+                status: Some(failed),
+            },
+            ExpectedCall {
+                program: "sh".to_string(),
+                args: vec!["-c".to_string(), pre],
+                output: None,
+                status: Some(failed),
+            },
+        ]);
+        let mut args = Args {
+            pre_cmd: Some("make".to_string()),
+            post_cmd: Some("./restart".to_string()),
+            on_hook_error: Some(OnHookError::Warn),
+            ..Default::default()
+        };
+        let mut synced = 0;
+        with_hooks(&runner, "example", &local, "~/app", &args, || {
+            synced += 1;
+            Ok(())
+        })
+        .expect("warn keeps going");
+
+        args.on_hook_error = None;
+        let result = with_hooks(&runner, "example", &local, "~/app", &args, || {
+            synced += 1;
+            Ok(())
+        });
+        assert!(result.is_err());
+        assert_eq!(synced, 1);
+    }
+
+    #[test]
+    fn exec_runs_the_command_in_the_mapped_directory() {
+        let cli = Cli::try_parse_from([
+            "syncz", "exec", ".", "gpu1", "--", "python", "train.py", "--lr", "0.1",
+        ])
+        .expect("parse");
+        let Some(Commands::Exec(exec)) = cli.command else {
+            panic!("expected exec subcommand");
+        };
+        assert_eq!(exec.args.host.as_deref(), Some("gpu1"));
+        assert_eq!(exec.command, vec!["python", "train.py", "--lr", "0.1"]);
+
+        let args = Args {
+            exec: exec.command,
+            ..Default::default()
+        };
+        let mut expected = ssh_args(&args);
+        expected.push("-t".to_string());
+        expected.push("gpu1".to_string());
+        expected.push(format!(
+            "cd {} && python train.py --lr 0.1",
+            remote_shell_path("~/proj")
+        ));
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+        exec_remote(
+            &runner,
+            "gpu1",
+            &std::env::temp_dir(),
+            "~/proj",
+            &args,
+            true,
+        )
+        .expect("exec");
+    }
+
+    #[test]
+    fn shell_opens_a_login_shell_in_the_remote_directory() {
+        let cli = Cli::try_parse_from(["syncz", "shell", ".", "gpu1"]).expect("parse");
+        assert!(matches!(cli.command, Some(Commands::Shell(_))));
+
+        let mut expected = ssh_args(&Args::default());
+        expected.push("-t".to_string());
+        expected.push("gpu1".to_string());
+        expected.push(format!(
+            "cd {} || echo 'syncz: staying in the home directory' >&2; exec \"$SHELL\" -l",
+            remote_shell_path("~/proj")
+        ));
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+        open_shell(
+            &runner,
+            "gpu1",
+            &std::env::temp_dir(),
+            "~/proj",
+            &Args::default(),
+        )
+        .expect("shell");
+    }
+
+    #[test]
+    fn checksum_applies_to_transfers_and_dry_runs() {
+        let config = parse_config("[hosts.nas]\nchecksum = true\n").expect("config");
+        let mut args = Args::default();
+        assert!(!base_rsync_args(&args, true).contains(&"--checksum".to_string()));
+        config.hosts["nas"].apply(&mut args);
+        assert!(base_rsync_args(&args, true).contains(&"--checksum".to_string()));
+        assert!(base_rsync_args(&args, false).contains(&"--checksum".to_string()));
+    }
+
+    #[test]
+    fn byte_sizes_parse_with_suffixes() {
+        let parse = |s: &str| s.parse::<ByteSize>().map(|b| b.0).ok();
+        assert_eq!(parse("1024"), Some(1024));
+        assert_eq!(parse("10K"), Some(10 * 1024));
+        assert_eq!(parse("500M"), Some(500 << 20));
+        assert_eq!(parse("1.5G"), Some(3 << 29));
+        assert_eq!(parse("2GiB"), Some(2 << 30));
+        assert_eq!(parse("2gb"), Some(2 << 30));
+        assert_eq!(parse("big"), None);
+        assert_eq!(parse("5X"), None);
+
+        let config = parse_config("max_total_size = \"20G\"\n").expect("config");
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert_eq!(args.max_total_size, Some(ByteSize(20 << 30)));
+        assert!(parse_config("max_total_size = \"lots\"\n").is_err());
+    }
+
+    #[test]
+    fn selection_tree_expands_checked_directories() {
+        let changes = parse_changes(
+            ">f+++++++++|README.md|10\ncd+++++++++|src/|0\n>f.st......|src/a.rs|20\n>f+++++++++|src/util/b.rs|30\n*deleting  |gone.txt|0\n",
+        );
+        let items = selection_items(&changes);
+        let layout: Vec<(&str, usize)> = items
+            .iter()
+            .map(|item| (item.path.as_str(), item.depth))
+            .collect();
+        assert_eq!(
+            layout,
+            vec![
+                ("README.md", 0),
+                ("src/", 0),
+                ("src/a.rs", 1),
+                ("src/util/", 1),
+                ("src/util/b.rs", 2),
+            ]
+        );
+
+        assert_eq!(resolve_selection(&items, &[0]), vec!["README.md"]);
+        assert_eq!(
+            resolve_selection(&items, &[3]),
+            vec!["src/util/b.rs".to_string()]
+        );
+        assert_eq!(
+            resolve_selection(&items, &[0, 1]),
+            vec!["README.md", "src/a.rs", "src/util/b.rs"]
+        );
+    }
+
+    #[test]
+    fn interactive_conflicts_with_yes_and_dry_run() {
+        assert!(Cli::try_parse_from(["syncz", "-i", "--push"]).is_ok());
+        assert!(Cli::try_parse_from(["syncz", "-i", "-y"]).is_err());
+        assert!(Cli::try_parse_from(["syncz", "-i", "-d"]).is_err());
+
+        // Without any pre-transfer checks no dry run is spent; only the
+        // free space lookup runs (and here finds nothing to check).
+        let mut df_args = ssh_args(&Args::default());
+        df_args.push("example".to_string());
+        df_args.push(
+            "d=\"$HOME/app\"; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; df -Pk \"$d\""
+                .to_string(),
+        );
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: df_args,
+            output: Some(std::process::Output {
+                status: std::process::ExitStatus::from_raw(1 << 8),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        let proceed = preflight(
+            &runner,
+            "example",
+            Path::new("/tmp/app"),
+            "~/app",
+            false,
+            &Args::default(),
+            false,
+            None,
+        )
+        .expect("preflight");
+        assert!(proceed);
+    }
+
+    #[test]
+    fn non_interactive_declines_prompts_unless_yes() {
+        let args = Args {
+            non_interactive: true,
+            ..Default::default()
+        };
+        assert!(!confirm("Delete these files?", &args).expect("confirm"));
+        let args = Args {
+            non_interactive: true,
+            yes: true,
+            ..Default::default()
+        };
+        assert!(confirm("Delete these files?", &args).expect("confirm"));
+
+        let select = Args {
+            non_interactive: true,
+            select: true,
+            ..Default::default()
+        };
+        assert!(select.validate().is_err());
+    }
+
+    #[test]
+    fn parallel_chunks_balance_by_size() {
+        let changes = parse_changes(
+            "cd+++++++++|data/|0\n>f+++++++++|data/big.bin|900\n>f+++++++++|data/a.txt|300\n>f+++++++++|data/b.txt|300\n>f+++++++++|data/c.txt|200\n*deleting  |old|0\n",
+        );
+        let chunks = split_into_chunks(&changes, 2);
+        assert_eq!(
+            chunks,
+            vec![
+                vec!["data/".to_string(), "data/big.bin".to_string()],
+                vec![
+                    "data/a.txt".to_string(),
+                    "data/b.txt".to_string(),
+                    "data/c.txt".to_string(),
+                ],
+            ]
+        );
+        assert_eq!(split_into_chunks(&changes, 10).len(), 4);
+        assert!(split_into_chunks(&[], 4).is_empty());
+    }
+
+    #[test]
+    fn library_session_maps_options_and_reports() {
+        let options = SyncOptions {
+            checksum: true,
+            delete: true,
+            exclude: vec!["*.ckpt".to_string()],
+            ..Default::default()
+        };
+        let session = SyncSession::new("/tmp/app", Endpoint::new("gpu1", "/srv/app"), options)
+            .expect("session");
+        let args = session.args(Direction::Pull).expect("args");
+        assert!(args.pull && !args.push);
+        assert!(args.yes && args.non_interactive);
+        let rsync = base_rsync_args(&args, true);
+        assert!(rsync.contains(&"--checksum".to_string()));
+        assert!(rsync.contains(&"--delete".to_string()));
+        assert!(rsync.contains(&"--exclude=*.ckpt".to_string()));
+
+        let summary = DryRunSummary {
+            transferred_line: None,
+            transferred_bytes: Some(12),
+            deletions: vec!["old.txt".to_string()],
+            changes: parse_changes(">f+++++++++|new.txt|12\n*deleting  |old.txt|0\n"),
+            renames: Vec::new(),
+        };
+        let report = DryRunReport::from(summary);
+        assert_eq!(
+            report.files,
+            vec![FileChange {
+                path: "new.txt".to_string(),
+                change: ">f+++++++++".to_string(),
+                size: Some(12),
+            }]
+        );
+        assert_eq!(report.deletions, vec!["old.txt"]);
+        assert!(report.tree.contains("new.txt"));
+    }
+
+    #[test]
+    fn only_connection_failures_are_retried() {
+        for code in [10, 12, 30, 35, 255] {
+            assert!(
+                RsyncError::new(Some(code), "", false).is_retryable(),
+                "{}",
+                code
+            );
+        }
+        for code in [1, 2, 3, 11, 23] {
+            assert!(
+                !RsyncError::new(Some(code), "", false).is_retryable(),
+                "{}",
+                code
+            );
+        }
+        assert!(!RsyncError::new(None, "", false).is_retryable());
+    }
+
+    #[test]
+    fn retry_delay_doubles_up_to_a_cap() {
+        assert_eq!(retry_delay(2, 0), Duration::from_secs(2));
+        assert_eq!(retry_delay(2, 1), Duration::from_secs(4));
+        assert_eq!(retry_delay(2, 3), Duration::from_secs(16));
+        assert_eq!(
+            retry_delay(2, 40),
+            Duration::from_secs(MAX_RETRY_DELAY_SECS)
+        );
+        assert_eq!(retry_delay(0, 5), Duration::ZERO);
+    }
+
+    #[test]
+    fn retries_come_from_cli_or_config() {
+        let config = parse_config("retries = 3\nretry_delay = 10\n").expect("config");
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert_eq!((args.retries, args.retry_delay), (Some(3), Some(10)));
+
+        let mut args = Args {
+            retries: Some(1),
+            ..Default::default()
+        };
+        config.apply(&mut args);
+        assert_eq!(args.retries, Some(1));
+    }
+
+    #[test]
+    fn rsync_errors_explain_the_exit_code() {
+        let stderr = "rsync: link_stat \"/home/me/gone\" failed: No such file or directory (2)\n\
+                      rsync error: some files/attrs were not transferred (see previous errors) (code 23) at main.c(1338)\n";
+        let err = RsyncError::new(Some(23), stderr, false);
+        assert_eq!(
+            err.to_string(),
+            "rsync failed: partial transfer: some files could not be transferred (exit code 23): \
+             rsync: link_stat \"/home/me/gone\" failed: No such file or directory (2)"
+        );
+        assert_eq!(err.exit_code(), 23);
+
+        let err = RsyncError::new(
+            Some(255),
+            "ssh: connect to host box port 22: Connection refused\n",
+            true,
+        );
+        assert!(err
+            .to_string()
+            .starts_with("rsync dry run failed: ssh connection failed"));
+        assert!(err.to_string().ends_with("Connection refused"));
+
+        assert_eq!(RsyncError::new(None, "", false).exit_code(), 1);
+    }
+
+    #[test]
+    fn exit_code_passes_rsync_codes_through_context() {
+        let err = anyhow::Error::new(RsyncError::new(Some(24), "", false))
+            .context("2 of 2 parallel transfers failed");
+        assert_eq!(exit_code(&err), 24);
+        assert_eq!(exit_code(&anyhow!("no host given")), 1);
+    }
+
+    #[test]
+    fn failed_dry_run_reports_rsync_exit_code() {
+        let args = Args::default();
+        let (src, dst) = sync_endpoints("box", Path::new("/home/me/app"), "~/app", false, false);
+        let mut cmd_args = base_rsync_args(&args, true);
+        cmd_args.extend([
+            "--dry-run".to_string(),
+            "--itemize-changes".to_string(),
+            "--out-format=%i|%n|%l".to_string(),
+            src,
+            dst,
+        ]);
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "rsync".to_string(),
+            args: cmd_args,
+            output: Some(std::process::Output {
+                status: std::process::ExitStatus::from_raw(12 << 8),
+                stdout: Vec::new(),
+                stderr:
+                    b"rsync: connection unexpectedly closed (0 bytes received so far) [sender]\n"
+                        .to_vec(),
+            }),
+            status: None,
+        }]);
+        let err = run_dry_run(
+            &runner,
+            "box",
+            Path::new("/home/me/app"),
+            "~/app",
+            false,
+            &args,
+            false,
+            None,
+        )
+        .err()
+        .expect("dry run should fail");
+        assert_eq!(exit_code(&err), 12);
+        assert!(err.to_string().contains("connection unexpectedly closed"));
+    }
+
+    #[test]
+    fn command_line_quotes_only_what_needs_it() {
+        let args: Vec<String> = [
+            "-avzu",
+            "--out-format=%i|%n",
+            "-e",
+            "ssh -o ControlPersist=60",
+            "/home/me/my app/",
+            "box:~/app/",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            command_line("rsync", &args),
+            "rsync -avzu '--out-format=%i|%n' -e 'ssh -o ControlPersist=60' '/home/me/my app/' box:~/app/"
+        );
+    }
+
+    fn history_entry(path: &str, host: &str, exit_code: i32) -> HistoryEntry {
+        HistoryEntry {
+            timestamp: "2024-05-01T10:00:00+02:00".to_string(),
+            direction: "push".to_string(),
+            hosts: vec![host.to_string()],
+            path: PathBuf::from(path),
+            remote_path: Some("~/app".to_string()),
+            bytes: 2048,
+            duration_ms: 1500,
+            exit_code,
+            error: None,
+            cwd: PathBuf::from(path),
+            argv: vec!["push".to_string(), host.to_string()],
+        }
+    }
+
+    #[test]
+    fn history_round_trips_and_skips_bad_lines() {
+        let dir = std::env::temp_dir().join(format!("syncz-history-{}", std::process::id()));
+        let path = dir.join("history.jsonl");
+        let first = history_entry("/home/me/app", "box", 0);
+        let second = history_entry("/home/me/lib", "gpu", 23);
+        append_history(&path, &first).expect("append");
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut f| writeln!(f, "not json"))
+            .expect("garbage");
+        append_history(&path, &second).expect("append");
+        assert_eq!(load_history(&path).expect("load"), vec![first, second]);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn history_filters_newest_first() {
+        let entries = vec![
+            history_entry("/home/me/app", "box", 0),
+            history_entry("/home/me/app/sub", "gpu", 23),
+            history_entry("/home/me/lib", "box", 0),
+        ];
+        let opts = HistoryArgs {
+            path: None,
+            host: None,
+            failed: false,
+            limit: 20,
+            json: false,
+        };
+        let all = filter_history(&entries, &opts, None);
+        assert_eq!(all[0].path, PathBuf::from("/home/me/lib"));
+
+        let under_app = filter_history(&entries, &opts, Some(Path::new("/home/me/app")));
+        assert_eq!(under_app.len(), 2);
+
+        let on_box = HistoryArgs {
+            host: Some("box".to_string()),
+            limit: 1,
+            ..opts
+        };
+        let shown = filter_history(&entries, &on_box, None);
+        assert_eq!(shown.len(), 1);
+        assert_eq!(shown[0].path, PathBuf::from("/home/me/lib"));
+
+        let failed = HistoryArgs {
+            host: None,
+            failed: true,
+            limit: 20,
+            ..on_box
+        };
+        assert_eq!(filter_history(&entries, &failed, None)[0].exit_code, 23);
+    }
+
+    #[test]
+    fn ssh_config_follows_includes() {
+        let home = std::env::temp_dir().join(format!("syncz-ssh-{}", std::process::id()));
+        let ssh_dir = home.join(".ssh");
+        fs::create_dir_all(ssh_dir.join("config.d")).expect("mkdir");
+        fs::create_dir_all(home.join("work")).expect("mkdir");
+        fs::write(
+            ssh_dir.join("config"),
+            "Include config.d/*.conf ~/work/ssh_config missing/*\nHost main\n  HostName main.example.com\n",
+        )
+        .expect("config");
+        fs::write(ssh_dir.join("config.d/b.conf"), "Host=lab-gpu3 lab-gpu4\n").expect("b");
+        fs::write(ssh_dir.join("config.d/a.conf"), "Host cluster *.internal\n").expect("a");
+        fs::write(ssh_dir.join("config.d/notes.txt"), "Host ignored\n").expect("txt");
+        fs::write(
+            home.join("work/ssh_config"),
+            "Include config.d/a.conf\nHost work\n",
+        )
+        .expect("work");
+
+        let mut options = Vec::new();
+        read_ssh_config(&ssh_dir.join("config"), &ssh_dir, &home, 0, &mut options).expect("read");
+        let hosts: Vec<&str> = options
+            .iter()
+            .filter(|(key, _)| key == "Host")
+            .map(|(_, value)| value.as_str())
+            .collect();
+        assert_eq!(
+            hosts,
+            vec![
+                "cluster *.internal",
+                "lab-gpu3 lab-gpu4",
+                "cluster *.internal",
+                "work",
+                "main"
+            ]
+        );
+        assert!(options.contains(&("HostName".to_string(), "main.example.com".to_string())));
+
+        fs::write(ssh_dir.join("loop"), "Include loop\n").expect("loop");
+        let mut options = Vec::new();
+        assert!(read_ssh_config(&ssh_dir.join("loop"), &ssh_dir, &home, 0, &mut options).is_err());
+        let _ = fs::remove_dir_all(&home);
+    }
+
+    #[test]
+    fn ssh_hosts_resolve_options_like_ssh() {
+        let config = "User everyone\n\
+                      Host lab-* !lab-admin\n  User researcher\n  Port 2222\n\
+                      Host lab-gpu3 lab-admin\n  HostName 10.0.0.3\n  User ignored\n\
+                      Match host foo\n  User matched\n\
+                      Host *\n  ForwardAgent yes\n";
+        let options: Vec<(String, String)> = config
+            .lines()
+            .filter_map(split_ssh_option)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let hosts = parse_ssh_hosts(&options);
+        let aliases: Vec<&str> = hosts.iter().map(|h| h.alias.as_str()).collect();
+        assert_eq!(aliases, vec!["lab-admin", "lab-gpu3"]);
+
+        let gpu = &hosts[1];
+        assert_eq!(gpu.hostname.as_deref(), Some("10.0.0.3"));
+        assert_eq!(gpu.user.as_deref(), Some("everyone"));
+        assert_eq!(gpu.port, Some(2222));
+        assert_eq!(
+            gpu.options.get("forwardagent").map(String::as_str),
+            Some("yes")
+        );
+
+        let admin = &hosts[0];
+        assert_eq!(admin.port, None);
+
+        assert!(ssh_pattern_matches("LAB-?PU*", "lab-gpu3"));
+        assert!(!ssh_pattern_matches("lab-?", "lab-gpu3"));
+    }
+
+    #[test]
+    fn host_rows_show_target_and_last_sync() {
+        let hosts = vec![
+            SshHost {
+                alias: "box".to_string(),
+                hostname: Some("box.example.com".to_string()),
+                user: Some("me".to_string()),
+                port: Some(2200),
+                ..Default::default()
+            },
+            SshHost {
+                alias: "lab-gpu3".to_string(),
+                hostname: Some("10.0.0.3".to_string()),
+                ..Default::default()
+            },
+        ];
+        let now = chrono::DateTime::parse_from_rfc3339("2024-05-03T12:00:00+00:00").unwrap();
+        let mut history = vec![history_entry("/home/me/app", "box", 0)];
+        history[0].timestamp = "2024-05-01T12:00:00+00:00".to_string();
+        let rows = format_host_rows(&hosts, &last_used_by_host(&history), now);
+        assert_eq!(
+            rows,
+            vec![
+                "box       me@box.example.com:2200  synced 2d ago",
+                "lab-gpu3  10.0.0.3",
+            ]
+        );
+    }
+
+    #[test]
+    fn partial_host_names_expand_to_one_alias() {
+        let aliases: Vec<String> = ["lab-gpu3", "lab-gpu4", "home-server", "hpc"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let matched = |host: &str| match_host_alias(host, &aliases);
+        assert_eq!(matched("gpu3").unwrap().as_deref(), Some("lab-gpu3"));
+        assert_eq!(matched("home").unwrap().as_deref(), Some("home-server"));
+        assert_eq!(matched("hsrv").unwrap().as_deref(), Some("home-server"));
+        assert_eq!(matched("hpc").unwrap(), None);
+        assert_eq!(matched("box.example.com").unwrap(), None);
+        assert_eq!(matched("zzz").unwrap(), None);
+
+        let err = matched("gpu").unwrap_err().to_string();
+        assert!(err.contains("lab-gpu3, lab-gpu4"), "{}", err);
+    }
+
+    #[test]
+    fn host_specs_may_carry_user_and_port() {
+        let split = |spec: &str| split_host_spec(spec).unwrap();
+        assert_eq!(split("box:2222"), ("box".to_string(), Some(2222)));
+        assert_eq!(split("me@box:22"), ("me@box".to_string(), Some(22)));
+        assert_eq!(split("me@box"), ("me@box".to_string(), None));
+        assert_eq!(split("fe80::1"), ("fe80::1".to_string(), None));
+        assert!(split_host_spec("box:99999").is_err());
+        assert_eq!(host_alias("me@box"), "box");
+        assert_eq!(host_alias("box"), "box");
+
+        let args = Args {
+            port: Some(2222),
+            no_multiplex: true,
+            ..Default::default()
+        };
+        assert_eq!(ssh_command(&args), "ssh -p 2222");
+        let args = Args {
+            port: Some(2222),
+            ..Default::default()
+        };
+        assert!(ssh_command(&args).starts_with("ssh -p 2222 -o ControlMaster=auto"));
+    }
+
+    #[test]
+    fn identity_and_ssh_opts_reach_ssh_and_rsync() {
+        let args = Args {
+            identity: Some(PathBuf::from("/keys/lab key")),
+            ssh_opts: vec![
+                "-o StrictHostKeyChecking=accept-new".to_string(),
+                "-o 'ProxyCommand=ssh -W %h:%p gw'".to_string(),
+            ],
+            no_multiplex: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            ssh_args(&args),
+            vec![
+                "-i",
+                "/keys/lab key",
+                "-o",
+                "StrictHostKeyChecking=accept-new",
+                "-o",
+                "ProxyCommand=ssh -W %h:%p gw",
+            ]
+        );
+        assert_eq!(
+            ssh_command(&args),
+            "ssh -i '/keys/lab key' -o StrictHostKeyChecking=accept-new -o 'ProxyCommand=ssh -W %h:%p gw'"
+        );
+
+        let config = parse_config("[hosts.lab]\nidentity = \"/keys/lab\"\nssh_opts = [\"-A\"]\n")
+            .expect("config");
+        let mut args = Args::default();
+        config.hosts["lab"].apply(&mut args);
+        assert_eq!(args.identity, Some(PathBuf::from("/keys/lab")));
+        assert_eq!(args.ssh_opts, vec!["-A"]);
+    }
+
+    #[test]
+    fn split_words_handles_quotes_and_escapes() {
+        assert_eq!(
+            split_words(r#"-o "A=b c" 'd "e"' f\ g  """#),
+            vec!["-o", "A=b c", "d \"e\"", "f g", ""]
+        );
+    }
+
+    #[test]
+    fn jump_host_applies_to_every_ssh_call() {
+        let config = parse_config("[hosts.gpu]\njump = \"me@gateway\"\n").expect("config");
+        let mut args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        config.hosts["gpu"].apply(&mut args);
+        assert_eq!(ssh_command(&args), "ssh -J me@gateway");
+        assert!(base_rsync_args(&args, true).contains(&"ssh -J me@gateway".to_string()));
+
+        let mut args = Args {
+            jump: Some("a,b:2222".to_string()),
+            ..Default::default()
+        };
+        config.hosts["gpu"].apply(&mut args);
+        assert_eq!(&ssh_args(&args)[..2], ["-J", "a,b:2222"]);
+    }
+
+    #[test]
+    fn remote_sudo_wraps_rsync_and_file_commands() {
+        let args = Args {
+            remote_sudo: true,
+            ..Default::default()
+        };
+        assert!(base_rsync_args(&args, true).contains(&"--rsync-path=sudo rsync".to_string()));
+        assert!(!base_rsync_args(&Args::default(), true)
+            .iter()
+            .any(|a| a.starts_with("--rsync-path")));
+
+        let mut expected = ssh_args(&args);
+        expected.push("box".to_string());
+        expected.push("sudo mkdir -p '/etc/app'".to_string());
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+        ensure_remote_parent(&runner, "box", "/etc/app", &args).expect("mkdir");
+
+        let mut expected = ssh_args(&args);
+        expected.push("box".to_string());
+        expected.push("sudo test -f '/etc/app.conf'".to_string());
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected,
+            output: None,
+            status: Some(ok_status()),
+        }]);
+        assert!(remote_is_file(&runner, "box", "/etc/app.conf", &args).expect("test -f"));
+    }
+
+    #[test]
+    fn rsync_path_comes_from_cli_or_host_config() {
+        let config =
+            parse_config("[hosts.nas]\nrsync_path = \"/opt/bin/rsync\"\n").expect("config");
+        let mut args = Args::default();
+        config.hosts["nas"].apply(&mut args);
+        assert!(base_rsync_args(&args, false).contains(&"--rsync-path=/opt/bin/rsync".to_string()));
+
+        args.remote_sudo = true;
+        assert_eq!(remote_rsync(&args).as_deref(), Some("sudo /opt/bin/rsync"));
+
+        let mut args = Args {
+            rsync_path: Some("/usr/local/bin/rsync".to_string()),
+            ..Default::default()
+        };
+        config.hosts["nas"].apply(&mut args);
+        assert_eq!(remote_rsync(&args).as_deref(), Some("/usr/local/bin/rsync"));
+    }
+
+    #[test]
+    fn check_remote_tells_unreachable_hosts_from_failed_logins() {
+        let args = Args::default();
+        let mut expected = preflight_ssh_args(&args);
+        expected.push("gpu".to_string());
+        expected.push("rsync --version".to_string());
+        let failure = |stderr: &str| ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected.clone(),
+            output: Some(Output {
+                status: std::process::ExitStatus::from_raw(255 << 8),
+                stdout: Vec::new(),
+                stderr: stderr.as_bytes().to_vec(),
+            }),
+            status: None,
+        };
+        let runner = FakeRunner::new(vec![
+            failure("ssh: connect to host 10.0.0.7 port 22: Connection refused\n"),
+            failure("ssh: connect to host 10.0.0.7 port 22: Connection timed out\n"),
+            failure(
+                "debug1: Offering public key\ndeploy@10.0.0.7: Permission denied (publickey).\n",
+            ),
+        ]);
+        let mut errors =
+            (0..3).map(|_| check_remote(&runner, "gpu", &args).unwrap_err().to_string());
+        assert_eq!(
+            errors.next().unwrap(),
+            "host unreachable: gpu: ssh: connect to host 10.0.0.7 port 22: Connection refused"
+        );
+        assert_eq!(
+            errors.next().unwrap(),
+            "host unreachable: gpu: ssh: connect to host 10.0.0.7 port 22: Connection timed out"
+        );
+        assert_eq!(
+            errors.next().unwrap(),
+            "authentication failed for gpu: deploy@10.0.0.7: Permission denied (publickey).; \
+             check --identity, your ssh agent and ~/.ssh/config"
+        );
+    }
+
+    #[test]
+    fn remote_rsync_check_reports_missing_binary() {
+        let args = Args {
+            rsync_path: Some("/opt/bin/rsync".to_string()),
+            ..Default::default()
+        };
+        let mut expected = ssh_args(&args);
+        expected.extend(["-o".to_string(), "ConnectTimeout=10".to_string()]);
+        expected.push("nas".to_string());
+        expected.push("/opt/bin/rsync --version".to_string());
+        let call = |code: i32, stdout: &[u8], stderr: &[u8]| ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected.clone(),
+            output: Some(Output {
+                status: std::process::ExitStatus::from_raw(code << 8),
+                stdout: stdout.to_vec(),
+                stderr: stderr.to_vec(),
+            }),
+            status: None,
+        };
+        let runner = FakeRunner::new(vec![
+            call(
+                0,
+                b"rsync  version 3.2.7  protocol version 31\nCopyright...\n",
+                b"",
+            ),
+            call(127, b"", b"sh: /opt/bin/rsync: not found\n"),
+            call(
+                255,
+                b"",
+                b"ssh: connect to host nas port 22: Connection refused\n",
+            ),
+        ]);
+        assert_eq!(check_remote_rsync(&runner, "nas", &args).unwrap(), "3.2.7");
+        let err = check_remote_rsync(&runner, "nas", &args)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("/opt/bin/rsync not found on nas"),
+            "{}",
+            err
+        );
+        let err = check_remote_rsync(&runner, "nas", &args)
+            .unwrap_err()
+            .to_string();
+        assert!(err.starts_with("host unreachable: nas:"), "{}", err);
+        assert!(err.ends_with("Connection refused"), "{}", err);
+
+        let err = ssh_failure("nas", "user@nas: Permission denied (publickey).\n").to_string();
+        assert!(err.starts_with("authentication failed for nas:"), "{}", err);
+        let err = ssh_failure(
+            "nas",
+            "ssh: Could not resolve hostname nas: Name or service not known\n",
+        );
+        assert!(err.to_string().starts_with("host unreachable"), "{}", err);
+        let err = ssh_failure(
+            "nas",
+            "kex_exchange_identification: read: Connection reset\n",
+        );
+        assert!(err.to_string().starts_with("could not connect"), "{}", err);
+
+        assert_eq!(
+            parse_rsync_version("openrsync: protocol version 29\nrsync version 2.6.9 compatible\n")
+                .as_deref(),
+            Some("2.6.9")
+        );
+        assert_eq!(parse_rsync_version("busybox\n"), None);
+    }
+
+    #[test]
+    fn sftp_backend_maps_paths_ports_and_patterns() {
+        assert_eq!(sftp::sftp_path("~"), ".");
+        assert_eq!(sftp::sftp_path("~/src/app"), "src/app");
+        assert_eq!(sftp::sftp_path("/srv/app"), "/srv/app");
+
+        let endpoint = sftp::parse_endpoint(
+            "user deploy\nhostname 10.0.0.7\nport 2222\n\
+             identityfile ~/.ssh/id_ed25519\nidentityfile ~/.ssh/id_rsa\n\
+             userknownhostsfile ~/.ssh/known_hosts ~/.ssh/known_hosts2\n\
+             proxycommand none\nproxyjump bastion,admin@gate:2200\nconnecttimeout none\n",
+        )
+        .expect("endpoint");
+        assert_eq!(
+            endpoint,
+            sftp::Endpoint {
+                hostname: "10.0.0.7".to_string(),
+                port: 2222,
+                user: "deploy".to_string(),
+                identity_files: vec!["~/.ssh/id_ed25519".to_string(), "~/.ssh/id_rsa".to_string()],
+                known_hosts: vec![
+                    "~/.ssh/known_hosts".to_string(),
+                    "~/.ssh/known_hosts2".to_string()
+                ],
+                proxy_jump: Some("bastion,admin@gate:2200".to_string()),
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            sftp::proxy_command(&endpoint).unwrap(),
+            [
+                "ssh",
+                "-W",
+                "10.0.0.7:2222",
+                "-J",
+                "bastion",
+                "ssh://admin@gate:2200"
+            ]
+        );
+        let endpoint = sftp::Endpoint {
+            proxy_command: Some("nc -X 5 -x proxy:1080 %h %p # 100%%".to_string()),
+            ..endpoint
+        };
+        assert_eq!(
+            sftp::proxy_command(&endpoint).unwrap(),
+            ["sh", "-c", "nc -X 5 -x proxy:1080 10.0.0.7 2222 # 100%"]
+        );
+        assert!(sftp::parse_endpoint("port 22\n").is_err());
+
+        let args = Args {
+            exclude: vec!["*.ckpt".to_string(), "data/".to_string()],
+            include: vec!["keep.ckpt".to_string()],
+            ..Default::default()
+        };
+        assert!(sftp::is_excluded("model.ckpt", false, &args));
+        assert!(!sftp::is_excluded("keep.ckpt", false, &args));
+        assert!(sftp::is_excluded("data", true, &args));
+        assert!(!sftp::is_excluded("data", false, &args));
+        assert!(sftp::is_excluded("node_modules", true, &args));
+        let all = Args {
+            all: true,
+            ..Default::default()
+        };
+        assert!(!sftp::is_excluded("node_modules", true, &all));
+    }
+
+    #[test]
+    fn sftp_push_plan_creates_directories_then_uploads() {
+        let dir = std::env::temp_dir().join(format!("syncz-sftp-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).expect("mkdir");
+        fs::create_dir_all(dir.join("node_modules")).expect("mkdir");
+        fs::write(dir.join("src/main.rs"), "fn main() {}").expect("write");
+        fs::write(dir.join("my file.txt"), "hi").expect("write");
+
+        let entries = sftp::local_entries(&dir, &Args::default()).expect("entries");
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["my file.txt", "src", "src/main.rs"]);
+
+        let plan = sftp::push_plan(&dir, "src/app", &entries);
+        let mkdir = |p: &str| sftp::Step::Mkdir(p.to_string());
+        let put = |from: &str, to: &str| sftp::Step::Put(dir.join(from), to.to_string());
+        assert_eq!(
+            plan,
+            vec![
+                mkdir("src"),
+                mkdir("src/app"),
+                put("my file.txt", "src/app/my file.txt"),
+                mkdir("src/app/src"),
+                put("src/main.rs", "src/app/src/main.rs"),
+            ]
+        );
+        let file = dir.join("my file.txt");
+        assert_eq!(
+            sftp::push_plan(&file, "/srv/notes.txt", &[]),
+            vec![
+                mkdir("/srv"),
+                sftp::Step::Put(file.clone(), "/srv/notes.txt".to_string())
+            ]
+        );
+        fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn sftp_backend_rejects_rsync_only_features() {
+        let args = Args {
+            backend: Some(Backend::Sftp),
+            delete: true,
+            ..Default::default()
+        };
+        let runner = FakeRunner::new(vec![]);
+        let err = check_remote(&runner, "nas", &args).unwrap_err().to_string();
+        assert_eq!(err, "--delete isn't supported with --backend sftp");
+
+        let config = parse_config("[hosts.nas]\nbackend = \"sftp\"\n").expect("config");
+        let mut args = Args::default();
+        config.hosts["nas"].apply(&mut args);
+        assert_eq!(args.backend, Some(Backend::Sftp));
+        let mut probe = preflight_ssh_args(&args);
+        probe.extend(["nas".to_string(), "true".to_string()]);
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: probe,
+            output: Some(Output {
+                status: ok_status(),
+                stdout: Vec::new(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        assert!(check_remote(&runner, "nas", &args).is_ok());
+    }
+
+    #[test]
+    fn delta_rebuilds_edited_files_from_matching_blocks() {
+        let rebuild = |old: &[u8], new: &[u8]| {
+            let signature = delta::decode_signature(&delta::signature(old).unwrap()).unwrap();
+            let mut frames = Vec::new();
+            let sent = delta::send_delta(&signature, new, &mut frames).unwrap();
+            assert_eq!(sent, frames.len() as u64);
+            let mut out = Vec::new();
+            let (received, size) = delta::receive_delta(old, &mut &frames[..], &mut out).unwrap();
+            assert_eq!((received, size), (sent, new.len() as u64));
+            assert_eq!(out, new);
+            frames
+        };
+
+        let old: Vec<u8> = (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let mut new = old.clone();
+        new.splice(5_000..5_010, b"inserted bytes".iter().copied());
+        new.truncate(18_000);
+        let frames = rebuild(&old, &new);
+        assert!(
+            frames.len() < new.len() / 4,
+            "delta too large: {}",
+            frames.len()
+        );
+        rebuild(&[], &new);
+        rebuild(&old, &[]);
+
+        // Frames are checked, and a bad one still leaves the stream at the
+        // next message.
+        let mut corrupt = frames.clone();
+        corrupt[20] ^= 0xff;
+        corrupt.extend_from_slice(b"next");
+        let mut input = &corrupt[..];
+        assert!(delta::receive_delta(&old[..], &mut input, &mut Vec::new()).is_err());
+        assert_eq!(input, b"next");
+        assert!(
+            delta::receive_delta(&old[..], &mut &frames[..frames.len() - 1], &mut Vec::new())
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn delta_streams_large_files_in_windows() {
+        let old: Vec<u8> = (0..delta::WINDOW as u32 + 300_000)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect();
+        let mut new = old.clone();
+        new.splice(100..100, b"shifted across the window".iter().copied());
+        let signature = delta::decode_signature(&delta::signature(&old[..]).unwrap()).unwrap();
+        assert_eq!(signature.len(), 2);
+        let mut frames = Vec::new();
+        delta::send_delta(&signature, &new[..], &mut frames).unwrap();
+        assert!(frames.len() < 64 << 10, "delta too large: {}", frames.len());
+        let mut out = Vec::new();
+        delta::receive_delta(&old[..], &mut &frames[..], &mut out).unwrap();
+        assert!(out == new);
+    }
+
+    #[test]
+    fn native_backend_pushes_and_pulls_through_a_server() {
+        let base = std::env::temp_dir().join(format!("syncz-native-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        let local = base.join("local");
+        let remote = base.join("remote");
+        fs::create_dir_all(local.join("src")).expect("mkdir");
+        fs::create_dir_all(local.join("node_modules")).expect("mkdir");
+        fs::write(local.join("src/main.rs"), "fn main() {}\n".repeat(500)).expect("write");
+        fs::write(local.join("node_modules/dep.js"), "x").expect("write");
+
+        let session = |local: &Path, push: bool| {
+            let (client_read, server_write) = std::io::pipe().expect("pipe");
+            let (server_read, client_write) = std::io::pipe().expect("pipe");
+            let root = remote.clone();
+            let server = std::thread::spawn(move || {
+                native::serve(&root, BufReader::new(server_read), server_write)
+            });
+            let mut conn = native::Connection::open(BufReader::new(client_read), client_write)
+                .expect("hello")
+                .expect("server started");
+            let stats = if push {
+                conn.push(local, &Args::default())
+            } else {
+                conn.pull(local, &Args::default())
+            };
+            drop(conn);
+            server.join().expect("server thread").expect("serve");
+            stats.expect("transfer")
+        };
+
+        let stats = session(&local, true);
+        assert_eq!(stats.files, 1);
+        assert!(remote.join("src/main.rs").is_file());
+        assert!(!remote.join("node_modules").exists());
+        assert_eq!(session(&local, true).files, 0);
+
+        fs::write(
+            remote.join("src/main.rs"),
+            "fn main() { run() }\n".repeat(500),
+        )
+        .expect("write");
+        fs::write(remote.join("notes.txt"), "hello").expect("write");
+        let stats = session(&local, false);
+        assert_eq!(stats.files, 2);
+        assert_eq!(
+            fs::read_to_string(local.join("src/main.rs")).unwrap(),
+            "fn main() { run() }\n".repeat(500)
+        );
+        assert_eq!(
+            fs::read_to_string(local.join("notes.txt")).unwrap(),
+            "hello"
+        );
+        fs::remove_dir_all(&base).expect("cleanup");
+    }
+
+    #[test]
+    fn bootstrap_streams_a_tar_of_the_file_list_into_the_remote_directory() {
+        let args = Args {
+            port: Some(2222),
+            no_multiplex: true,
+            remote_sudo: true,
+            ..Default::default()
+        };
+        let list = Path::new("/tmp/files.txt");
+        let (tar, ssh) =
+            bootstrap_commands("host", Path::new("/home/me/app"), "~/app", list, &args);
+        assert_eq!(
+            tar,
+            vec![
+                "-czf",
+                "-",
+                "-C",
+                "/home/me/app",
+                "--no-recursion",
+                "--null",
+                "-T",
+                "/tmp/files.txt"
+            ]
+        );
+        assert_eq!(
+            ssh,
+            vec![
+                "-p",
+                "2222",
+                "host",
+                "sudo mkdir -p \"$HOME/app\" && sudo tar -xzf - -C \"$HOME/app\""
+            ]
+        );
+
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec![
+                "-p".to_string(),
+                "2222".to_string(),
+                "host".to_string(),
+                "test ! -e \"$HOME/app\" || (out=$(ls -A \"$HOME/app\") && test -z \"$out\")"
+                    .to_string(),
+            ],
+            output: None,
+            status: Some(std::process::ExitStatus::from_raw(1 << 8)),
+        }]);
+        assert!(!remote_is_empty(&runner, "host", "~/app", &args).unwrap());
+    }
+
+    #[test]
+    fn remote_specs_need_a_path_after_the_host() {
+        assert_eq!(
+            remote_spec("gpu1:~/data"),
+            Some(("gpu1".to_string(), "~/data".to_string()))
+        );
+        assert_eq!(
+            remote_spec("me@nas:/srv/app"),
+            Some(("me@nas".to_string(), "/srv/app".to_string()))
+        );
+        assert_eq!(remote_spec("gpu1:2222"), None);
+        assert_eq!(remote_spec("gpu1"), None);
+        assert_eq!(remote_spec("./a:b"), None);
+        assert_eq!(remote_spec("gpu1:"), None);
+    }
+
+    #[test]
+    fn remote_to_remote_runs_rsync_on_the_source_host() {
+        let args = Args {
+            no_multiplex: true,
+            all: true,
+            ..Default::default()
+        };
+        let src = ("a".to_string(), "~/data".to_string());
+        let dst = ("b".to_string(), "/srv/data".to_string());
+        let mut rsync = base_rsync_args(&args, false);
+        let e = rsync.iter().position(|a| a == "-e").expect("-e");
+        rsync.drain(e..e + 2);
+        let mut remote = command_line("rsync", &rsync);
+        remote.push_str(" 'data/' 'b:/srv/data/'");
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec!["a".to_string(), "test -f \"$HOME/data\"".to_string()],
+                output: None,
+                status: Some(std::process::ExitStatus::from_raw(1 << 8)),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec!["b".to_string(), "mkdir -p '/srv'".to_string()],
+                output: None,
+                status: Some(ok_status()),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec!["-A".to_string(), "a".to_string(), remote],
+                output: None,
+                status: Some(ok_status()),
+            },
+        ]);
+        remote_to_remote(&runner, &src, &dst, &args).expect("sync");
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_snapshot_per_day_and_week() {
+        let names: Vec<String> = [
+            "2024-05-20T09:00",
+            "2024-05-27T09:00",
+            "2024-05-31T08:00",
+            "2024-05-31T18:00",
+            "2024-06-01T12:00",
+            "2024-06-02T12:00",
+            "2024-06-02T18:30",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            snapshots_to_prune(&names, Some(2), None),
+            vec![
+                "2024-05-20T09:00",
+                "2024-05-27T09:00",
+                "2024-05-31T08:00",
+                "2024-05-31T18:00",
+                "2024-06-02T12:00"
+            ]
+        );
+        // Weeks start on Monday: 05-27..06-02 is one week, 05-20 the one before.
+        assert_eq!(
+            snapshots_to_prune(&names, Some(1), Some(2)),
+            vec![
+                "2024-05-27T09:00",
+                "2024-05-31T08:00",
+                "2024-05-31T18:00",
+                "2024-06-01T12:00",
+                "2024-06-02T12:00"
+            ]
+        );
+        assert!(snapshots_to_prune(&names[..1], Some(0), Some(0)).is_empty());
+    }
+
+    #[test]
+    fn snapshots_are_listed_oldest_first_and_link_to_the_previous_one() {
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec![
+                "nas".to_string(),
+                "ls -1 \"$HOME/backups/app\" 2>/dev/null".to_string(),
+            ],
+            output: Some(std::process::Output {
+                status: ok_status(),
+                stdout: b"2024-06-02T12:00\nlatest\n2024-06-01T12:00\nnotes.txt\n".to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        assert_eq!(
+            list_snapshots(&runner, "nas", "~/backups/app", &args).unwrap(),
+            vec!["2024-06-01T12:00", "2024-06-02T12:00"]
+        );
+        assert_eq!(
+            snapshot_dir(Path::new("/home/me/src/app"), &args),
+            "~/backups/app"
+        );
+        let args = Args {
+            snapshot: true,
+            ..Default::default()
+        };
+        assert!(!base_rsync_args(&args, false).contains(&"--inplace".to_string()));
+    }
+
+    #[test]
+    fn undo_restores_the_latest_backup_set_for_the_path_and_host() {
+        let sets = vec![
+            BackupSet {
+                timestamp: "2024-06-01T12-00-00".to_string(),
+                host: "nas".to_string(),
+                path: PathBuf::from("/home/me/app"),
+                pulled: false,
+                dest_dir: "/home/me/app".to_string(),
+                backup_dir: "/home/me/.syncz-trash/2024-06-01T12-00-00".to_string(),
+            },
+            BackupSet {
+                timestamp: "2024-06-02T12-00-00".to_string(),
+                host: "gpu".to_string(),
+                path: PathBuf::from("/home/me/app"),
+                pulled: false,
+                dest_dir: "/home/me/app".to_string(),
+                backup_dir: "/home/me/.syncz-trash/2024-06-02T12-00-00".to_string(),
+            },
+        ];
+        assert_eq!(
+            latest_backup_set(&sets, "nas", Path::new("/home/me/app")),
+            Some(0)
+        );
+        assert_eq!(latest_backup_set(&sets, "nas", Path::new("/home/me")), None);
+
+        let args = Args {
+            no_multiplex: true,
+            yes: true,
+            ..Default::default()
+        };
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec![
+                    "nas".to_string(),
+                    "test -d '/home/me/.syncz-trash/2024-06-01T12-00-00'".to_string(),
+                ],
+                output: None,
+                status: Some(ok_status()),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec![
+                    "nas".to_string(),
+                    "rsync -a '/home/me/.syncz-trash/2024-06-01T12-00-00/' '/home/me/app/'"
+                        .to_string(),
+                ],
+                output: None,
+                status: Some(ok_status()),
+            },
+        ]);
+        assert!(restore_backup_set(&runner, &sets[0], &args).unwrap());
+    }
+
+    #[test]
+    fn newer_remote_files_are_the_updates_a_pull_would_bring() {
+        let change = |code: &str, path: &str| Change {
+            code: code.to_string(),
+            path: path.to_string(),
+            size: None,
+        };
+        let changes = vec![
+            change(">f.st......", "src/edited.rs"),
+            change(">f+++++++++", "src/new_on_remote.rs"),
+            change("cd+++++++++", "assets/"),
+            change("*deleting", "local_only.txt"),
+            change(">f..t......", "notes.md"),
+        ];
+        assert_eq!(newer_on_remote(&changes), vec!["src/edited.rs", "notes.md"]);
+
+        let args = Args {
+            force: true,
+            ..Default::default()
+        };
+        assert_eq!(base_rsync_args(&args, true)[0], "-avz");
+        assert_eq!(base_rsync_args(&Args::default(), true)[0], "-avzu");
+    }
+
+    #[test]
+    fn existing_file_filters_are_exclusive_and_passed_to_rsync() {
+        let args = Args {
+            ignore_existing: true,
+            ..Default::default()
+        };
+        assert!(base_rsync_args(&args, true).contains(&"--ignore-existing".to_string()));
+        let args = Args {
+            existing: true,
+            ..Default::default()
+        };
+        assert!(base_rsync_args(&args, true).contains(&"--existing".to_string()));
+
+        for flags in [
+            ["--ignore-existing", "--existing"],
+            ["--update", "--force"],
+            ["--update", "--existing"],
+        ] {
+            let result = Cli::try_parse_from(["syncz", ".", "host", flags[0], flags[1]]);
+            assert!(result.is_err(), "{:?} should conflict", flags);
+        }
+        assert!(Cli::try_parse_from(["syncz", ".", "host", "--update"]).is_ok());
+    }
+
+    #[test]
+    fn protected_remote_paths_block_the_sync_unless_allowed() {
+        let mut args = Args::default();
+        assert!(check_protected("~/src/app", &args).is_ok());
+        for dest in ["~", "~/", "/", "/etc/", "/etc"] {
+            assert!(check_protected(dest, &args).is_err(), "{}", dest);
+        }
+        assert!(check_protected("/etc/nginx", &args).is_ok());
+
+        let config = parse_config("protected_paths = [\"/srv\"]\n").expect("config");
+        config.apply(&mut args);
+        assert!(check_protected("/srv", &args).is_err());
+        assert!(check_protected("/", &args).is_err(), "contains /srv");
+        assert!(check_protected("~", &args).is_ok());
+        args.allow_protected = true;
+        assert!(check_protected("/srv", &args).is_ok());
+    }
+
+    #[test]
+    fn syncing_a_directory_onto_itself_or_a_parent_is_refused() {
+        let home = Path::new("/home/me");
+        let local = Path::new("/home/me/src/app");
+        for remote in ["~/src/app", "~/src", "/home/me/src/app/build", "~/src/app/"] {
+            assert!(
+                check_overlap("localhost", local, remote, home).is_err(),
+                "{}",
+                remote
+            );
+        }
+        assert!(check_overlap("localhost", local, "~/src/app2", home).is_ok());
+        assert!(check_overlap("127.0.0.1", local, "/backup/app", home).is_ok());
+        assert!(check_overlap("gpu1", local, "~/src/app", home).is_ok());
+        assert!(check_overlap("me@localhost:2222", local, "~/src", home).is_err());
+
+        let src = ("nas".to_string(), "~/data".to_string());
+        let dst = ("nas".to_string(), "~".to_string());
+        let args = Args::default();
+        let runner = FakeRunner::new(vec![]);
+        let err = remote_to_remote(&runner, &src, &dst, &args).unwrap_err();
+        assert!(err.to_string().contains("overlap"), "{}", err);
+    }
+
+    #[test]
+    fn free_space_comes_from_df_on_the_nearest_existing_directory() {
+        let df = "Filesystem     1024-blocks      Used Available Capacity Mounted on\n\
+                  /dev/sda1        102400000  90000000  12400000      88% /\n";
+        assert_eq!(parse_df_available(df), Some(12_400_000 * 1024));
+        assert_eq!(parse_df_available("df: /nope: No such file\n"), None);
+
+        let dir = std::env::temp_dir().join(format!("syncz-df-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).expect("mkdir");
+        fs::write(dir.join("a.bin"), vec![0u8; 4096]).expect("write");
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec![
+                "nas".to_string(),
+                "d=\"$HOME/app\"; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; df -Pk \"$d\""
+                    .to_string(),
+            ],
+            output: Some(std::process::Output {
+                status: ok_status(),
+                stdout: df.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        // Everything fits, so no dry run is needed to size the transfer.
+        check_free_space(&runner, "nas", &dir, "~/app", false, &args, false, None).expect("fits");
+        fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn pulls_check_local_free_space_against_the_remote_size() {
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        let dir = std::env::temp_dir();
+        let df = format!(
+            "Filesystem 1024-blocks Used Available Capacity Mounted on\n/dev/x 100 90 10 90% {}\n",
+            dir.display()
+        );
+        let local = dir.join(format!("syncz-pull-df-{}", std::process::id()));
+        let output = |stdout: &str| {
+            Some(std::process::Output {
+                status: ok_status(),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            })
+        };
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "df".to_string(),
+                args: vec!["-Pk".to_string(), dir.display().to_string()],
+                output: output(&df),
+                status: None,
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: vec!["nas".to_string(), "du -sk \"$HOME/app\"".to_string()],
+                output: output("8\t/home/me/app\n"),
+                status: None,
+            },
+        ]);
+        // 8K of remote data fits in 10K, so no dry run is needed.
+        check_free_space(&runner, "nas", &local, "~/app", false, &args, true, None).expect("fits");
+        assert!(Cli::try_parse_from(["syncz", ".", "host", "--pull", "--force"]).is_ok());
+    }
+
+    #[test]
+    fn a_second_sync_of_the_same_path_and_host_is_refused() {
+        let dir = std::env::temp_dir().join(format!("syncz-lock-test-{}", std::process::id()));
+        let local = Path::new("/home/me/project");
+        let path = lock_path(&dir, "server", local);
+        assert_eq!(path, lock_path(&dir, "server", local));
+        assert_ne!(path, lock_path(&dir, "other", local));
+        assert_ne!(path, lock_path(&dir, "server", Path::new("/home/me/other")));
+
+        let held = acquire_lock(&path, "/home/me/project with server", false, true).unwrap();
+        let err = acquire_lock(&path, "/home/me/project with server", false, true)
+            .err()
+            .expect("second lock is refused");
+        let message = err.to_string();
+        assert!(message.contains("already syncing /home/me/project with server"));
+        assert!(message.contains(&format!("pid {}", std::process::id())));
+        assert!(message.contains("--wait"));
+        drop(held);
+        assert!(acquire_lock(&path, "/home/me/project with server", false, true).is_ok());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn dry_run_tree_colors_changes_and_rolls_up_directory_sizes() {
+        let changes = parse_changes(
+            ">f.st......|src/main.rs|1200\n>f+++++++++|src/new.rs|300\ncd+++++++++|docs/|4096\n\
+             >f+++++++++|docs/a.md|10\n.f...p.....|run.sh|50\n*deleting  |old.txt|0\n",
+        );
+        assert_eq!(
+            render_tree(&changes, TreeView::default()),
+            "├── docs/ (10 B)\n\
+             │   └── a.md (10 B)\n\
+             ├── old.txt [deleted]\n\
+             ├── run.sh (50 B)\n\
+             └── src/ (1.46 KB)\n    \
+             ├── main.rs (1.17 KB)\n    \
+             └── new.rs (300 B)"
+        );
+
+        let colored = render_tree(
+            &changes,
+            TreeView {
+                color: true,
+                ..Default::default()
+            },
+        );
+        let lines: Vec<&str> = colored.lines().collect();
+        assert_eq!(lines[0], "├── \x1b[32mdocs/\x1b[0m \x1b[2m(10 B)\x1b[0m");
+        assert_eq!(lines[2], "├── \x1b[31mold.txt [deleted]\x1b[0m");
+        assert_eq!(lines[3], "├── \x1b[36mrun.sh\x1b[0m \x1b[2m(50 B)\x1b[0m");
+        assert_eq!(lines[4], "└── src/ \x1b[2m(1.46 KB)\x1b[0m");
+        assert_eq!(
+            lines[5],
+            "    ├── \x1b[33mmain.rs\x1b[0m \x1b[2m(1.17 KB)\x1b[0m"
+        );
+    }
+
+    #[test]
+    fn dry_run_tree_marks_deletions() {
+        let changes = parse_changes(
+            "*deleting  |build/out.o|0\n*deleting  |build/|0\n>f+++++++++|build.rs|20\n",
+        );
+        assert_eq!(
+            render_tree(&changes, TreeView::default()),
+            "├── build/ [deleted]\n│   └── out.o [deleted]\n└── build.rs (20 B)"
+        );
+    }
+
+    #[test]
+    fn dry_run_tree_collapses_deep_and_crowded_directories() {
+        let mut output = String::from(">f+++++++++|src/lib/a.rs|10\n>f+++++++++|src/b.rs|5\n");
+        for i in 0..=TREE_MAX_ENTRIES {
+            output.push_str(&format!(">f+++++++++|data/{:03}.bin|1024\n", i));
+        }
+        let changes = parse_changes(&output);
+        let tree = render_tree(&changes, TreeView::default());
+        let lines: Vec<&str> = tree.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                "├── data/ (101.00 KB)",
+                "│   └── … 101 file(s), 101.00 KB",
+                "└── src/ (15 B)",
+                "    ├── b.rs (5 B)",
+                "    └── lib/ (10 B)",
+                "        └── a.rs (10 B)",
+            ]
+        );
+
+        let view = TreeView {
+            depth: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(
+            render_tree(&changes, view),
+            "├── data/ (101.00 KB)\n│   └── … 101 file(s), 101.00 KB\n\
+             └── src/ (15 B)\n    └── … 2 file(s), 15 B"
+        );
+    }
+
+    #[test]
+    fn dry_run_json_lists_every_change_with_its_kind() {
+        let summary = DryRunSummary {
+            transferred_line: None,
+            transferred_bytes: Some(12),
+            deletions: vec!["old.txt".to_string()],
+            changes: parse_changes(
+                ">f+++++++++|new.txt|12\n.f...p.....|run.sh|50\ncd+++++++++|docs/|4096\n\
+                 *deleting  |old.txt|0\n",
+            ),
+            renames: Vec::new(),
+        };
+        assert_eq!(
+            dry_run_json(&summary, "box", false),
+            json!({
+                "event": "dry_run",
+                "direction": "push",
+                "host": "box",
+                "files": [
+                    { "path": "new.txt", "change": ">f+++++++++", "kind": "new", "is_dir": false, "size": 12 },
+                    { "path": "run.sh", "change": ".f...p.....", "kind": "perms", "is_dir": false, "size": 50 },
+                    { "path": "docs/", "change": "cd+++++++++", "kind": "new", "is_dir": true, "size": 4096 },
+                    { "path": "old.txt", "change": "*deleting", "kind": "deleted", "is_dir": false, "size": 0 },
+                ],
+                "deletions": ["old.txt"],
+                "renames": [],
+                "total_size": 62,
+                "transferred_bytes": 12,
+            })
+        );
+    }
+
+    #[test]
+    fn pager_falls_back_to_less_keeping_colors() {
+        assert_eq!(pager_command(None), "less -R");
+        assert_eq!(pager_command(Some("  ".to_string())), "less -R");
+        assert_eq!(pager_command(Some("most".to_string())), "most");
+    }
+
+    #[test]
+    fn only_restricts_the_transfer_to_matching_paths() {
+        let args = Args {
+            all: true,
+            exclude: vec!["secrets/".to_string()],
+            only: vec!["*.toml".to_string(), "configs/".to_string()],
+            ..Default::default()
+        };
+        let rsync = base_rsync_args(&args, true);
+        let rules: Vec<&str> = rsync
+            .iter()
+            .map(String::as_str)
+            .skip_while(|a| *a != "--exclude=secrets/")
+            .collect();
+        assert_eq!(
+            rules,
+            vec![
+                "--exclude=secrets/",
+                "--include=*/",
+                "--include=*.toml",
+                "--include=configs/",
+                "--include=configs/**",
+                "--exclude=*",
+                "--prune-empty-dirs",
+            ]
+        );
+
+        assert!(!sftp::is_excluded("Cargo.toml", false, &args));
+        assert!(!sftp::is_excluded("app/configs/prod/db.yaml", false, &args));
+        assert!(!sftp::is_excluded("data", true, &args));
+        assert!(sftp::is_excluded("data/train.csv", false, &args));
+    }
+
+    #[test]
+    fn progress2_lines_give_bytes_speed_and_eta() {
+        assert_eq!(
+            parse_progress2("     1,234,567  45%    1.23MB/s    0:00:12 (xfr#5, to-chk=10/100)"),
+            Some(TransferProgress {
+                bytes: 1_234_567,
+                percent: 45,
+                speed: "1.23MB/s".to_string(),
+                eta: "0:00:12".to_string(),
+            })
+        );
+        assert_eq!(parse_progress2(">f+++++++++|notes 100%.txt|12"), None);
+        assert_eq!(parse_progress2("sending incremental file list"), None);
+
+        let mut records = Vec::new();
+        read_records(
+            &b"a.txt\n  10  1%  1.00kB/s  0:00:09\r  20  2%  2.00kB/s  0:00:08\r\nb.txt"[..],
+            |record| records.push(record),
+        );
+        assert_eq!(
+            records,
+            vec![
+                "a.txt",
+                "  10  1%  1.00kB/s  0:00:09",
+                "  20  2%  2.00kB/s  0:00:08",
+                "",
+                "b.txt"
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_progress_counts_files_found_so_far() {
+        assert_eq!(
+            scanned_files("          0   0%    0.00kB/s    0:00:00 (xfr#0, ir-chk=1000/123456)"),
+            Some(123_456)
+        );
+        assert_eq!(scanned_files(" 12,345 files..."), Some(12_345));
+        assert_eq!(
+            scanned_files("    1,024 100%  1.00MB/s  0:00:00 (xfr#1, to-chk=9/10)"),
+            None
+        );
+        assert_eq!(format_count(123_456), "123,456");
+        assert_eq!(format_count(999), "999");
+        assert_eq!(format_count(1_000_000), "1,000,000");
+    }
+
+    #[test]
+    fn interrupted_transfers_exit_130_with_a_resume_hint() {
+        let err = anyhow::Error::new(Interrupted { sent: 3 << 20 });
+        assert_eq!(exit_code(&err), 130);
+        let message = err.to_string();
+        assert!(message.contains("3.00 MB"));
+        assert!(message.contains("run the same command again to resume"));
+    }
+
+    #[test]
+    fn timeout_bounds_ssh_connects_and_stalled_transfers() {
+        let args = Args {
+            timeout: Some(30),
+            no_multiplex: true,
+            ssh_opts: vec!["-o ConnectTimeout=5".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            ssh_args(&args),
+            vec![
+                "-o",
+                "ConnectTimeout=5",
+                "-o",
+                "ConnectTimeout=30",
+                "-o",
+                "ServerAliveInterval=10",
+                "-o",
+                "ServerAliveCountMax=3",
+            ]
+        );
+        assert!(base_rsync_args(&args, false).contains(&"--timeout=30".to_string()));
+        assert!(!base_rsync_args(&Args::default(), false)
+            .iter()
+            .any(|a| a.starts_with("--timeout")));
+
+        let mut args = Args::default();
+        parse_config("timeout = 20").unwrap().apply(&mut args);
+        assert_eq!(args.timeout, Some(20));
+    }
+
+    #[test]
+    fn remote_probe_checks_type_parent_and_space_in_one_ssh_call() {
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        let script = "p=\"$HOME/app\"; \
+            if test -f \"$p\"; then echo type=file; elif test -d \"$p\"; then echo type=dir; \
+            elif test -e \"$p\"; then echo type=other; else echo type=missing; fi; \
+            mkdir -p \"$HOME\" || echo mkdir=failed; \
+            d=$p; while [ ! -e \"$d\" ]; do d=$(dirname \"$d\"); done; \
+            echo \"free=$(df -Pk \"$d\" 2>/dev/null | awk 'NR==2 {print $4}')\"";
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec!["nas".to_string(), script.to_string()],
+            output: Some(std::process::Output {
+                status: ok_status(),
+                stdout: b"type=dir\nfree=2048\n".to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        let probe = probe_remote(&runner, "nas", "~/app", Some("~"), false, &args).unwrap();
+        assert_eq!(
+            probe,
+            RemoteProbe {
+                kind: RemoteKind::Dir,
+                free: Some(2 << 20),
+                size: None,
+            }
+        );
+
+        assert_eq!(
+            parse_probe("type=file\nmkdir=failed\nfree=\nsize=12\n"),
+            (
+                RemoteProbe {
+                    kind: RemoteKind::File,
+                    free: None,
+                    size: Some(12 << 10),
+                },
+                false
+            )
+        );
+    }
+
+    #[test]
+    fn connect_opens_a_persistent_master_unless_one_is_running() {
+        let args = Args {
+            port: Some(2222),
+            ..Default::default()
+        };
+        let mut check = ssh_args(&args);
+        check.extend(["-O".to_string(), "check".to_string(), "box".to_string()]);
+        let mut master = ssh_args(&Args {
+            no_multiplex: true,
+            ..args.clone()
+        });
+        master.extend(
+            [
+                "-o",
+                "ControlMaster=yes",
+                "-o",
+                "ControlPersist=yes",
+                "-o",
+                CONTROL_PATH,
+                "-N",
+                "-f",
+                "box",
+            ]
+            .map(String::from),
+        );
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: check.clone(),
+                output: None,
+                status: Some(std::process::ExitStatus::from_raw(255 << 8)),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: master,
+                output: None,
+                status: Some(ok_status()),
+            },
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: check,
+                output: None,
+                status: Some(ok_status()),
+            },
+        ]);
+        connect_master(&runner, "box", &args).expect("connect");
+        // Already open: nothing else runs.
+        connect_master(&runner, "box", &args).expect("connect again");
+
+        let mut exit = ssh_args(&args);
+        exit.extend(["-O".to_string(), "exit".to_string(), "box".to_string()]);
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: exit,
+            output: None,
+            status: Some(std::process::ExitStatus::from_raw(255 << 8)),
+        }]);
+        let err = disconnect_master(&runner, "box", &args).unwrap_err();
+        assert!(err.to_string().contains("no open connection to box"));
+    }
+
+    #[test]
+    fn desktop_notification_reports_bytes_duration_or_error() {
+        let mut entry = HistoryEntry {
+            timestamp: "2024-05-01T10:00:00+00:00".to_string(),
+            direction: "push".to_string(),
+            hosts: vec!["nas".to_string()],
+            path: PathBuf::from("/home/me/src/app"),
+            remote_path: Some("~/src/app".to_string()),
+            bytes: 3 << 30,
+            duration_ms: 1_234_000,
+            exit_code: 0,
+            error: None,
+            cwd: PathBuf::from("/home/me"),
+            argv: Vec::new(),
+        };
+        let (title, body) = desktop_notification(&entry);
+        assert_eq!(title, "syncz: push to nas finished");
+        assert_eq!(body, "app: 3.00 GB in 20m 34s");
+
+        entry.exit_code = 12;
+        entry.error = Some("rsync failed: connection dropped".to_string());
+        let (title, body) = desktop_notification(&entry);
+        assert_eq!(title, "syncz: push to nas failed");
+        assert_eq!(
+            body,
+            "app: rsync failed: connection dropped (after 20m 34s)"
+        );
+
+        assert_eq!(format_duration(999), "0s");
+        assert_eq!(format_duration(61_000), "1m 01s");
+        assert_eq!(format_duration(3_725_000), "1h 02m");
+
+        let config = parse_config("notify = true\n").expect("config");
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert!(args.notify);
+    }
+
+    #[test]
+    fn result_hooks_post_json_to_urls_and_pipe_it_to_commands() {
+        let entry = HistoryEntry {
+            timestamp: "2024-05-01T10:00:00+00:00".to_string(),
+            direction: "pull".to_string(),
+            hosts: vec!["nas".to_string()],
+            path: PathBuf::from("/home/me/backup"),
+            remote_path: Some("/data".to_string()),
+            bytes: 2048,
+            duration_ms: 1500,
+            exit_code: 23,
+            error: Some("partial transfer".to_string()),
+            cwd: PathBuf::from("/home/me"),
+            argv: Vec::new(),
+        };
+        let payload = result_payload(&entry);
+        assert_eq!(payload["status"], "failure");
+        assert_eq!(payload["host"], "nas");
+        assert_eq!(payload["path"], "/home/me/backup");
+        assert_eq!(payload["direction"], "pull");
+        assert_eq!(payload["bytes"], 2048);
+        assert_eq!(payload["duration_ms"], 1500);
+        assert_eq!(payload["error"], "partial transfer");
+        assert_eq!(
+            payload["text"],
+            "syncz: pull from nas failed\nbackup: partial transfer (after 1s)"
+        );
+
+        let body = payload.to_string();
+        let mut curl: Vec<String> = [
+            "-fsS",
+            "-m",
+            "10",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+        ]
+        .map(String::from)
+        .to_vec();
+        curl.push(body.clone());
+        curl.push("https://hooks.example.com/T0".to_string());
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "curl".to_string(),
+                args: curl,
+                output: Some(Output {
+                    status: ok_status(),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+                status: None,
+            },
+            ExpectedCall {
+                program: "sh".to_string(),
+                args: vec![
+                    "-c".to_string(),
+                    format!("printf '%s\\n' {} | logger -t syncz", shell_escape(&body)),
+                ],
+                output: Some(Output {
+                    status: ok_status(),
+                    stdout: Vec::new(),
+                    stderr: Vec::new(),
+                }),
+                status: None,
+            },
+        ]);
+        run_result_hook(&runner, "https://hooks.example.com/T0", &payload).expect("webhook");
+        run_result_hook(&runner, "logger -t syncz", &payload).expect("command");
+
+        let config =
+            parse_config("on_failure = \"https://hooks.example.com/T0\"\n").expect("config");
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert_eq!(
+            args.on_failure.as_deref(),
+            Some("https://hooks.example.com/T0")
+        );
+        assert_eq!(args.on_success, None);
+    }
+
+    #[test]
+    fn job_files_turn_into_syncz_command_lines() {
+        let file = parse_job_file(
+            r#"
+[[jobs]]
+name = "photos"
+path = "~/Pictures"
+host = "nas"
+direction = "push"
+remote_path = "/data/photos"
+exclude = ["*.tmp", "cache/"]
+flags = ["--delete", "--yes"]
+
+[[jobs]]
+name = "notes"
+path = "notes"
+host = "box"
+direction = "both"
+
+[[jobs]]
+name = "logs"
+path = "logs"
+direction = "pull"
+"#,
+        )
+        .expect("jobs");
+        let names: Vec<&str> = file.jobs.iter().map(|j| j.name.as_str()).collect();
+        assert_eq!(names, vec!["photos", "notes", "logs"]);
+        assert_eq!(
+            job_argv(&file.jobs[0], None),
+            vec![
+                "push",
+                "~/Pictures",
+                "nas",
+                "--remote-path",
+                "/data/photos",
+                "--exclude",
+                "*.tmp",
+                "--exclude",
+                "cache/",
+                "--delete",
+                "--yes",
+                "--json",
+            ]
+        );
+        assert_eq!(
+            job_argv(&file.jobs[1], Some(Path::new("/etc/syncz.toml"))),
+            vec![
+                "notes",
+                "box",
+                "--both",
+                "--config",
+                "/etc/syncz.toml",
+                "--json"
+            ]
+        );
+        // No host: the child falls back to the host last used for the path.
+        assert_eq!(
+            job_argv(&file.jobs[2], None),
+            vec!["pull", "logs", "--last", "--json"]
+        );
+
+        assert!(parse_job_file("[[jobs]]\nname = \"a\"\npath = \".\"\nflagz = []\n").is_err());
+        let err = parse_job_file(
+            "[[jobs]]\nname = \"a\"\npath = \".\"\n[[jobs]]\nname = \"a\"\npath = \"b\"\n",
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "job 'a' is defined twice");
+
+        let mut outcome = JobOutcome::default();
+        for line in [
+            r#"{"event":"connection","host":"nas","reused":false}"#,
+            r#"{"event":"transfer","direction":"push","host":"nas","sent_bytes":10,"stats":{"sent_bytes":2048,"received_bytes":64}}"#,
+            "not json",
+            r#"{"event":"error","message":"rsync failed","exit_code":23}"#,
+        ] {
+            outcome.observe(line);
+        }
+        assert_eq!(outcome.bytes, 2112);
+        assert_eq!(outcome.error.as_deref(), Some("rsync failed"));
+    }
+
+    #[test]
+    fn schedule_writes_timers_and_launchd_agents_for_a_job() {
+        assert_eq!(schedule::parse_interval("90").unwrap(), 90);
+        assert_eq!(schedule::parse_interval("30m").unwrap(), 1800);
+        assert_eq!(schedule::parse_interval("1h").unwrap(), 3600);
+        assert_eq!(schedule::parse_interval("2d").unwrap(), 172_800);
+        assert!(schedule::parse_interval("1w").is_err());
+        assert!(schedule::parse_interval("0m").is_err());
+        assert_eq!(schedule::format_interval(5400), "90m");
+        assert_eq!(schedule::format_interval(86400), "1d");
+
+        let command = [
+            "/usr/local/bin/syncz".to_string(),
+            "run".to_string(),
+            "/home/me/my jobs.toml".to_string(),
+            "photos".to_string(),
+        ];
+        let (service, timer) = schedule::systemd_units("photos", &command, 3600);
+        assert!(service
+            .contains("ExecStart=/usr/local/bin/syncz run \"/home/me/my jobs.toml\" photos\n"));
+        assert!(service.contains("Type=oneshot\n"));
+        assert!(timer.contains("OnUnitActiveSec=3600s\n"));
+        assert!(timer.contains("WantedBy=timers.target\n"));
+        assert_eq!(
+            schedule::unit_interval(schedule::Scheduler::Systemd, &timer),
+            Some(3600)
+        );
+
+        let plist = schedule::launchd_plist(
+            "photos",
+            &command,
+            1800,
+            Path::new("/Users/me/.local/share/syncz/logs/photos.log"),
+        );
+        assert!(plist.contains("<string>com.syncz.photos</string>"));
+        assert!(plist.contains("        <string>/home/me/my jobs.toml</string>\n"));
+        assert!(plist.contains("<integer>1800</integer>"));
+        assert_eq!(
+            schedule::unit_interval(schedule::Scheduler::Launchd, &plist),
+            Some(1800)
+        );
+    }
+
+    #[test]
+    fn agent_pauses_resumes_and_triggers_jobs() {
+        let file = parse_job_file(
+            r#"
+[[jobs]]
+name = "code"
+path = "~/src/app"
+host = "box"
+watch = true
+
+[[jobs]]
+name = "photos"
+path = "~/Pictures"
+host = "nas"
+direction = "push"
+every = "1h"
+"#,
+        )
+        .expect("jobs");
+        assert_eq!(
+            agent::watch_argv(&file.jobs[0], None),
+            vec!["watch", "~/src/app", "box", "--json"]
+        );
+        assert!(parse_job_file(
+            "[[jobs]]\nname = \"a\"\npath = \".\"\ndirection = \"pull\"\nwatch = true\n"
+        )
+        .is_err());
+        assert!(
+            parse_job_file("[[jobs]]\nname = \"a\"\npath = \".\"\nevery = \"soon\"\n").is_err()
+        );
+
+        let now = Instant::now();
+        let mut jobs: Vec<agent::AgentJob> = file
+            .jobs
+            .into_iter()
+            .map(|job| agent::AgentJob::new(job, now).expect("job"))
+            .collect();
+        assert_eq!(jobs[1].every, Some(3600));
+        assert_eq!(jobs[1].due, Some(now));
+
+        let reply = agent::handle(&mut jobs, &agent::Request::Pause { job: None }, now);
+        assert_eq!(reply["ok"], true);
+        assert!(jobs.iter().all(|j| j.paused));
+        assert_eq!(reply["jobs"][1]["state"], "paused");
+        assert_eq!(reply["jobs"][1]["mode"], "every 1h");
+
+        let resume = agent::Request::Resume {
+            job: Some("photos".to_string()),
+        };
+        agent::handle(&mut jobs, &resume, now);
+        assert!(jobs[0].paused && !jobs[1].paused);
+
+        let trigger = agent::Request::Trigger {
+            job: "code".to_string(),
+        };
+        assert_eq!(agent::handle(&mut jobs, &trigger, now)["ok"], true);
+        assert!(jobs[0].triggered);
+        let missing = agent::Request::Trigger {
+            job: "nope".to_string(),
+        };
+        let reply = agent::handle(&mut jobs, &missing, now);
+        assert_eq!(reply["error"], "no job named 'nope'");
+
+        let request: agent::Request =
+            serde_json::from_str(r#"{"command":"pause","job":"code"}"#).expect("request");
+        assert!(matches!(request, agent::Request::Pause { job: Some(ref j) } if j == "code"));
+
+        jobs[1].observe(r#"{"event":"transfer","stats":{"sent_bytes":100,"received_bytes":20}}"#);
+        let last = jobs[1].last.clone().expect("sync");
+        assert!(last.ok);
+        assert_eq!(last.bytes, 120);
+        let status = agent::handle(&mut jobs, &agent::Request::Status, now);
+        let table = agent::format_status(&status["jobs"]);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines[0], "JOB     MODE      STATE   LAST RUN");
+        assert_eq!(lines[1], "code    watch     paused  never");
+        assert!(
+            lines[2].starts_with("photos  every 1h  idle    ✅ "),
+            "{}",
+            lines[2]
+        );
+        assert!(lines[2].ends_with(" 120 B (next in 0s)"), "{}", lines[2]);
+    }
+
+    #[test]
+    fn dashboard_shows_jobs_transfers_conflicts_and_log() {
+        let file = parse_job_file(
+            "[[jobs]]\nname = \"code\"\npath = \".\"\nhost = \"box\"\nwatch = true\n",
+        )
+        .expect("jobs");
+        let now = Instant::now();
+        let mut jobs: Vec<agent::AgentJob> = file
+            .jobs
+            .into_iter()
+            .map(|job| agent::AgentJob::new(job, now).expect("job"))
+            .collect();
+        jobs[0].observe(r#"{"event":"watch","changed":["a.rs","b.rs"]}"#);
+        jobs[0].observe(
+            r#"{"event":"progress","host":"box","bytes":4096,"percent":40,"speed":"1.50MB/s"}"#,
+        );
+        jobs[0].observe(r#"{"event":"conflicts","paths":["notes.md"]}"#);
+        assert_eq!(jobs[0].queued, 2);
+        assert_eq!(jobs[0].progress, Some((40, "1.50MB/s".to_string())));
+
+        let view = dashboard::View {
+            reply: agent::handle(&mut jobs, &agent::Request::Status, now),
+            ..Default::default()
+        };
+        let mut terminal =
+            ratatui::Terminal::new(ratatui::backend::TestBackend::new(100, 16)).expect("terminal");
+        terminal
+            .draw(|frame| dashboard::draw(frame, &view))
+            .expect("draw");
+        let buffer = terminal.backend().buffer();
+        let screen: Vec<String> = (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect()
+            })
+            .collect();
+        let screen = screen.join("\n");
+        assert!(screen.contains("code"), "{}", screen);
+        assert!(screen.contains("40% 1.50MB/s"), "{}", screen);
+        assert!(
+            screen.contains("code: 1 file(s) changed on both sides: notes.md"),
+            "{}",
+            screen
+        );
+        assert!(
+            screen.contains("code: 1 conflict(s): notes.md"),
+            "{}",
+            screen
+        );
+
+        jobs[0].observe(r#"{"event":"transfer","stats":{"sent_bytes":100,"received_bytes":20}}"#);
+        assert_eq!(jobs[0].queued, 0);
+        assert_eq!(jobs[0].progress, None);
+        assert_eq!(jobs[0].last.as_ref().map(|l| l.bytes), Some(120));
+    }
+
+    #[test]
+    fn git_tracked_lists_existing_files_and_implies_push() {
+        let stdout = "src/lib.rs\0it's \"quoted\".txt\0gone.rs\0";
+        assert_eq!(
+            parse_tracked_files(stdout, |p| p != "gone.rs"),
+            vec!["src/lib.rs", "it's \"quoted\".txt"]
+        );
+
+        let args = Args {
+            git_tracked: true,
+            ..Default::default()
+        };
+        assert!(args.is_push());
+        assert!(!args.is_pull());
+        let pull = Args {
+            pull: true,
+            git_tracked: true,
+            ..Default::default()
+        };
+        assert!(pull.validate().is_err());
+    }
+
+    #[test]
+    fn git_diff_defaults_to_head_and_takes_a_ref_with_equals() {
+        let parse = |argv: &[&str]| Cli::try_parse_from(argv).map(|cli| cli.args);
+        let args = parse(&["syncz", "--git-diff", ".", "gpu"]).expect("parse");
+        assert_eq!(args.git_diff.as_deref(), Some("HEAD"));
+        assert_eq!(args.path.as_deref(), Some("."));
+        let args = parse(&["syncz", ".", "gpu", "--git-diff=main"]).expect("parse");
+        assert_eq!(args.git_diff.as_deref(), Some("main"));
+        assert!(parse(&["syncz", ".", "gpu", "--git-diff", "--since", "main"]).is_err());
+
+        let args = Args {
+            delete_removed: true,
+            ..Default::default()
+        };
+        assert!(args.validate().is_err());
+    }
+
+    #[test]
+    fn githook_pushes_the_work_tree_without_failing_git() {
+        let script = githook::hook_script("/usr/local/bin/syncz", Some("gpu box"), Some("deploy"));
+        let lines: Vec<&str> = script.lines().collect();
+        assert_eq!(lines[0], "#!/bin/sh");
+        assert!(lines[1].starts_with("# Installed by `syncz githook install`"));
+        assert_eq!(
+            lines[2],
+            "'/usr/local/bin/syncz' push \"$(git rev-parse --show-toplevel)\" 'gpu box' --profile 'deploy' --non-interactive || true"
+        );
+
+        // Without a host the hook follows the project file.
+        let script = githook::hook_script("/bin/syncz", None, None);
+        assert!(script.ends_with(
+            "'/bin/syncz' push \"$(git rev-parse --show-toplevel)\" --non-interactive || true\n"
+        ));
+    }
+
+    #[test]
+    fn diff_fetches_the_remote_file_and_compares_it_first() {
+        let host = "example";
+        let remote = "~/etc/app.toml";
+        let local = std::env::temp_dir().join(format!("syncz-diff-local-{}", std::process::id()));
+        fs::create_dir_all(&local).expect("dir");
+        let local = local.join("app.toml");
+        fs::write(&local, "port = 8080\n").expect("local");
+        let copy = std::env::temp_dir().join(format!("syncz-diff-{}-app.toml", std::process::id()));
+        let args = Args::default();
+        let mut ssh = ssh_args(&args);
+        ssh.push(host.to_string());
+        ssh.push(format!("cat -- {}", remote_shell_path(remote)));
+        let diff =
+            "--- example:~/etc/app.toml\n+++ app.toml\n@@ -1 +1 @@\n-port = 80\n+port = 8080\n";
+        let runner = FakeRunner::new(vec![
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args: ssh,
+                output: Some(Output {
+                    status: ok_status(),
+                    stdout: b"port = 80\n".to_vec(),
+                    stderr: Vec::new(),
+                }),
+                status: None,
+            },
+            ExpectedCall {
+                program: "diff".to_string(),
+                args: vec![
+                    "-u".to_string(),
+                    "--label".to_string(),
+                    format!("{}:{}", host, remote),
+                    "--label".to_string(),
+                    local.to_string_lossy().to_string(),
+                    copy.to_string_lossy().to_string(),
+                    local.to_string_lossy().to_string(),
+                ],
+                output: Some(Output {
+                    status: std::process::ExitStatus::from_raw(1 << 8),
+                    stdout: diff.as_bytes().to_vec(),
+                    stderr: Vec::new(),
+                }),
+                status: None,
+            },
+        ]);
+
+        let fetched = fetch_remote_file(&runner, host, &local, remote, &args).expect("fetch");
+        assert_eq!(fetched, copy);
+        assert_eq!(fs::read_to_string(&copy).expect("copy"), "port = 80\n");
+        let result = unified_diff(&runner, host, &local, remote, &copy).expect("diff");
+        assert_eq!(result.as_deref(), Some(diff));
+        assert!(color_diff(diff).contains("\x1b[31m-port = 80\x1b[0m\n\x1b[32m+port = 8080\x1b[0m"));
+        let _ = fs::remove_file(&copy);
+    }
+
+    #[test]
+    fn remote_paths_map_back_to_local_paths() {
+        let config = parse_config(
+            r#"
+[remote_paths]
+"~/work" = "/data/me"
+"~/scratch" = "/scratch/$USER"
+
+[hosts.cluster.remote_paths]
+"~/work/ml" = "/data/me/ml-runs"
+"#,
+        )
+        .expect("parse config");
+        let home = Path::new("/home/me");
+        let local = |host: &str, remote: &str| local_for_remote(&config, host, remote, home);
+
+        assert_eq!(
+            local("gpu", "~/projects/app/logs"),
+            Path::new("/home/me/projects/app/logs")
+        );
+        // Already expanded by the local shell.
+        assert_eq!(local("gpu", "/home/me/app/"), Path::new("/home/me/app"));
+        assert_eq!(local("gpu", "~"), Path::new("/home/me"));
+        assert_eq!(local("gpu", "logs"), Path::new("/home/me/logs"));
+        assert_eq!(local("gpu", "/data/me/app"), Path::new("/home/me/work/app"));
+        assert_eq!(local("gpu", "/data/me"), Path::new("/home/me/work"));
+        assert_eq!(local("gpu", "/data/meta"), Path::new("/data/meta"));
+        assert_eq!(
+            local("cluster", "/data/me/ml-runs/exp1"),
+            Path::new("/home/me/work/ml/exp1")
+        );
+        assert_eq!(local("gpu", "/scratch/me/x"), Path::new("/scratch/me/x"));
+    }
+
+    #[test]
+    fn clone_names_the_directory_and_binds_it_to_the_remote() {
+        assert_eq!(clone_dir_name("~/projects/app/"), Some("app"));
+        assert_eq!(clone_dir_name("/srv/data"), Some("data"));
+        assert_eq!(clone_dir_name("logs"), Some("logs"));
+        assert_eq!(clone_dir_name("~"), None);
+        assert_eq!(clone_dir_name("/"), None);
+
+        let contents = clone_project_file("gpu", "~/projects/app");
+        let project: ProjectConfig = toml::from_str(&contents).expect("project file");
+        assert_eq!(project.host.as_deref(), Some("gpu"));
+        assert_eq!(project.remote_path.as_deref(), Some("~/projects/app"));
+        assert_eq!(project.exclude, vec![".syncz.toml"]);
+    }
+
+    #[test]
+    fn project_remotes_are_added_removed_and_selected() {
+        let original = "# build box\nhost = \"build-box\"\nremote_path = \"/srv/app\"\n";
+        let added = format!(
+            "{}{}{}",
+            original,
+            remote_table("prod", "server1", Some("/srv/prod")),
+            remote_table("staging", "server2", None)
+        );
+        let config: ProjectConfig = toml::from_str(&added).expect("project file");
+        let mut project = Project {
+            root: PathBuf::from("/home/me/app"),
+            config,
+        };
+        let home = Path::new("/home/me");
+
+        assert_eq!(
+            project.select_remote("staging").expect("staging"),
+            "server2"
+        );
+        assert_eq!(
+            project.remote_path(Path::new("/home/me/app/src"), home),
+            "/srv/app/src"
+        );
+        assert_eq!(project.select_remote("prod").expect("prod"), "server1");
+        assert_eq!(
+            project.remote_path(Path::new("/home/me/app/src"), home),
+            "/srv/prod/src"
+        );
+        let err = project.select_remote("qa").unwrap_err().to_string();
+        assert!(err.contains("known: prod, staging"), "{}", err);
+
+        let removed = remove_remote_table(&added, "prod").expect("prod table");
+        assert_eq!(
+            removed,
+            format!("{}\n[remotes.staging]\nhost = \"server2\"\n", original)
+        );
+        let removed = remove_remote_table(&removed, "staging").expect("staging table");
+        assert_eq!(removed, original);
+        assert_eq!(remove_remote_table(&removed, "prod"), None);
+        assert!(check_remote_name("prod.eu").is_err());
+    }
+
+    #[test]
+    fn host_groups_expand_into_a_multi_host_push() {
+        let config = parse_config(
+            r#"
+[groups]
+gpu = ["gpu1", "gpu2", "gpu3"]
+cpu = ["cpu1"]
+"#,
+        )
+        .expect("parse config");
+        let mut args = Args {
+            host: Some("@gpu".to_string()),
+            extra_hosts: vec!["box".to_string(), "@cpu".to_string()],
+            hosts: vec!["@cpu".to_string()],
+            ..Default::default()
+        };
+        expand_host_groups(&mut args, &config.groups).expect("expand");
+        assert_eq!(args.host.as_deref(), Some("gpu1"));
+        assert_eq!(args.extra_hosts, vec!["gpu2", "gpu3", "box", "cpu1"]);
+        assert_eq!(args.hosts, vec!["cpu1"]);
+
+        let mut args = Args {
+            host: Some("@tpu".to_string()),
+            ..Default::default()
+        };
+        let err = expand_host_groups(&mut args, &config.groups)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("available: cpu, gpu"), "{}", err);
+    }
+
+    #[test]
+    fn failover_uses_the_first_reachable_host() {
+        let probe = |host: &str, stderr: &str| {
+            let mut args = preflight_ssh_args(&Args::default());
+            args.push(host.to_string());
+            args.push("true".to_string());
+            ExpectedCall {
+                program: "ssh".to_string(),
+                args,
+                output: Some(Output {
+                    status: if stderr.is_empty() {
+                        ok_status()
+                    } else {
+                        std::process::ExitStatus::from_raw(255 << 8)
+                    },
+                    stdout: Vec::new(),
+                    stderr: stderr.as_bytes().to_vec(),
+                }),
+                status: None,
+            }
+        };
+        let args = Args {
+            failover: vec!["vpn-box".to_string(), "lan-box".to_string()],
+            ..Default::default()
+        };
+        let config = Config::default();
+
+        let runner = FakeRunner::new(vec![
+            probe(
+                "vpn-box",
+                "ssh: connect to host vpn-box port 22: Connection timed out\n",
+            ),
+            probe("lan-box", ""),
+        ]);
+        let host = pick_failover_host(&runner, &args, &config).expect("failover");
+        assert_eq!(host, "lan-box");
+
+        let runner = FakeRunner::new(vec![probe(
+            "vpn-box",
+            "me@vpn-box: Permission denied (publickey).\n",
+        )]);
+        let err = pick_failover_host(&runner, &args, &config)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("authentication failed for vpn-box"),
+            "{}",
+            err
+        );
+
+        let runner = FakeRunner::new(vec![
+            probe("vpn-box", "ssh: Could not resolve hostname vpn-box\n"),
+            probe(
+                "lan-box",
+                "ssh: connect to host lan-box port 22: No route to host\n",
+            ),
+        ]);
+        let err = pick_failover_host(&runner, &args, &config)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("host unreachable: lan-box"), "{}", err);
+    }
+
+    #[test]
+    fn tailnet_peers_join_the_host_picker() {
+        let status = r#"{
+            "Self": {"DNSName": "laptop.tail1234.ts.net.", "Online": true},
+            "Peer": {
+                "nodekey:a": {"DNSName": "gpu1.tail1234.ts.net.", "TailscaleIPs": ["100.64.0.1"], "Online": true},
+                "nodekey:b": {"DNSName": "nas.tail1234.ts.net.", "TailscaleIPs": ["100.64.0.2"], "Online": false},
+                "nodekey:c": {"DNSName": "", "TailscaleIPs": ["100.64.0.3"], "Online": true},
+                "nodekey:d": {"DNSName": "lab.tail1234.ts.net.", "TailscaleIPs": ["100.64.0.4"], "Online": false}
+            }
+        }"#;
+        let peers = parse_tailscale_status(status).expect("status");
+        let names: Vec<&str> = peers.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(
+            names,
+            vec![
+                "100.64.0.3",
+                "gpu1.tail1234.ts.net",
+                "lab.tail1234.ts.net",
+                "nas.tail1234.ts.net"
+            ]
+        );
+
+        let mut hosts = vec![SshHost {
+            alias: "lab".to_string(),
+            hostname: Some("10.0.0.9".to_string()),
+            ..Default::default()
+        }];
+        merge_tailnet_peers(&mut hosts, peers);
+        let rows = format_host_rows(&hosts, &BTreeMap::new(), chrono::Local::now().into());
+        assert_eq!(
+            rows,
+            vec![
+                "lab                   10.0.0.9    ○ offline",
+                "100.64.0.3            100.64.0.3  ● online",
+                "gpu1.tail1234.ts.net  100.64.0.1  ● online",
+                "nas.tail1234.ts.net   100.64.0.2  ○ offline",
+            ]
+        );
+    }
+
+    #[test]
+    fn lan_hosts_join_the_host_picker() {
+        let output = "+;eth0;IPv6;raspberrypi;SSH Remote Terminal;local\n\
+=;eth0;IPv6;raspberrypi;SSH Remote Terminal;local;raspberrypi.local;fe80::1;22;\n\
+=;eth0;IPv4;raspberrypi;SSH Remote Terminal;local;raspberrypi.local;192.168.1.20;22;\n\
+=;wlan0;IPv4;nas;SSH Remote Terminal;local;nas.local;192.168.1.30;2222;\n\
+=;eth0;IPv4;build;SSH Remote Terminal;local;build.local;192.168.1.40;22;\n";
+        let found = parse_avahi_browse(output);
+        let names: Vec<(&str, &str)> = found
+            .iter()
+            .map(|h| (h.name.as_str(), h.address.as_str()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                ("build.local", "192.168.1.40"),
+                ("nas.local", "192.168.1.30"),
+                ("raspberrypi.local", "192.168.1.20"),
+            ]
+        );
+
+        let mut hosts = vec![SshHost {
+            alias: "build".to_string(),
+            hostname: Some("10.0.0.4".to_string()),
+            ..Default::default()
+        }];
+        merge_lan_hosts(&mut hosts, found);
+        assert_eq!(hosts[1].alias, "nas.local:2222");
+        let rows = format_host_rows(&hosts, &BTreeMap::new(), chrono::Local::now().into());
+        assert_eq!(
+            rows,
+            vec![
+                "build              10.0.0.4           ● LAN",
+                "nas.local:2222     192.168.1.30:2222  ● LAN",
+                "raspberrypi.local  192.168.1.20       ● LAN",
+            ]
+        );
+
+        let addresses = ["fe80::1", "192.168.1.50", "10.0.0.9"].map(|a| a.parse().unwrap());
+        let host = lan_host("mini.local.", 22, addresses.into_iter()).unwrap();
+        assert_eq!(
+            (host.name.as_str(), host.address.as_str(), host.port),
+            ("mini.local", "10.0.0.9", 22)
+        );
+        let v6 = lan_host("pi.local.", 2222, ["fe80::2".parse().unwrap()].into_iter()).unwrap();
+        assert_eq!(v6.address, "fe80::2");
+        assert!(lan_host("gone.local.", 22, std::iter::empty()).is_none());
+
+        let cli = Cli::try_parse_from(["syncz", "--discover", "tailscale,lan"]).expect("parse");
+        assert_eq!(cli.args.discover, vec![Discover::Tailscale, Discover::Lan]);
+    }
+
+    #[test]
+    fn rclone_backend_maps_paths_filters_and_dry_runs() {
+        assert_eq!(rclone::rclone_path("s3", "~/src/app"), "s3:src/app");
+        assert_eq!(rclone::rclone_path("s3", "~"), "s3:");
+        assert_eq!(
+            rclone::rclone_path("s3", "bucket/backups/app"),
+            "s3:bucket/backups/app"
+        );
+
+        let args = Args {
+            delete: true,
+            all: true,
+            exclude: vec!["data/".to_string(), "*.log".to_string()],
+            include: vec!["keep.log".to_string()],
+            max_size: Some("1M".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            rclone::rclone_args("/home/me/app", "s3:bucket/app", false, &args).expect("args"),
+            vec![
+                "sync",
+                "/home/me/app",
+                "s3:bucket/app",
+                "--filter",
+                "+ keep.log",
+                "--filter",
+                "- data/**",
+                "--filter",
+                "- *.log",
+                "--max-size",
+                "1048576B",
+            ]
+        );
+
+        let stderr = "2024/05/01 10:00:00 NOTICE: src/main.rs: Skipped copy as --dry-run is set (size 1.2Ki)\n\
+2024/05/01 10:00:00 NOTICE: README.md: Skipped update as --dry-run is set (size 300)\n\
+2024/05/01 10:00:00 NOTICE: old.txt: Skipped delete as --dry-run is set (size 10)\n\
+2024/05/01 10:00:00 NOTICE: \n\
+Transferred:   \t  1.500 KiB / 1.500 KiB, 100%, 0 B/s, ETA -\n";
+        let summary = rclone::parse_dry_run(stderr);
+        let kinds: Vec<(&str, ChangeKind)> = summary
+            .changes
+            .iter()
+            .map(|c| (c.path.as_str(), c.kind()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("src/main.rs", ChangeKind::New),
+                ("README.md", ChangeKind::Modified),
+                ("old.txt", ChangeKind::Deleted),
+            ]
+        );
+        assert_eq!(summary.deletions, vec!["old.txt"]);
+    }
+
+    #[test]
+    fn rclone_backend_checks_the_remote_exists() {
+        let args = Args {
+            backend: Some(Backend::Rclone),
+            ..Default::default()
+        };
+        let listremotes = |stdout: &str| {
+            FakeRunner::new(vec![ExpectedCall {
+                program: "rclone".to_string(),
+                args: vec!["listremotes".to_string()],
+                output: Some(Output {
+                    status: ok_status(),
+                    stdout: stdout.as_bytes().to_vec(),
+                    stderr: Vec::new(),
+                }),
+                status: None,
+            }])
+        };
+        assert!(check_remote(&listremotes("gdrive:\ns3:\n"), "s3", &args).is_ok());
+        let err = check_remote(&listremotes("gdrive:\n"), "s3", &args)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "s3 isn't an rclone remote; add it with `rclone config`"
+        );
+
+        let args = Args {
+            backend: Some(Backend::Rclone),
+            post_cmd: Some("make".to_string()),
+            ..Default::default()
+        };
+        let err = check_remote(&FakeRunner::new(vec![]), "s3", &args)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--post-cmd isn't supported with --backend rclone");
+    }
+
+    #[test]
+    fn to_a_path_syncs_two_local_directories() {
+        assert!(local::is_path("/mnt/backup/app"));
+        assert!(local::is_path("~/backup"));
+        assert!(local::is_path("../copy"));
+        assert!(!local::is_path("staging"));
+
+        let (src, dst) =
+            sync_endpoints("", Path::new("/home/me/app"), "/mnt/usb/app", false, false);
+        assert_eq!(src, "/home/me/app/");
+        assert_eq!(dst, "/mnt/usb/app/");
+
+        let dir = std::env::temp_dir().join(format!("syncz-local-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("app")).expect("mkdir");
+        let runner = FakeRunner::new(vec![]);
+        let err = local::sync(
+            &runner,
+            &dir.join("app"),
+            &dir.join("app/backup"),
+            &Args::default(),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("overlaps"), "{}", err);
+        let args = Args {
+            watch: true,
+            ..Default::default()
+        };
+        let err = local::sync(&runner, &dir.join("app"), &dir.join("backup"), &args)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--watch isn't supported with --to PATH");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn encrypted_pushes_and_pulls_only_move_changed_files() {
+        assert_eq!(encrypt::recipient("age:age1xyz").expect("age"), "age1xyz");
+        assert!(encrypt::recipient("gpg:me@example.com").is_err());
+        assert_eq!(
+            encrypt::blob_name("src/main.rs", "age1xyz", false),
+            "src/main.rs.age"
+        );
+        let hidden = encrypt::blob_name("src/main.rs", "age1xyz", true);
+        assert!(!hidden.contains("main"), "{}", hidden);
+        assert_ne!(hidden, encrypt::blob_name("src/main.rs", "age1abc", true));
+
+        let dir = std::env::temp_dir().join(format!("syncz-encrypt-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).expect("mkdir");
+        fs::write(dir.join("src/main.rs"), "fn main() {}").expect("write");
+        fs::write(dir.join("notes.txt"), "hello").expect("write");
+        let entries = sftp::local_entries(&dir, &Args::default()).expect("entries");
+
+        let (manifest, changed) = encrypt::plan_push(
+            &dir,
+            &entries,
+            &encrypt::Manifest::default(),
+            "age1xyz",
+            false,
+        )
+        .expect("plan");
+        assert_eq!(changed, vec!["notes.txt", "src/main.rs"]);
+        let (again, changed) =
+            encrypt::plan_push(&dir, &entries, &manifest, "age1xyz", false).expect("plan");
+        assert!(changed.is_empty());
+        assert_eq!(again, manifest);
+
+        // The remote copy of notes.txt changed since; the local one didn't.
+        let mut remote = manifest.clone();
+        remote.files.get_mut("notes.txt").expect("entry").hash = "other".to_string();
+        let (fetch, conflicts) = encrypt::plan_pull(&dir, &remote, &manifest, false).expect("plan");
+        assert_eq!(fetch, vec!["notes.txt"]);
+        assert!(conflicts.is_empty());
+
+        // Now it was edited here too.
+        fs::write(dir.join("notes.txt"), "hello, edited").expect("write");
+        let (fetch, conflicts) = encrypt::plan_pull(&dir, &remote, &manifest, false).expect("plan");
+        assert!(fetch.is_empty());
+        assert_eq!(conflicts, vec!["notes.txt"]);
+        let (fetch, _) = encrypt::plan_pull(&dir, &remote, &manifest, true).expect("plan");
+        assert_eq!(fetch, vec!["notes.txt"]);
+        let _ = fs::remove_dir_all(&dir);
+
+        let args = Args {
+            encrypt: Some("age:age1xyz".to_string()),
+            delete: true,
+            ..Default::default()
+        };
+        let err = check_remote(&FakeRunner::new(vec![]), "box", &args)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--delete isn't supported with --encrypt");
+        let args = Args {
+            encrypt: Some("age:age1xyz".to_string()),
+            git_tracked: true,
+            ..Default::default()
+        };
+        assert_eq!(encrypt::unsupported(&args), Some("--git-tracked"));
+    }
+
+    #[test]
+    fn encrypted_syncs_to_several_hosts_stage_separately() {
+        // push_many runs one encrypted push per host at the same time.
+        let dirs: Vec<PathBuf> = (0..4)
+            .map(|_| std::thread::spawn(|| encrypt::staging_dir().expect("staging")))
+            .collect::<Vec<_>>()
+            .into_iter()
+            .map(|t| t.join().unwrap())
+            .collect();
+        for (i, dir) in dirs.iter().enumerate() {
+            assert!(dir.is_dir());
+            assert!(!dirs[i + 1..].contains(dir), "{} reused", dir.display());
+        }
+        for dir in &dirs {
+            let _ = fs::remove_dir_all(dir);
+        }
+    }
+
+    #[test]
+    fn verify_reports_files_whose_checksums_differ() {
+        let stdout = "<fcs.......|app/main.rs|120\n\
+.f..t......|app/lib.rs|80\n\
+.d..t......|app/|0\n\
+<f+++++++++|app/new.rs|10\n";
+        let preview = DryRunSummary {
+            transferred_line: None,
+            transferred_bytes: None,
+            deletions: Vec::new(),
+            changes: parse_changes(stdout),
+            renames: Vec::new(),
+        };
+        assert_eq!(
+            verify_mismatches(&preview),
+            vec!["app/main.rs", "app/new.rs"]
+        );
+
+        let cli = Cli::try_parse_from(["syncz", "push", "--verify"]).expect("parse");
+        let Some(Commands::Push(args)) = cli.command else {
+            panic!("expected push");
+        };
+        assert!(args.verify);
+        let config = parse_config("verify = true\n").expect("config");
+        let mut args = Args::default();
+        config.apply(&mut args);
+        assert!(args.verify);
+    }
+
+    #[test]
+    fn verify_checks_the_remote_against_recorded_checksums() {
+        let (changed, missing) = sums::parse_check(
+            "photos/a.jpg: FAILED\nphotos/b.jpg: FAILED open or read\nsha256sum: WARNING: 1 computed checksum did NOT match\n",
+        );
+        assert_eq!(changed, vec!["photos/a.jpg"]);
+        assert_eq!(missing, vec!["photos/b.jpg"]);
+
+        let args = Args {
+            no_multiplex: true,
+            ..Default::default()
+        };
+        let script = "sha() { if command -v sha256sum >/dev/null 2>&1; then sha256sum \"$@\"; else shasum -a 256 \"$@\"; fi; }; \
+cd \"$HOME/archive\" && test -f .syncz-sha256sums || exit 3; sha -c --quiet .syncz-sha256sums";
+        let mut expected = ssh_args(&args);
+        expected.push("nas".to_string());
+        expected.push(format!("sh -c {}", shell_escape(script)));
+        let runner = FakeRunner::new(vec![ExpectedCall {
+            program: "ssh".to_string(),
+            args: expected,
+            output: Some(Output {
+                status: std::process::ExitStatus::from_raw(1 << 8),
+                stdout: b"photos/a.jpg: FAILED\n".to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        }]);
+        let err = sums::verify(&runner, "nas", "~/archive", &args)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "1 file(s) on nas don't match their recorded checksums");
+
+        let cli = Cli::try_parse_from(["syncz", "verify", ".", "nas"]).expect("parse");
+        assert!(matches!(cli.command, Some(Commands::Verify(_))));
+    }
+
+    #[test]
+    fn estimate_uses_the_rate_of_recent_syncs() {
+        let entry =
+            |host: &str, direction: &str, bytes: u64, ms: u64, exit_code: i32| HistoryEntry {
+                timestamp: "2026-01-01T00:00:00+00:00".to_string(),
+                direction: direction.to_string(),
+                hosts: vec![host.to_string()],
+                path: PathBuf::from("/tmp/app"),
+                remote_path: None,
+                bytes,
+                duration_ms: ms,
+                exit_code,
+                error: None,
+                cwd: PathBuf::from("/tmp"),
+                argv: Vec::new(),
+            };
+        let history = vec![
+            entry("gpu", "push", 40 << 20, 4_000, 0),
+            entry("gpu", "push", 20 << 20, 2_000, 0),
+            entry("gpu", "push", 100, 10, 0),
+            entry("gpu", "push", 90 << 20, 1_000, 23),
+            entry("gpu", "pull", 90 << 20, 1_000, 0),
+            entry("nas", "push", 90 << 20, 1_000, 0),
+        ];
+        let (rate, runs) = history_rate(&history, "gpu", "push").expect("rate");
+        assert_eq!(runs, 2);
+        assert_eq!(rate as u64, 10 << 20);
+        assert!(history_rate(&history, "lab", "push").is_none());
+
+        assert_eq!(format_rate(45e6 / 8.0), "45 Mbit/s");
+        assert_eq!(format_rate(1.25e9 / 8.0), "1.2 Gbit/s");
+        assert_eq!(format_rate(64e3 / 8.0), "64 kbit/s");
+    }
+
+    #[test]
+    fn top_lists_the_biggest_transferred_files() {
+        let changes = parse_changes(
+            ">f+++++++++|small.txt|12\n>f.st......|runs/model.ckpt|8589934592\n\
+             .f...p.....|big-but-only-chmod.bin|999999999999\ncd+++++++++|runs/|4096\n\
+             *deleting  |old.bin|0\n>f+++++++++|data.csv|2048\n",
+        );
+        let paths: Vec<&str> = largest_files(&changes, 2)
+            .iter()
+            .map(|c| c.path.as_str())
+            .collect();
+        assert_eq!(paths, ["runs/model.ckpt", "data.csv"]);
+        assert_eq!(largest_files(&changes, 10).len(), 3);
+
+        let cli = Cli::try_parse_from(["syncz", "-d", "--top", "5"]).unwrap();
+        assert_eq!(cli.args.top, Some(5));
+        assert!(Cli::try_parse_from(["syncz", "-d", "--top", "0"]).is_err());
+    }
+
+    #[test]
+    fn bench_recommends_only_clear_wins() {
+        let ms = |list: [u64; 6]| -> Vec<Duration> {
+            list.iter().map(|&ms| Duration::from_millis(ms)).collect()
+        };
+        // A LAN: compression and the delta algorithm only cost CPU.
+        assert_eq!(
+            bench::recommend(&ms([900, 300, 800, 400, 390, 200])),
+            ["--no-compress", "--whole-file"]
+        );
+        // A slow link: -z wins and small deltas beat whole files.
+        assert!(bench::recommend(&ms([4000, 9000, 300, 350, 340, 9000])).is_empty());
+        // Within the margin is noise.
+        assert!(bench::recommend(&ms([1000, 950, 500, 500, 480, 470])).is_empty());
+        assert_eq!(
+            bench::config_lines(&["--no-compress".to_string(), "--whole-file".to_string()]),
+            ["compress = false", "whole_file = true"]
+        );
+        assert_eq!(
+            bench::config_lines(&["--block-size=131072".to_string()]),
+            ["block_size = 131072"]
+        );
+
+        let data = vec![0u8; (2 << 20) + 10];
+        let changed = bench::edited(&data);
+        assert_eq!(changed.len(), data.len());
+        assert_eq!(changed.iter().filter(|&&b| b != 0).count(), 64 + 64 + 10);
+
+        let config = parse_config("[hosts.nas]\nrsync_args = [\"--no-compress\"]\n").unwrap();
+        let mut args = Args::default();
+        config.hosts["nas"].apply(&mut args);
+        assert_eq!(args.rsync_args, ["--no-compress"]);
+    }
+
+    #[test]
+    fn compression_follows_the_link_and_the_content() {
+        let args = Args::default();
+        let rsync = base_rsync_args(&args, true);
+        assert_eq!(rsync[0], "-avzu");
+        let skip = rsync
+            .iter()
+            .find_map(|a| a.strip_prefix("--skip-compress="))
+            .expect("skip list");
+        assert!(skip.split('/').any(|e| e == "zst"));
+
+        let args = Args {
+            compress: Some(false),
+            force: true,
+            ..Default::default()
+        };
+        let rsync = base_rsync_args(&args, true);
+        assert_eq!(rsync[0], "-av");
+        assert!(!rsync.iter().any(|a| a.starts_with("--skip-compress")));
+
+        let args = Args {
+            compress_choice: Some("zstd".to_string()),
+            compress_level: Some(3),
+            skip_compress: vec![".ckpt".to_string()],
+            ..Default::default()
+        };
+        let rsync = base_rsync_args(&args, true);
+        assert!(rsync
+            .iter()
+            .any(|a| a.starts_with("--skip-compress=") && a.ends_with("/ckpt")));
+        assert!(rsync.contains(&"--compress-choice=zstd".to_string()));
+        assert!(rsync.contains(&"--compress-level=3".to_string()));
+        let runner = FakeRunner::new(Vec::new());
+        assert!(use_compression(&runner, "nas", &args));
+
+        let ssh_g = |stdout: &str| ExpectedCall {
+            program: "ssh".to_string(),
+            args: vec!["-G".to_string(), "nas".to_string()],
+            output: Some(Output {
+                status: ok_status(),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        };
+        let runner = FakeRunner::new(vec![
+            ssh_g("hostname 192.168.1.20\nproxyjump none\n"),
+            ssh_g("hostname 10.0.0.5\nproxyjump me@gateway\n"),
+            ssh_g("hostname 203.0.113.9\n"),
+        ]);
+        assert!(on_local_network(&runner, "nas", &Args::default()));
+        assert!(!on_local_network(&runner, "nas", &Args::default()));
+        assert!(!on_local_network(&runner, "nas", &Args::default()));
+
+        assert!(is_lan_address("127.0.0.1".parse().unwrap()));
+        assert!(is_lan_address("172.20.1.1".parse().unwrap()));
+        assert!(is_lan_address("fe80::1".parse().unwrap()));
+        assert!(is_lan_address("fd12::1".parse().unwrap()));
+        assert!(!is_lan_address("100.101.1.2".parse().unwrap()));
+        assert!(!is_lan_address("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn delta_tuning_flags_come_from_the_host_unless_overridden() {
+        let config = parse_config(
+            "[hosts.nas]\nwhole_file = true\n\n[hosts.vps]\nblock_size = 65536\nfuzzy = true\n",
+        )
+        .unwrap();
+        let mut args = Args::default();
+        config.hosts["nas"].apply(&mut args);
+        let rsync = base_rsync_args(&args, true);
+        assert!(rsync.contains(&"--whole-file".to_string()));
+        assert!(!rsync.iter().any(|a| a.starts_with("--block-size")));
+
+        let mut args = Args {
+            block_size: Some(8192),
+            ..Default::default()
+        };
+        config.hosts["nas"].apply(&mut args);
+        assert!(!args.whole_file);
+
+        let mut args = Args::default();
+        config.hosts["vps"].apply(&mut args);
+        let rsync = base_rsync_args(&args, true);
+        assert!(rsync.contains(&"--block-size=65536".to_string()));
+        assert!(rsync.contains(&"--fuzzy".to_string()));
+
+        let mut args = Args {
+            whole_file: true,
+            ..Default::default()
+        };
+        config.hosts["vps"].apply(&mut args);
+        assert_eq!(args.block_size, None);
+
+        assert!(Cli::try_parse_from(["syncz", "-W", "--block-size", "4096"]).is_err());
+        assert!(Cli::try_parse_from(["syncz", "--block-size", "1000000"]).is_err());
+    }
+
+    #[test]
+    fn dry_run_pairs_deleted_and_new_files_with_the_same_contents() {
+        let removed = parse_sized_sums(
+            "4096 aaaa  models/old.ckpt\n4096 bbbb  notes.txt\n12 cccc  a b.txt\n",
+        );
+        assert_eq!(removed[2], (12, "cccc".to_string(), "a b.txt".to_string()));
+        let sent =
+            parse_sized_sums("4096 aaaa  models/new.ckpt\n4096 dddd  other.txt\n12 cccc  c.txt\n");
+        assert_eq!(
+            pair_renames(&removed, &sent),
+            [
+                Rename {
+                    from: "models/old.ckpt".to_string(),
+                    to: "models/new.ckpt".to_string(),
+                    size: 4096
+                },
+                Rename {
+                    from: "a b.txt".to_string(),
+                    to: "c.txt".to_string(),
+                    size: 12
+                },
+            ]
+        );
+
+        let changes = parse_changes(
+            ">f+++++++++|models/new.ckpt|4096\n>f+++++++++|fresh.txt|99\n*deleting  |models/old.ckpt|0\n",
+        );
+        let deletions = vec!["models/old.ckpt".to_string()];
+        let call = |program: &str, args: Vec<String>, stdout: &str| ExpectedCall {
+            program: program.to_string(),
+            args,
+            output: Some(Output {
+                status: ok_status(),
+                stdout: stdout.as_bytes().to_vec(),
+                stderr: Vec::new(),
+            }),
+            status: None,
+        };
+        let mut remote = ssh_args(&Args::default());
+        remote.push("box".to_string());
+        remote.push(format!(
+            "sh -c {} sh '99 4096' \"$HOME/app\" {}",
+            shell_escape(&sized_sums_script()),
+            shell_escape("models/old.ckpt")
+        ));
+        let local: Vec<String> = vec![
+            "-c".to_string(),
+            sized_sums_script(),
+            "sh".to_string(),
+            "4096".to_string(),
+            "/home/me/app".to_string(),
+            "models/new.ckpt".to_string(),
+        ];
+        let runner = FakeRunner::new(vec![
+            call("ssh", remote, "4096 aaaa  models/old.ckpt\n"),
+            call("sh", local, "4096 aaaa  models/new.ckpt\n"),
+        ]);
+        let renames = detect_renames(
+            &runner,
+            &Side::local(Path::new("/home/me/app")),
+            &Side::new("box", "~/app"),
+            &changes,
+            &deletions,
+            &Args::default(),
+        )
+        .expect("renames");
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].to, "models/new.ckpt");
+
+        let args = Args {
+            delete: true,
+            fuzzy: true,
+            ..Default::default()
+        };
+        assert!(base_rsync_args(&args, false).contains(&"--delete-delay".to_string()));
+    }
+
+    #[test]
+    fn files_from_paths_are_made_relative_to_the_synced_directory() {
+        let root = Path::new("/home/me/app");
+        assert_eq!(
+            parse_file_list("src/main.rs\n./README.md\r\n\nsrc/main.rs\n", root, root).unwrap(),
+            ["src/main.rs", "README.md"]
+        );
+        // `fd -0` run from a subdirectory, and an absolute path.
+        assert_eq!(
+            parse_file_list(
+                "lib.rs\0../docs/a b.md\0/home/me/app/Cargo.toml\0",
+                root,
+                &root.join("src")
+            )
+            .unwrap(),
+            ["src/lib.rs", "docs/a b.md", "Cargo.toml"]
+        );
+        let err = parse_file_list("../other/x\n", root, root)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "/home/me/other/x (from --files-from) is outside /home/me/app"
+        );
+
+        assert!(Cli::try_parse_from(["syncz", "--files-from", "-", "--delete"]).is_err());
+        // Listed directories go whole, so a worker would resend the others' files.
+        assert!(
+            Cli::try_parse_from(["syncz", "--files-from", "-", "--parallel", "4", "box"]).is_err()
+        );
+        let cli = Cli::try_parse_from(["syncz", "--files-from", "list.txt", "box"]).unwrap();
+        assert_eq!(cli.args.files_from.as_deref(), Some("list.txt"));
+    }
+
+    #[test]
+    fn several_paths_sync_as_a_list_under_their_common_directory() {
+        let dir = std::env::temp_dir().join(format!("syncz-paths-{}", std::process::id()));
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::create_dir_all(dir.join("configs")).unwrap();
+        fs::create_dir_all(dir.join("scripts")).unwrap();
+        fs::write(dir.join("scripts/deploy.sh"), "#!/bin/sh\n").unwrap();
+        let at = |p: &str| dir.join(p).to_string_lossy().to_string();
+
+        let cli = Cli::try_parse_from([
+            "syncz".to_string(),
+            at("src"),
+            at("configs"),
+            at("scripts/deploy.sh"),
+            "box".to_string(),
+            "box2".to_string(),
+        ])
+        .unwrap();
+        let mut args = cli.args;
+        let extra = take_extra_paths(&mut args);
+        assert_eq!(extra, [at("configs"), at("scripts/deploy.sh")]);
+        assert_eq!(args.host.as_deref(), Some("box"));
+        assert_eq!(args.extra_hosts, ["box2"]);
+        combine_paths(&mut args, &[vec![at("src")], extra].concat()).unwrap();
+        assert_eq!(args.path, Some(dir.to_string_lossy().to_string()));
+        assert_eq!(
+            args.listed_files.unwrap(),
+            ["src", "configs", "scripts/deploy.sh"]
+        );
+
+        // A positional that only happens to exist locally (tests run in the
+        // crate root) is still a host.
+        let cli = Cli::try_parse_from([
+            "syncz".to_string(),
+            at("src"),
+            "src".to_string(),
+            "box".to_string(),
+        ])
+        .unwrap();
+        let mut args = cli.args;
+        assert!(take_extra_paths(&mut args).is_empty());
+        assert_eq!(args.host.as_deref(), Some("src"));
+        assert_eq!(args.extra_hosts, ["box"]);
+
+        let (root, listed) = common_root(&[dir.join("scripts/deploy.sh"), dir.join("scripts")]);
+        assert_eq!(root, dir.join("scripts"));
+        assert_eq!(listed, ["deploy.sh", "."]);
+
+        let mut args = Args {
+            delete: true,
+            ..Default::default()
+        };
+        let err = combine_paths(&mut args, &[at("src"), at("configs")])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--delete can't be combined with several paths");
+        let mut args = Args {
+            parallel: Some(4),
+            ..Default::default()
+        };
+        let err = combine_paths(&mut args, &[at("src"), at("configs")])
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "--parallel can't be combined with several paths");
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn exclude_files_and_syncignore_add_to_the_excludes() {
+        assert_eq!(
+            exclude_patterns("*.ckpt\r\n# weights\n\n; old\nwandb/\n"),
+            ["*.ckpt", "wandb/"]
+        );
+        assert_eq!(
+            syncignore_rules(
+                "- *.ckpt\n+ data/keep.csv\ndata/\n-/ /scratch\nexclude *.tmp\n!\n.env\n"
+            ),
+            [
+                "- *.ckpt",
+                "+ data/keep.csv",
+                "- data/",
+                "-/ /scratch",
+                "exclude *.tmp",
+                "!",
+                "- .env"
+            ]
+        );
+
+        let root = std::env::temp_dir().join(format!("syncz-syncignore-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        assert!(syncignore_filters(&root).unwrap().is_empty());
+        fs::write(root.join(".syncignore"), "+ keep.log\n*.log\n").unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        let mut filters = syncignore_filters(&root).unwrap();
+        filters.extend(gitignore_filters(&root, false).unwrap());
+        assert_eq!(
+            filters,
+            [
+                "--filter=+ keep.log",
+                "--filter=- *.log",
+                "--filter=- *.log"
+            ]
+        );
+        let _ = fs::remove_dir_all(&root);
+
+        let cli = Cli::try_parse_from([
+            "syncz",
+            "--exclude-from",
+            "a.txt",
+            "--exclude-from",
+            "b.txt",
+        ])
+        .unwrap();
+        assert_eq!(cli.args.exclude_from.len(), 2);
+    }
+}
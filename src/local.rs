@@ -0,0 +1,125 @@
+//! `--to PATH`: syncs between two local directories, such as a project and
+//! its backup on an external drive. rsync runs without a remote shell, so
+//! there is no host; the excludes, dry-run tree, deletion prompt and progress
+//! display are the ones remote syncs use.
+
+use crate::{
+    confirm_mirror, direction_name, emit, guard_transfer_size, paths_overlap, report_dry_run,
+    run_dry_run, run_rsync, verify_transfer, Args, Backend, CommandRunner,
+};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+// rsync's endpoints take a host prefix; none means a local path.
+const NO_HOST: &str = "";
+
+// A `--to` value naming a path rather than one of the project's remotes,
+// whose names are only letters, digits, `-` and `_`.
+pub(crate) fn is_path(to: &str) -> bool {
+    to.contains('/') || to.starts_with('.') || to.starts_with('~')
+}
+
+// Everything that needs a remote shell on the other side.
+fn unsupported(args: &Args) -> Option<&'static str> {
+    if args.backend.is_some_and(|b| b != Backend::Rsync) {
+        Some("--backend")
+    } else if args.both {
+        Some("--both")
+    } else if args.watch {
+        Some("--watch")
+    } else if args.status {
+        Some("status")
+    } else if args.snapshot {
+        Some("snapshot")
+    } else if args.undo {
+        Some("undo")
+    } else if args.shell {
+        Some("shell")
+    } else if args.diff {
+        Some("diff")
+    } else if !args.exec.is_empty() {
+        Some("exec")
+    } else if args.post_cmd.is_some() {
+        Some("--post-cmd")
+    } else if args.remote_sudo {
+        Some("--remote-sudo")
+    } else if args.select || args.interactive {
+        Some("--select")
+    } else if args.since.is_some() || args.git_tracked {
+        Some("--since")
+    } else if args.listed_files.is_some() {
+        Some("--files-from (or several paths)")
+    } else if args.parallel.is_some() {
+        Some("--parallel")
+    } else if args.encrypt.is_some() {
+        Some("--encrypt")
+    } else {
+        None
+    }
+}
+
+// Copies `local_path` into `dest`, or back from it with --pull. Unlike a
+// remote sync, no direction means one way: a backup, not a round trip.
+pub(crate) fn sync(
+    runner: &dyn CommandRunner,
+    local_path: &Path,
+    dest: &Path,
+    args: &Args,
+) -> Result<()> {
+    if let Some(flag) = unsupported(args) {
+        bail!("{} isn't supported with --to PATH", flag);
+    }
+    if paths_overlap(local_path, dest) {
+        bail!(
+            "{} overlaps {}; syncing a directory with itself or a directory around it would copy files onto themselves",
+            dest.display(),
+            local_path.display()
+        );
+    }
+    let pulling = args.pull;
+    let (from, to) = if pulling {
+        (dest, local_path)
+    } else {
+        (local_path, dest)
+    };
+    if !from.exists() {
+        bail!("{} doesn't exist", from.display());
+    }
+    let is_file = from.is_file();
+    let dest_str = dest.to_string_lossy().to_string();
+    if args.json {
+        emit(json!({
+            "event": "endpoints",
+            "direction": direction_name(pulling),
+            "source": from.display().to_string(),
+            "destination": to.display().to_string(),
+        }));
+    }
+
+    if args.dry_run {
+        let summary = run_dry_run(
+            runner, NO_HOST, local_path, &dest_str, is_file, args, pulling, None,
+        )?;
+        report_dry_run(&summary, NO_HOST, args, pulling);
+        return Ok(());
+    }
+
+    let dir = if is_file { to.parent() } else { Some(to) };
+    if let Some(dir) = dir {
+        fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+    if args.delete || args.max_total_size.is_some() {
+        let preview = run_dry_run(
+            runner, NO_HOST, local_path, &dest_str, is_file, args, pulling, None,
+        )?;
+        confirm_mirror(&preview, NO_HOST, local_path, &dest_str, args, pulling)?;
+        guard_transfer_size(&preview, args, pulling)?;
+    }
+    run_rsync(NO_HOST, local_path, &dest_str, is_file, args, pulling, None)?;
+    if args.verify && !pulling {
+        verify_transfer(runner, NO_HOST, local_path, &dest_str, is_file, args, None)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,599 @@
+//! `--backend native` (experimental): delta transfers without rsync on either
+//! end. syncz logs in with libssh2 like `--backend sftp`, starts `syncz
+//! server` on the remote, and the two sides run the rsync algorithm from
+//! `delta` themselves. Remotes without syncz get whole-file sftp copies.
+//!
+//! Each message is a JSON line, followed by `len` bytes of payload when the
+//! message has one. File contents travel as a stream of delta frames after
+//! a `patch` request or a `delta` reply.
+
+use crate::sftp::{self, is_excluded, local_entries, max_size};
+use crate::{delta, emit, format_size, remote_shell_path, Args, CommandRunner};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tracing::info;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    // Every file and directory under the root, or the root itself if it's a file.
+    List,
+    // The signature of a file, unless it already has this size and mtime.
+    Signature {
+        path: String,
+        size: u64,
+        mtime: i64,
+    },
+    // Rebuild a file from the delta frames that follow.
+    Patch {
+        path: String,
+        mtime: i64,
+        mode: Option<u32>,
+    },
+    Mkdir {
+        path: String,
+    },
+    // Delta frames of a file against the signature in the payload.
+    Delta {
+        path: String,
+        len: u64,
+    },
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct Reply {
+    error: Option<String>,
+    len: u64,
+    same: bool,
+    mtime: i64,
+    mode: Option<u32>,
+    entries: Vec<RemoteEntry>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RemoteEntry {
+    path: String,
+    is_dir: bool,
+    size: u64,
+    mtime: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    version: String,
+}
+
+// What a transfer moved: files updated, their total size and the bytes the
+// deltas actually took.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct NativeStats {
+    pub(crate) files: usize,
+    pub(crate) size: u64,
+    pub(crate) sent: u64,
+}
+
+fn write_message(writer: &mut impl Write, message: &impl Serialize, payload: &[u8]) -> Result<()> {
+    serde_json::to_writer(&mut *writer, message)?;
+    writer.write_all(b"\n")?;
+    writer.write_all(payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+fn read_message<T: for<'de> Deserialize<'de>>(reader: &mut impl BufRead) -> Result<Option<T>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(serde_json::from_str(&line).with_context(|| {
+        format!("unexpected message from syncz server: {}", line.trim())
+    })?))
+}
+
+fn read_payload(reader: &mut impl Read, len: u64) -> Result<Vec<u8>> {
+    let mut payload = Vec::new();
+    reader.take(len).read_to_end(&mut payload)?;
+    if payload.len() as u64 != len {
+        bail!("connection closed in the middle of a transfer");
+    }
+    Ok(payload)
+}
+
+// Serves requests for files under `root` until the client hangs up. Failures
+// on a single file are reported back instead of ending the session.
+pub(crate) fn serve(root: &Path, mut input: impl BufRead, mut output: impl Write) -> Result<()> {
+    let hello = Hello {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    };
+    write_message(&mut output, &hello, &[])?;
+    while let Some(request) = read_message::<Request>(&mut input)? {
+        let result = match request {
+            Request::Patch { path, mtime, mode } => patch(root, &path, mtime, mode, &mut input),
+            Request::Delta { path, len } => {
+                let signature = read_payload(&mut input, len)?;
+                match open_delta(root, &path, &signature) {
+                    Ok((file, signature, reply)) => {
+                        write_message(&mut output, &reply, &[])?;
+                        delta::send_delta(&signature, file, &mut output)?;
+                        output.flush()?;
+                        continue;
+                    }
+                    Err(err) => Err(err),
+                }
+            }
+            request => handle(root, request),
+        };
+        let (reply, data) = result.unwrap_or_else(|err| {
+            let reply = Reply {
+                error: Some(format!("{:#}", err)),
+                ..Default::default()
+            };
+            (reply, Vec::new())
+        });
+        write_message(&mut output, &reply, &data)?;
+    }
+    Ok(())
+}
+
+fn handle(root: &Path, request: Request) -> Result<(Reply, Vec<u8>)> {
+    match request {
+        Request::List => {
+            let mut entries = Vec::new();
+            if !root.exists() {
+                bail!("{} does not exist", root.display());
+            } else if root.is_file() {
+                entries.push(remote_entry(root, String::new())?);
+            } else {
+                let all = Args {
+                    all: true,
+                    ..Default::default()
+                };
+                for entry in local_entries(root, &all)? {
+                    entries.push(remote_entry(&root.join(&entry.path), entry.path)?);
+                }
+            }
+            Ok((
+                Reply {
+                    entries,
+                    ..Default::default()
+                },
+                Vec::new(),
+            ))
+        }
+        Request::Signature { path, size, mtime } => {
+            let target = resolve(root, &path)?;
+            if let Ok(meta) = fs::metadata(&target) {
+                if meta.is_file() && meta.len() == size && mtime_of(&meta) == mtime {
+                    let reply = Reply {
+                        same: true,
+                        ..Default::default()
+                    };
+                    return Ok((reply, Vec::new()));
+                }
+            }
+            let signature = delta::signature(open_or_empty(&target)?)?;
+            let reply = Reply {
+                len: signature.len() as u64,
+                ..Default::default()
+            };
+            Ok((reply, signature))
+        }
+        Request::Mkdir { path } => {
+            let target = resolve(root, &path)?;
+            fs::create_dir_all(&target)
+                .with_context(|| format!("failed to create {}", target.display()))?;
+            Ok((Reply::default(), Vec::new()))
+        }
+        Request::Patch { .. } | Request::Delta { .. } => unreachable!("streamed by serve"),
+    }
+}
+
+// Rebuilds a file from the frames on `input`, which are read to the end even
+// when the file can't be written.
+fn patch(
+    root: &Path,
+    path: &str,
+    mtime: i64,
+    mode: Option<u32>,
+    input: &mut impl Read,
+) -> Result<(Reply, Vec<u8>)> {
+    let mut pending = true;
+    let result = resolve(root, path).and_then(|target| {
+        write_file(&target, mtime, mode, |file| {
+            let base = open_or_empty(&target)?;
+            pending = false;
+            delta::receive_delta(base, input, file)
+        })
+    });
+    if pending {
+        delta::skip(input)?;
+    }
+    result.map(|_| (Reply::default(), Vec::new()))
+}
+
+fn open_delta(
+    root: &Path,
+    path: &str,
+    signature: &[u8],
+) -> Result<(BufReader<fs::File>, Vec<fast_rsync::Signature>, Reply)> {
+    let target = resolve(root, path)?;
+    let file =
+        fs::File::open(&target).with_context(|| format!("failed to read {}", target.display()))?;
+    let meta = file.metadata()?;
+    let reply = Reply {
+        mtime: mtime_of(&meta),
+        mode: mode_of(&meta),
+        ..Default::default()
+    };
+    Ok((
+        BufReader::new(file),
+        delta::decode_signature(signature)?,
+        reply,
+    ))
+}
+
+fn remote_entry(path: &Path, rel: String) -> Result<RemoteEntry> {
+    let meta = fs::metadata(path).with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(RemoteEntry {
+        path: rel,
+        is_dir: meta.is_dir(),
+        size: if meta.is_dir() { 0 } else { meta.len() },
+        mtime: mtime_of(&meta),
+    })
+}
+
+// Paths come from the other end, so they must stay inside the root. An empty
+// path is the root itself.
+fn resolve(root: &Path, rel: &str) -> Result<PathBuf> {
+    let rel = Path::new(rel);
+    if !rel.components().all(|c| matches!(c, Component::Normal(_))) {
+        bail!("refusing path outside the sync root: {}", rel.display());
+    }
+    Ok(root.join(rel))
+}
+
+// The old copy of a file to diff against; a missing file is empty.
+fn open_or_empty(path: &Path) -> Result<Box<dyn Read>> {
+    match fs::File::open(path) {
+        Ok(file) => Ok(Box::new(BufReader::new(file))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Box::new(io::empty())),
+        Err(err) => Err(err).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+// Lets `fill` write next to the target and renames over it, so an interrupted
+// transfer never leaves a half-written file behind.
+fn write_file<T>(
+    target: &Path,
+    mtime: i64,
+    mode: Option<u32>,
+    fill: impl FnOnce(&mut fs::File) -> Result<T>,
+) -> Result<T> {
+    let parent = target
+        .parent()
+        .ok_or_else(|| anyhow!("unable to resolve parent of {}", target.display()))?;
+    fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+    let name = target
+        .file_name()
+        .ok_or_else(|| anyhow!("invalid file name: {}", target.display()))?;
+    let tmp = parent.join(format!(".{}.syncz-tmp", name.to_string_lossy()));
+    let mut file =
+        fs::File::create(&tmp).with_context(|| format!("failed to write {}", tmp.display()))?;
+    let result = fill(&mut file).and_then(|value| {
+        file.set_modified(UNIX_EPOCH + Duration::from_secs(mtime.max(0) as u64))?;
+        #[cfg(unix)]
+        if let Some(mode) = mode {
+            use std::os::unix::fs::PermissionsExt;
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        #[cfg(not(unix))]
+        let _ = mode;
+        drop(file);
+        fs::rename(&tmp, target)
+            .with_context(|| format!("failed to update {}", target.display()))?;
+        Ok(value)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    result
+}
+
+fn mtime_of(meta: &fs::Metadata) -> i64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |d| d.as_secs() as i64)
+}
+
+#[cfg(unix)]
+fn mode_of(meta: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+    Some(meta.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn mode_of(_meta: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+// The client end of a `syncz server` session.
+pub(crate) struct Connection<R, W> {
+    reader: R,
+    writer: W,
+}
+
+impl<R: BufRead, W: Write> Connection<R, W> {
+    // Waits for the server's greeting; `None` means it never started.
+    pub(crate) fn open(mut reader: R, writer: W) -> Result<Option<Self>> {
+        let Some(hello) = read_message::<Hello>(&mut reader)? else {
+            return Ok(None);
+        };
+        info!(version = hello.version, "remote syncz server");
+        Ok(Some(Connection { reader, writer }))
+    }
+
+    fn request(&mut self, request: &Request, payload: &[u8]) -> Result<(Reply, Vec<u8>)> {
+        write_message(&mut self.writer, request, payload)?;
+        let reply = self.reply()?;
+        let data = read_payload(&mut self.reader, reply.len)?;
+        Ok((reply, data))
+    }
+
+    fn reply(&mut self) -> Result<Reply> {
+        let reply: Reply = read_message(&mut self.reader)?
+            .ok_or_else(|| anyhow!("syncz server closed the connection"))?;
+        if let Some(error) = reply.error {
+            bail!("{}", error);
+        }
+        Ok(reply)
+    }
+
+    // Sends everything under `local_path` that the remote copy lacks or has
+    // with a different size or mtime.
+    pub(crate) fn push(&mut self, local_path: &Path, args: &Args) -> Result<NativeStats> {
+        let mut stats = NativeStats::default();
+        if !local_path.is_dir() {
+            self.push_file(local_path, String::new(), args, &mut stats)?;
+            return Ok(stats);
+        }
+        self.request(
+            &Request::Mkdir {
+                path: String::new(),
+            },
+            &[],
+        )?;
+        for entry in local_entries(local_path, args)? {
+            if entry.is_dir {
+                self.request(&Request::Mkdir { path: entry.path }, &[])?;
+            } else {
+                let source = local_path.join(&entry.path);
+                self.push_file(&source, entry.path, args, &mut stats)?;
+            }
+        }
+        Ok(stats)
+    }
+
+    fn push_file(
+        &mut self,
+        source: &Path,
+        path: String,
+        args: &Args,
+        stats: &mut NativeStats,
+    ) -> Result<()> {
+        let meta =
+            fs::metadata(source).with_context(|| format!("failed to read {}", source.display()))?;
+        let mtime = mtime_of(&meta);
+        let (reply, signature) = self.request(
+            &Request::Signature {
+                path: path.clone(),
+                size: meta.len(),
+                mtime,
+            },
+            &[],
+        )?;
+        if reply.same {
+            return Ok(());
+        }
+        let signature = delta::decode_signature(&signature)?;
+        let file = fs::File::open(source)
+            .with_context(|| format!("failed to read {}", source.display()))?;
+        let mode = if args.no_perms { None } else { mode_of(&meta) };
+        write_message(&mut self.writer, &Request::Patch { path, mtime, mode }, &[])?;
+        let sent = delta::send_delta(&signature, BufReader::new(file), &mut self.writer)?;
+        self.writer.flush()?;
+        self.reply()?;
+        stats.files += 1;
+        stats.size += meta.len();
+        stats.sent += sent;
+        Ok(())
+    }
+
+    // Fetches what differs on the remote into `local_path`, skipping what a
+    // push would exclude.
+    pub(crate) fn pull(&mut self, local_path: &Path, args: &Args) -> Result<NativeStats> {
+        let max_size = max_size(args)?;
+        let (reply, _) = self.request(&Request::List, &[])?;
+        let mut stats = NativeStats::default();
+        for entry in reply.entries {
+            let target = if entry.path.is_empty() {
+                local_path.to_path_buf()
+            } else {
+                resolve(local_path, &entry.path)?
+            };
+            if entry.is_dir {
+                if !skipped(&entry.path, true, args) {
+                    fs::create_dir_all(&target)
+                        .with_context(|| format!("failed to create {}", target.display()))?;
+                }
+                continue;
+            }
+            if (!entry.path.is_empty() && skipped(&entry.path, false, args))
+                || max_size.is_some_and(|max| entry.size > max)
+            {
+                continue;
+            }
+            if let Ok(meta) = fs::metadata(&target) {
+                if meta.len() == entry.size && mtime_of(&meta) == entry.mtime {
+                    continue;
+                }
+            }
+            let signature = delta::signature(open_or_empty(&target)?)?;
+            let request = Request::Delta {
+                path: entry.path,
+                len: signature.len() as u64,
+            };
+            write_message(&mut self.writer, &request, &signature)?;
+            let reply = self.reply()?;
+            let mode = if args.no_perms { None } else { reply.mode };
+            let reader = &mut self.reader;
+            let (sent, size) = write_file(&target, reply.mtime, mode, |file| {
+                delta::receive_delta(open_or_empty(&target)?, reader, file)
+            })?;
+            stats.files += 1;
+            stats.size += size;
+            stats.sent += sent;
+        }
+        Ok(stats)
+    }
+}
+
+// The server lists everything, so entries inside an excluded directory have
+// to be skipped here too.
+fn skipped(path: &str, is_dir: bool, args: &Args) -> bool {
+    let mut prefix = String::new();
+    for part in path.split('/') {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(part);
+        let dir = is_dir || prefix.len() < path.len();
+        if is_excluded(&prefix, dir, args) {
+            return true;
+        }
+    }
+    false
+}
+
+pub(crate) fn push(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    match with_server(runner, host, remote_path, args, |conn| {
+        conn.push(local_path, args)
+    })? {
+        Some(stats) => report("push", host, &stats, args),
+        None => {
+            eprintln!("{}", without_server(host));
+            return sftp::push(runner, host, local_path, remote_path, args);
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn pull(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    match with_server(runner, host, remote_path, args, |conn| {
+        conn.pull(local_path, args)
+    })? {
+        Some(stats) => report("pull", host, &stats, args),
+        None => {
+            eprintln!("{}", without_server(host));
+            return sftp::pull(runner, host, local_path, remote_path, args);
+        }
+    }
+    Ok(())
+}
+
+fn without_server(host: &str) -> String {
+    format!(
+        "⚠️  syncz isn't installed on {}; copying whole files over sftp instead",
+        host
+    )
+}
+
+fn report(direction: &str, host: &str, stats: &NativeStats, args: &Args) {
+    if args.json {
+        emit(json!({
+            "event": "transfer",
+            "direction": direction,
+            "host": host,
+            "backend": "native",
+            "files": stats.files,
+            "total_bytes": stats.size,
+            "sent_bytes": stats.sent,
+        }));
+    } else {
+        let verb = if direction == "push" { "to" } else { "from" };
+        println!(
+            "Updated {} file(s) ({}) {} {}, {} transferred",
+            stats.files,
+            format_size(stats.size),
+            verb,
+            host,
+            format_size(stats.sent)
+        );
+    }
+}
+
+// Starts `syncz server` on the host over an ssh2 session and runs `session`
+// against it; `None` when the host has no syncz. The remote shell may not
+// have ~/.cargo/bin on its PATH, so it's added.
+fn with_server<T>(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+    session: impl FnOnce(&mut Connection<BufReader<ssh2::Stream>, ssh2::Stream>) -> Result<T>,
+) -> Result<Option<T>> {
+    let ssh = sftp::open_session(runner, host, args)?;
+    let mut channel = ssh
+        .session
+        .channel_session()
+        .with_context(|| format!("failed to open an ssh channel to {}", host))?;
+    let command = format!(
+        "PATH=\"$PATH:$HOME/.cargo/bin\" syncz server {}",
+        remote_shell_path(remote_path)
+    );
+    info!(host, command, "running");
+    channel
+        .exec(&command)
+        .with_context(|| format!("failed to start syncz server on {}", host))?;
+    let opened = Connection::open(BufReader::new(channel.stream(0)), channel.stream(0))?;
+    let Some(mut conn) = opened else {
+        channel.wait_close()?;
+        match channel.exit_status()? {
+            127 => return Ok(None),
+            status => bail!(
+                "could not start syncz server on {} (exit status {})",
+                host,
+                status
+            ),
+        }
+    };
+    let started = Instant::now();
+    let result = session(&mut conn);
+    drop(conn);
+    let _ = channel.send_eof();
+    let _ = channel.wait_close();
+    info!(
+        status = channel.exit_status().unwrap_or(-1),
+        elapsed_ms = started.elapsed().as_millis() as u64,
+        "finished"
+    );
+    result.map(Some)
+}
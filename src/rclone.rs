@@ -0,0 +1,249 @@
+//! `--backend rclone`: cloud storage and anything else rclone can reach. HOST
+//! names an rclone remote (`s3`, `gdrive`, ...) and the remote path is a path
+//! inside it, such as `bucket/backups/app`. Path mapping, excludes and the
+//! dry-run tree work as with rsync; rclone does the copying.
+
+use crate::{
+    emit, report_dry_run, sftp, Args, Change, CommandRunner, DryRunSummary, DEFAULT_EXCLUDES,
+};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::io::IsTerminal;
+use std::path::Path;
+
+// What rclone can't do that the rsync backend does: the sftp list, except
+// that deletions are mirrored (with `rclone sync`), plus remote commands.
+pub(crate) fn unsupported(args: &Args) -> Option<&'static str> {
+    if !args.exec.is_empty() {
+        Some("exec")
+    } else if args.post_cmd.is_some() {
+        Some("--post-cmd")
+    } else {
+        sftp::unsupported(&Args {
+            delete: false,
+            ..args.clone()
+        })
+    }
+}
+
+// There is no ssh to preflight; instead make sure the remote is configured,
+// since rclone would otherwise treat `s3:bucket` as a local directory name.
+pub(crate) fn check(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<()> {
+    if let Some(flag) = unsupported(args) {
+        bail!("{} isn't supported with --backend rclone", flag);
+    }
+    let output = runner
+        .output("rclone", &["listremotes".to_string()])
+        .with_context(|| "failed to run rclone; is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "rclone listremotes failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let remotes = String::from_utf8_lossy(&output.stdout);
+    if !remotes
+        .lines()
+        .any(|r| r.trim().trim_end_matches(':') == host)
+    {
+        bail!(
+            "{} isn't an rclone remote; add it with `rclone config`",
+            host
+        );
+    }
+    Ok(())
+}
+
+pub(crate) fn push(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let source = local_path.to_string_lossy().to_string();
+    let target = rclone_path(host, remote_path);
+    transfer(
+        runner,
+        host,
+        &source,
+        &target,
+        local_path.is_file(),
+        args,
+        false,
+    )
+}
+
+pub(crate) fn pull(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let source = rclone_path(host, remote_path);
+    let target = local_path.to_string_lossy().to_string();
+    transfer(
+        runner,
+        host,
+        &source,
+        &target,
+        local_path.is_file(),
+        args,
+        true,
+    )
+}
+
+fn transfer(
+    runner: &dyn CommandRunner,
+    host: &str,
+    source: &str,
+    target: &str,
+    is_file: bool,
+    args: &Args,
+    pulling: bool,
+) -> Result<()> {
+    let mut cmd_args = rclone_args(source, target, is_file, args)?;
+    if args.dry_run {
+        cmd_args.push("--dry-run".to_string());
+        let output = runner
+            .output("rclone", &cmd_args)
+            .with_context(|| "failed to run rclone")?;
+        if !output.status.success() {
+            bail!(
+                "rclone dry run failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        let summary = parse_dry_run(&String::from_utf8_lossy(&output.stderr));
+        report_dry_run(&summary, host, args, pulling);
+        return Ok(());
+    }
+    if args.json {
+        cmd_args.push("--quiet".to_string());
+    } else if std::io::stdout().is_terminal() {
+        cmd_args.push("--progress".to_string());
+    }
+    let status = runner
+        .status("rclone", &cmd_args)
+        .with_context(|| "failed to run rclone")?;
+    if !status.success() {
+        bail!("rclone transfer with {} failed ({})", host, status);
+    }
+    let direction = if pulling { "pull" } else { "push" };
+    if args.json {
+        emit(json!({
+            "event": "transfer",
+            "direction": direction,
+            "host": host,
+            "backend": "rclone",
+        }));
+    } else if pulling {
+        println!("Downloaded {} via rclone", source);
+    } else {
+        println!("Uploaded to {} via rclone", target);
+    }
+    Ok(())
+}
+
+// rclone paths are relative to the remote's root, so `~` means the root.
+pub(crate) fn rclone_path(host: &str, remote_path: &str) -> String {
+    let path = match remote_path.strip_prefix('~') {
+        Some(rest) => rest.trim_start_matches('/'),
+        None => remote_path,
+    };
+    format!("{}:{}", host, path)
+}
+
+// `copy` (or `copyto` for a single file), or `sync` when deletions are
+// mirrored, with the rsync backend's excludes and size limit as filters.
+pub(crate) fn rclone_args(
+    source: &str,
+    target: &str,
+    is_file: bool,
+    args: &Args,
+) -> Result<Vec<String>> {
+    let command = if is_file {
+        "copyto"
+    } else if args.delete {
+        "sync"
+    } else {
+        "copy"
+    };
+    let mut list = vec![command.to_string(), source.to_string(), target.to_string()];
+    if !is_file {
+        for rule in filter_rules(args) {
+            list.push("--filter".to_string());
+            list.push(rule);
+        }
+    }
+    if let Some(max) = sftp::max_size(args)? {
+        list.push("--max-size".to_string());
+        list.push(format!("{}B", max));
+    }
+    Ok(list)
+}
+
+// rclone matches a pattern without a `/` against the name at any depth like
+// rsync does; a directory pattern has to name everything below it instead.
+pub(crate) fn filter_rules(args: &Args) -> Vec<String> {
+    let pattern = |p: &str| match p.strip_suffix('/') {
+        Some(dir) => format!("{}/**", dir),
+        None => p.to_string(),
+    };
+    let mut rules: Vec<String> = args
+        .include
+        .iter()
+        .map(|p| format!("+ {}", pattern(p)))
+        .collect();
+    let defaults = if args.all { &[][..] } else { DEFAULT_EXCLUDES };
+    rules.extend(
+        defaults
+            .iter()
+            .copied()
+            .chain(args.exclude.iter().map(String::as_str))
+            .map(|p| format!("- {}", pattern(p))),
+    );
+    if !args.only.is_empty() {
+        rules.extend(args.only.iter().map(|p| format!("+ {}", pattern(p))));
+        rules.push("- **".to_string());
+    }
+    rules
+}
+
+// `--dry-run` reports each skipped action on stderr, e.g.
+// `2024/05/01 10:00:00 NOTICE: src/main.rs: Skipped copy as --dry-run is set (size 1.2Ki)`.
+// Copies are new files, updates changed ones and deletes mirrored removals.
+pub(crate) fn parse_dry_run(stderr: &str) -> DryRunSummary {
+    let mut changes = Vec::new();
+    let mut deletions = Vec::new();
+    for line in stderr.lines() {
+        let Some((_, notice)) = line.split_once("NOTICE: ") else {
+            continue;
+        };
+        let Some((path, action)) = notice.rsplit_once(": Skipped ") else {
+            continue;
+        };
+        let code = match action.split(' ').next() {
+            Some("copy") => ">f+++++++++",
+            Some("update") => ">f.st......",
+            Some("delete") => {
+                deletions.push(path.to_string());
+                "*deleting"
+            }
+            _ => continue,
+        };
+        changes.push(Change {
+            code: code.to_string(),
+            path: path.to_string(),
+            size: None,
+        });
+    }
+    DryRunSummary {
+        transferred_line: None,
+        transferred_bytes: None,
+        deletions,
+        changes,
+        renames: Vec::new(),
+    }
+}
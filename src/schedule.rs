@@ -0,0 +1,367 @@
+//! `syncz schedule`: runs a job from a job file periodically through the
+//! system's own scheduler, a systemd user timer on Linux and a launchd agent
+//! on macOS, so there is no syncz process to keep alive in between.
+
+use crate::{
+    data_dir, parse_job_file, shell_escape, CommandRunner, ScheduleArgs, ScheduleCommand,
+    ScheduleInstallArgs,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Scheduler {
+    Systemd,
+    Launchd,
+}
+
+impl Scheduler {
+    fn current() -> Self {
+        if cfg!(target_os = "macos") {
+            Scheduler::Launchd
+        } else {
+            Scheduler::Systemd
+        }
+    }
+
+    fn unit_dir(self) -> Result<PathBuf> {
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+        Ok(match self {
+            Scheduler::Systemd => home.join(".config").join("systemd").join("user"),
+            Scheduler::Launchd => home.join("Library").join("LaunchAgents"),
+        })
+    }
+
+    // The file that holds the interval and that `list` looks for.
+    fn unit_file(self, job: &str) -> String {
+        match self {
+            Scheduler::Systemd => format!("syncz-{}.timer", job),
+            Scheduler::Launchd => format!("{}.plist", launchd_label(job)),
+        }
+    }
+
+    fn job_of(self, file_name: &str) -> Option<&str> {
+        match self {
+            Scheduler::Systemd => file_name.strip_prefix("syncz-")?.strip_suffix(".timer"),
+            Scheduler::Launchd => file_name.strip_prefix("com.syncz.")?.strip_suffix(".plist"),
+        }
+    }
+}
+
+pub(crate) fn run(runner: &dyn CommandRunner, opts: &ScheduleArgs) -> Result<()> {
+    let scheduler = Scheduler::current();
+    match &opts.command {
+        ScheduleCommand::Install(install_opts) => install(runner, scheduler, install_opts),
+        ScheduleCommand::List => list(scheduler),
+        ScheduleCommand::Remove { job } => remove(runner, scheduler, job),
+    }
+}
+
+fn install(
+    runner: &dyn CommandRunner,
+    scheduler: Scheduler,
+    opts: &ScheduleInstallArgs,
+) -> Result<()> {
+    check_job_name(&opts.job)?;
+    let secs = parse_interval(&opts.every)?;
+    let file = match &opts.file {
+        Some(file) => file.clone(),
+        None => crate::default_jobs_path()?,
+    };
+    let file = std::path::absolute(&file)?;
+    let contents =
+        fs::read_to_string(&file).with_context(|| format!("failed to read {}", file.display()))?;
+    let jobs = parse_job_file(&contents)
+        .with_context(|| format!("invalid job file {}", file.display()))?;
+    if !jobs.jobs.iter().any(|j| j.name == opts.job) {
+        bail!("no job named '{}' in {}", opts.job, file.display());
+    }
+    let exe = std::env::current_exe().with_context(|| "failed to locate the syncz binary")?;
+    let exe = exe.canonicalize().unwrap_or(exe);
+    let command = [
+        exe.display().to_string(),
+        "run".to_string(),
+        file.display().to_string(),
+        opts.job.clone(),
+    ];
+
+    let dir = scheduler.unit_dir()?;
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    match scheduler {
+        Scheduler::Systemd => {
+            let (service, timer) = systemd_units(&opts.job, &command, secs);
+            write_unit(&dir.join(format!("syncz-{}.service", opts.job)), &service)?;
+            write_unit(&dir.join(scheduler.unit_file(&opts.job)), &timer)?;
+            systemctl(runner, &["daemon-reload"])?;
+            systemctl(
+                runner,
+                &["enable", "--now", &scheduler.unit_file(&opts.job)],
+            )?;
+        }
+        Scheduler::Launchd => {
+            let path = dir.join(scheduler.unit_file(&opts.job));
+            let log = data_dir()?.join("logs").join(format!("{}.log", opts.job));
+            if let Some(parent) = log.parent() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("failed to create {}", parent.display()))?;
+            }
+            // Replacing a loaded agent needs it unloaded first.
+            if path.exists() {
+                let _ = launchctl(runner, "unload", &path);
+            }
+            write_unit(&path, &launchd_plist(&opts.job, &command, secs, &log))?;
+            launchctl(runner, "load", &path)?;
+        }
+    }
+    println!(
+        "⏰ Job '{}' runs every {} ({})",
+        opts.job,
+        format_interval(secs),
+        dir.join(scheduler.unit_file(&opts.job)).display()
+    );
+    Ok(())
+}
+
+fn list(scheduler: Scheduler) -> Result<()> {
+    let dir = scheduler.unit_dir()?;
+    let mut rows = Vec::new();
+    if let Ok(entries) = fs::read_dir(&dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let Some(job) = scheduler.job_of(&name) else {
+                continue;
+            };
+            let every = fs::read_to_string(entry.path())
+                .ok()
+                .and_then(|unit| unit_interval(scheduler, &unit))
+                .map_or_else(|| "?".to_string(), format_interval);
+            rows.push((job.to_string(), every));
+        }
+    }
+    if rows.is_empty() {
+        println!("No scheduled jobs");
+        return Ok(());
+    }
+    rows.sort();
+    let width = rows.iter().map(|(job, _)| job.len()).max().unwrap_or(0);
+    for (job, every) in rows {
+        println!("{:width$}  every {}", job, every, width = width);
+    }
+    Ok(())
+}
+
+fn remove(runner: &dyn CommandRunner, scheduler: Scheduler, job: &str) -> Result<()> {
+    check_job_name(job)?;
+    let dir = scheduler.unit_dir()?;
+    let path = dir.join(scheduler.unit_file(job));
+    if !path.exists() {
+        bail!("job '{}' isn't scheduled", job);
+    }
+    match scheduler {
+        Scheduler::Systemd => {
+            systemctl(runner, &["disable", "--now", &scheduler.unit_file(job)])?;
+            remove_unit(&path)?;
+            remove_unit(&dir.join(format!("syncz-{}.service", job)))?;
+            systemctl(runner, &["daemon-reload"])?;
+        }
+        Scheduler::Launchd => {
+            launchctl(runner, "unload", &path)?;
+            remove_unit(&path)?;
+        }
+    }
+    println!("Job '{}' is no longer scheduled", job);
+    Ok(())
+}
+
+// Job names become unit file names and labels.
+fn check_job_name(job: &str) -> Result<()> {
+    let valid = !job.is_empty()
+        && job
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        bail!(
+            "job name '{}' can't be scheduled; use letters, digits, '-' and '_'",
+            job
+        );
+    }
+    Ok(())
+}
+
+// "90s", "30m", "1h", "1d"; a bare number is seconds.
+pub(crate) fn parse_interval(s: &str) -> Result<u64> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, unit) = s.split_at(split);
+    let number: u64 = number
+        .parse()
+        .map_err(|_| anyhow!("invalid interval '{}' (expected e.g. 30m, 1h or 1d)", s))?;
+    let scale = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 3600,
+        "d" => 86400,
+        _ => bail!("invalid interval '{}' (expected e.g. 30m, 1h or 1d)", s),
+    };
+    if number == 0 {
+        bail!("the interval must be longer than zero");
+    }
+    Ok(number * scale)
+}
+
+pub(crate) fn format_interval(secs: u64) -> String {
+    for (unit, scale) in [("d", 86400), ("h", 3600), ("m", 60)] {
+        if secs.is_multiple_of(scale) {
+            return format!("{}{}", secs / scale, unit);
+        }
+    }
+    format!("{}s", secs)
+}
+
+// A oneshot service running the job and a timer starting it every `secs`.
+// OnBootSec makes the first run happen right away when the timer is enabled
+// after boot, and OnUnitActiveSec repeats it.
+pub(crate) fn systemd_units(job: &str, command: &[String], secs: u64) -> (String, String) {
+    let exec: Vec<String> = command.iter().map(|arg| systemd_quote(arg)).collect();
+    let service = format!(
+        "# Written by `syncz schedule install`; remove with `syncz schedule remove {job}`.\n\
+         [Unit]\n\
+         Description=syncz job {job}\n\
+         \n\
+         [Service]\n\
+         Type=oneshot\n\
+         ExecStart={}\n",
+        exec.join(" "),
+    );
+    let timer = format!(
+        "# Written by `syncz schedule install`; remove with `syncz schedule remove {job}`.\n\
+         [Unit]\n\
+         Description=Run syncz job {job} every {}\n\
+         \n\
+         [Timer]\n\
+         OnBootSec={secs}s\n\
+         OnUnitActiveSec={secs}s\n\
+         \n\
+         [Install]\n\
+         WantedBy=timers.target\n",
+        format_interval(secs),
+    );
+    (service, timer)
+}
+
+fn systemd_quote(arg: &str) -> String {
+    let arg = arg.replace('%', "%%");
+    if arg
+        .chars()
+        .any(|c| c.is_whitespace() || "\"'\\;$".contains(c))
+    {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg
+    }
+}
+
+fn launchd_label(job: &str) -> String {
+    format!("com.syncz.{}", job)
+}
+
+pub(crate) fn launchd_plist(job: &str, command: &[String], secs: u64, log: &Path) -> String {
+    let arguments: String = command
+        .iter()
+        .map(|arg| format!("        <string>{}</string>\n", xml_escape(arg)))
+        .collect();
+    let log = xml_escape(&log.display().to_string());
+    format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n\
+         <plist version=\"1.0\">\n\
+         <dict>\n\
+         \x20   <key>Label</key>\n\
+         \x20   <string>{}</string>\n\
+         \x20   <key>ProgramArguments</key>\n\
+         \x20   <array>\n\
+         {arguments}\
+         \x20   </array>\n\
+         \x20   <key>StartInterval</key>\n\
+         \x20   <integer>{secs}</integer>\n\
+         \x20   <key>RunAtLoad</key>\n\
+         \x20   <true/>\n\
+         \x20   <key>StandardOutPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         \x20   <key>StandardErrorPath</key>\n\
+         \x20   <string>{log}</string>\n\
+         </dict>\n\
+         </plist>\n",
+        launchd_label(job),
+    )
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// The interval a unit written by `install` runs at.
+pub(crate) fn unit_interval(scheduler: Scheduler, unit: &str) -> Option<u64> {
+    match scheduler {
+        Scheduler::Systemd => unit
+            .lines()
+            .find_map(|l| l.strip_prefix("OnUnitActiveSec="))?
+            .strip_suffix('s')?
+            .parse()
+            .ok(),
+        Scheduler::Launchd => {
+            let (_, rest) = unit.split_once("<key>StartInterval</key>")?;
+            let (_, rest) = rest.split_once("<integer>")?;
+            rest.split_once("</integer>")?.0.trim().parse().ok()
+        }
+    }
+}
+
+fn write_unit(path: &Path, contents: &str) -> Result<()> {
+    fs::write(path, contents).with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn remove_unit(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+            Err(e).with_context(|| format!("failed to remove {}", path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn systemctl(runner: &dyn CommandRunner, command: &[&str]) -> Result<()> {
+    let mut args = vec!["--user".to_string()];
+    args.extend(command.iter().map(|s| s.to_string()));
+    let status = runner
+        .status("systemctl", &args)
+        .with_context(|| "failed to run systemctl")?;
+    if !status.success() {
+        bail!("systemctl --user {} failed ({})", command.join(" "), status);
+    }
+    Ok(())
+}
+
+fn launchctl(runner: &dyn CommandRunner, command: &str, plist: &Path) -> Result<()> {
+    let args = vec![
+        command.to_string(),
+        "-w".to_string(),
+        plist.display().to_string(),
+    ];
+    let status = runner
+        .status("launchctl", &args)
+        .with_context(|| "failed to run launchctl")?;
+    if !status.success() {
+        bail!(
+            "launchctl {} {} failed ({})",
+            command,
+            shell_escape(&plist.display().to_string()),
+            status
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,200 @@
+use crate::{
+    gitignore_filters, lock_sync, map_to_remote, normalize_path, pull, push, remote_is_file,
+    render_tree, run_dry_run, syncignore_filters, Args, Change, DryRunSummary, RealRunner,
+    TreeView,
+};
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+
+/// Which way files move.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    /// Local to remote.
+    Push,
+    /// Remote to local.
+    Pull,
+}
+
+/// The remote side of a sync: an ssh host (alias, `user@host`, ...) and a path on it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Endpoint {
+    pub host: String,
+    pub path: String,
+}
+
+impl Endpoint {
+    pub fn new(host: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            host: host.into(),
+            path: path.into(),
+        }
+    }
+
+    /// The CLI's default mapping: a path under the local home directory lands
+    /// at the same place under the remote home (`~/src/app` -> `host:~/src/app`).
+    pub fn mirror(host: impl Into<String>, local_path: &Path) -> Result<Self> {
+        let home = dirs::home_dir().ok_or_else(|| anyhow!("unable to resolve home dir"))?;
+        let local_path = normalize_path(local_path)?;
+        Ok(Self::new(host, map_to_remote(&local_path, &home)))
+    }
+}
+
+/// Transfer options; each field matches the CLI flag of the same name.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    pub all: bool,
+    pub large: bool,
+    pub gitignore: bool,
+    pub max_size: Option<String>,
+    pub backup: bool,
+    pub no_perms: bool,
+    pub checksum: bool,
+    /// Delete destination files missing from the source, without asking.
+    pub delete: bool,
+    pub bwlimit: Option<String>,
+    pub no_multiplex: bool,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    /// Extra flags passed verbatim to rsync.
+    pub rsync_args: Vec<String>,
+}
+
+/// One changed path from a dry run.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileChange {
+    pub path: String,
+    /// rsync's itemize code, e.g. `>f.st......`.
+    pub change: String,
+    pub size: Option<u64>,
+}
+
+/// What a sync would do.
+#[derive(Clone, Debug)]
+pub struct DryRunReport {
+    pub files: Vec<FileChange>,
+    pub deletions: Vec<String>,
+    pub transferred_bytes: Option<u64>,
+    /// The changes rendered as the tree the CLI prints.
+    pub tree: String,
+}
+
+impl From<DryRunSummary> for DryRunReport {
+    fn from(summary: DryRunSummary) -> Self {
+        let changes: Vec<Change> = summary
+            .changes
+            .into_iter()
+            .filter(|c| !c.is_deletion())
+            .collect();
+        Self {
+            tree: render_tree(&changes, TreeView::default()),
+            files: changes
+                .into_iter()
+                .map(|c| FileChange {
+                    path: c.path,
+                    change: c.code,
+                    size: c.size,
+                })
+                .collect(),
+            deletions: summary.deletions,
+            transferred_bytes: summary.transferred_bytes,
+        }
+    }
+}
+
+/// A local path paired with a remote endpoint. Transfers report progress on
+/// the terminal like the CLI does, but never prompt.
+pub struct SyncSession {
+    local_path: PathBuf,
+    endpoint: Endpoint,
+    options: SyncOptions,
+}
+
+impl SyncSession {
+    pub fn new(
+        local_path: impl AsRef<Path>,
+        endpoint: Endpoint,
+        options: SyncOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            local_path: normalize_path(local_path.as_ref())?,
+            endpoint,
+            options,
+        })
+    }
+
+    pub fn local_path(&self) -> &Path {
+        &self.local_path
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    pub fn dry_run(&self, direction: Direction) -> Result<DryRunReport> {
+        let args = self.args(direction)?;
+        let pulling = direction == Direction::Pull;
+        let is_file = if pulling {
+            remote_is_file(&RealRunner, &self.endpoint.host, &self.endpoint.path, &args)?
+        } else {
+            self.local_path.is_file()
+        };
+        let summary = run_dry_run(
+            &RealRunner,
+            &self.endpoint.host,
+            &self.local_path,
+            &self.endpoint.path,
+            is_file,
+            &args,
+            pulling,
+            None,
+        )?;
+        Ok(summary.into())
+    }
+
+    pub fn push(&self) -> Result<()> {
+        self.sync(Direction::Push)
+    }
+
+    pub fn pull(&self) -> Result<()> {
+        self.sync(Direction::Pull)
+    }
+
+    pub fn sync(&self, direction: Direction) -> Result<()> {
+        let args = self.args(direction)?;
+        let (host, remote) = (&self.endpoint.host, &self.endpoint.path);
+        let _lock = lock_sync(host, &self.local_path, &args)?;
+        match direction {
+            Direction::Push => push(&RealRunner, host, &self.local_path, remote, &args, ""),
+            Direction::Pull => pull(&RealRunner, host, &self.local_path, remote, &args, ""),
+        }
+    }
+
+    pub(crate) fn args(&self, direction: Direction) -> Result<Args> {
+        let o = &self.options;
+        let mut args = Args {
+            push: direction == Direction::Push,
+            pull: direction == Direction::Pull,
+            all: o.all,
+            large: o.large,
+            max_size: o.max_size.clone(),
+            backup: o.backup,
+            no_perms: o.no_perms,
+            checksum: o.checksum,
+            delete: o.delete,
+            bwlimit: o.bwlimit.clone(),
+            no_multiplex: o.no_multiplex,
+            exclude: o.exclude.clone(),
+            include: o.include.clone(),
+            rsync_args: o.rsync_args.clone(),
+            yes: true,
+            non_interactive: true,
+            ..Default::default()
+        };
+        args.filters = syncignore_filters(&self.local_path)?;
+        if o.gitignore && self.local_path.is_dir() {
+            args.filters
+                .extend(gitignore_filters(&self.local_path, o.all)?);
+        }
+        Ok(args)
+    }
+}
@@ -0,0 +1,673 @@
+//! `--backend sftp`: whole-file copies over SFTP with libssh2, for remotes
+//! that have an sftp server but no rsync (appliances, containers). `ssh -G`
+//! resolves the connection settings, so ssh_config aliases, ports, users,
+//! keys and jump hosts keep working; logins go through ssh-agent or the
+//! configured keys, and the host key is checked against known_hosts.
+
+use crate::{
+    emit, expand_path, format_size, ssh_args, Args, ByteSize, CommandRunner, DEFAULT_EXCLUDES,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::json;
+use ssh2::{CheckResult, FileStat, KnownHostFileKind, Session, Sftp};
+use std::fs;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::os::fd::OwnedFd;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+// Buffer for uploads; libssh2 pipelines a large write into many packets.
+const CHUNK: usize = 256 << 10;
+
+// OpenSSH's order, so the key offered is the one known_hosts most likely has.
+const HOST_KEY_TYPES: &str = "ssh-ed25519,ecdsa-sha2-nistp256,ecdsa-sha2-nistp384,ecdsa-sha2-nistp521,rsa-sha2-512,rsa-sha2-256,ssh-rsa";
+
+// Features built on rsync's dry runs or filters that sftp can't provide.
+pub(crate) fn unsupported(args: &Args) -> Option<&'static str> {
+    if args.delete {
+        Some("--delete")
+    } else if args.both {
+        Some("--both")
+    } else if args.status {
+        Some("status")
+    } else if args.undo {
+        Some("undo")
+    } else if args.snapshot {
+        Some("snapshot")
+    } else if args.watch {
+        Some("--watch")
+    } else if args.remote_sudo {
+        Some("--remote-sudo")
+    } else if args.select {
+        Some("--select")
+    } else if args.interactive {
+        Some("--interactive")
+    } else if args.since.is_some() {
+        Some("--since")
+    } else if args.git_tracked {
+        Some("--git-tracked")
+    } else if args.listed_files.is_some() {
+        Some("--files-from (or several paths)")
+    } else if args.parallel.is_some() {
+        Some("--parallel")
+    } else if args.max_total_size.is_some() {
+        Some("--max-total-size")
+    } else if args.verify {
+        Some("--verify")
+    } else {
+        None
+    }
+}
+
+// A file or directory to upload, relative to the synced directory.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct LocalEntry {
+    pub(crate) path: String,
+    pub(crate) is_dir: bool,
+    pub(crate) size: u64,
+}
+
+pub(crate) fn push(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let remote = sftp_path(remote_path);
+    let entries = if local_path.is_dir() {
+        local_entries(local_path, args)?
+    } else {
+        Vec::new()
+    };
+    let files = entries.iter().filter(|e| !e.is_dir).count().max(1);
+    let bytes: u64 = if local_path.is_dir() {
+        entries.iter().map(|e| e.size).sum()
+    } else {
+        fs::metadata(local_path)
+            .with_context(|| format!("failed to read {}", local_path.display()))?
+            .len()
+    };
+
+    if args.dry_run {
+        if args.json {
+            let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+            emit(json!({
+                "event": "dry_run",
+                "direction": "push",
+                "host": host,
+                "files": paths,
+                "transferred_bytes": bytes,
+            }));
+        } else {
+            println!("Would upload to {}:{} via sftp:", host, remote_path);
+            for entry in entries.iter().filter(|e| !e.is_dir) {
+                println!("  {}  ({})", entry.path, format_size(entry.size));
+            }
+            println!("{} file(s), {}", files, format_size(bytes));
+        }
+        return Ok(());
+    }
+
+    let connection = connect(runner, host, args)?;
+    for step in push_plan(local_path, &remote, &entries) {
+        match step {
+            Step::Mkdir(dir) => {
+                let dir = Path::new(&dir);
+                if connection.sftp.stat(dir).is_err() {
+                    connection.sftp.mkdir(dir, 0o755).with_context(|| {
+                        format!("failed to create {} on {}", dir.display(), host)
+                    })?;
+                }
+            }
+            Step::Put(source, target) => upload(&connection.sftp, &source, &target)
+                .with_context(|| format!("failed to upload {} to {}", source.display(), host))?,
+        }
+    }
+    if args.json {
+        emit(json!({
+            "event": "transfer",
+            "direction": "push",
+            "host": host,
+            "backend": "sftp",
+            "files": files,
+            "sent_bytes": bytes,
+        }));
+    } else {
+        println!(
+            "Uploaded {} file(s) ({}) to {} via sftp",
+            files,
+            format_size(bytes),
+            host
+        );
+    }
+    Ok(())
+}
+
+// Downloads into a scratch path next to `local_path`, then moves the files
+// into place, so a failed pull doesn't leave half-written files behind.
+pub(crate) fn pull(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    if args.dry_run {
+        bail!("--dry-run isn't available for pulls with --backend sftp");
+    }
+    let parent = local_path
+        .parent()
+        .ok_or_else(|| anyhow!("unable to resolve local parent"))?;
+    fs::create_dir_all(parent).with_context(|| format!("failed to create {}", parent.display()))?;
+    let scratch = parent.join(format!(
+        ".syncz-sftp-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+    let connection = connect(runner, host, args)?;
+    let remote = PathBuf::from(sftp_path(remote_path));
+    let result = connection
+        .sftp
+        .stat(&remote)
+        .with_context(|| format!("{}:{} doesn't exist", host, remote_path))
+        .and_then(|stat| download(&connection.sftp, &remote, &stat, &scratch))
+        .and_then(|()| merge_into(&scratch, local_path));
+    let _ = fs::remove_dir_all(&scratch);
+    let _ = fs::remove_file(&scratch);
+    let moved = result?;
+    if args.json {
+        emit(json!({
+            "event": "transfer",
+            "direction": "pull",
+            "host": host,
+            "backend": "sftp",
+            "files": moved,
+        }));
+    } else {
+        println!("Downloaded {} file(s) from {} via sftp", moved, host);
+    }
+    Ok(())
+}
+
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+
+// An open SFTP channel on a logged-in session.
+struct Connection {
+    sftp: Sftp,
+    _ssh: SshSession,
+}
+
+// A logged-in ssh session, and the jump host command carrying it, if any.
+pub(crate) struct SshSession {
+    pub(crate) session: Session,
+    proxy: Option<Child>,
+}
+
+impl Drop for SshSession {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.proxy {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+// What `ssh -G` resolved for a host: where to connect and how to log in.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct Endpoint {
+    pub(crate) hostname: String,
+    pub(crate) port: u16,
+    pub(crate) user: String,
+    pub(crate) identity_files: Vec<String>,
+    pub(crate) known_hosts: Vec<String>,
+    pub(crate) host_key_alias: Option<String>,
+    pub(crate) proxy_jump: Option<String>,
+    pub(crate) proxy_command: Option<String>,
+    pub(crate) connect_timeout: Option<u64>,
+}
+
+// `ssh -G` prints one lowercase `key value` per line; `none` means unset.
+pub(crate) fn parse_endpoint(stdout: &str) -> Result<Endpoint> {
+    let mut endpoint = Endpoint {
+        port: 22,
+        ..Default::default()
+    };
+    for line in stdout.lines() {
+        let Some((key, value)) = line.split_once(' ') else {
+            continue;
+        };
+        let value = value.trim();
+        let set = (value != "none").then(|| value.to_string());
+        match key {
+            "hostname" => endpoint.hostname = value.to_string(),
+            "port" => {
+                endpoint.port = value
+                    .parse()
+                    .with_context(|| format!("bad port {} from ssh -G", value))?
+            }
+            "user" => endpoint.user = value.to_string(),
+            "identityfile" => endpoint.identity_files.push(value.to_string()),
+            "userknownhostsfile" => endpoint
+                .known_hosts
+                .extend(value.split_whitespace().map(str::to_string)),
+            "hostkeyalias" => endpoint.host_key_alias = set,
+            "proxyjump" => endpoint.proxy_jump = set,
+            "proxycommand" => endpoint.proxy_command = set,
+            "connecttimeout" => endpoint.connect_timeout = value.parse().ok(),
+            _ => {}
+        }
+    }
+    if endpoint.hostname.is_empty() || endpoint.user.is_empty() {
+        bail!("ssh -G printed no hostname or user");
+    }
+    Ok(endpoint)
+}
+
+fn resolve(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<Endpoint> {
+    let mut cmd_args = ssh_args(args);
+    cmd_args.extend(["-G".to_string(), host.to_string()]);
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh -G")?;
+    if !output.status.success() {
+        bail!(
+            "ssh -G {} failed: {}",
+            host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    parse_endpoint(&String::from_utf8_lossy(&output.stdout))
+}
+
+// The command that carries the connection when ssh would go through a jump
+// host or a ProxyCommand: its stdin and stdout become the socket.
+pub(crate) fn proxy_command(endpoint: &Endpoint) -> Option<Vec<String>> {
+    if let Some(command) = &endpoint.proxy_command {
+        let command = command
+            .replace("%%", "\0")
+            .replace("%h", &endpoint.hostname)
+            .replace("%p", &endpoint.port.to_string())
+            .replace("%r", &endpoint.user)
+            .replace('\0', "%");
+        return Some(vec!["sh".to_string(), "-c".to_string(), command]);
+    }
+    let jumps = endpoint.proxy_jump.as_ref()?;
+    let (earlier, last) = match jumps.rsplit_once(',') {
+        Some((earlier, last)) => (Some(earlier), last),
+        None => (None, jumps.as_str()),
+    };
+    let mut command = vec![
+        "ssh".to_string(),
+        "-W".to_string(),
+        format!("{}:{}", endpoint.hostname, endpoint.port),
+    ];
+    if let Some(earlier) = earlier {
+        command.extend(["-J".to_string(), earlier.to_string()]);
+    }
+    command.push(format!("ssh://{}", last));
+    Some(command)
+}
+
+fn connect(runner: &dyn CommandRunner, host: &str, args: &Args) -> Result<Connection> {
+    let ssh = open_session(runner, host, args)?;
+    let sftp = ssh
+        .session
+        .sftp()
+        .with_context(|| format!("{} has no sftp server", host))?;
+    Ok(Connection { sftp, _ssh: ssh })
+}
+
+pub(crate) fn open_session(
+    runner: &dyn CommandRunner,
+    host: &str,
+    args: &Args,
+) -> Result<SshSession> {
+    let endpoint = resolve(runner, host, args)?;
+    let timeout = args
+        .timeout
+        .or(endpoint.connect_timeout)
+        .map(Duration::from_secs);
+    let mut session = Session::new().with_context(|| "failed to start an ssh session")?;
+    let mut proxy = None;
+    match proxy_command(&endpoint) {
+        Some(command) => {
+            let (ours, theirs) = UnixStream::pair()?;
+            let child = Command::new(&command[0])
+                .args(&command[1..])
+                .stdin(Stdio::from(OwnedFd::from(theirs.try_clone()?)))
+                .stdout(Stdio::from(OwnedFd::from(theirs)))
+                .spawn()
+                .with_context(|| format!("failed to run {}", command[0]))?;
+            proxy = Some(child);
+            session.set_tcp_stream(ours);
+        }
+        None => {
+            let addr = (endpoint.hostname.as_str(), endpoint.port)
+                .to_socket_addrs()
+                .ok()
+                .and_then(|mut addrs| addrs.next())
+                .ok_or_else(|| anyhow!("can't resolve {} ({})", host, endpoint.hostname))?;
+            let stream = match timeout {
+                Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+                None => TcpStream::connect(addr),
+            }
+            .with_context(|| format!("can't reach {} at {}", host, addr))?;
+            session.set_tcp_stream(stream);
+        }
+    }
+    if let Some(timeout) = args.timeout {
+        session.set_timeout((timeout * 1000).min(u32::MAX as u64) as u32);
+    }
+    let _ = session.method_pref(ssh2::MethodType::HostKey, HOST_KEY_TYPES);
+    session
+        .handshake()
+        .with_context(|| format!("ssh handshake with {} failed", host))?;
+    check_host_key(&session, host, &endpoint)?;
+    authenticate(&session, host, &endpoint)?;
+    Ok(SshSession { session, proxy })
+}
+
+// Only hosts already in known_hosts: there is no prompt to accept a new key.
+fn check_host_key(session: &Session, host: &str, endpoint: &Endpoint) -> Result<()> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("{} sent no host key", host))?;
+    let mut known = session.known_hosts()?;
+    for file in &endpoint.known_hosts {
+        let path = expand_path(file)?;
+        if path.is_file() {
+            // Lines libssh2 can't parse (certificate authorities) are skipped.
+            let _ = known.read_file(&path, KnownHostFileKind::OpenSSH);
+        }
+    }
+    let name = endpoint
+        .host_key_alias
+        .as_deref()
+        .unwrap_or(&endpoint.hostname);
+    match known.check_port(name, endpoint.port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(
+            "the host key of {} doesn't match the one in known_hosts; refusing to connect",
+            host
+        ),
+        CheckResult::NotFound => bail!(
+            "{} isn't in known_hosts; connect once with `ssh {}` to check and accept its key",
+            host,
+            host
+        ),
+        CheckResult::Failure => bail!("couldn't check the host key of {}", host),
+    }
+}
+
+fn authenticate(session: &Session, host: &str, endpoint: &Endpoint) -> Result<()> {
+    let user = &endpoint.user;
+    if std::env::var_os("SSH_AUTH_SOCK").is_some() {
+        let _ = session.userauth_agent(user);
+    }
+    for file in &endpoint.identity_files {
+        if session.authenticated() {
+            break;
+        }
+        let key = expand_path(file)?;
+        if key.is_file() {
+            let _ = session.userauth_pubkey_file(user, None, &key, None);
+        }
+    }
+    if !session.authenticated() {
+        bail!(
+            "authentication to {} as {} failed; syncz logs in with ssh-agent or a key without a passphrase",
+            host,
+            user
+        );
+    }
+    Ok(())
+}
+
+// Like `put -p`: the mode and modification time come along.
+fn upload(sftp: &Sftp, source: &Path, target: &str) -> Result<()> {
+    let meta = fs::metadata(source)?;
+    let mut file = fs::File::open(source)?;
+    let remote = sftp.create(Path::new(target))?;
+    let mut writer = io::BufWriter::with_capacity(CHUNK, remote);
+    io::copy(&mut file, &mut writer)?;
+    writer.flush()?;
+    drop(writer);
+    let mtime = meta
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs());
+    sftp.setstat(
+        Path::new(target),
+        FileStat {
+            size: None,
+            uid: None,
+            gid: None,
+            perm: Some(meta.permissions().mode() & 0o7777),
+            atime: mtime,
+            mtime,
+        },
+    )?;
+    Ok(())
+}
+
+// Copies a remote file, or a directory recursively, to `local`. Symlinks to
+// files are followed; symlinks to directories are skipped, as on push.
+fn download(sftp: &Sftp, remote: &Path, stat: &FileStat, local: &Path) -> Result<()> {
+    if stat.is_dir() {
+        fs::create_dir_all(local)
+            .with_context(|| format!("failed to create {}", local.display()))?;
+        for (path, mut entry) in sftp.readdir(remote)? {
+            if entry.file_type().is_symlink() {
+                match sftp.stat(&path) {
+                    Ok(target) if !target.is_dir() => entry = target,
+                    _ => continue,
+                }
+            }
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            download(sftp, &path, &entry, &local.join(name))?;
+        }
+        return Ok(());
+    }
+    let mut file = sftp
+        .open(remote)
+        .with_context(|| format!("failed to open {}", remote.display()))?;
+    let mut out =
+        fs::File::create(local).with_context(|| format!("failed to write {}", local.display()))?;
+    io::copy(&mut file, &mut out)
+        .with_context(|| format!("failed to download {}", remote.display()))?;
+    if let Some(mtime) = stat.mtime {
+        let _ = out.set_modified(UNIX_EPOCH + Duration::from_secs(mtime));
+    }
+    if let Some(perm) = stat.perm {
+        let _ = fs::set_permissions(local, fs::Permissions::from_mode(perm & 0o7777));
+    }
+    Ok(())
+}
+
+// sftp paths are relative to the login directory rather than `~`-prefixed.
+pub(crate) fn sftp_path(remote_path: &str) -> String {
+    match remote_path.strip_prefix('~') {
+        Some("") => ".".to_string(),
+        Some(rest) if rest.starts_with('/') => rest.trim_start_matches('/').to_string(),
+        _ => remote_path.to_string(),
+    }
+}
+
+// One step of a push; directories are created unless they already exist.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Step {
+    Mkdir(String),
+    Put(PathBuf, String),
+}
+
+// The steps for a push: create the remote directory chain, then upload every
+// entry.
+pub(crate) fn push_plan(local_path: &Path, remote: &str, entries: &[LocalEntry]) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let is_dir = local_path.is_dir();
+    let dir_chain = if is_dir {
+        remote
+    } else {
+        remote.rsplit_once('/').map_or("", |(parent, _)| parent)
+    };
+    let mut prefix = String::new();
+    for part in dir_chain.split('/') {
+        if part.is_empty() {
+            if prefix.is_empty() {
+                prefix.push('/');
+            }
+            continue;
+        }
+        if !prefix.is_empty() && !prefix.ends_with('/') {
+            prefix.push('/');
+        }
+        prefix.push_str(part);
+        if part != "." {
+            steps.push(Step::Mkdir(prefix.clone()));
+        }
+    }
+    if !is_dir {
+        steps.push(Step::Put(local_path.to_path_buf(), remote.to_string()));
+    }
+    for entry in entries {
+        let target = format!("{}/{}", remote.trim_end_matches('/'), entry.path);
+        if entry.is_dir {
+            steps.push(Step::Mkdir(target));
+        } else {
+            steps.push(Step::Put(local_path.join(&entry.path), target));
+        }
+    }
+    steps
+}
+
+// Walks the directory in sorted order, skipping what the rsync backend
+// would exclude by default or via --exclude (unless --include'd) and files
+// over the size limit. Directory symlinks are not followed.
+pub(crate) fn local_entries(root: &Path, args: &Args) -> Result<Vec<LocalEntry>> {
+    let max_size = max_size(args)?;
+    let mut entries = Vec::new();
+    let mut stack = vec![PathBuf::new()];
+    while let Some(rel_dir) = stack.pop() {
+        let dir = root.join(&rel_dir);
+        let mut children: Vec<_> = fs::read_dir(&dir)
+            .with_context(|| format!("failed to read {}", dir.display()))?
+            .filter_map(|e| e.ok())
+            .collect();
+        children.sort_by_key(|e| std::cmp::Reverse(e.file_name()));
+        for child in children {
+            let rel = rel_dir.join(child.file_name());
+            let rel_str = rel.to_string_lossy().replace('\\', "/");
+            let Ok(meta) = fs::metadata(child.path()) else {
+                continue;
+            };
+            let is_dir = meta.is_dir();
+            if is_excluded(&rel_str, is_dir, args) {
+                continue;
+            }
+            if is_dir {
+                if child.file_type().map(|t| t.is_symlink()).unwrap_or(false) {
+                    continue;
+                }
+                stack.push(rel);
+            } else if max_size.is_some_and(|max| meta.len() > max) {
+                continue;
+            }
+            entries.push(LocalEntry {
+                path: rel_str,
+                is_dir,
+                size: if is_dir { 0 } else { meta.len() },
+            });
+        }
+    }
+    entries.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(entries)
+}
+
+// The per-file limit the rsync backend would pass as --max-size.
+pub(crate) fn max_size(args: &Args) -> Result<Option<u64>> {
+    Ok(match &args.max_size {
+        Some(size) => Some(size.parse::<ByteSize>()?.0),
+        None if !args.large && !args.all => Some(10 << 20),
+        None => None,
+    })
+}
+
+pub(crate) fn is_excluded(rel: &str, is_dir: bool, args: &Args) -> bool {
+    if args.include.iter().any(|p| matches_pattern(p, rel, is_dir)) {
+        return false;
+    }
+    let defaults = if args.all { &[][..] } else { DEFAULT_EXCLUDES };
+    defaults
+        .iter()
+        .copied()
+        .chain(args.exclude.iter().map(String::as_str))
+        .any(|p| matches_pattern(p, rel, is_dir))
+        || (!is_dir && !args.only.is_empty() && !args.only.iter().any(|p| matches_only(p, rel)))
+}
+
+// A directory pattern given to --only takes in everything below it.
+fn matches_only(pattern: &str, rel: &str) -> bool {
+    if pattern.ends_with('/') {
+        rel.match_indices('/')
+            .any(|(i, _)| matches_pattern(pattern, &rel[..i], true))
+    } else {
+        matches_pattern(pattern, rel, false)
+    }
+}
+
+// rsync-style patterns: a trailing `/` only matches directories, a pattern
+// with a `/` matches the whole relative path, otherwise the name alone.
+fn matches_pattern(pattern: &str, rel: &str, is_dir: bool) -> bool {
+    let dir_only = pattern.ends_with('/');
+    if dir_only && !is_dir {
+        return false;
+    }
+    let pattern = pattern.trim_end_matches('/');
+    let Ok(glob) = glob::Pattern::new(pattern.trim_start_matches('/')) else {
+        return false;
+    };
+    if pattern.contains('/') {
+        glob.matches(rel)
+    } else {
+        glob.matches(rel.rsplit('/').next().unwrap_or(rel))
+    }
+}
+
+// Moves downloaded files over the local copy, creating directories as
+// needed. Returns how many files were moved.
+fn merge_into(scratch: &Path, local_path: &Path) -> Result<usize> {
+    if scratch.is_file() {
+        fs::rename(scratch, local_path)
+            .with_context(|| format!("failed to update {}", local_path.display()))?;
+        return Ok(1);
+    }
+    let mut moved = 0;
+    let mut stack = vec![PathBuf::new()];
+    while let Some(rel_dir) = stack.pop() {
+        let target_dir = local_path.join(&rel_dir);
+        fs::create_dir_all(&target_dir)
+            .with_context(|| format!("failed to create {}", target_dir.display()))?;
+        for entry in fs::read_dir(scratch.join(&rel_dir))? {
+            let entry = entry?;
+            let rel = rel_dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                stack.push(rel);
+            } else {
+                let target = local_path.join(&rel);
+                fs::rename(entry.path(), &target)
+                    .with_context(|| format!("failed to update {}", target.display()))?;
+                moved += 1;
+            }
+        }
+    }
+    Ok(moved)
+}
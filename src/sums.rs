@@ -0,0 +1,187 @@
+//! `syncz manifest` and `syncz verify`: SHA-256 checksums of a directory's
+//! files, kept next to its remote copy in `sha256sum` format, and a later
+//! check of the remote against them. The check runs entirely on the remote,
+//! so finding bit rot or tampering in an archive doesn't download it.
+
+use crate::sftp::local_entries;
+use crate::{
+    emit, ensure_remote_parent, privileged, remote_shell_path, run_rsync, shell_escape, ssh_args,
+    Args, CommandRunner,
+};
+use anyhow::{bail, Context, Result};
+use serde_json::json;
+use std::fs;
+use std::path::Path;
+
+pub(crate) const SUMS_FILE: &str = ".syncz-sha256sums";
+
+// sha256sum where there is one, otherwise macOS's shasum, which reads and
+// writes the same format.
+pub(crate) const SHA256: &str = "sha() { if command -v sha256sum >/dev/null 2>&1; then sha256sum \"$@\"; else shasum -a 256 \"$@\"; fi; }";
+
+// Files per sha256sum invocation, to stay under the argument size limit.
+const BATCH: usize = 500;
+
+pub(crate) fn write_manifest(
+    runner: &dyn CommandRunner,
+    host: &str,
+    local_path: &Path,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    if !local_path.is_dir() {
+        bail!(
+            "a manifest covers a directory, got {}",
+            local_path.display()
+        );
+    }
+    let files: Vec<String> = local_entries(local_path, args)?
+        .into_iter()
+        .filter(|e| !e.is_dir && e.path != SUMS_FILE)
+        .map(|e| e.path)
+        .collect();
+    let mut sums = String::new();
+    for batch in files.chunks(BATCH) {
+        sums.push_str(&local_sums(runner, local_path, batch)?);
+    }
+    let target = format!("{}/{}", remote_path.trim_end_matches('/'), SUMS_FILE);
+    if args.dry_run {
+        print!("{}", sums);
+        return Ok(());
+    }
+
+    let dir = std::env::temp_dir().join(format!("syncz-sums-{}", std::process::id()));
+    fs::create_dir_all(&dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let copy = dir.join(SUMS_FILE);
+    let result = fs::write(&copy, &sums)
+        .with_context(|| format!("failed to write {}", copy.display()))
+        .and_then(|()| ensure_remote_parent(runner, host, remote_path, args))
+        .and_then(|()| {
+            let upload = Args {
+                all: true,
+                ..args.clone()
+            };
+            run_rsync(host, &copy, &target, true, &upload, false, None)
+        });
+    let _ = fs::remove_dir_all(&dir);
+    result?;
+    if args.json {
+        emit(json!({
+            "event": "manifest",
+            "host": host,
+            "path": target,
+            "files": files.len(),
+        }));
+    } else {
+        println!(
+            "📜 Recorded SHA-256 checksums of {} file(s) in {}:{}",
+            files.len(),
+            host,
+            target
+        );
+    }
+    Ok(())
+}
+
+fn local_sums(runner: &dyn CommandRunner, root: &Path, files: &[String]) -> Result<String> {
+    let mut cmd_args = vec![
+        "-c".to_string(),
+        format!("{}; cd \"$1\" && shift && sha -- \"$@\"", SHA256),
+        "sh".to_string(),
+        root.to_string_lossy().to_string(),
+    ];
+    cmd_args.extend(files.iter().cloned());
+    let output = runner
+        .output("sh", &cmd_args)
+        .with_context(|| "failed to run sha256sum")?;
+    if !output.status.success() {
+        bail!(
+            "sha256sum failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub(crate) fn verify(
+    runner: &dyn CommandRunner,
+    host: &str,
+    remote_path: &str,
+    args: &Args,
+) -> Result<()> {
+    let script = format!(
+        "{}; cd {} && test -f {} || exit 3; sha -c --quiet {}",
+        SHA256,
+        remote_shell_path(remote_path),
+        SUMS_FILE,
+        SUMS_FILE
+    );
+    let mut cmd_args = ssh_args(args);
+    cmd_args.push(host.to_string());
+    cmd_args.push(privileged(
+        args,
+        &format!("sh -c {}", shell_escape(&script)),
+    ));
+    let output = runner
+        .output("ssh", &cmd_args)
+        .with_context(|| "failed to run ssh")?;
+    if output.status.code() == Some(3) {
+        bail!(
+            "no checksums at {}:{}; record them with `syncz manifest`",
+            host,
+            remote_path
+        );
+    }
+    let (changed, missing) = parse_check(&String::from_utf8_lossy(&output.stdout));
+    if !output.status.success() && changed.is_empty() && missing.is_empty() {
+        bail!(
+            "checksum verification on {} failed: {}",
+            host,
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    if args.json {
+        emit(json!({
+            "event": "verify",
+            "host": host,
+            "ok": changed.is_empty() && missing.is_empty(),
+            "mismatched": changed,
+            "missing": missing,
+        }));
+    } else if changed.is_empty() && missing.is_empty() {
+        println!(
+            "🔒 Every file in {}:{} matches its recorded checksum",
+            host, remote_path
+        );
+    } else {
+        for path in &changed {
+            println!("  ✗ changed  {}", path);
+        }
+        for path in &missing {
+            println!("  ✗ missing  {}", path);
+        }
+    }
+    if !changed.is_empty() || !missing.is_empty() {
+        bail!(
+            "{} file(s) on {} don't match their recorded checksums",
+            changed.len() + missing.len(),
+            host
+        );
+    }
+    Ok(())
+}
+
+// `sha256sum -c --quiet` prints only failures: `path: FAILED`, or
+// `path: FAILED open or read` for files that are gone.
+pub(crate) fn parse_check(stdout: &str) -> (Vec<String>, Vec<String>) {
+    let mut changed = Vec::new();
+    let mut missing = Vec::new();
+    for line in stdout.lines() {
+        if let Some(path) = line.strip_suffix(": FAILED open or read") {
+            missing.push(path.to_string());
+        } else if let Some(path) = line.strip_suffix(": FAILED") {
+            changed.push(path.to_string());
+        }
+    }
+    (changed, missing)
+}